@@ -1,5 +1,7 @@
 import os
+import json
 import datetime
+import gzip
 import cantools
 import click
 import capnp
@@ -15,16 +17,63 @@ capnp.remove_import_hook()
 schema = capnp.load(os.path.abspath(SCHEMA))
 db = cantools.database.load_file(DBC)
 
-def run(host, port, topic):
-    def on_connect(client, userdata, flags, rc):
+COMPRESSIONS = ('gzip', 'lz4', 'zstd')
+
+def decompress(compression, payload, dictionary):
+    if compression == 'gzip':
+        return gzip.decompress(payload)
+    if compression == 'lz4':
+        import lz4.frame
+        return lz4.frame.decompress(payload)
+    if compression == 'zstd':
+        import zstandard
+        dict_data = zstandard.ZstdCompressionDict(dictionary) if dictionary else None
+        return zstandard.ZstdDecompressor(dict_data=dict_data).decompressobj().decompress(payload)
+    return payload
+
+def can_frames(chunk):
+    """CAN frames of either chunk layout, the groups of layout 2 expanded in time order."""
+    frames = [dict(time=f.time, channel=f.channel, id=f.id, data=bytes(f.data)) for f in chunk.can]
+    for group in chunk.canGroups:
+        times = iter(group.times)
+        for data, repeats in zip(group.data, group.repeats):
+            for _ in range(repeats):
+                frames.append(dict(time=(chunk.base + next(times)) / 1e6,
+                                   channel=chunk.canChannels[group.channel], id=group.id, data=bytes(data)))
+    return sorted(frames, key=lambda f: f['time'])
+
+def run(host, port, topic, mqtt5, dictionary):
+    def on_connect(client, userdata, flags, rc, properties=None):
         print("Connected with result code "+str(rc))
         client.subscribe(topic)
 
     def on_message(client, userdata, data):
-        chunk = schema.Chunk.from_bytes_packed(data.payload)
-        print(chunk.to_dict())            
+        # MQTT 5 publishes carry the encoding as content type, 3.1.1 in the topic suffix
+        # and the compression as a user property or a second suffix like <topic>/capnp/zstd
+        content_type = getattr(data.properties, 'ContentType', None)
+        user_properties = dict(getattr(data.properties, 'UserProperty', []))
+        topic = data.topic
+        compression = user_properties.get('compression')
+        if compression is None and topic.rsplit('/', 1)[-1] in COMPRESSIONS:
+            topic, compression = topic.rsplit('/', 1)
+        payload = decompress(compression, data.payload, dictionary)
+
+        if content_type == 'application/json' or topic.endswith('/json'):
+            print(json.loads(payload))
+            return
+        if content_type is not None:
+            print(user_properties)
+        chunk = schema.Chunk.from_bytes_packed(payload)
+        decoded = chunk.to_dict()
+        decoded.pop('canChannels', None)
+        decoded.pop('canGroups', None)
+        decoded['can'] = can_frames(chunk)
+        print(decoded)            
 
-    client = mqtt.Client()
+    if mqtt5:
+        client = mqtt.Client(protocol=mqtt.MQTTv5)
+    else:
+        client = mqtt.Client()
     client.on_connect = on_connect
     client.on_message = on_message
 
@@ -36,9 +85,11 @@ def run(host, port, topic):
 @click.option('--host', default='localhost', help='mqtt broker host')
 @click.option('--port', default=1883)
 @click.option('--topic', default='hello/test/capnp')
-def main(host, port, topic):
+@click.option('--mqtt5', is_flag=True, help='connect with MQTT 5')
+@click.option('--dictionary', type=click.File('rb'), help='zstd dictionary from --train-dictionary')
+def main(host, port, topic, mqtt5, dictionary):
     """Simple program for test."""
-    run(host, port, topic)
+    run(host, port, topic, mqtt5, dictionary.read() if dictionary else None)
 
 if __name__ == '__main__':
     main()
\ No newline at end of file
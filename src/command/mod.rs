@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use log::*;
+use chrono::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc::{channel, error::TrySendError, Receiver, Sender}, watch, Mutex};
+use tokio::task;
+use tokio::time::Instant;
+
+mod request;
+
+use request::{Command, Envelope, Request, Response};
+
+use crate::config::Config;
+use crate::output::{closed_logs, log_lines, publish, Control, MqttStats};
+use crate::tasks::Tasks;
+use crate::update::Update;
+
+/// Log lines are sent in parts of about this many bytes.
+const UPLOAD_PART: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+struct LogLine {
+    time: DateTime<Utc>,
+}
+
+/// Signed commands from `<topic>/<device_id>/cmd`, answered on the request's
+/// response topic or `<topic>/<device_id>/resp`.
+pub struct CommandTask {
+    config: watch::Receiver<Config>,
+    key: Vec<u8>,
+    max_age: i64,
+    base: String,
+    seen: VecDeque<(i64, String)>,  // recent request ids, against replays
+
+    rx: Receiver<Vec<u8>>,
+    control: Sender<Control>,
+    updates: Sender<Update>,
+    tasks: Arc<Mutex<Tasks>>,
+    stats: Arc<MqttStats>,
+    started: Instant,
+}
+
+/// Send the lines of the logs at `paths` logged between `start` and `end` in
+/// parts of about `UPLOAD_PART` bytes, returns how many lines there were.
+fn log_parts(paths: Vec<PathBuf>, start: DateTime<Utc>, end: DateTime<Utc>, parts: Sender<Vec<u8>>) -> Result<usize, String> {
+    let mut lines = 0;
+    let mut part = String::new();
+    for path in paths {
+        let reader = match log_lines(&path) {
+            Ok(reader) => reader,
+            Err(_) => continue,
+        };
+        for line in reader {
+            let line = line.map_err(|e| e.to_string())?;
+            let time = match serde_json::from_str::<LogLine>(&line) {
+                Ok(line) => line.time,
+                Err(_) => continue,
+            };
+            if (time < start) | (time >= end) {
+                continue;
+            }
+
+            part.push_str(&line);
+            part.push('\n');
+            lines += 1;
+            if part.len() >= UPLOAD_PART {
+                // the upload gave up
+                if parts.blocking_send(std::mem::take(&mut part).into_bytes()).is_err() {
+                    return Ok(lines);
+                }
+            }
+        }
+    }
+    if !part.is_empty() {
+        let _ = parts.blocking_send(part.into_bytes());
+    }
+    Ok(lines)
+}
+
+/// Replace a secret in a JSON tree, if it is set.
+fn redact(value: &mut Value, path: &[&str]) {
+    let mut value = value;
+    for key in path {
+        value = match value.get_mut(*key) {
+            Some(next) => next,
+            None => return,
+        };
+    }
+    if !value.is_null() {
+        *value = json!("***");
+    }
+}
+
+/// Replace every value of a JSON object, like a table of headers.
+fn redact_values(value: &mut Value, path: &[&str]) {
+    let mut value = value;
+    for key in path {
+        value = match value.get_mut(*key) {
+            Some(next) => next,
+            None => return,
+        };
+    }
+    if let Some(values) = value.as_object_mut() {
+        for value in values.values_mut() {
+            *value = json!("***");
+        }
+    }
+}
+
+impl CommandTask {
+    pub fn new(
+        config: watch::Receiver<Config>, rx: Receiver<Vec<u8>>, control: Sender<Control>,
+        updates: Sender<Update>, tasks: Arc<Mutex<Tasks>>, stats: Arc<MqttStats>
+    ) -> Self {
+        let (command, base) = {
+            let config = config.borrow();
+            let base = format!("{}/{}", config.mqtt_config().topic, config.id());
+            (config.command_config().expect("no [command] section"), base)
+        };
+
+        CommandTask {
+            config,
+            key: command.key.into_bytes(),
+            max_age: command.max_age,
+            base,
+            seen: VecDeque::new(),
+
+            rx,
+            control,
+            updates,
+            tasks,
+            stats,
+            started: Instant::now(),
+        }
+    }
+
+    /// Topic the commands arrive on.
+    pub fn topic(&self) -> String {
+        format!("{}/cmd", self.base)
+    }
+
+    async fn publish(&self, topic: &str, data: Vec<u8>) -> Result<(), String> {
+        publish(&self.control, topic, data).await
+            .map_err(|e| format!("publish on {}: {}", topic, e))
+    }
+
+    /// Refuse stale requests and ones we have already run.
+    fn fresh(&mut self, request: &Request) -> bool {
+        let now = Utc::now().timestamp();
+        while let Some((time, _)) = self.seen.front() {
+            if *time >= now - self.max_age {
+                break;
+            }
+            self.seen.pop_front();
+        }
+
+        if (request.time - now).abs() > self.max_age {
+            return false;
+        }
+        if self.seen.iter().any(|(_, id)| *id == request.id) {
+            return false;
+        }
+        self.seen.push_back((request.time, request.id.clone()));
+        true
+    }
+
+    async fn status(&self) -> Value {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime": self.started.elapsed().as_secs(),
+            "delivered": self.stats.delivered.load(Ordering::Relaxed),
+            "failed": self.stats.failed.load(Ordering::Relaxed),
+            "tasks": self.tasks.lock().await.status(),
+        })
+    }
+
+    fn get_config(&self) -> Result<Value, String> {
+        let mut config = serde_json::to_value(&*self.config.borrow()).map_err(|e| e.to_string())?;
+        redact(&mut config, &["mqtt", "password"]);
+        redact(&mut config, &["mqtt", "tls", "key_password"]);
+        redact(&mut config, &["command", "key"]);
+        redact(&mut config, &["upload", "secret_key"]);
+        // websocket headers carry tokens like Authorization
+        redact_values(&mut config, &["mqtt", "websocket", "headers"]);
+        Ok(config)
+    }
+
+    /// Hand a config to the updater. Waiting for the broker can take up to
+    /// `rollback_timeout`, the outcome is reported on `<base>/config`.
+    fn apply_config(&self, id: &str, config: Value) -> Result<Value, String> {
+        let config: Config = serde_json::from_value(config).map_err(|e| e.to_string())?;
+        config.validate()?;
+
+        let source = format!("command {}", id);
+        match self.updates.try_send(Update { config, source }) {
+            Ok(()) => Ok(json!({ "accepted": true, "report": format!("{}/config", self.base) })),
+            Err(TrySendError::Full(_)) => Err("too many updates waiting".to_string()),
+            Err(TrySendError::Closed(_)) => Err("updater is gone".to_string()),
+        }
+    }
+
+    /// Replace one section of the running config.
+    fn set_config(&self, id: &str, section: &str, value: Value) -> Result<Value, String> {
+        let mut config = serde_json::to_value(&*self.config.borrow()).map_err(|e| e.to_string())?;
+        match config.get_mut(section) {
+            Some(current) => *current = value,
+            None => return Err(format!("unknown section {}", section)),
+        }
+        self.apply_config(id, config)
+    }
+
+    /// Publish the logged chunks between `start` and `end` to `<base>/log/<id>`.
+    async fn upload_log(&self, id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Value, String> {
+        let log = self.config.borrow().log_config();
+        // time rotated files outside the window are not even opened
+        let mut paths: Vec<PathBuf> = closed_logs(Path::new(&log.path)).unwrap_or_default()
+            .into_iter()
+            .filter(|closed| closed.overlaps(start, end))
+            .map(|closed| closed.path)
+            .collect();
+        paths.push(PathBuf::from(&log.path));
+
+        let topic = format!("{}/log/{}", self.base, id);
+        // decompressing blocks, the parts come back as they fill
+        let (tx, mut rx) = channel(2);
+        let reader = task::spawn_blocking(move || log_parts(paths, start, end, tx));
+        let mut parts = 0;
+        while let Some(part) = rx.recv().await {
+            self.publish(&topic, part).await?;
+            parts += 1;
+        }
+        let lines = reader.await.map_err(|e| e.to_string())??;
+
+        Ok(json!({ "topic": topic, "parts": parts, "lines": lines }))
+    }
+
+    async fn execute(&mut self, request: Request) -> Response {
+        let id = request.id.clone();
+        let result = match request.command {
+            Command::PING => Ok(json!("pong")),
+            Command::STATUS => Ok(self.status().await),
+            Command::GET_CONFIG => self.get_config(),
+            Command::SET_CONFIG { section, value } => self.set_config(&id, &section, value),
+            Command::APPLY_CONFIG { config } => self.apply_config(&id, config),
+            Command::RESTART { task } => {
+                match self.tasks.lock().await.restart(&task).await {
+                    Ok(()) => Ok(Value::Null),
+                    Err(e) => Err(format!("{}: {}", task, e)),
+                }
+            },
+            Command::FLUSH => {
+                match self.control.send(Control::Flush).await {
+                    Ok(()) => Ok(Value::Null),
+                    Err(_) => Err("output is gone".to_string()),
+                }
+            },
+            Command::UPLOAD_LOG { start, end } => self.upload_log(&id, start, end).await,
+        };
+
+        match result {
+            Ok(result) => Response::ok(&id, result),
+            Err(e) => Response::error(&id, &e),
+        }
+    }
+
+    async fn handle(&mut self, payload: &[u8]) {
+        let envelope: Envelope = match serde_json::from_slice(payload) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("malformed command: {}", e);
+                return;
+            }
+        };
+        // nothing goes back to senders we can't authenticate
+        let request = match envelope.open(&self.key) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("refused command: {}", e);
+                return;
+            }
+        };
+
+        let topic = request.response_topic.clone()
+            .unwrap_or_else(|| format!("{}/resp", self.base));
+        let response = match self.fresh(&request) {
+            true => self.execute(request).await,
+            false => Response::error(&request.id, "expired or replayed"),
+        };
+
+        if let Err(e) = self.publish(&topic, serde_json::to_vec(&response).unwrap()).await {
+            error!("dropped response: {}", e);
+        }
+    }
+
+    pub async fn run(mut self) {
+        while let Some(payload) = self.rx.recv().await {
+            self.handle(&payload).await;
+        }
+    }
+}
+
+#[test]
+fn test_redact() {
+    let mut config = json!({
+        "mqtt": { "password": "secret", "tls": null, "websocket": { "headers": { "Authorization": "Bearer t", "X-Api-Key": "k" } } },
+        "command": { "key": "k" },
+    });
+    redact(&mut config, &["mqtt", "password"]);
+    redact(&mut config, &["mqtt", "tls", "key_password"]);
+    redact(&mut config, &["command", "key"]);
+    redact_values(&mut config, &["mqtt", "websocket", "headers"]);
+    redact_values(&mut config, &["upload", "headers"]);
+    assert_eq!(config, json!({
+        "mqtt": { "password": "***", "tls": null, "websocket": { "headers": { "Authorization": "***", "X-Api-Key": "***" } } },
+        "command": { "key": "***" },
+    }));
+}
+
+#[tokio::test]
+async fn test_log_parts() {
+    let path = std::env::temp_dir().join(format!("iot-edge-parts-{}.log", std::process::id()));
+    std::fs::write(&path, concat!(
+        "{\"time\":\"2022-04-15T10:00:00Z\"}\n",
+        "{\"time\":\"2022-04-15T11:00:00Z\"}\n",
+        "not a chunk\n",
+        "{\"time\":\"2022-04-15T12:00:00Z\"}\n",
+    )).unwrap();
+
+    let start = Utc.with_ymd_and_hms(2022, 4, 15, 11, 0, 0).unwrap();
+    let (tx, mut rx) = channel(2);
+    let reader = task::spawn_blocking({
+        let path = path.clone();
+        move || log_parts(vec![path], start, start + chrono::Duration::hours(2), tx)
+    });
+    let part = rx.recv().await.unwrap();
+    assert!(rx.recv().await.is_none());
+    assert_eq!(reader.await.unwrap(), Ok(2));
+    assert_eq!(part, b"{\"time\":\"2022-04-15T11:00:00Z\"}\n{\"time\":\"2022-04-15T12:00:00Z\"}\n");
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,98 @@
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::errors::IotEdgeError;
+
+/// What arrives on the command topic, `payload` is the JSON request as signed.
+#[derive(Debug, Deserialize)]
+pub struct Envelope {
+    pub payload: String,
+    pub signature: String,      // hex HMAC-SHA256 of payload
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    pub id: String,                     // correlation id, echoed in the response
+    pub time: i64,                      // unix seconds, stale requests are refused
+    pub response_topic: Option<String>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum Command {
+    PING,
+    STATUS,
+    GET_CONFIG,
+    SET_CONFIG { section: String, value: Value },
+    APPLY_CONFIG { config: Value },
+    RESTART { task: String },
+    FLUSH,
+    UPLOAD_LOG { start: DateTime<Utc>, end: DateTime<Utc> },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Response {
+    pub id: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub result: Value,
+}
+
+impl Response {
+    pub fn ok(id: &str, result: Value) -> Self {
+        Response { id: id.to_string(), ok: true, error: None, result }
+    }
+
+    pub fn error(id: &str, error: &str) -> Self {
+        Response { id: id.to_string(), ok: false, error: Some(error.to_string()), result: Value::Null }
+    }
+}
+
+impl Envelope {
+    /// Check the signature and parse the request it covers.
+    pub fn open(&self, key: &[u8]) -> Result<Request, IotEdgeError> {
+        let signature = hex::decode(&self.signature)
+            .map_err(|_| IotEdgeError::Generic("malformed signature"))?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(self.payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| IotEdgeError::Generic("bad signature"))?;
+
+        serde_json::from_str(&self.payload).map_err(|_| IotEdgeError::Generic("malformed request"))
+    }
+}
+
+#[cfg(test)]
+fn sign(key: &[u8], payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[test]
+fn test_envelope() {
+    let key = b"secret";
+    let payload = r#"{"id": "42", "time": 1650000000, "type": "RESTART", "task": "gps"}"#.to_string();
+    let envelope = Envelope { signature: sign(key, &payload), payload };
+
+    let request = envelope.open(key).unwrap();
+    assert_eq!(request.id, "42");
+    assert!(matches!(request.command, Command::RESTART { task } if task == "gps"));
+
+    assert!(envelope.open(b"other").is_err());
+
+    let payload = r#"{"id": "43", "time": 1650000000, "type": "UPLOAD_LOG", "start": "2022-04-15T00:00:00Z", "end": "2022-04-16T00:00:00Z"}"#;
+    let envelope = Envelope { signature: sign(key, payload), payload: payload.to_string() };
+    assert!(matches!(envelope.open(key).unwrap().command, Command::UPLOAD_LOG { .. }));
+
+    let response = Response::error("42", "no such task");
+    assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"id":"42","ok":false,"error":"no such task"}"#);
+}
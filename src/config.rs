@@ -1,31 +1,193 @@
-use std::path::Path;
-use toml;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::collections::{BTreeMap, BTreeSet};
+use bytesize::ByteSize;
+use regex::Regex;
 use serde_derive::{
     Serialize,
     Deserialize
 };
 
+use crate::dbc::Dbc;
+use crate::message::SCHEMA_VERSION;
+
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ConfigCan {
     pub frequency: u16,
+    // captures of every classic frame, not just the sampled ones, CAN FD is not captured
+    #[serde(default)]
+    pub candump: Option<ConfigCapture>,     // candump -l
+    #[serde(default)]
+    pub asc: Option<ConfigCapture>,         // Vector ASC
+    #[serde(default)]
+    pub blf: Option<ConfigCapture>,         // Vector BLF
+    #[serde(default)]
+    pub mf4: Option<ConfigCapture>,         // ASAM MDF4
+    #[serde(default)]
+    pub pcapng: Option<ConfigCapture>,      // Wireshark
+    #[serde(default)]
+    pub pcapng_listen: Option<String>,      // address to stream pcapng on, like 127.0.0.1:5555
+    #[serde(default)]
+    pub dbc: Vec<String>,                   // DBC files to decode signals with
 }
 
 impl Default for ConfigCan {
     fn default() -> Self {
         ConfigCan {
             frequency: 100,
+            candump: None,
+            asc: None,
+            blf: None,
+            mf4: None,
+            pcapng: None,
+            pcapng_listen: None,
+            dbc: Vec::new(),
         }
     }
 }
 
+/// Raw CAN capture file, rotated like the log.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct ConfigLog {
+pub struct ConfigCapture {
     pub path: String,
+    #[serde(default = "default_capture_size")]
+    pub rotate_size: String,
+    #[serde(default = "default_capture_keep")]
+    pub rotate_keep: usize,
+    #[serde(default)]
+    pub rotate_compress: bool,  // BLF is compressed inside already
+    #[serde(default = "default_rotate")]
+    pub rotate: LogRotation,
+    #[serde(default = "default_compression")]
+    pub compression: LogCompression,
+}
+
+fn default_capture_size() -> String {
+    "100M".to_string()
+}
+
+fn default_capture_keep() -> usize {
+    7
+}
+
+impl ConfigCapture {
+    /// The file logger settings it amounts to.
+    pub fn log_config(&self) -> ConfigLog {
+        ConfigLog {
+            path: self.path.clone(),
+            rotate_size: self.rotate_size.clone(),
+            rotate_keep: self.rotate_keep,
+            rotate_compress: self.rotate_compress,
+            include_success: true,
+            rotate: self.rotate.clone(),
+            compression: self.compression.clone(),
+            encoder: Encoder::JSON,
+        }
+    }
+}
+
+/// Tables of the Parquet export, signals only with a DBC to decode frames with.
+pub const PARQUET_TABLES: [&str; 3] = ["can", "gps", "signals"];
+
+/// Parquet tables of the chunks as they are stored, a file per table
+/// rotated like the log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigParquet {
+    pub path: String,               // tables are named after it, <stem>-can.parquet
     pub rotate_size: String,
     pub rotate_keep: usize,
+    pub rotate: LogRotation,
+    pub row_group_rows: usize,      // a row group never spans hours either
+    pub compression: ParquetCompression,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ParquetCompression {
+    NONE,
+    SNAPPY,
+    GZIP,
+    ZSTD,       // needs the zstd feature
+}
+
+impl Default for ConfigParquet {
+    fn default() -> Self {
+        ConfigParquet {
+            path: "logs/export.parquet".to_string(),
+            rotate_size: "100M".to_string(),
+            rotate_keep: 48,
+            rotate: LogRotation::HOURLY,
+            row_group_rows: 100_000,
+            compression: ParquetCompression::SNAPPY,
+        }
+    }
+}
+
+impl ConfigParquet {
+    /// Active file of a table, next to the path.
+    pub fn table(&self, name: &str) -> PathBuf {
+        let path = Path::new(&self.path);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!("{}-{}.parquet", stem, name))
+    }
+
+    /// The file logger settings of a table.
+    #[cfg(feature = "parquet")]
+    pub fn log_config(&self, name: &str) -> ConfigLog {
+        ConfigLog {
+            path: self.table(name).to_string_lossy().to_string(),
+            rotate_size: self.rotate_size.clone(),
+            rotate_keep: self.rotate_keep,
+            rotate_compress: false,     // compressed inside
+            include_success: true,
+            rotate: self.rotate.clone(),
+            compression: LogCompression::GZIP,
+            encoder: Encoder::JSON,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum LogRotation {
+    SIZE,       // <path>.1 is the newest closed file
+    HOURLY,     // closed files are named after their period, <stem>-2026-10-18T10.<ext>
+    DAILY,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum LogCompression {
+    GZIP,
+    ZSTD,       // needs the zstd feature
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigLog {
+    pub path: String,
+    pub rotate_size: String,    // also applies within a period
+    pub rotate_keep: usize,
     pub rotate_compress: bool,
-    pub include_success: bool,
+    pub include_success: bool,  // false logs only chunks the broker never got
+    #[serde(default = "default_rotate")]
+    pub rotate: LogRotation,
+    #[serde(default = "default_compression")]
+    pub compression: LogCompression,    // for rotate_compress
+    #[serde(default = "default_log_encoder")]
+    pub encoder: Encoder,               // BINARY for framed capnp chunks
+}
+
+fn default_rotate() -> LogRotation {
+    LogRotation::SIZE
+}
+
+fn default_compression() -> LogCompression {
+    LogCompression::GZIP
+}
+
+fn default_log_encoder() -> Encoder {
+    Encoder::JSON
 }
 
 impl Default for ConfigLog {
@@ -36,6 +198,28 @@ impl Default for ConfigLog {
             rotate_keep: 7, 
             rotate_compress: false,
             include_success: true,
+            rotate: LogRotation::SIZE,
+            compression: LogCompression::GZIP,
+            encoder: Encoder::JSON,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigQueue {
+    pub path: String,           // directory on the SD card
+    pub segment_size: String,
+    pub max_size: String,       // oldest segments are evicted beyond this
+    pub drain_rate: usize,      // chunks per second when catching up, 0 for unlimited
+}
+
+impl Default for ConfigQueue {
+    fn default() -> Self {
+        ConfigQueue {
+            path: "queue".to_string(),
+            segment_size: "16M".to_string(),
+            max_size: "1G".to_string(),
+            drain_rate: 10,
         }
     }
 }
@@ -53,19 +237,309 @@ impl Default for ConfigGps {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigGpioLine {
+    pub name: String,
+    pub offset: u32,
+    #[serde(default)]
+    pub active_low: bool,
+    #[serde(default)]
+    pub ignition: bool,     // drives start/stop behaviour of the gateway
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigGpio {
+    pub chip: String,
+    pub debounce: u64,      // milliseconds
+    pub lines: Vec<ConfigGpioLine>,
+}
+
+impl Default for ConfigGpio {
+    fn default() -> Self {
+        ConfigGpio {
+            chip: "/dev/gpiochip0".to_string(),
+            debounce: 20,
+            lines: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DataType {
+    BOOL,
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    F64,
+}
+
+impl DataType {
+    /// Size of one value in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            DataType::BOOL | DataType::U8 | DataType::I8 => 1,
+            DataType::U16 | DataType::I16 => 2,
+            DataType::U32 | DataType::I32 | DataType::F32 => 4,
+            DataType::F64 => 8,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ModbusTransport {
+    TCP,
+    RTU,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigModbusRegister {
+    pub name: String,
+    pub unit: u8,
+    pub function: u8,       // 1: coils, 2: discrete inputs, 3: holding, 4: input registers
+    pub address: u16,
+    pub count: u16,
+    pub data_type: DataType,
+    pub scale: f64,
+    pub interval: u64,      // poll interval, milliseconds
+}
+
+impl Default for ConfigModbusRegister {
+    fn default() -> Self {
+        ConfigModbusRegister {
+            name: "value".to_string(),
+            unit: 1,
+            function: 3,
+            address: 0,
+            count: 1,
+            data_type: DataType::U16,
+            scale: 1.0,
+            interval: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigModbus {
+    pub name: String,
+    pub transport: ModbusTransport,
+    pub host: String,
+    pub port: u16,
+    pub device: String,
+    pub baud_rate: u32,
+    pub registers: Vec<ConfigModbusRegister>,
+}
+
+impl Default for ConfigModbus {
+    fn default() -> Self {
+        ConfigModbus {
+            name: "modbus".to_string(),
+            transport: ModbusTransport::TCP,
+            host: "127.0.0.1".to_string(),
+            port: 502,
+            device: "/dev/ttyUSB0".to_string(),
+            baud_rate: 9600,
+            registers: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigSystem {
+    pub interval: u64,      // seconds
+}
+
+impl Default for ConfigSystem {
+    fn default() -> Self {
+        ConfigSystem { interval: 60 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigField {
+    pub name: String,
+    pub offset: usize,      // byte offset in the frame
+    pub data_type: DataType,
+    pub scale: f64,
+}
+
+impl Default for ConfigField {
+    fn default() -> Self {
+        ConfigField {
+            name: "value".to_string(),
+            offset: 0,
+            data_type: DataType::U8,
+            scale: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SerialParser {
+    LINE {
+        separator: Option<String>,
+        #[serde(default)]
+        names: Vec<String>,
+    },
+    REGEX {
+        pattern: String,    // named captures become fields
+    },
+    BINARY {
+        length: usize,
+        #[serde(default)]
+        fields: Vec<ConfigField>,
+    },
+    SLIP {
+        #[serde(default)]
+        fields: Vec<ConfigField>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigSerial {
+    pub name: String,
+    pub device: String,
+    pub baud_rate: u32,
+    pub data_bits: u8,
+    pub parity: String,     // NONE, ODD or EVEN
+    pub stop_bits: u8,
+    pub parser: SerialParser,
+    pub max_frame: usize,   // bytes without a frame end before they are dropped
+}
+
+impl Default for ConfigSerial {
+    fn default() -> Self {
+        ConfigSerial {
+            name: "serial".to_string(),
+            device: "/dev/ttyS1".to_string(),
+            baud_rate: 9600,
+            data_bits: 8,
+            parity: "NONE".to_string(),
+            stop_bits: 1,
+            parser: SerialParser::LINE { separator: None, names: Vec::new() },
+            max_frame: 4096,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ModemBackend {
+    AT,
+    MODEMMANAGER,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigModem {
+    pub backend: ModemBackend,
+    pub device: String,     // AT command port
+    pub baud_rate: u32,
+    pub modem: String,      // ModemManager modem index or path
+    pub interface: String,  // network interface for the data counters
+    pub interval: u64,      // seconds
+}
+
+impl Default for ConfigModem {
+    fn default() -> Self {
+        ConfigModem {
+            backend: ModemBackend::AT,
+            device: "/dev/ttyUSB2".to_string(),
+            baud_rate: 115200,
+            modem: "0".to_string(),
+            interface: "wwan0".to_string(),
+            interval: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum Encoder {
     JSON,
     BINARY,
 }
 
+/// Compression of chunk payloads after they are encoded.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum PayloadCompression {
+    NONE,
+    GZIP,
+    LZ4,        // frame format, needs the lz4 feature
+    ZSTD,       // needs the zstd feature, a trained dictionary helps small chunks most
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum MqttProtocol {
+    V4,     // MQTT 3.1.1
+    V5,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigMqttTls {
+    pub ca: String,                     // PEM bundle of trusted CAs
+    #[serde(default)]
+    pub client_cert: Option<String>,    // PEM certificate chain for client authentication
+    #[serde(default)]
+    pub client_key: Option<String>,     // PEM key, PKCS#8 encrypted when key_password is set
+    #[serde(default)]
+    pub key_password: Option<String>,
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    #[serde(default)]
+    pub server_name: Option<String>,    // SNI and name to verify the broker certificate against, defaults to host
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigMqttWebsocket {
+    pub path: String,
+    pub headers: BTreeMap<String, String>,  // extra headers for the upgrade request
+}
+
+impl Default for ConfigMqttWebsocket {
+    fn default() -> Self {
+        ConfigMqttWebsocket {
+            path: "/mqtt".to_string(),
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ConfigMqtt {
     pub host: String,
     pub port: u16,
     pub topic: String,
     pub encoder: Encoder,
+    pub compression: PayloadCompression,    // shown by a topic suffix, or a user property on MQTT 5
+    pub dictionary: Option<String>,         // for ZSTD, made by --train-dictionary
     pub chunk_size: usize,
     pub chunk_period: i64,
+    pub chunk_layout: u32,  // 1 a CanMessage per frame, 2 CAN frames grouped by ID
+    pub status_topic: Option<String>,   // publish status messages here instead of chunking them
+    pub ack_timeout: u64,   // seconds to wait for a chunk to be acknowledged
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<ConfigMqttTls>,
+    pub websocket: Option<ConfigMqttWebsocket>,    // wss when tls is also set
+    pub protocol: MqttProtocol,
+    pub message_expiry: u32,    // seconds, 0 never expires (MQTT 5)
+    pub topic_alias: bool,      // MQTT 5
+    pub user_properties: BTreeMap<String, String>,  // extra device metadata on every publish (MQTT 5)
+    pub qos: u8,
+    pub retain: bool,
+    pub keep_alive: u64,        // seconds, 0 disables pings
+    pub clean_session: bool,    // false resumes the broker session and resends unacknowledged chunks
+    pub session_expiry: u32,    // seconds the broker keeps a persistent session (MQTT 5)
+    pub inflight: u16,          // publishes on the wire before waiting for acknowledgements
+    pub presence_topic: Option<String>,     // retained "online", "offline" as last will
 }
 
 impl Default for ConfigMqtt {
@@ -75,8 +549,141 @@ impl Default for ConfigMqtt {
             port: 1883,
             topic: "test".to_string(),
             encoder: Encoder::BINARY, 
+            compression: PayloadCompression::NONE,
+            dictionary: None,
             chunk_size: 2048,
             chunk_period: 5,
+            chunk_layout: 1,
+            status_topic: None,
+            ack_timeout: 30,
+            username: None,
+            password: None,
+            tls: None,
+            websocket: None,
+            protocol: MqttProtocol::V4,
+            message_expiry: 0,
+            topic_alias: true,
+            user_properties: BTreeMap::new(),
+            qos: 1,
+            retain: false,
+            keep_alive: 5,
+            clean_session: true,
+            session_expiry: 3600,
+            inflight: 10,
+            presence_topic: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigCommand {
+    pub key: String,        // shared secret for the HMAC-SHA256 command signatures
+    #[serde(default = "default_max_age")]
+    pub max_age: i64,       // seconds a signed command stays valid
+}
+
+fn default_max_age() -> i64 {
+    300
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigUpdate {
+    pub watch_dir: Option<String>,  // *.toml dropped here is applied, then moved to applied/ or rejected/
+    pub interval: u64,              // seconds between looks at watch_dir
+    pub rollback_timeout: u64,      // seconds to get back to the broker before the old config returns
+}
+
+impl Default for ConfigUpdate {
+    fn default() -> Self {
+        ConfigUpdate {
+            watch_dir: None,
+            interval: 5,
+            rollback_timeout: 120,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum AfterUpload {
+    DELETE,
+    ARCHIVE,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigUpload {
+    pub endpoint: String,       // http(s)://host[:port] of the S3 or MinIO service
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    #[serde(default = "default_path_style")]
+    pub path_style: bool,       // <endpoint>/<bucket>/<key> rather than <bucket>.<host>/<key>, MinIO wants this
+    #[serde(default = "default_key_template")]
+    pub key_template: String,   // {device}, {date}, {time}, {seq} and {ext} are filled in
+    #[serde(default = "default_upload_interval")]
+    pub interval: u64,          // seconds between uploads
+    #[serde(default = "default_part_size")]
+    pub part_size: String,      // larger files go up in parts of this size, at least 5MiB
+    #[serde(default)]
+    pub spool: Option<String>,  // closed log files wait here until uploaded, <log dir>/upload by default
+    #[serde(default = "default_max_spool")]
+    pub max_spool: String,      // the oldest spooled files are dropped above this
+    #[serde(default = "default_after")]
+    pub after: AfterUpload,
+    #[serde(default)]
+    pub archive: Option<String>,    // where ARCHIVE moves uploaded files, <spool>/archive by default
+    #[serde(default)]
+    pub parquet: bool,              // the closed Parquet tables go up too
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_path_style() -> bool {
+    true
+}
+
+fn default_key_template() -> String {
+    "{device}/{date}/{seq}{ext}".to_string()
+}
+
+fn default_upload_interval() -> u64 {
+    3600
+}
+
+fn default_part_size() -> String {
+    "8M".to_string()
+}
+
+fn default_max_spool() -> String {
+    "1GiB".to_string()
+}
+
+fn default_after() -> AfterUpload {
+    AfterUpload::DELETE
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigUsb {
+    pub devices: String,            // regex on kernel block device names to export to, partitions by default
+    pub mount: String,              // mount point, created when missing
+    pub filesystems: Vec<String>,   // tried in turn
+    pub dir: String,                // on the drive, {device} is the device id
+    pub delete: bool,               // remove log files once their copy is verified
+}
+
+impl Default for ConfigUsb {
+    fn default() -> Self {
+        ConfigUsb {
+            devices: "^sd[a-z]+[0-9]+$".to_string(),
+            mount: "/run/iot-edge/usb".to_string(),
+            filesystems: vec!["vfat".to_string(), "exfat".to_string(), "ext4".to_string()],
+            dir: "iot-edge/{device}".to_string(),
+            delete: false,
         }
     }
 }
@@ -85,9 +692,20 @@ impl Default for ConfigMqtt {
 pub struct Config {
     pub device_id: String,
     pub log: Option<ConfigLog>,
+    pub queue: Option<ConfigQueue>,
     pub can: Option<ConfigCan>,
     pub gps: Option<ConfigGps>,
+    pub gpio: Option<ConfigGpio>,
+    pub modbus: Option<Vec<ConfigModbus>>,
+    pub system: Option<ConfigSystem>,
+    pub modem: Option<ConfigModem>,
+    pub serial: Option<Vec<ConfigSerial>>,
     pub mqtt: Option<ConfigMqtt>,
+    pub command: Option<ConfigCommand>,
+    pub update: Option<ConfigUpdate>,
+    pub upload: Option<ConfigUpload>,
+    pub usb: Option<ConfigUsb>,
+    pub parquet: Option<ConfigParquet>,
 }
 
 impl Config {
@@ -106,12 +724,63 @@ impl Config {
             None => ConfigCan::default(),
         }
     }
+    pub fn gpio_config(&self) -> ConfigGpio {
+        match &self.gpio {
+            Some(config) => config.clone(),
+            None => ConfigGpio::default(),
+        }
+    }
+    pub fn modbus_config(&self) -> Vec<ConfigModbus> {
+        match &self.modbus {
+            Some(config) => config.clone(),
+            None => Vec::new(),
+        }
+    }
+    pub fn system_config(&self) -> ConfigSystem {
+        match &self.system {
+            Some(config) => config.clone(),
+            None => ConfigSystem::default(),
+        }
+    }
+    pub fn modem_config(&self) -> Option<ConfigModem> {
+        self.modem.clone()
+    }
+    pub fn serial_config(&self) -> Vec<ConfigSerial> {
+        match &self.serial {
+            Some(config) => config.clone(),
+            None => Vec::new(),
+        }
+    }
     pub fn log_config(&self) -> ConfigLog {
         match &self.log {
             Some(config) => config.clone(),
             None => ConfigLog::default(),
         }
     }
+    pub fn queue_config(&self) -> ConfigQueue {
+        match &self.queue {
+            Some(config) => config.clone(),
+            None => ConfigQueue::default(),
+        }
+    }
+    pub fn update_config(&self) -> ConfigUpdate {
+        match &self.update {
+            Some(config) => config.clone(),
+            None => ConfigUpdate::default(),
+        }
+    }
+    pub fn command_config(&self) -> Option<ConfigCommand> {
+        self.command.clone()
+    }
+    pub fn upload_config(&self) -> Option<ConfigUpload> {
+        self.upload.clone()
+    }
+    pub fn usb_config(&self) -> Option<ConfigUsb> {
+        self.usb.clone()
+    }
+    pub fn parquet_config(&self) -> Option<ConfigParquet> {
+        self.parquet.clone()
+    }
     pub fn mqtt_config(&self) -> ConfigMqtt {
         match &self.mqtt {
             Some(config) => config.clone(),
@@ -125,16 +794,216 @@ impl Default for Config {
         Config {
             device_id: "test".to_string(),
             log: Some(ConfigLog::default()),
+            queue: Some(ConfigQueue::default()),
             can: Some(ConfigCan::default()),
             mqtt: Some(ConfigMqtt::default()),
             gps: Some(ConfigGps::default()),
+            gpio: None,
+            modbus: None,
+            system: Some(ConfigSystem::default()),
+            modem: None,
+            serial: None,
+            command: None,
+            update: None,
+            upload: None,
+            usb: None,
+            parquet: None,
+        }
+    }
+}
+
+fn unique<'a>(section: &str, names: impl Iterator<Item = &'a String>) -> Result<(), String> {
+    let mut seen = BTreeSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            return Err(format!("{}: duplicate name {}", section, name));
         }
     }
+    Ok(())
 }
 
-impl From<&Path> for Config {
-    fn from(path: &Path) -> Self {
-        toml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+fn size(section: &str, value: &str) -> Result<(), String> {
+    match ByteSize::from_str(value) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("{}: invalid size {}", section, value)),
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Replace the file at `path`, a crash leaves either the old or the new one.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        // through a toml::Value so tables end up after plain values
+        let value = toml::Value::try_from(self).map_err(|e| e.to_string())?;
+        let text = toml::to_string(&value).map_err(|e| e.to_string())?;
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text).map_err(|e| e.to_string())?;
+        fs::rename(&tmp, path).map_err(|e| e.to_string())
+    }
+
+    /// Catch what serde can't before anything is started with it.
+    pub fn validate(&self) -> Result<(), String> {
+        let log = self.log_config();
+        size("log", &log.rotate_size)?;
+        if cfg!(not(feature = "zstd")) & log.rotate_compress & (log.compression == LogCompression::ZSTD) {
+            return Err("log: built without zstd".to_string());
+        }
+        let can = self.can_config();
+        let captures = [
+            ("can.candump", &can.candump),
+            ("can.asc", &can.asc),
+            ("can.blf", &can.blf),
+            ("can.mf4", &can.mf4),
+            ("can.pcapng", &can.pcapng),
+        ];
+        for (name, capture) in captures {
+            if let Some(capture) = capture {
+                size(name, &capture.rotate_size)?;
+                if cfg!(not(feature = "zstd")) & capture.rotate_compress & (capture.compression == LogCompression::ZSTD) {
+                    return Err(format!("{}: built without zstd", name));
+                }
+            }
+        }
+        if let Some(listen) = &can.pcapng_listen {
+            let addr = listen.parse::<SocketAddr>().map_err(|_| format!("can: pcapng_listen {} is not an address", listen))?;
+            // anyone who connects gets every frame, reach it through ssh
+            if !addr.ip().is_loopback() {
+                return Err(format!("can: pcapng_listen {} is not a loopback address", listen));
+            }
+        }
+        for dbc in can.dbc.iter() {
+            Dbc::open(Path::new(dbc)).map_err(|e| format!("can: dbc {}: {}", dbc, e))?;
+        }
+        let queue = self.queue_config();
+        size("queue", &queue.segment_size)?;
+        size("queue", &queue.max_size)?;
+
+        let mqtt = self.mqtt_config();
+        // undelivered chunks are logged from the queue, JSON can't be turned back into capnp
+        if !log.include_success & (mqtt.encoder == Encoder::JSON) & (log.encoder == Encoder::BINARY) {
+            return Err("log: include_success = false needs mqtt BINARY for a BINARY log".to_string());
+        }
+        if mqtt.qos > 2 {
+            return Err("mqtt: qos must be 0, 1 or 2".to_string());
+        }
+        if (mqtt.inflight == 0) | (mqtt.chunk_size == 0) {
+            return Err("mqtt: inflight and chunk_size must be at least 1".to_string());
+        }
+        if !(1..=SCHEMA_VERSION).contains(&mqtt.chunk_layout) {
+            return Err(format!("mqtt: chunk_layout must be 1 to {}", SCHEMA_VERSION));
+        }
+        if mqtt.websocket.is_some() & mqtt.tls.as_ref().is_some_and(|tls| tls.server_name.is_some()) {
+            return Err("mqtt: tls server_name does not work with websocket".to_string());
+        }
+        match mqtt.compression {
+            PayloadCompression::LZ4 if cfg!(not(feature = "lz4")) => return Err("mqtt: built without lz4".to_string()),
+            PayloadCompression::ZSTD if cfg!(not(feature = "zstd")) => return Err("mqtt: built without zstd".to_string()),
+            _ => {},
+        }
+        if let Some(dictionary) = &mqtt.dictionary {
+            if mqtt.compression != PayloadCompression::ZSTD {
+                return Err("mqtt: a dictionary needs ZSTD compression".to_string());
+            }
+            fs::metadata(dictionary).map_err(|e| format!("mqtt: dictionary {}: {}", dictionary, e))?;
+        }
+
+        if let Some(command) = &self.command {
+            if command.key.is_empty() {
+                return Err("command: key must be set".to_string());
+            }
+        }
+        if let Some(upload) = &self.upload {
+            size("upload", &upload.part_size)?;
+            size("upload", &upload.max_spool)?;
+            if ByteSize::from_str(&upload.part_size).unwrap() < ByteSize::mib(5) {
+                return Err("upload: part_size must be at least 5MiB".to_string());
+            }
+            if upload.interval == 0 {
+                return Err("upload: interval must be at least 1".to_string());
+            }
+            if !upload.endpoint.starts_with("http://") & !upload.endpoint.starts_with("https://") {
+                return Err("upload: endpoint must be an http or https URL".to_string());
+            }
+        }
+        if let Some(parquet) = &self.parquet {
+            if cfg!(not(feature = "parquet")) {
+                return Err("parquet: built without parquet".to_string());
+            }
+            size("parquet", &parquet.rotate_size)?;
+            if parquet.row_group_rows == 0 {
+                return Err("parquet: row_group_rows must be at least 1".to_string());
+            }
+            if cfg!(not(feature = "zstd")) & (parquet.compression == ParquetCompression::ZSTD) {
+                return Err("parquet: built without zstd".to_string());
+            }
+        }
+        if let Some(usb) = &self.usb {
+            Regex::new(&usb.devices).map_err(|e| format!("usb: devices: {}", e))?;
+            if usb.filesystems.is_empty() {
+                return Err("usb: filesystems must not be empty".to_string());
+            }
+        }
+        if self.system_config().interval == 0 {
+            return Err("system: interval must be at least 1".to_string());
+        }
+        if self.modem_config().is_some_and(|modem| modem.interval == 0) {
+            return Err("modem: interval must be at least 1".to_string());
+        }
+        let update = self.update_config();
+        if (update.interval == 0) | (update.rollback_timeout == 0) {
+            return Err("update: interval and rollback_timeout must be at least 1".to_string());
+        }
+
+        let gpio = self.gpio_config();
+        unique("gpio", gpio.lines.iter().map(|line| &line.name))?;
+        if gpio.lines.iter().filter(|line| line.ignition).count() > 1 {
+            return Err("gpio: only one line can be the ignition".to_string());
+        }
+
+        let modbus = self.modbus_config();
+        unique("modbus", modbus.iter().map(|modbus| &modbus.name))?;
+        for register in modbus.iter().flat_map(|modbus| modbus.registers.iter()) {
+            if !(1..=4).contains(&register.function) | (register.interval == 0) {
+                return Err(format!("modbus: register {} needs function 1-4 and an interval", register.name));
+            }
+        }
+
+        let serial = self.serial_config();
+        unique("serial", serial.iter().map(|serial| &serial.name))?;
+        for serial in serial.iter() {
+            if !["NONE", "ODD", "EVEN"].contains(&serial.parity.as_str()) {
+                return Err(format!("serial: {} parity must be NONE, ODD or EVEN", serial.name));
+            }
+            if serial.max_frame == 0 {
+                return Err(format!("serial: {} max_frame must be at least 1", serial.name));
+            }
+            match &serial.parser {
+                SerialParser::REGEX { pattern } => {
+                    Regex::new(pattern).map_err(|e| format!("serial: {}: {}", serial.name, e))?;
+                },
+                SerialParser::BINARY { length: 0, .. } => {
+                    return Err(format!("serial: {} binary length must be at least 1", serial.name));
+                },
+                SerialParser::BINARY { length, fields }
+                    if fields.iter().any(|field| field.offset + field.data_type.size() > *length) => {
+                    return Err(format!("serial: {} field beyond the frame length", serial.name));
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -149,13 +1018,130 @@ fn test_config() {
     topic = "hello/test"
     encoder = "JSON"
     chunk_size = 2048
+    chunk_period = 5
+    username = "gateway"
+    password = "secret"
+    protocol = "V5"
+    message_expiry = 86400
+    user_properties = { fleet = "north" }
+    qos = 2
+    keep_alive = 30
+    clean_session = false
+    presence_topic = "hello/test/presence"
+
+    [mqtt.tls]
+    ca = "/etc/iot-edge/ca.pem"
+    client_cert = "/etc/iot-edge/client.pem"
+    client_key = "/etc/iot-edge/client.key"
+    alpn = ["mqtt"]
+
+    [mqtt.websocket]
+    path = "/mqtt"
+    headers = { Authorization = "Bearer token" }
     
     [gps]
     host = "127.0.0.1"
     port = 2947
+
+    [command]
+    key = "0123456789abcdef"
+
+    [upload]
+    endpoint = "http://127.0.0.1:9000"
+    bucket = "iot-edge"
+    access_key = "minioadmin"
+    secret_key = "minioadmin"
+    after = "ARCHIVE"
+
+    [usb]
+    devices = "^sd[a-z][0-9]$"
+    delete = true
+
+    [gpio]
+    chip = "/dev/gpiochip0"
+    debounce = 20
+
+    [[gpio.lines]]
+    name = "ignition"
+    offset = 17
+    ignition = true
+
+    [[gpio.lines]]
+    name = "door"
+    offset = 18
+    active_low = true
+
+    [[modbus]]
+    name = "reefer"
+    transport = "TCP"
+    host = "192.168.1.20"
+
+    [[modbus.registers]]
+    name = "supply_temp"
+    function = 4
+    address = 100
+    data_type = "I16"
+    scale = 0.1
+
+    [[modbus.registers]]
+    name = "runtime"
+    unit = 2
+    address = 200
+    count = 2
+    data_type = "U32"
+    interval = 60000
+
+    [modem]
+    backend = "MODEMMANAGER"
+    interface = "wwan0"
+
+    [[serial]]
+    name = "thermometer"
+    device = "/dev/ttyS2"
+    baud_rate = 19200
+    parser = { type = "REGEX", pattern = 'T=(?P<temp>-?\d+\.\d+)' }
+
+    [[serial]]
+    name = "tpms"
+    device = "/dev/ttyS3"
+    parity = "EVEN"
+
+    [serial.parser]
+    type = "BINARY"
+    length = 8
+    fields = [
+        { name = "sensor", offset = 0, data_type = "U8" },
+        { name = "pressure", offset = 1, data_type = "U16", scale = 0.1 },
+    ]
     "#).unwrap();
     println!("{:#?}", config);
+    config.validate().unwrap();
 
-    let config = Config::from(Path::new("config.toml.sample"));
+    let config = Config::load(Path::new("config.toml.sample")).unwrap();
     println!("{:#?}", config);
+    assert!(config.validate().is_ok());
+
+    let mqtt = ConfigMqtt { chunk_layout: 3, ..config.mqtt_config() };
+    let config = Config { mqtt: Some(mqtt), ..config };
+    assert_eq!(config.validate(), Err("mqtt: chunk_layout must be 1 to 2".to_string()));
+
+    let config = Config { mqtt: None, system: Some(ConfigSystem { interval: 0 }), ..config };
+    assert_eq!(config.validate(), Err("system: interval must be at least 1".to_string()));
+
+    let config = Config { system: None, modem: Some(ConfigModem { interval: 0, ..Default::default() }), ..config };
+    assert_eq!(config.validate(), Err("modem: interval must be at least 1".to_string()));
+
+    let config = Config { modem: None, update: Some(ConfigUpdate { rollback_timeout: 0, ..Default::default() }), ..config };
+    assert_eq!(config.validate(), Err("update: interval and rollback_timeout must be at least 1".to_string()));
+
+    let serial = ConfigSerial {
+        parser: SerialParser::BINARY { length: 0, fields: Vec::new() },
+        ..Default::default()
+    };
+    let config = Config { update: None, serial: Some(vec![serial]), ..config };
+    assert_eq!(config.validate(), Err("serial: serial binary length must be at least 1".to_string()));
+
+    let can = ConfigCan { pcapng_listen: Some("0.0.0.0:5555".to_string()), ..Default::default() };
+    let config = Config { serial: None, can: Some(can), ..config };
+    assert_eq!(config.validate(), Err("can: pcapng_listen 0.0.0.0:5555 is not a loopback address".to_string()));
 }
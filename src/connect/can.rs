@@ -1,5 +1,7 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use log::warn;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
 use chrono::prelude::*;
 
 use futures_util::stream::StreamExt;
@@ -9,21 +11,26 @@ use crate::message::{
     Message,
     CanMessage,
 };
+use crate::output::{CaptureFrame, CaptureStats};
 
 pub struct CanTask {
     tx: Sender<Message>,
     bus: CANSocket,
     freq: u16,
     dev: String,
+    captures: Vec<Sender<CaptureFrame>>,
+    stats: Arc<CaptureStats>,
 }
 
 impl CanTask {
-    pub fn new(ifname: &str, tx: Sender<Message>, freq: u16) -> Self {
+    pub fn new(ifname: &str, tx: Sender<Message>, freq: u16, captures: Vec<Sender<CaptureFrame>>, stats: Arc<CaptureStats>) -> Self {
         CanTask {
             dev: ifname.to_string(),
             bus: CANSocket::open(ifname).unwrap(),
             freq,
             tx,
+            captures,
+            stats,
         }
     }
 
@@ -33,15 +40,25 @@ impl CanTask {
 
         while let Some(Ok(frame)) = self.bus.next().await {
             let time: DateTime<Utc> = Utc::now();
-            if time.timestamp_millis() / interval == current.timestamp_millis() / interval {
-                continue;
-            }
-    
             let msg = CanMessage {
                 time,
                 channel: self.dev.clone(),
+                extended: frame.is_extended(),
                 frame
             };
+
+            // captures take every frame, chunks only a sample; a capture
+            // that falls behind loses frames rather than the bus
+            for capture in self.captures.iter() {
+                match capture.try_send(CaptureFrame::from(&msg)) {
+                    Ok(()) => {},
+                    Err(TrySendError::Full(_)) => { self.stats.dropped.fetch_add(1, Ordering::Relaxed); },
+                    Err(e) => { warn!("{:?}", e) }
+                }
+            }
+            if time.timestamp_millis() / interval == current.timestamp_millis() / interval {
+                continue;
+            }
             
             match self.tx.send(Message::CAN(msg)).await {
                 Ok(()) => {},
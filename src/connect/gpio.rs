@@ -0,0 +1,214 @@
+use std::sync::Arc;
+use log::*;
+use tokio::sync::{mpsc::Sender, watch};
+use tokio::time::{self, Duration};
+use chrono::prelude::*;
+
+use futures_util::stream::StreamExt;
+use gpio_cdev::{
+    Chip,
+    AsyncLineEventHandle,
+    LineRequestFlags,
+    EventRequestFlags,
+};
+
+use crate::message::{
+    Message,
+    GpioMessage,
+};
+use crate::config::ConfigGpioLine;
+use crate::errors::IotEdgeError;
+
+/// Edges and level of an input line, a simulated one in the tests.
+pub trait InputLine: Send {
+    /// Kernel timestamp of the next edge, None once the line is gone.
+    async fn edge(&mut self) -> Option<Result<u64, gpio_cdev::Error>>;
+
+    fn value(&self) -> Result<bool, gpio_cdev::Error>;
+}
+
+/// A line requested through the GPIO character device.
+pub struct CdevLine(AsyncLineEventHandle);
+
+impl InputLine for CdevLine {
+    async fn edge(&mut self) -> Option<Result<u64, gpio_cdev::Error>> {
+        self.0.next().await.map(|event| event.map(|event| event.timestamp()))
+    }
+
+    fn value(&self) -> Result<bool, gpio_cdev::Error> {
+        self.0.as_ref().get_value().map(|value| value != 0)
+    }
+}
+
+pub struct GpioTask<L: InputLine = CdevLine> {
+    tx: Sender<Message>,
+    line: L,
+    name: String,
+    offset: u32,
+    debounce: Duration,
+    ignition: Option<Arc<watch::Sender<bool>>>,
+}
+
+impl GpioTask {
+    pub fn new(
+        chip: &str, line: &ConfigGpioLine, debounce: u64,
+        tx: Sender<Message>, ignition: Option<Arc<watch::Sender<bool>>>
+    ) -> Result<Self, IotEdgeError> {
+        let mut flags = LineRequestFlags::INPUT;
+        if line.active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW;
+        }
+
+        let request = |e: gpio_cdev::Error| IotEdgeError::GpioError(format!("{} line {}: {}", chip, line.offset, e));
+        let events = Chip::new(chip).map_err(request)?
+            .get_line(line.offset).map_err(request)?
+            .async_events(flags, EventRequestFlags::BOTH_EDGES, "iot-edge")
+            .map_err(request)?;
+
+        Ok(GpioTask::with_line(CdevLine(events), line, debounce, tx, ignition))
+    }
+}
+
+impl<L: InputLine> GpioTask<L> {
+    fn with_line(
+        line: L, config: &ConfigGpioLine, debounce: u64,
+        tx: Sender<Message>, ignition: Option<Arc<watch::Sender<bool>>>
+    ) -> Self {
+        GpioTask {
+            name: config.name.clone(),
+            offset: config.offset,
+            debounce: Duration::from_millis(debounce),
+            line,
+            tx,
+            ignition,
+        }
+    }
+
+    fn value(&self) -> bool {
+        match self.line.value() {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("{}: read gpio line {} failed: {}", self.name, self.offset, e);
+                false
+            }
+        }
+    }
+
+    async fn emit(&self, value: bool, timestamp: u64) {
+        if let Some(ignition) = &self.ignition {
+            ignition.send_replace(value);
+        }
+
+        let msg = GpioMessage {
+            time: Utc::now(),
+            timestamp,
+            name: self.name.clone(),
+            offset: self.offset,
+            value,
+        };
+
+        if let Err(e) = self.tx.send(Message::GPIO(msg)).await {
+            warn!("{:?}", e)
+        }
+    }
+
+    pub async fn run(&mut self) {
+        // report the initial level, there is no kernel event behind it
+        let mut state = self.value();
+        self.emit(state, 0).await;
+
+        while let Some(timestamp) = self.line.edge().await {
+            let timestamp = match timestamp {
+                Ok(timestamp) => timestamp,
+                Err(e) => {
+                    warn!("{}: gpio event error: {}", self.name, e);
+                    continue;
+                }
+            };
+
+            // swallow the contact bounce, then sample the settled level
+            let line = &mut self.line;
+            let _ = time::timeout(self.debounce, async {
+                while line.edge().await.is_some() {}
+            }).await;
+
+            let value = self.value();
+            if value != state {
+                state = value;
+                self.emit(value, timestamp).await;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_missing_chip() {
+    let line = ConfigGpioLine { name: "ignition".to_string(), offset: 17, active_low: false, ignition: true };
+    let (tx, _rx) = tokio::sync::mpsc::channel(1);
+    match GpioTask::new("/dev/gpiochip-missing", &line, 20, tx, None) {
+        Err(IotEdgeError::GpioError(e)) => assert!(e.starts_with("/dev/gpiochip-missing line 17"), "{}", e),
+        _ => panic!("a missing chip has to fail"),
+    }
+}
+
+#[cfg(test)]
+struct SimLine {
+    edges: tokio::sync::mpsc::UnboundedReceiver<u64>,
+    level: Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[cfg(test)]
+impl InputLine for SimLine {
+    async fn edge(&mut self) -> Option<Result<u64, gpio_cdev::Error>> {
+        self.edges.recv().await.map(Ok)
+    }
+
+    fn value(&self) -> Result<bool, gpio_cdev::Error> {
+        Ok(self.level.load(std::sync::atomic::Ordering::SeqCst))
+    }
+}
+
+#[tokio::test]
+async fn test_debounce() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let (edges, rx) = tokio::sync::mpsc::unbounded_channel();
+    let level = Arc::new(AtomicBool::new(false));
+    let line = SimLine { edges: rx, level: level.clone() };
+    let config = ConfigGpioLine { name: "ignition".to_string(), offset: 17, active_low: false, ignition: true };
+    let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+    let (ignition, watch) = watch::channel(false);
+    let mut task = GpioTask::with_line(line, &config, 20, tx, Some(Arc::new(ignition)));
+    let handle = tokio::spawn(async move { task.run().await });
+    let next = |rx: &mut tokio::sync::mpsc::Receiver<Message>| match rx.try_recv() {
+        Ok(Message::GPIO(msg)) => Some((msg.value, msg.timestamp)),
+        _ => None,
+    };
+
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(next(&mut rx), Some((false, 0)));
+
+    // a bouncing contact that settles high is one change, at its first edge
+    level.store(true, Ordering::SeqCst);
+    for timestamp in [1, 2, 3] {
+        edges.send(timestamp).unwrap();
+    }
+    time::sleep(Duration::from_millis(80)).await;
+    assert_eq!((next(&mut rx), next(&mut rx)), (Some((true, 1)), None));
+    assert!(*watch.borrow());
+
+    // bouncing back to the same level is no change
+    edges.send(4).unwrap();
+    edges.send(5).unwrap();
+    time::sleep(Duration::from_millis(80)).await;
+    assert_eq!(next(&mut rx), None);
+
+    level.store(false, Ordering::SeqCst);
+    edges.send(6).unwrap();
+    time::sleep(Duration::from_millis(80)).await;
+    assert_eq!(next(&mut rx), Some((false, 6)));
+    assert!(!*watch.borrow());
+
+    drop(edges);
+    handle.await.unwrap();
+}
@@ -1,4 +1,4 @@
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{broadcast, mpsc::Sender};
 use tokio::net::TcpStream;
 use tokio::io::AsyncWriteExt;
 use tokio::io::{BufReader, AsyncBufReadExt};
@@ -16,14 +16,16 @@ pub struct GpsTask {
     host: String,
     port: u16,
     tx: Sender<Message>,
+    fixes: broadcast::Sender<GpsMessage>,
 }
 
 impl GpsTask {
-    pub fn new(config: &ConfigGps, tx: Sender<Message>) -> Self {
+    pub fn new(config: &ConfigGps, tx: Sender<Message>, fixes: broadcast::Sender<GpsMessage>) -> Self {
         GpsTask {
             host: config.host.to_string(),
             port: config.port,
             tx,
+            fixes,
         }
     }
 
@@ -36,7 +38,7 @@ impl GpsTask {
             "json": true,
             "raw": 0u8,
         });
-        let msg = format!("?WATCH={}\n", watch_data.to_string());
+        let msg = format!("?WATCH={}\n", watch_data);
         stream.write_all(msg.as_bytes()).await.unwrap();
     
         let mut inner = BufReader::new(stream);
@@ -51,7 +53,7 @@ impl GpsTask {
                 }
             }
     
-            if buf == "" {
+            if buf.is_empty() {
                 debug!("empty line received from GPSD");
                 continue;
             }
@@ -63,15 +65,14 @@ impl GpsTask {
                     warn!("deserializing response failed: {:?}, buf: {}", e, buf);
                 },
                 Ok(response) => {
-                    match response {
-                        Response::Tpv(resp) => {
-                            let gps_msg = match GpsMessage::try_from(&resp) {
-                                Ok(msg) => msg,
-                                _ => continue
-                            };
-                            self.tx.send(Message::GPS(gps_msg)).await.unwrap();
-                        },
-                        _ => {}
+                    if let Response::Tpv(resp) = response {
+                        let gps_msg = match GpsMessage::try_from(&resp) {
+                            Ok(msg) => msg,
+                            _ => continue
+                        };
+                        // no capture listening is fine
+                        let _ = self.fixes.send(gps_msg.clone());
+                        self.tx.send(Message::GPS(gps_msg)).await.unwrap();
                     }
                 }
             }
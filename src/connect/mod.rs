@@ -1,5 +1,17 @@
 mod can;
 mod gps;
+mod gpio;
+mod modbus;
+mod system;
+mod modem;
+mod serial;
+mod usb;
 
 pub use can::CanTask;
-pub use gps::GpsTask;
\ No newline at end of file
+pub use gps::GpsTask;
+pub use gpio::GpioTask;
+pub use modbus::ModbusTask;
+pub use system::SystemTask;
+pub use modem::ModemTask;
+pub use serial::SerialTask;
+pub use usb::UsbTask;
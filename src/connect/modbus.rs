@@ -0,0 +1,163 @@
+use std::io;
+use log::*;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{self, Duration, Instant};
+use chrono::prelude::*;
+
+use tokio_modbus::prelude::*;
+use tokio_modbus::client::Context;
+use tokio_serial::SerialStream;
+
+use crate::message::{
+    Message,
+    ModbusMessage,
+};
+use crate::config::{ConfigModbus, ConfigModbusRegister, DataType, ModbusTransport};
+use crate::utils::decode_value;
+
+fn is_link_error(e: &io::Error) -> bool {
+    matches!(e.kind(),
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::TimedOut
+    )
+}
+
+pub struct ModbusTask {
+    tx: Sender<Message>,
+    config: ConfigModbus,
+}
+
+impl ModbusTask {
+    pub fn new(config: &ConfigModbus, tx: Sender<Message>) -> Self {
+        ModbusTask {
+            config: config.clone(),
+            tx,
+        }
+    }
+
+    async fn connect(&self) -> io::Result<Context> {
+        match self.config.transport {
+            ModbusTransport::TCP => {
+                let addr = format!("{}:{}", self.config.host, self.config.port);
+                let found = tokio::net::lookup_host(&addr).await?.next();
+                let addr = match found {
+                    Some(addr) => addr,
+                    None => return Err(io::Error::new(io::ErrorKind::NotFound, addr)),
+                };
+                tcp::connect(addr).await
+            },
+            ModbusTransport::RTU => {
+                let builder = tokio_serial::new(&self.config.device, self.config.baud_rate);
+                let port = SerialStream::open(&builder)?;
+                rtu::connect(port).await
+            }
+        }
+    }
+
+    async fn read(ctx: &mut Context, reg: &ConfigModbusRegister) -> io::Result<Vec<u8>> {
+        ctx.set_slave(Slave(reg.unit));
+        let bits = |coils: Vec<bool>| -> Vec<u8> { coils.into_iter().map(|c| c as u8).collect() };
+        let words = |regs: Vec<u16>| -> Vec<u8> { regs.into_iter().flat_map(|r| r.to_be_bytes()).collect() };
+
+        match reg.function {
+            1 => ctx.read_coils(reg.address, reg.count).await.map(bits),
+            2 => ctx.read_discrete_inputs(reg.address, reg.count).await.map(bits),
+            3 => ctx.read_holding_registers(reg.address, reg.count).await.map(words),
+            4 => ctx.read_input_registers(reg.address, reg.count).await.map(words),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported function code")),
+        }
+    }
+
+    /// Coils and discrete inputs are one bit each, whatever data type the register has.
+    fn values(reg: &ConfigModbusRegister, data: &[u8]) -> Vec<f64> {
+        let data_type = match reg.function {
+            1 | 2 => &DataType::BOOL,
+            _ => &reg.data_type,
+        };
+        data.chunks(data_type.size())
+            .filter_map(|b| decode_value(data_type, b))
+            .collect()
+    }
+
+    async fn emit(&self, reg: &ConfigModbusRegister, data: &[u8]) {
+        let values = Self::values(reg, data);
+
+        let time = Utc::now();
+        for (pos, value) in values.iter().enumerate() {
+            let name = match values.len() {
+                1 => reg.name.clone(),
+                _ => format!("{}.{}", reg.name, pos),
+            };
+            let msg = ModbusMessage {
+                time,
+                source: self.config.name.clone(),
+                name,
+                value: value * reg.scale,
+            };
+            if let Err(e) = self.tx.send(Message::MODBUS(msg)).await {
+                warn!("{:?}", e)
+            }
+        }
+    }
+
+    pub async fn run(&self) {
+        loop {
+            let mut ctx = match self.connect().await {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    warn!("{}: modbus connect failed: {}", self.config.name, e);
+                    time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+
+            let mut due: Vec<Instant> = vec![Instant::now(); self.config.registers.len()];
+            'poll: loop {
+                let next = match due.iter().min() {
+                    Some(next) => *next,
+                    None => return,
+                };
+                time::sleep_until(next).await;
+
+                for (pos, reg) in self.config.registers.iter().enumerate() {
+                    if due[pos] > Instant::now() {
+                        continue;
+                    }
+                    due[pos] = next + Duration::from_millis(reg.interval);
+
+                    let read = time::timeout(Duration::from_secs(1), Self::read(&mut ctx, reg));
+                    let result = match read.await {
+                        Ok(result) => result,
+                        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "no response")),
+                    };
+
+                    match result {
+                        Ok(data) => self.emit(reg, &data).await,
+                        Err(e) if is_link_error(&e) => {
+                            warn!("{}: {}: {}, reconnecting", self.config.name, reg.name, e);
+                            break 'poll;
+                        },
+                        Err(e) => {
+                            // exception response from the slave, the link itself is fine
+                            warn!("{}: {}: {}", self.config.name, reg.name, e);
+                        }
+                    }
+                }
+            }
+            time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[test]
+fn test_coils() {
+    let reg = ConfigModbusRegister { function: 1, count: 3, ..Default::default() };
+    assert_eq!(ModbusTask::values(&reg, &[1, 0, 1]), vec![1.0, 0.0, 1.0]);
+
+    let reg = ConfigModbusRegister { function: 3, count: 2, ..Default::default() };
+    assert_eq!(ModbusTask::values(&reg, &[0x01, 0x02, 0xFF, 0xFF]), vec![258.0, 65535.0]);
+}
@@ -0,0 +1,382 @@
+use std::io;
+use std::fs;
+use log::*;
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{self, Duration};
+use chrono::prelude::*;
+
+use tokio_serial::SerialStream;
+
+use crate::message::{
+    Message,
+    ModemMessage,
+};
+use crate::config::{ConfigModem, ModemBackend};
+
+/// Minimal AT command channel, generic over the port so tests can run it on a pty.
+pub struct AtPort<T> {
+    port: BufReader<T>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AtPort<T> {
+    pub fn new(port: T) -> Self {
+        AtPort { port: BufReader::new(port) }
+    }
+
+    async fn exchange(&mut self, cmd: &str) -> io::Result<Vec<String>> {
+        self.port.get_mut().write_all(format!("{}\r", cmd).as_bytes()).await?;
+
+        let mut lines = Vec::new();
+        loop {
+            let mut buf = String::new();
+            if self.port.read_line(&mut buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            match buf.trim() {
+                "" => continue,
+                "OK" => return Ok(lines),
+                line if line == cmd => continue,    // echo
+                line if line.contains("ERROR") => {
+                    return Err(io::Error::other(line.to_string()));
+                },
+                line => lines.push(line.to_string()),
+            }
+        }
+    }
+
+    /// Send `cmd` and collect the response lines up to the final `OK`.
+    pub async fn command(&mut self, cmd: &str) -> io::Result<Vec<String>> {
+        match time::timeout(Duration::from_secs(3), self.exchange(cmd)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, cmd.to_string())),
+        }
+    }
+
+    /// First response line carrying `prefix`, with the prefix stripped.
+    async fn query(&mut self, cmd: &str, prefix: &str) -> Option<String> {
+        match self.command(cmd).await {
+            Ok(lines) => lines.into_iter()
+                .find(|line| line.starts_with(prefix))
+                .map(|line| line[prefix.len()..].trim().to_string()),
+            Err(e) => {
+                debug!("{}: {}", cmd, e);
+                None
+            }
+        }
+    }
+
+    pub async fn status(&mut self) -> ModemMessage {
+        let mut msg = ModemMessage {
+            time: Utc::now(),
+            ..Default::default()
+        };
+
+        if let Some(csq) = self.query("AT+CSQ", "+CSQ:").await {
+            msg.rssi = parse_csq(&csq);
+        }
+        if let Some(cesq) = self.query("AT+CESQ", "+CESQ:").await {
+            let (rsrq, rsrp) = parse_cesq(&cesq);
+            msg.rsrq = rsrq;
+            msg.rsrp = rsrp;
+        }
+        if let Some(qeng) = self.query("AT+QENG=\"servingcell\"", "+QENG:").await {
+            msg.sinr = parse_qeng_sinr(&qeng);
+        }
+        if let Some(cops) = self.query("AT+COPS?", "+COPS:").await {
+            if let Some((operator, technology)) = parse_cops(&cops) {
+                msg.operator = operator;
+                msg.technology = technology;
+            }
+        }
+        if let Ok(lines) = self.command("AT+CGSN").await {
+            msg.imei = lines.into_iter()
+                .find(|line| line.chars().all(|c| c.is_ascii_digit()))
+                .unwrap_or_default();
+        }
+        for (cmd, prefix) in [("AT+CCID", "+CCID:"), ("AT+QCCID", "+QCCID:"), ("AT+ICCID", "+ICCID:")] {
+            if let Some(iccid) = self.query(cmd, prefix).await {
+                msg.iccid = iccid.trim_matches('"').to_string();
+                break;
+            }
+        }
+
+        msg
+    }
+}
+
+pub struct ModemTask {
+    tx: Sender<Message>,
+    config: ConfigModem,
+}
+
+impl ModemTask {
+    pub fn new(config: &ConfigModem, tx: Sender<Message>) -> Self {
+        ModemTask {
+            config: config.clone(),
+            tx,
+        }
+    }
+
+    async fn at_status(&self, at: &mut Option<AtPort<SerialStream>>) -> Option<ModemMessage> {
+        if at.is_none() {
+            let builder = tokio_serial::new(&self.config.device, self.config.baud_rate);
+            match SerialStream::open(&builder) {
+                Ok(port) => {
+                    let mut port = AtPort::new(port);
+                    let _ = port.command("ATE0").await;
+                    *at = Some(port);
+                },
+                Err(e) => {
+                    warn!("open modem port {} failed: {}", self.config.device, e);
+                    return None;
+                }
+            }
+        }
+
+        let port = at.as_mut()?;
+        if port.command("AT").await.is_err() {
+            warn!("modem on {} is not responding, reopening", self.config.device);
+            *at = None;
+            return None;
+        }
+        Some(port.status().await)
+    }
+
+    /// Status through ModemManager, using mmcli as the D-Bus client.
+    async fn mm_status(&self) -> Option<ModemMessage> {
+        let modem = mmcli(&["-m", &self.config.modem]).await?;
+        let signal = mmcli(&["-m", &self.config.modem, "--signal-get"]).await;
+        let sim = match modem["modem"]["generic"]["sim"].as_str() {
+            Some(path) if path != "--" => mmcli(&["-i", path]).await,
+            _ => None,
+        };
+
+        Some(parse_mm(&modem, signal.as_ref(), sim.as_ref()))
+    }
+
+    pub async fn run(&self) {
+        let mut interval = time::interval(Duration::from_secs(self.config.interval));
+        let mut at = None;
+
+        if let ModemBackend::MODEMMANAGER = self.config.backend {
+            // extended signal values are only reported once polling is enabled
+            let rate = self.config.interval.to_string();
+            mmcli(&["-m", &self.config.modem, &format!("--signal-setup={}", rate)]).await;
+        }
+
+        loop {
+            interval.tick().await;
+
+            let status = match self.config.backend {
+                ModemBackend::AT => self.at_status(&mut at).await,
+                ModemBackend::MODEMMANAGER => self.mm_status().await,
+            };
+            let mut msg = match status {
+                Some(msg) => msg,
+                None => continue,
+            };
+
+            let stats = format!("/sys/class/net/{}/statistics", self.config.interface);
+            let counter = |name: &str| fs::read_to_string(format!("{}/{}", stats, name))
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(0);
+            msg.rx_bytes = counter("rx_bytes");
+            msg.tx_bytes = counter("tx_bytes");
+
+            if let Err(e) = self.tx.send(Message::MODEM(msg)).await {
+                warn!("{:?}", e)
+            }
+        }
+    }
+}
+
+async fn mmcli(args: &[&str]) -> Option<Value> {
+    let output = Command::new("mmcli").args(args).arg("-J").output().await;
+    match output {
+        Ok(output) if output.status.success() => serde_json::from_slice(&output.stdout).ok(),
+        Ok(output) => {
+            debug!("mmcli {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+            None
+        },
+        Err(e) => {
+            warn!("mmcli: {}", e);
+            None
+        }
+    }
+}
+
+fn fields(s: &str) -> Vec<&str> {
+    s.split(',').map(|f| f.trim().trim_matches('"')).collect()
+}
+
+/// `+CSQ: <rssi>,<ber>`, rssi index 0-31 maps to -113..-51 dBm.
+fn parse_csq(s: &str) -> Option<f64> {
+    match fields(s).first()?.parse::<u8>().ok()? {
+        n @ 0..=31 => Some(-113.0 + 2.0 * n as f64),
+        _ => None,
+    }
+}
+
+/// `+CESQ: <rxlev>,<ber>,<rscp>,<ecno>,<rsrq>,<rsrp>`, returns (rsrq, rsrp).
+fn parse_cesq(s: &str) -> (Option<f64>, Option<f64>) {
+    let f = fields(s);
+    let get = |pos: usize| f.get(pos).and_then(|v| v.parse::<u8>().ok());
+
+    let rsrq = match get(4) {
+        Some(n @ 0..=34) => Some(-20.0 + 0.5 * n as f64),
+        _ => None,
+    };
+    let rsrp = match get(5) {
+        Some(n @ 0..=97) => Some(-141.0 + n as f64),
+        _ => None,
+    };
+    (rsrq, rsrp)
+}
+
+/// Quectel `+QENG: "servingcell",<state>,"LTE",...,<rsrp>,<rsrq>,<rssi>,<sinr>,...`
+fn parse_qeng_sinr(s: &str) -> Option<f64> {
+    let f = fields(s);
+    match f.get(2) {
+        Some(&"LTE") => f.get(16)?.parse().ok(),
+        _ => None,
+    }
+}
+
+fn access_technology(act: &str) -> &'static str {
+    match act {
+        "0" | "1" | "3" | "8" => "GSM",
+        "2" | "4" | "5" | "6" => "UMTS",
+        "7" | "9" => "LTE",
+        "10" | "13" => "NR5G-NSA",
+        "11" | "12" => "NR5G",
+        _ => "",
+    }
+}
+
+/// `+COPS: <mode>,<format>,"<oper>",<act>`, returns (operator, technology).
+fn parse_cops(s: &str) -> Option<(String, String)> {
+    let f = fields(s);
+    let operator = f.get(2)?.to_string();
+    let technology = f.get(3).map(|act| access_technology(act)).unwrap_or("");
+    Some((operator, technology.to_string()))
+}
+
+fn parse_mm(modem: &Value, signal: Option<&Value>, sim: Option<&Value>) -> ModemMessage {
+    let text = |v: &Value| match v.as_str() {
+        Some("--") | None => String::new(),
+        Some(s) => s.to_string(),
+    };
+    let number = |v: &Value| v.as_str().and_then(|s| s.parse::<f64>().ok());
+
+    let generic = &modem["modem"]["generic"];
+    let technology = match &generic["access-technologies"] {
+        Value::Array(techs) => techs.iter().map(text).collect::<Vec<_>>().join(","),
+        _ => String::new(),
+    };
+
+    let mut msg = ModemMessage {
+        time: Utc::now(),
+        operator: text(&modem["modem"]["3gpp"]["operator-name"]),
+        imei: text(&modem["modem"]["3gpp"]["imei"]),
+        technology: technology.to_uppercase(),
+        ..Default::default()
+    };
+
+    if let Some(signal) = signal {
+        let signal = &signal["modem"]["signal"];
+        let rat = ["5g", "lte", "umts", "gsm"].into_iter()
+            .map(|rat| &signal[rat])
+            .find(|rat| number(&rat["rssi"]).is_some());
+        if let Some(rat) = rat {
+            msg.rssi = number(&rat["rssi"]);
+            msg.rsrp = number(&rat["rsrp"]);
+            msg.rsrq = number(&rat["rsrq"]);
+            msg.sinr = number(&rat["snr"]);
+        }
+    }
+    if let Some(sim) = sim {
+        msg.iccid = text(&sim["sim"]["properties"]["iccid"]);
+    }
+
+    msg
+}
+
+#[test]
+fn test_parse_at() {
+    assert_eq!(parse_csq("18,99"), Some(-77.0));
+    assert_eq!(parse_csq("99,99"), None);
+    assert_eq!(parse_cesq("99,99,255,255,20,45"), (Some(-10.0), Some(-96.0)));
+    assert_eq!(parse_cesq("99,99,255,255,255,255"), (None, None));
+    assert_eq!(
+        parse_cops(r#"0,0,"CHINA MOBILE",7"#),
+        Some(("CHINA MOBILE".to_string(), "LTE".to_string()))
+    );
+    assert_eq!(
+        parse_qeng_sinr(r#""servingcell","NOCONN","LTE","FDD",460,00,5F1A20B,269,1300,3,5,5,2540,-97,-11,-66,14,32"#),
+        Some(14.0)
+    );
+}
+
+#[test]
+fn test_parse_mm() {
+    let modem = serde_json::json!({
+        "modem": {
+            "generic": { "access-technologies": ["lte"], "sim": "/org/freedesktop/ModemManager1/SIM/0" },
+            "3gpp": { "imei": "861234567890123", "operator-name": "CHINA MOBILE" }
+        }
+    });
+    let signal = serde_json::json!({
+        "modem": { "signal": {
+            "gsm": { "rssi": "--" },
+            "lte": { "rssi": "-68.00", "rsrp": "-97.00", "rsrq": "-11.00", "snr": "7.20" }
+        }}
+    });
+    let sim = serde_json::json!({ "sim": { "properties": { "iccid": "89860012345678901234" } } });
+
+    let msg = parse_mm(&modem, Some(&signal), Some(&sim));
+    assert_eq!(msg.technology, "LTE");
+    assert_eq!(msg.operator, "CHINA MOBILE");
+    assert_eq!(msg.rsrp, Some(-97.0));
+    assert_eq!(msg.sinr, Some(7.2));
+    assert_eq!(msg.iccid, "89860012345678901234");
+}
+
+#[tokio::test]
+async fn test_at_emulator() {
+    let (host, modem) = SerialStream::pair().unwrap();
+
+    tokio::spawn(async move {
+        let mut modem = BufReader::new(modem);
+        loop {
+            let mut buf = Vec::new();
+            if modem.read_until(b'\r', &mut buf).await.unwrap_or(0) == 0 {
+                break;
+            }
+            let reply = match String::from_utf8_lossy(&buf).trim() {
+                "AT+CSQ" => "\r\n+CSQ: 21,99\r\n\r\nOK\r\n",
+                "AT+CESQ" => "\r\n+CESQ: 99,99,255,255,22,40\r\n\r\nOK\r\n",
+                "AT+COPS?" => "\r\n+COPS: 0,0,\"CHN-UNICOM\",7\r\n\r\nOK\r\n",
+                "AT+CGSN" => "\r\n861234567890123\r\n\r\nOK\r\n",
+                "AT+CCID" => "\r\n+CCID: 89860112345678901234\r\n\r\nOK\r\n",
+                _ => "\r\nERROR\r\n",
+            };
+            modem.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        }
+    });
+
+    let mut at = AtPort::new(host);
+    let msg = at.status().await;
+    assert_eq!(msg.rssi, Some(-71.0));
+    assert_eq!(msg.rsrp, Some(-101.0));
+    assert_eq!(msg.rsrq, Some(-9.0));
+    assert_eq!(msg.sinr, None);
+    assert_eq!(msg.operator, "CHN-UNICOM");
+    assert_eq!(msg.technology, "LTE");
+    assert_eq!(msg.imei, "861234567890123");
+    assert_eq!(msg.iccid, "89860112345678901234");
+}
@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use log::*;
+use regex::Regex;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{self, Duration};
+use chrono::prelude::*;
+
+use tokio_serial::{SerialStream, DataBits, Parity, StopBits};
+
+use crate::message::{
+    Message,
+    RecordMessage,
+    FieldValue,
+};
+use crate::config::{ConfigSerial, ConfigField, SerialParser};
+use crate::utils::decode_value;
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+type Fields = BTreeMap<String, FieldValue>;
+
+/// Splits the byte stream into frames and turns each frame into fields.
+pub struct Parser {
+    config: SerialParser,
+    regex: Option<Regex>,
+    buf: Vec<u8>,
+    max_frame: usize,
+}
+
+impl Parser {
+    pub fn new(config: &SerialParser, max_frame: usize) -> Self {
+        let regex = match config {
+            SerialParser::REGEX { pattern } => {
+                Some(Regex::new(pattern).expect("invalid serial parser pattern"))
+            },
+            _ => None,
+        };
+
+        Parser {
+            config: config.clone(),
+            regex,
+            buf: Vec::new(),
+            max_frame,
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        match &self.config {
+            SerialParser::LINE { .. } | SerialParser::REGEX { .. } => {
+                let end = self.buf.iter().position(|b| *b == b'\n')?;
+                let mut line: Vec<u8> = self.buf.drain(..=end).collect();
+                while let Some(b'\n') | Some(b'\r') = line.last() {
+                    line.pop();
+                }
+                Some(line)
+            },
+            SerialParser::BINARY { length, .. } => {
+                if self.buf.len() < *length {
+                    return None;
+                }
+                Some(self.buf.drain(..*length).collect())
+            },
+            SerialParser::SLIP { .. } => {
+                let end = self.buf.iter().position(|b| *b == SLIP_END)?;
+                let frame: Vec<u8> = self.buf.drain(..=end).collect();
+                Some(slip_decode(&frame[..end]))
+            },
+        }
+    }
+
+    fn parse(&self, frame: &[u8]) -> Option<Fields> {
+        let mut fields = Fields::new();
+
+        match &self.config {
+            SerialParser::LINE { separator, names } => {
+                let line = String::from_utf8_lossy(frame);
+                let line = line.trim();
+                match separator {
+                    Some(sep) => {
+                        for (pos, value) in line.split(sep.as_str()).enumerate() {
+                            let name = names.get(pos).cloned().unwrap_or_else(|| pos.to_string());
+                            fields.insert(name, FieldValue::from(value.trim()));
+                        }
+                    },
+                    None => {
+                        fields.insert("line".to_string(), FieldValue::Text(line.to_string()));
+                    }
+                }
+            },
+            SerialParser::REGEX { .. } => {
+                let line = String::from_utf8_lossy(frame);
+                let regex = self.regex.as_ref()?;
+                let captures = regex.captures(&line)?;
+                for name in regex.capture_names().flatten() {
+                    if let Some(value) = captures.name(name) {
+                        fields.insert(name.to_string(), FieldValue::from(value.as_str()));
+                    }
+                }
+            },
+            SerialParser::BINARY { fields: layout, .. } | SerialParser::SLIP { fields: layout } => {
+                decode_fields(layout, frame, &mut fields);
+            },
+        }
+
+        match fields.is_empty() {
+            true => None,
+            false => Some(fields),
+        }
+    }
+
+    /// Feed received bytes, returns the records completed by them.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Fields> {
+        self.buf.extend_from_slice(data);
+
+        let mut records = Vec::new();
+        while let Some(frame) = self.next_frame() {
+            if frame.is_empty() {
+                continue;
+            }
+            if let Some(fields) = self.parse(&frame) {
+                records.push(fields);
+            }
+        }
+        // a frame end that never comes, binary frames are drained at their length
+        if self.buf.len() > self.max_frame {
+            warn!("serial: no frame end in {} bytes, dropped", self.buf.len());
+            self.buf.clear();
+        }
+        records
+    }
+}
+
+fn decode_fields(layout: &[ConfigField], frame: &[u8], fields: &mut Fields) {
+    if layout.is_empty() {
+        let hex: String = frame.iter().map(|b| format!("{:02X}", b)).collect();
+        fields.insert("data".to_string(), FieldValue::Text(hex));
+        return;
+    }
+
+    for field in layout {
+        let value = frame.get(field.offset..)
+            .and_then(|buf| decode_value(&field.data_type, buf));
+        if let Some(value) = value {
+            fields.insert(field.name.clone(), FieldValue::Number(value * field.scale));
+        }
+    }
+}
+
+fn slip_decode(frame: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frame.len());
+    let mut escaped = false;
+
+    for b in frame {
+        match (escaped, *b) {
+            (false, SLIP_ESC) => { escaped = true; continue; },
+            (true, SLIP_ESC_END) => out.push(SLIP_END),
+            (true, SLIP_ESC_ESC) => out.push(SLIP_ESC),
+            (_, b) => out.push(b),
+        }
+        escaped = false;
+    }
+    out
+}
+
+pub struct SerialTask {
+    tx: Sender<Message>,
+    config: ConfigSerial,
+}
+
+impl SerialTask {
+    pub fn new(config: &ConfigSerial, tx: Sender<Message>) -> Self {
+        SerialTask {
+            config: config.clone(),
+            tx,
+        }
+    }
+
+    fn open(&self) -> tokio_serial::Result<SerialStream> {
+        let data_bits = match self.config.data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            _ => DataBits::Eight,
+        };
+        let parity = match self.config.parity.as_str() {
+            "ODD" => Parity::Odd,
+            "EVEN" => Parity::Even,
+            _ => Parity::None,
+        };
+        let stop_bits = match self.config.stop_bits {
+            2 => StopBits::Two,
+            _ => StopBits::One,
+        };
+
+        let builder = tokio_serial::new(&self.config.device, self.config.baud_rate)
+            .data_bits(data_bits)
+            .parity(parity)
+            .stop_bits(stop_bits);
+        SerialStream::open(&builder)
+    }
+
+    pub async fn run(&self) {
+        loop {
+            let mut port = match self.open() {
+                Ok(port) => port,
+                Err(e) => {
+                    warn!("{}: open {} failed: {}", self.config.name, self.config.device, e);
+                    time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+
+            let mut parser = Parser::new(&self.config.parser, self.config.max_frame);
+            let mut buf = [0u8; 1024];
+            loop {
+                let size = match port.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(size) => size,
+                    Err(e) => {
+                        warn!("{}: read failed: {}", self.config.name, e);
+                        break;
+                    }
+                };
+
+                for fields in parser.push(&buf[..size]) {
+                    let msg = RecordMessage {
+                        time: Utc::now(),
+                        source: self.config.name.clone(),
+                        fields,
+                    };
+                    if let Err(e) = self.tx.send(Message::RECORD(msg)).await {
+                        warn!("{:?}", e)
+                    }
+                }
+            }
+            time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[test]
+fn test_parser() {
+    use crate::config::DataType;
+
+    let mut line = Parser::new(&SerialParser::LINE {
+        separator: Some(",".to_string()),
+        names: vec!["weight".to_string(), "unit".to_string()],
+    }, 4096);
+    assert!(line.push(b"12.5,").is_empty());
+    let records = line.push(b"kg\r\n7,lb\r\n");
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["weight"], FieldValue::Number(12.5));
+    assert_eq!(records[1]["unit"], FieldValue::Text("lb".to_string()));
+
+    let mut regex = Parser::new(&SerialParser::REGEX {
+        pattern: r"T=(?P<temp>-?\d+\.\d+) H=(?P<humidity>\d+)%".to_string(),
+    }, 4096);
+    let records = regex.push(b"noise\nT=-3.5 H=40%\n");
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["temp"], FieldValue::Number(-3.5));
+    assert_eq!(records[0]["humidity"], FieldValue::Number(40.0));
+
+    let layout = vec![
+        ConfigField { name: "id".to_string(), offset: 0, data_type: DataType::U8, scale: 1.0 },
+        ConfigField { name: "level".to_string(), offset: 1, data_type: DataType::I16, scale: 0.1 },
+    ];
+    let mut binary = Parser::new(&SerialParser::BINARY { length: 3, fields: layout.clone() }, 4096);
+    let records = binary.push(&[0x07, 0x01, 0x00, 0x08]);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["id"], FieldValue::Number(7.0));
+    assert_eq!(records[0]["level"], FieldValue::Number(25.6));
+
+    let mut slip = Parser::new(&SerialParser::SLIP { fields: layout }, 4096);
+    let records = slip.push(&[SLIP_END, 0x05, SLIP_ESC, SLIP_ESC_END, 0x00, SLIP_END]);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["id"], FieldValue::Number(5.0));
+    assert_eq!(records[0]["level"], FieldValue::Number(-16384.0 * 0.1));
+
+    // garbage without a line end is dropped instead of piling up
+    let mut line = Parser::new(&SerialParser::LINE { separator: None, names: Vec::new() }, 8);
+    assert!(line.push(b"0123456789").is_empty());
+    let records = line.push(b"ok\n");
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["line"], FieldValue::Text("ok".to_string()));
+}
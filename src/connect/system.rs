@@ -0,0 +1,191 @@
+use std::fs;
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use log::*;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{self, Duration};
+use chrono::prelude::*;
+
+use crate::message::{
+    Message,
+    SystemMessage,
+    NetCounters,
+};
+use crate::config::ConfigSystem;
+use crate::output::{CaptureStats, MqttStats};
+
+pub struct SystemTask {
+    tx: Sender<Message>,
+    capacity: usize,    // free slots of the channel when the task started
+    interval: u64,
+    path: String,
+    cpu: (u64, u64),    // (busy, total) jiffies of the previous sample
+    stats: Arc<MqttStats>,
+    capture: Arc<CaptureStats>,
+}
+
+impl SystemTask {
+    pub fn new(
+        config: &ConfigSystem, data_path: &str, tx: Sender<Message>, stats: Arc<MqttStats>, capture: Arc<CaptureStats>
+    ) -> Self {
+        let path = match Path::new(data_path).parent() {
+            Some(dir) if dir != Path::new("") => dir.to_string_lossy().to_string(),
+            _ => ".".to_string(),
+        };
+
+        SystemTask {
+            interval: config.interval,
+            path,
+            cpu: (0, 0),
+            capacity: tx.capacity(),
+            stats,
+            capture,
+            tx,
+        }
+    }
+
+    fn cpu(&mut self) -> f64 {
+        let (busy, total) = match fs::read_to_string("/proc/stat") {
+            Ok(stat) => parse_stat(&stat).unwrap_or((0, 0)),
+            Err(_) => (0, 0),
+        };
+        let (prev_busy, prev_total) = self.cpu;
+        self.cpu = (busy, total);
+
+        match total.saturating_sub(prev_total) {
+            0 => 0.0,
+            delta => busy.saturating_sub(prev_busy) as f64 * 100.0 / delta as f64,
+        }
+    }
+
+    fn sample(&mut self) -> SystemMessage {
+        let read = |path: &str| fs::read_to_string(path).unwrap_or_default();
+
+        let meminfo = read("/proc/meminfo");
+        let (disk_total, disk_free) = disk_usage(&self.path).unwrap_or((0, 0));
+
+        SystemMessage {
+            time: Utc::now(),
+            cpu: self.cpu(),
+            load: first_field(&read("/proc/loadavg")),
+            mem_total: parse_kb(&meminfo, "MemTotal:"),
+            mem_available: parse_kb(&meminfo, "MemAvailable:"),
+            disk_total,
+            disk_free,
+            uptime: first_field(&read("/proc/uptime")),
+            rss: parse_kb(&read("/proc/self/status"), "VmRSS:"),
+            backlog: self.capacity.saturating_sub(self.tx.capacity()),
+            net: parse_net_dev(&read("/proc/net/dev")),
+            delivered: self.stats.delivered.load(Ordering::Relaxed),
+            failed: self.stats.failed.load(Ordering::Relaxed),
+            capture_dropped: self.capture.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub async fn run(&mut self) {
+        let mut interval = time::interval(Duration::from_secs(self.interval));
+
+        loop {
+            interval.tick().await;
+            let msg = self.sample();
+            if let Err(e) = self.tx.send(Message::SYSTEM(msg)).await {
+                warn!("{:?}", e)
+            }
+        }
+    }
+}
+
+fn disk_usage(path: &str) -> Option<(u64, u64)> {
+    let path = CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    let frsize = stat.f_frsize as u64;
+    Some((stat.f_blocks as u64 * frsize, stat.f_bavail as u64 * frsize))
+}
+
+fn first_field(s: &str) -> f64 {
+    s.split_whitespace()
+        .next()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0.0)
+}
+
+/// Value of a `Key:   1234 kB` line in bytes.
+fn parse_kb(s: &str, key: &str) -> u64 {
+    s.lines()
+        .find(|line| line.starts_with(key))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .unwrap_or(0)
+}
+
+fn parse_stat(s: &str) -> Option<(u64, u64)> {
+    let line = s.lines().find(|line| line.starts_with("cpu "))?;
+    let fields: Vec<u64> = line.split_whitespace()
+        .skip(1)
+        .filter_map(|v| v.parse().ok())
+        .collect();
+
+    // user nice system idle iowait irq softirq steal ...
+    let total: u64 = fields.iter().sum();
+    let idle = fields.get(3).unwrap_or(&0) + fields.get(4).unwrap_or(&0);
+    Some((total - idle, total))
+}
+
+fn parse_net_dev(s: &str) -> Vec<NetCounters> {
+    s.lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (name, data) = line.split_once(':')?;
+            let v: Vec<u64> = data.split_whitespace()
+                .filter_map(|v| v.parse().ok())
+                .collect();
+            if v.len() < 11 {
+                return None;
+            }
+
+            Some(NetCounters {
+                name: name.trim().to_string(),
+                rx_bytes: v[0],
+                rx_packets: v[1],
+                rx_errors: v[2],
+                tx_bytes: v[8],
+                tx_packets: v[9],
+                tx_errors: v[10],
+            })
+        })
+        .filter(|counters| counters.name != "lo")
+        .collect()
+}
+
+#[test]
+fn test_parse_proc() {
+    let meminfo = "MemTotal:         117892 kB\n\
+                   MemFree:           10212 kB\n\
+                   MemAvailable:      63544 kB\n";
+    assert_eq!(parse_kb(meminfo, "MemTotal:"), 117892 * 1024);
+    assert_eq!(parse_kb(meminfo, "MemAvailable:"), 63544 * 1024);
+    assert_eq!(parse_kb(meminfo, "SwapTotal:"), 0);
+
+    let stat = "cpu  100 0 50 800 50 0 0 0 0 0\ncpu0 100 0 50 800 50 0 0 0 0 0\n";
+    assert_eq!(parse_stat(stat), Some((150, 1000)));
+
+    assert_eq!(first_field("0.42 0.30 0.21 1/123 4567\n"), 0.42);
+
+    let net = "Inter-|   Receive                                                |  Transmit\n \
+               face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n    \
+               lo:    1000      10    0    0    0     0          0         0     1000      10    0    0    0     0       0          0\n  \
+               wwan0: 2048      20    1    0    0     0          0         0     4096      40    2    0    0     0       0          0\n";
+    let counters = parse_net_dev(net);
+    assert_eq!(counters.len(), 1);
+    assert_eq!(counters[0].name, "wwan0");
+    assert_eq!(counters[0].rx_bytes, 2048);
+    assert_eq!(counters[0].tx_packets, 40);
+    assert_eq!(counters[0].tx_errors, 2);
+}
@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use log::*;
+use regex::Regex;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{unix::AsyncFd, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+use chrono::prelude::*;
+
+use crate::config::{ConfigLog, ConfigUsb};
+use crate::message::{ExportMessage, Message};
+use crate::output::{closed_logs, Control};
+
+const COPY_BUFFER: usize = 256 * 1024;
+
+/// Kernel uevent as its action and KEY=VALUE pairs.
+fn parse_uevent(buf: &[u8]) -> Option<BTreeMap<String, String>> {
+    let mut parts = buf.split(|b| *b == 0).filter(|part| !part.is_empty());
+    // "add@/devices/...", anything else is udev's re-broadcast
+    let header = String::from_utf8_lossy(parts.next()?).to_string();
+    header.split_once('@')?;
+
+    let mut event = BTreeMap::new();
+    for part in parts {
+        let part = String::from_utf8_lossy(part);
+        if let Some((key, value)) = part.split_once('=') {
+            event.insert(key.to_string(), value.to_string());
+        }
+    }
+    Some(event)
+}
+
+/// Netlink socket with the kernel's device events.
+fn uevents() -> io::Result<AsyncFd<OwnedFd>> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = 1;     // kernel events, udev's own go to group 2
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // the socket is owned and stays open as long as the AsyncFd
+    Ok(unsafe { AsyncFd::register(fd) }?)
+}
+
+async fn recv(socket: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = socket.readable().await?;
+        let result = guard.try_io(|fd| {
+            let n = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            match n < 0 {
+                true => Err(io::Error::last_os_error()),
+                false => Ok(n as usize),
+            }
+        });
+        if let Ok(result) = result {
+            return result;
+        }
+    }
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Mount `device` at `target` with the first filesystem that takes it.
+fn mount(device: &Path, target: &Path, filesystems: &[String]) -> io::Result<String> {
+    let (source, target) = (cstring(device)?, cstring(target)?);
+    let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOATIME;
+    let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no filesystem to try");
+    for fstype in filesystems {
+        let fs = CString::new(fstype.as_str()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let ret = unsafe { libc::mount(source.as_ptr(), target.as_ptr(), fs.as_ptr(), flags, std::ptr::null()) };
+        if ret == 0 {
+            return Ok(fstype.clone());
+        }
+        error = io::Error::last_os_error();
+    }
+    Err(error)
+}
+
+/// Flush and unmount, detaching lazily when the drive is gone or still busy.
+async fn unmount(target: &Path) {
+    unsafe { libc::sync() };
+    let target = match cstring(target) {
+        Ok(target) => target,
+        Err(_) => return,
+    };
+    for _ in 0..5 {
+        if unsafe { libc::umount2(target.as_ptr(), 0) } == 0 {
+            return;
+        }
+        time::sleep(Duration::from_secs(1)).await;
+    }
+    warn!("usb: lazy unmount: {}", io::Error::last_os_error());
+    unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+}
+
+/// Copy a file, returns the SHA-256 of what was read.
+async fn copy(from: &Path, to: &Path) -> io::Result<String> {
+    let mut reader = File::open(from).await?;
+    let mut writer = File::create(to).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; COPY_BUFFER];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n]).await?;
+    }
+    writer.sync_all().await?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// SHA-256 of a file as the drive has it, not as the page cache does.
+async fn checksum(path: &Path) -> io::Result<String> {
+    let mut reader = File::open(path).await?;
+    unsafe { libc::posix_fadvise(reader.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; COPY_BUFFER];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Copies the closed log files to USB drives as they are plugged in, with a
+/// manifest of checksums, and reports how it goes as EXPORT status messages.
+pub struct UsbTask {
+    config: ConfigUsb,
+    devices: Regex,
+    log: PathBuf,
+    device_id: String,
+    tx: Sender<Message>,
+    control: Sender<Control>,
+}
+
+impl UsbTask {
+    pub fn new(
+        config: &ConfigUsb, log: &ConfigLog, device_id: &str, tx: Sender<Message>, control: Sender<Control>
+    ) -> Self {
+        UsbTask {
+            config: config.clone(),
+            devices: Regex::new(&config.devices).unwrap(),
+            log: PathBuf::from(&log.path),
+            device_id: device_id.to_string(),
+            tx,
+            control,
+        }
+    }
+
+    async fn report(&self, msg: &ExportMessage) {
+        let msg = ExportMessage { time: Utc::now(), ..msg.clone() };
+        if let Err(e) = self.tx.send(Message::EXPORT(msg)).await {
+            error!("{}", e);
+        }
+    }
+
+    /// Hard link the closed log files into `pins`, a rotation while they are
+    /// copied renames the files but can't change what the links point at.
+    fn pin_all(&self, pins: &Path) -> io::Result<Vec<PathBuf>> {
+        let _ = fs::remove_dir_all(pins);
+        fs::create_dir_all(pins)?;
+        let mut pinned = vec![];
+        for closed in closed_logs(&self.log)? {
+            let pin = pins.join(closed.path.file_name().unwrap());
+            fs::hard_link(&closed.path, &pin)?;
+            pinned.push(pin);
+        }
+        Ok(pinned)
+    }
+
+    /// Copy and verify every pinned file into `dir`, returns the pins that
+    /// made it with their checksums.
+    async fn copy_all(&self, dir: &Path, pins: &Path, progress: &mut ExportMessage) -> io::Result<Vec<(PathBuf, String)>> {
+        let files = self.pin_all(pins)?;
+        progress.total_files = files.len() as u32;
+        progress.total_bytes = files.iter().filter_map(|path| fs::metadata(path).ok()).map(|m| m.len()).sum();
+        progress.state = "COPYING".to_string();
+        self.report(progress).await;
+
+        fs::create_dir_all(dir)?;
+        let mut manifest = vec![];
+        let mut copied = vec![];
+        for path in files {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let target = dir.join(&name);
+            let sha256 = copy(&path, &target).await?;
+            if checksum(&target).await? != sha256 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} does not verify", name)));
+            }
+
+            let size = fs::metadata(&target)?.len();
+            manifest.push(json!({ "name": name, "size": size, "sha256": sha256 }));
+            copied.push((path, sha256));
+            progress.files += 1;
+            progress.bytes += size;
+            self.report(progress).await;
+        }
+
+        // the manifest goes last, a drive pulled early has none
+        let manifest = json!({
+            "device_id": self.device_id,
+            "time": Utc::now(),
+            "files": manifest,
+        });
+        let mut file = File::create(dir.join("manifest.json")).await?;
+        file.write_all(serde_json::to_string_pretty(&manifest).unwrap().as_bytes()).await?;
+        file.sync_all().await?;
+
+        Ok(copied)
+    }
+
+    /// Pins that still hold what was copied, the rest stay behind.
+    async fn verified(copied: Vec<(PathBuf, String)>) -> Vec<PathBuf> {
+        let mut pins = vec![];
+        for (pin, sha256) in copied {
+            match checksum(&pin).await {
+                Ok(now) if now == sha256 => pins.push(pin),
+                _ => {
+                    warn!("usb: {} changed since it was copied, kept", pin.display());
+                    let _ = fs::remove_file(&pin);
+                },
+            }
+        }
+        pins
+    }
+
+    async fn export(&self, device: &str) {
+        let mut progress = ExportMessage { device: device.to_string(), state: "STARTED".to_string(), ..Default::default() };
+        self.report(&progress).await;
+
+        // next to the log, hard links need its filesystem
+        let pins = self.log.with_file_name(".usb-export");
+
+        let target = PathBuf::from(&self.config.mount);
+        let mounted = match fs::create_dir_all(&target) {
+            Ok(()) => mount(&Path::new("/dev").join(device), &target, &self.config.filesystems),
+            Err(e) => Err(e),
+        };
+        let result = match mounted {
+            Ok(fstype) => {
+                info!("usb: {} mounted ({}) at {}", device, fstype, target.display());
+                let dir = target
+                    .join(self.config.dir.replace("{device}", &self.device_id))
+                    .join(Utc::now().format("%Y%m%dT%H%M%S").to_string());
+                let result = self.copy_all(&dir, &pins, &mut progress).await;
+                unmount(&target).await;
+                result
+            },
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(copied) => {
+                info!("usb: {} files ({} bytes) exported to {}", progress.files, progress.bytes, device);
+                // the output deletes the files by their pins, and the pins with them
+                match self.config.delete {
+                    true => {
+                        let pruned = Self::verified(copied).await;
+                        if let Err(e) = self.control.send(Control::Prune(pruned)).await {
+                            error!("{}", e);
+                        }
+                    },
+                    false => { let _ = fs::remove_dir_all(&pins); },
+                }
+                progress.state = "DONE".to_string();
+            },
+            Err(e) => {
+                let _ = fs::remove_dir_all(&pins);
+                warn!("usb: export to {}: {}", device, e);
+                progress.state = "FAILED".to_string();
+                progress.error = e.to_string();
+            },
+        }
+        self.report(&progress).await;
+    }
+
+    pub async fn run(self) {
+        let socket = match uevents() {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("usb: uevent socket: {}", e);
+                return;
+            }
+        };
+
+        // exports run beside the uevent loop, one at a time as they share the mount point
+        let task = Arc::new(self);
+        let mut export: Option<JoinHandle<()>> = None;
+        let mut buf = vec![0; 8192];
+        loop {
+            let n = match recv(&socket, &mut buf).await {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("usb: {}", e);
+                    continue;
+                }
+            };
+            let event = match parse_uevent(&buf[..n]) {
+                Some(event) => event,
+                None => continue,
+            };
+            let is_add = event.get("ACTION").map(String::as_str) == Some("add");
+            let is_block = event.get("SUBSYSTEM").map(String::as_str) == Some("block");
+            if let (true, true, Some(device)) = (is_add, is_block, event.get("DEVNAME")) {
+                if !task.devices.is_match(device) {
+                    continue;
+                }
+                if export.as_ref().is_some_and(|export| !export.is_finished()) {
+                    warn!("usb: export still running, {} skipped", device);
+                    continue;
+                }
+                let (task, device) = (task.clone(), device.clone());
+                export = Some(tokio::spawn(async move { task.export(&device).await }));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_uevent() {
+    let buf = b"add@/devices/platform/usb/1-1/host0/block/sda/sda1\0ACTION=add\0\
+        DEVPATH=/devices/platform/usb/1-1/host0/block/sda/sda1\0SUBSYSTEM=block\0\
+        DEVNAME=sda1\0DEVTYPE=partition\0SEQNUM=1234\0";
+    let event = parse_uevent(buf).unwrap();
+    assert_eq!(event["ACTION"], "add");
+    assert_eq!(event["SUBSYSTEM"], "block");
+    assert_eq!(event["DEVNAME"], "sda1");
+
+    assert!(parse_uevent(b"libudev\0\0\0\0").is_none());
+}
+
+#[tokio::test]
+async fn test_copy() {
+    let dir = std::env::temp_dir().join(format!("iot-edge-usb-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("iot-edge.log.1"), "hello\n").unwrap();
+
+    let sha256 = copy(&dir.join("iot-edge.log.1"), &dir.join("copy")).await.unwrap();
+    assert_eq!(sha256, "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03");
+    assert_eq!(checksum(&dir.join("copy")).await.unwrap(), sha256);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
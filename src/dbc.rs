@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use regex::Regex;
+
+/// Where a signal sits in a multiplexed message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mux {
+    NONE,
+    MULTIPLEXOR,    // M, selects which of the others are in the frame
+    VALUE(u64),     // mN, only there when the multiplexor is N
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DbcSignal {
+    pub name: String,
+    pub start: u16,         // LSB for Intel, MSB for Motorola, in DBC bit numbering
+    pub length: u16,
+    pub little_endian: bool,
+    pub signed: bool,
+    pub factor: f64,
+    pub offset: f64,
+    pub unit: String,
+    pub mux: Mux,
+    pub values: Vec<(i64, String)>,     // VAL_ descriptions of raw values
+}
+
+impl DbcSignal {
+    /// Raw value, sign extended, or None when the frame is too short for it.
+    pub fn raw(&self, data: &[u8]) -> Option<i64> {
+        let bit = |pos: usize| data.get(pos / 8).map(|b| (b >> (pos % 8)) & 1);
+        let (start, length) = (self.start as usize, self.length.clamp(1, 64) as usize);
+        let mut raw: u64 = 0;
+        match self.little_endian {
+            true => for i in 0..length {
+                raw |= (bit(start + i)? as u64) << i;
+            },
+            // Motorola walks from the MSB down and on to the next byte
+            false => {
+                let mut pos = start;
+                for _ in 0..length {
+                    raw = (raw << 1) | bit(pos)? as u64;
+                    pos = match pos % 8 {
+                        0 => pos + 15,
+                        _ => pos - 1,
+                    };
+                }
+            },
+        }
+        if self.signed & (length < 64) & (raw >> (length - 1) & 1 == 1) {
+            raw |= u64::MAX << length;
+        }
+        Some(raw as i64)
+    }
+
+    /// Physical value of a raw one, MDF keeps raw values with the conversion.
+    #[allow(dead_code)]
+    pub fn value(&self, raw: i64) -> f64 {
+        raw as f64 * self.factor + self.offset
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DbcMessage {
+    pub id: u32,
+    pub extended: bool,
+    pub name: String,
+    pub size: u8,
+    pub signals: Vec<DbcSignal>,
+}
+
+impl DbcMessage {
+    /// Whether a multiplexed signal is in this frame, plain signals always are.
+    pub fn present(&self, signal: &DbcSignal, data: &[u8]) -> bool {
+        match signal.mux {
+            Mux::VALUE(value) => self.signals.iter()
+                .find(|s| s.mux == Mux::MULTIPLEXOR)
+                .and_then(|s| s.raw(data))
+                .is_some_and(|raw| raw as u64 == value),
+            _ => true,
+        }
+    }
+}
+
+/// The messages and signals of DBC files, enough to decode frames. Attributes,
+/// nodes and float signals are not read.
+#[derive(Clone, Debug, Default)]
+pub struct Dbc {
+    pub messages: Vec<DbcMessage>,
+}
+
+impl Dbc {
+    pub fn open(path: &Path) -> io::Result<Dbc> {
+        Ok(Dbc::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Several files as one, the first one wins when they define the same frame.
+    pub fn open_all(paths: &[String]) -> io::Result<Dbc> {
+        let mut dbc = Dbc::default();
+        for path in paths {
+            for message in Dbc::open(Path::new(path))?.messages {
+                if dbc.find(message.id, message.extended).is_none() {
+                    dbc.messages.push(message);
+                }
+            }
+        }
+        Ok(dbc)
+    }
+
+    pub fn parse(text: &str) -> Dbc {
+        let message = Regex::new(r"^BO_\s+(\d+)\s+(\w+)\s*:\s*(\d+)").unwrap();
+        let signal = Regex::new(
+            r#"^SG_\s+(\w+)\s*(M|m\d+)?\s*:\s*(\d+)\|(\d+)@([01])([+-])\s*\(([^,]+),([^)]+)\)\s*\[[^\]]*\]\s*"([^"]*)""#
+        ).unwrap();
+        let values = Regex::new(r"^VAL_\s+(\d+)\s+(\w+)\s+(.*);").unwrap();
+        let value = Regex::new(r#"(-?\d+)\s+"([^"]*)""#).unwrap();
+
+        let mut messages: Vec<DbcMessage> = Vec::new();
+        let mut descriptions = BTreeMap::new();
+        for line in text.lines().map(str::trim) {
+            if let Some(c) = message.captures(line) {
+                let id: u32 = c[1].parse().unwrap_or(0);
+                messages.push(DbcMessage {
+                    id: id & 0x1FFF_FFFF,
+                    extended: id & 0x8000_0000 != 0,
+                    name: c[2].to_string(),
+                    size: c[3].parse().unwrap_or(8),
+                    signals: Vec::new(),
+                });
+            } else if let (Some(c), Some(last)) = (signal.captures(line), messages.last_mut()) {
+                let mux = match c.get(2).map(|m| m.as_str()) {
+                    Some("M") => Mux::MULTIPLEXOR,
+                    Some(m) => Mux::VALUE(m[1..].parse().unwrap_or(0)),
+                    None => Mux::NONE,
+                };
+                last.signals.push(DbcSignal {
+                    name: c[1].to_string(),
+                    start: c[3].parse().unwrap_or(0),
+                    length: c[4].parse().unwrap_or(1),
+                    little_endian: &c[5] == "1",
+                    signed: &c[6] == "-",
+                    factor: c[7].trim().parse().unwrap_or(1.0),
+                    offset: c[8].trim().parse().unwrap_or(0.0),
+                    unit: c[9].to_string(),
+                    mux,
+                    values: Vec::new(),
+                });
+            } else if let Some(c) = values.captures(line) {
+                let table: Vec<(i64, String)> = value.captures_iter(&c[3])
+                    .filter_map(|v| Some((v[1].parse().ok()?, v[2].to_string())))
+                    .collect();
+                descriptions.insert((c[1].parse::<u32>().unwrap_or(0), c[2].to_string()), table);
+            }
+        }
+
+        // VECTOR__INDEPENDENT_SIG_MSG holds signals that are not on the bus
+        messages.retain(|m| (m.name != "VECTOR__INDEPENDENT_SIG_MSG") & !m.signals.is_empty());
+        for message in messages.iter_mut() {
+            let id = message.id | ((message.extended as u32) << 31);
+            for signal in message.signals.iter_mut() {
+                if let Some(table) = descriptions.remove(&(id, signal.name.clone())) {
+                    signal.values = table;
+                }
+            }
+        }
+        Dbc { messages }
+    }
+
+    pub fn find(&self, id: u32, extended: bool) -> Option<&DbcMessage> {
+        self.messages.iter().find(|m| (m.id == id) & (m.extended == extended))
+    }
+}
+
+#[test]
+fn test_parse() {
+    let dbc = Dbc::parse(r#"
+VERSION ""
+
+BO_ 3221225472 VECTOR__INDEPENDENT_SIG_MSG: 0 Vector__XXX
+ SG_ TrailerWeight : 0|16@1+ (2,0) [0|128510] "kg" Vector__XXX
+
+BO_ 2566712062 VEP4: 8 Vector__XXX
+ SG_ HybrdBatteryPackRemainingCharge : 0|16@1+ (0.0025,0) [0|160.6375] "%" Vector__XXX
+
+BO_ 408 TransmissionData: 8 EGS
+ SG_ ShifterPosition : 7|4@0+ (1,0) [0|15] "" XXX
+ SG_ Temperature : 15|12@0- (0.5,-40) [-1064|983.5] "degC" XXX
+ SG_ Page M : 32|2@1+ (1,0) [0|3] "" XXX
+ SG_ Odometer m1 : 40|24@1+ (0.1,0) [0|1677721.5] "km" XXX
+
+VAL_ 408 ShifterPosition 1 "D" 2 "S" 3 "N" 4 "R" 5 "P" ;
+"#);
+    assert_eq!(dbc.messages.len(), 2);
+    let vep4 = dbc.find(0x18FCEAFE, true).unwrap();
+    assert_eq!((vep4.name.as_str(), vep4.signals[0].factor, vep4.signals[0].unit.as_str()), ("VEP4", 0.0025, "%"));
+    assert_eq!(vep4.signals[0].raw(&[0x10, 0x27, 0, 0, 0, 0, 0, 0]), Some(10000));
+
+    let trans = dbc.find(408, false).unwrap();
+    let data = [0x30, 0xFF, 0xE0, 0, 0x01, 0x39, 0x30, 0];
+    assert_eq!(trans.signals[0].raw(&data), Some(3));
+    assert_eq!(trans.signals[0].values[2], (3, "N".to_string()));
+    // 0xFFE sign extended
+    assert_eq!(trans.signals[1].raw(&data), Some(-2));
+    assert_eq!(trans.signals[1].value(-2), -41.0);
+    assert_eq!((trans.signals[2].mux.clone(), trans.signals[3].mux.clone()), (Mux::MULTIPLEXOR, Mux::VALUE(1)));
+    assert_eq!(trans.signals[3].raw(&data), Some(0x3039));
+    assert!(trans.present(&trans.signals[3], &data));
+    assert!(!trans.present(&trans.signals[3], &[0, 0, 0, 0, 2, 0, 0, 0]));
+    assert_eq!(trans.signals[3].raw(&data[..5]), None);
+}
@@ -1,15 +1,28 @@
 use std::error::Error;
 use std::convert::From;
 use std::fmt;
-use rumqttc::{Request, SendError};
+use std::io;
+use rumqttc::{v5, ClientError, ConnectionError};
 
 
 #[derive(Debug)]
+#[allow(dead_code)]     // fields are shown through Debug
 pub enum IotEdgeError {
     Generic(&'static str),
+    IoError(io::Error),
+    ConfigError(String),
    
     MqttSendError,
-    MqttPubAckError,
+    MqttBacklogFull,        // too many publishes waiting for the client
+    MqttPubAckError(u16),   // packet id that was not acknowledged in time
+    MqttConnectionError(Box<ConnectionError>),
+    Mqtt5ConnectionError(Box<v5::ConnectionError>),
+    MqttDisconnectError(String),        // reason code of a broker initiated disconnect
+    MqttTlsError(String),   // certificate or key that could not be loaded
+
+    GpioError(String),      // chip or line that could not be requested
+    UploadError(String),        // the object store could not be reached
+    S3Error(u16, String),       // HTTP status and error code the object store answered with
 }
 
 impl fmt::Display for IotEdgeError {
@@ -20,15 +33,44 @@ impl fmt::Display for IotEdgeError {
 
 impl Error for IotEdgeError {}
 
-impl <'a>From<&'static str> for IotEdgeError {
+impl From<&'static str> for IotEdgeError {
     fn from(s: &'static str) -> Self {
         IotEdgeError::Generic(s)
     }
 }
 
-impl From<SendError<Request>> for IotEdgeError {
-    fn from(_: SendError<Request>) -> Self {
+impl From<io::Error> for IotEdgeError {
+    fn from(e: io::Error) -> Self {
+        IotEdgeError::IoError(e)
+    }
+}
+
+impl From<ClientError> for IotEdgeError {
+    fn from(_: ClientError) -> Self {
+        IotEdgeError::MqttSendError
+    }
+}
+
+impl From<ConnectionError> for IotEdgeError {
+    fn from(e: ConnectionError) -> Self {
+        IotEdgeError::MqttConnectionError(Box::new(e))
+    }
+}
+
+impl From<v5::ClientError> for IotEdgeError {
+    fn from(_: v5::ClientError) -> Self {
         IotEdgeError::MqttSendError
     }
 }
 
+impl From<v5::ConnectionError> for IotEdgeError {
+    fn from(e: v5::ConnectionError) -> Self {
+        IotEdgeError::Mqtt5ConnectionError(Box::new(e))
+    }
+}
+
+impl From<reqwest::Error> for IotEdgeError {
+    fn from(e: reqwest::Error) -> Self {
+        IotEdgeError::UploadError(e.to_string())
+    }
+}
@@ -1,27 +1,47 @@
+// enum variants are spelled the way config.toml and the JSON output have them
+#![allow(clippy::upper_case_acronyms)]
+
 use tokio::task;
 use futures::future::join_all;
 
+use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use clap::Parser;
 use anyhow::Result;
-use tokio::sync::mpsc::channel;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc::channel, watch, Mutex};
 
 mod config;
 mod errors;
+mod command;
 mod connect;
+mod dbc;
 mod message;
 mod output;
+mod tasks;
+mod update;
+mod upload;
 mod utils;
 
 pub mod chunk_capnp {
     include!(concat!(env!("OUT_DIR"), "/schema/chunk_capnp.rs"));
 }
 
-use output::Output;
+use output::{CaptureStats, Output, MqttStats};
+#[cfg(feature = "parquet")]
+use output::{ParquetExport, ParquetTask};
 use config::Config;
-use connect::{CanTask, GpsTask};
-use utils::can_devices;
+#[cfg(feature = "parquet")]
+use config::ConfigParquet;
+#[cfg(feature = "parquet")]
+use dbc::Dbc;
+use command::CommandTask;
+use errors::IotEdgeError;
+use tasks::{Context, Tasks, SECTIONS};
+use update::UpdateTask;
 
 
 #[derive(Parser)]
@@ -30,46 +50,124 @@ struct Cli {
     /// Config file
     #[clap(short, long, parse(from_os_str), value_name = "FILE")]
     config: Option<PathBuf>,
+
+    /// Print a log file as JSON lines and exit, binary logs included
+    #[clap(long, parse(from_os_str), value_name = "LOG")]
+    dump: Option<PathBuf>,
+
+    /// Convert a log file to Parquet tables named after --out and exit
+    #[cfg(feature = "parquet")]
+    #[clap(long, parse(from_os_str), value_name = "LOG", requires = "out")]
+    parquet: Option<PathBuf>,
+
+    /// Train a zstd dictionary for [mqtt] compression on a log file, written to --out
+    #[cfg(feature = "zstd")]
+    #[clap(long, parse(from_os_str), value_name = "LOG", requires = "out")]
+    train_dictionary: Option<PathBuf>,
+
+    /// Where --parquet and --train-dictionary write to, <stem>-can.parquet and so on
+    #[cfg(any(feature = "parquet", feature = "zstd"))]
+    #[clap(long, parse(from_os_str), value_name = "PATH")]
+    out: Option<PathBuf>,
+}
+
+/// SIGTERM or ctrl-c, the tasks are dropped after it and their writers finish their files.
+async fn shutdown() -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    select! {
+        _ = terminate.recv() => Ok(()),
+        result = tokio::signal::ctrl_c() => result,
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    if let Some(path) = cli.dump.as_deref() {
+        for line in output::log_lines(path)? {
+            println!("{}", line?);
+        }
+        return Ok(());
+    }
+
     let config = match cli.config.as_deref() {
         Some(config_path) => {
-            Config::from(config_path)
+            Config::load(config_path).map_err(IotEdgeError::ConfigError)?
         },
         _ => {
             Config::default()
         }
     };
 
+    #[cfg(feature = "zstd")]
+    if let (Some(log), Some(out)) = (cli.train_dictionary.as_deref(), cli.out.as_deref()) {
+        let dictionary = output::train(log, config.mqtt_config().chunk_layout, 112640)?;
+        std::fs::write(out, &dictionary)?;
+        println!("{} bytes", dictionary.len());
+        return Ok(());
+    }
+
+    #[cfg(feature = "parquet")]
+    if let (Some(log), Some(out)) = (cli.parquet.as_deref(), cli.out.as_deref()) {
+        let parquet_config = ConfigParquet {
+            path: out.to_string_lossy().to_string(),
+            ..config.parquet_config().unwrap_or_default()
+        };
+        let count = output::convert(log, &parquet_config, Dbc::open_all(&config.can_config().dbc)?)?;
+        println!("{} chunks", count);
+        return Ok(());
+    }
+
     let mut handles = Vec::new();
     let (source_tx, source_rx) = channel(65536);
+    let (ignition_tx, ignition_rx) = watch::channel(true);
+    let stats = Arc::new(MqttStats::default());
     let mut output = Output::new(
-        &config.id(), config.mqtt_config(), config.log_config(), source_rx);
-    handles.push(task::spawn(async move {
-        output.run().await;
-    }));
+        &config.id(), config.mqtt_config(), config.log_config(), config.queue_config(),
+        source_rx, ignition_rx, stats.clone())?;
 
-    let devices = can_devices();
-    for dev in devices {
-        let out = source_tx.clone();
-        let can_config = config.can_config();
-        handles.push(task::spawn(async move {
-            let mut can_task = CanTask::new(&dev, out, can_config.frequency);
-            can_task.run().await;
-        }));
+    // before the upload task looks for its closed files
+    #[cfg(feature = "parquet")]
+    if let Some(parquet_config) = config.parquet_config() {
+        let export = ParquetExport::new(&parquet_config, Dbc::open_all(&config.can_config().dbc)?, &config.id())?;
+        let (export_tx, export_rx) = channel(16);
+        output.export(export_tx);
+        handles.push(task::spawn(ParquetTask::new(export, export_rx).run()));
     }
 
-    let gps_config = config.gps_config();
+    // source tasks live in the registry so commands and config updates can restart them
+    let tasks = Arc::new(Mutex::new(Tasks::default()));
+    let context = Context {
+        tx: source_tx, ignition: Arc::new(ignition_tx), stats: stats.clone(), capture: Arc::new(CaptureStats::default()),
+        control: output.control(), fixes: broadcast::channel(64).0,
+    };
+    let mut registry = tasks.lock().await;
+    for section in SECTIONS {
+        registry.spawn_section(section, &config, &context);
+    }
+    drop(registry);
+
+    let (update_tx, update_rx) = channel(4);
+    let update = UpdateTask::new(
+        &config, cli.config.clone(), update_rx, output.online(), output.control(), tasks.clone(), context);
+    if config.command_config().is_some() {
+        let (command_tx, command_rx) = channel(16);
+        let command = CommandTask::new(
+            update.current(), command_rx, output.control(), update_tx, tasks.clone(), stats);
+        output.commands(&command.topic(), command_tx);
+        handles.push(task::spawn(command.run()));
+    }
+    handles.push(task::spawn(update.run()));
+
     handles.push(task::spawn(async move {
-        let task = GpsTask::new(&gps_config, source_tx);
-        task.run().await;
+        output.run().await;
     }));
-   
-    join_all(handles).await;
+
+    select! {
+        _ = join_all(handles) => {},
+        result = shutdown() => result?,
+    }
 
     Ok(())
 }
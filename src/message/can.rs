@@ -7,6 +7,7 @@ pub struct CanMessage {
     pub time: DateTime<Utc>,
     pub channel: String,
     pub frame: CANFrame,
+    pub extended: bool,     // as sent, CANFrame::new only flags IDs above 0x7FF
 }
 
 impl From<&CanMessage> for String {
@@ -16,7 +17,7 @@ impl From<&CanMessage> for String {
             can_hex_str.push_str(format!("{:02X}", b).as_ref());
         }
 
-        match msg.frame.is_extended() {
+        match msg.extended {
             true => format!(
                 "({}.{:06}) {} {:08X}#{}",
                 msg.time.timestamp(),
@@ -62,18 +63,19 @@ fn test_json() {
         println!("{:?}", record);
         let r = record.unwrap();
         let (secs, nsecs) = {
-            let secs = ((r.0 as f64) / 1000_000f64) as f64;
+            let secs = (r.0 as f64) / 1_000_000f64;
             let t1 = secs as i64;
-            let t2 = ((secs - t1 as f64) * 1000_000_000f64) as u32;
+            let t2 = ((secs - t1 as f64) * 1_000_000_000f64) as u32;
 
             (t1, t2)
         };
-        let ts = NaiveDateTime::from_timestamp(secs, nsecs);
+        let ts = DateTime::<Utc>::from_timestamp(secs, nsecs).unwrap();
         println!("{:#?}, {}.{}", ts, secs, nsecs);
         let msg = CanMessage {
-            time: DateTime::<Utc>::from_utc(ts, Utc),
+            time: ts,
             channel: "can1".to_string(),
             frame: r.1,
+            extended: r.1.is_extended(),
         };
         println!("{:?}", msg);
         let s = serde_json::to_string(&msg).unwrap();
@@ -0,0 +1,50 @@
+use serde::ser::{Serialize, Serializer, SerializeStruct};
+use chrono::prelude::*;
+
+
+/// Progress of copying the data files to a USB drive.
+#[derive(Debug, Clone, Default)]
+pub struct ExportMessage {
+    pub time: DateTime<Utc>,
+    pub device: String,     // block device, e.g. sda1
+    pub state: String,      // STARTED, COPYING, DONE or FAILED
+    pub files: u32,         // copied so far
+    pub total_files: u32,
+    pub bytes: u64,
+    pub total_bytes: u64,
+    pub error: String,
+}
+
+impl Serialize for ExportMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ExportMessage", 8)?;
+        state.serialize_field("ts", &self.time)?;
+        state.serialize_field("device", &self.device)?;
+        state.serialize_field("state", &self.state)?;
+        state.serialize_field("files", &self.files)?;
+        state.serialize_field("total_files", &self.total_files)?;
+        state.serialize_field("bytes", &self.bytes)?;
+        state.serialize_field("total_bytes", &self.total_bytes)?;
+        state.serialize_field("error", &self.error)?;
+        state.end()
+    }
+}
+
+#[test]
+fn test_json() {
+    let msg = ExportMessage {
+        time: Utc::now(),
+        device: "sda1".to_string(),
+        state: "COPYING".to_string(),
+        files: 1,
+        total_files: 3,
+        bytes: 1048576,
+        total_bytes: 3145728,
+        ..Default::default()
+    };
+    let s = serde_json::to_string(&msg).unwrap();
+    println!("{}", &s);
+}
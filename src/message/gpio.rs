@@ -0,0 +1,43 @@
+use serde::ser::{Serialize, Serializer, SerializeStruct};
+use chrono::prelude::*;
+
+
+#[derive(Debug, Clone)]
+pub struct GpioMessage {
+    pub time: DateTime<Utc>,
+    pub timestamp: u64,     // kernel event timestamp in nanoseconds
+    pub name: String,
+    pub offset: u32,
+    pub value: bool,
+}
+
+impl Serialize for GpioMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("GpioMessage", 5)?;
+        state.serialize_field("ts", &self.time)?;
+        state.serialize_field("kts", &self.timestamp)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("offset", &self.offset)?;
+        state.serialize_field("value", &self.value)?;
+        state.end()
+    }
+}
+
+#[test]
+fn test_json() {
+    let msg = GpioMessage {
+        time: Utc::now(),
+        timestamp: 1666000000123456789,
+        name: "ignition".to_string(),
+        offset: 17,
+        value: true,
+    };
+    let s = serde_json::to_string(&msg).unwrap();
+    println!("{}", &s);
+    let value: serde_json::Value = serde_json::from_str(&s).unwrap();
+    assert_eq!(value["kts"], 1666000000123456789u64);
+    assert_eq!((value["name"].as_str(), value["offset"].as_u64(), value["value"].as_bool()), (Some("ignition"), Some(17), Some(true)));
+}
@@ -1,22 +1,61 @@
+use std::collections::BTreeMap;
 use std::vec::Vec;
 use serde::Serialize;
 use chrono::prelude::*;
-use capnp::message::Builder;
+use capnp::message::{Builder, ReaderOptions};
 use capnp::serialize_packed;
+use socketcan::CANFrame;
 
 use crate::chunk_capnp;
 
 mod can;
 mod gps;
+mod gpio;
+mod modbus;
+mod system;
+mod modem;
+mod record;
+mod export;
 
 pub use can::CanMessage;
 pub use gps::GpsMessage;
+pub use gpio::GpioMessage;
+pub use modbus::ModbusMessage;
+pub use system::{SystemMessage, NetCounters};
+pub use modem::ModemMessage;
+pub use record::{RecordMessage, FieldValue};
+pub use export::ExportMessage;
+
+/// Newest chunk layout in schema/chunk.capnp, the one a binary log header states.
+/// Chunks are written in [mqtt] chunk_layout, 2 keeps CAN frames as groups.
+pub const SCHEMA_VERSION: u32 = 2;
+
+// CanGroup.flags
+const EXTENDED: u8 = 1;
+const REMOTE: u8 = 2;
+const ERROR: u8 = 4;
+
+/// Frames of one channel, id and flags, in the order they came in.
+type CanGroup<'a> = ((u16, u32, u8), Vec<&'a CanMessage>);
 
 #[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type")]
 pub enum Message {
     GPS(GpsMessage),
     CAN(CanMessage),
+    GPIO(GpioMessage),
+    MODBUS(ModbusMessage),
+    SYSTEM(SystemMessage),
+    MODEM(ModemMessage),
+    RECORD(RecordMessage),
+    EXPORT(ExportMessage),
+}
+
+impl Message {
+    /// Status messages describe the gateway itself rather than the vehicle.
+    pub fn is_status(&self) -> bool {
+        matches!(self, Message::SYSTEM(_) | Message::MODEM(_) | Message::EXPORT(_))
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -25,6 +64,12 @@ pub struct Chunk {
     id: String,   // identifier for this chunk
     can: Vec<CanMessage>,
     gps: Vec<GpsMessage>,
+    gpio: Vec<GpioMessage>,
+    modbus: Vec<ModbusMessage>,
+    system: Vec<SystemMessage>,
+    modem: Vec<ModemMessage>,
+    record: Vec<RecordMessage>,
+    export: Vec<ExportMessage>,
 }
 
 impl Chunk {
@@ -35,7 +80,13 @@ impl Chunk {
             time, 
             id: id.to_string(), 
             can: Vec::new(),
-            gps: Vec::new()
+            gps: Vec::new(),
+            gpio: Vec::new(),
+            modbus: Vec::new(),
+            system: Vec::new(),
+            modem: Vec::new(),
+            record: Vec::new(),
+            export: Vec::new(),
         }
     }
 
@@ -46,51 +97,410 @@ impl Chunk {
     pub fn push(&mut self, msg: Message) {
         match msg {
             Message::CAN(msg) => self.can.push(msg),
-            Message::GPS(msg) => self.gps.push(msg)
+            Message::GPS(msg) => self.gps.push(msg),
+            Message::GPIO(msg) => self.gpio.push(msg),
+            Message::MODBUS(msg) => self.modbus.push(msg),
+            Message::SYSTEM(msg) => self.system.push(msg),
+            Message::MODEM(msg) => self.modem.push(msg),
+            Message::RECORD(msg) => self.record.push(msg),
+            Message::EXPORT(msg) => self.export.push(msg),
         }
     }
 
+    #[cfg(feature = "parquet")]
+    pub fn can(&self) -> &[CanMessage] {
+        &self.can
+    }
+
+    #[cfg(feature = "parquet")]
+    pub fn gps(&self) -> &[GpsMessage] {
+        &self.gps
+    }
+
     pub fn len(&self) -> usize {
-        self.can.len() + self.gps.len()
+        self.can.len() + self.gps.len() + self.gpio.len() + self.modbus.len()
+            + self.system.len() + self.modem.len() + self.record.len() + self.export.len()
     }
 
-    pub fn to_vec(&self) -> Vec<u8> {
+    /// Chunk in the given layout, 1 keeps a CanMessage per frame, 2 groups them by ID.
+    pub fn to_vec(&self, layout: u32) -> Vec<u8> {
         let mut builder = Builder::new_default();
         let mut root = builder.init_root::<chunk_capnp::chunk::Builder>();
 
         root.set_id(&self.id);
-        root.set_time((self.time.timestamp_nanos() as f64) / 1000_000_000f64);
+        root.set_time(to_ts(&self.time));
+        root.set_layout(layout);
+        if layout == 1 {
+            let mut can_messages = root.reborrow().init_can(self.can.len() as u32);
+            for (pos, msg) in self.can.iter().enumerate() {
+                let mut can = can_messages.reborrow().get(pos as u32);
+                can.set_time(to_ts(&msg.time));
+                can.set_channel(&msg.channel);
+                can.set_id(msg.frame.id());
+                can.set_error(msg.frame.is_error());
+                can.set_remote(msg.frame.is_rtr());
+                can.set_extended(msg.extended);
+                can.set_data(msg.frame.data());
+                can.set_length(msg.frame.data().len() as u8);
+            }
+        } else {
+            let base = self.time.timestamp_micros();
+            root.set_base(base);
 
-        let mut can_messages = root.reborrow().init_can(self.can.len() as u32);
-        for (pos, msg) in self.can.iter().enumerate() {
-            let mut can = can_messages.reborrow().get(pos as u32);
-            let ts = (msg.time.timestamp_nanos() as f64) / 1000_000_000f64;
-            can.set_time(ts);
-            can.set_channel(&msg.channel);
-            can.set_id(msg.frame.id());
-            can.set_error(msg.frame.is_error());
-            can.set_remote(msg.frame.is_rtr());
-            can.set_extended(msg.frame.is_extended());
-            can.set_data(msg.frame.data());
-            can.set_length(msg.frame.data().len() as u8);
+            // groups in the order their first frame came in
+            let mut channels: Vec<&str> = Vec::new();
+            let mut index = BTreeMap::new();
+            let mut groups: Vec<CanGroup> = Vec::new();
+            for msg in self.can.iter() {
+                let channel = match channels.iter().position(|channel| *channel == msg.channel) {
+                    Some(pos) => pos,
+                    None => {
+                        channels.push(&msg.channel);
+                        channels.len() - 1
+                    }
+                };
+                let flags = (msg.extended as u8 * EXTENDED)
+                    | (msg.frame.is_rtr() as u8 * REMOTE)
+                    | (msg.frame.is_error() as u8 * ERROR);
+                let key = (channel as u16, msg.frame.id(), flags);
+                let pos = *index.entry(key).or_insert_with(|| {
+                    groups.push((key, Vec::new()));
+                    groups.len() - 1
+                });
+                groups[pos].1.push(msg);
+            }
 
+            let mut can_channels = root.reborrow().init_can_channels(channels.len() as u32);
+            for (pos, channel) in channels.iter().enumerate() {
+                can_channels.set(pos as u32, channel);
+            }
+
+            let mut can_groups = root.reborrow().init_can_groups(groups.len() as u32);
+            for (pos, ((channel, id, flags), frames)) in groups.iter().enumerate() {
+                let mut group = can_groups.reborrow().get(pos as u32);
+                group.set_id(*id);
+                group.set_channel(*channel);
+                group.set_flags(*flags);
+
+                let mut times = group.reborrow().init_times(frames.len() as u32);
+                for (pos, msg) in frames.iter().enumerate() {
+                    times.set(pos as u32, msg.time.timestamp_micros() - base);
+                }
+
+                // an unchanged payload is stored once for the whole run
+                let mut runs: Vec<(&[u8], u32)> = Vec::new();
+                for msg in frames.iter() {
+                    match runs.last_mut() {
+                        Some((data, repeats)) if *data == msg.frame.data() => *repeats += 1,
+                        _ => runs.push((msg.frame.data(), 1)),
+                    }
+                }
+                let mut data = group.reborrow().init_data(runs.len() as u32);
+                for (pos, (payload, _)) in runs.iter().enumerate() {
+                    data.set(pos as u32, payload);
+                }
+                let mut repeats = group.init_repeats(runs.len() as u32);
+                for (pos, (_, count)) in runs.iter().enumerate() {
+                    repeats.set(pos as u32, *count);
+                }
+            }
         }
 
         let mut gps_messages = root.reborrow().init_gps(self.gps.len() as u32);
         for (pos, msg) in self.gps.iter().enumerate() {
             let mut gps = gps_messages.reborrow().get(pos as u32);
-            let ts = (msg.time.timestamp_nanos() as f64) / 1000_000_000f64;
+            let ts = to_ts(&msg.time);
             gps.set_time(ts);
             gps.set_longitude(msg.longitude);
             gps.set_latitude(msg.latitude);
             gps.set_speed(msg.speed);
         }
 
+        let mut gpio_messages = root.reborrow().init_gpio(self.gpio.len() as u32);
+        for (pos, msg) in self.gpio.iter().enumerate() {
+            let mut gpio = gpio_messages.reborrow().get(pos as u32);
+            let ts = to_ts(&msg.time);
+            gpio.set_time(ts);
+            gpio.set_timestamp(msg.timestamp);
+            gpio.set_name(&msg.name);
+            gpio.set_offset(msg.offset);
+            gpio.set_value(msg.value);
+        }
+
+        let mut modbus_messages = root.reborrow().init_modbus(self.modbus.len() as u32);
+        for (pos, msg) in self.modbus.iter().enumerate() {
+            let mut modbus = modbus_messages.reborrow().get(pos as u32);
+            let ts = to_ts(&msg.time);
+            modbus.set_time(ts);
+            modbus.set_source(&msg.source);
+            modbus.set_name(&msg.name);
+            modbus.set_value(msg.value);
+        }
+
+        let mut system_messages = root.reborrow().init_system(self.system.len() as u32);
+        for (pos, msg) in self.system.iter().enumerate() {
+            let mut system = system_messages.reborrow().get(pos as u32);
+            let ts = to_ts(&msg.time);
+            system.set_time(ts);
+            system.set_cpu(msg.cpu);
+            system.set_load(msg.load);
+            system.set_mem_total(msg.mem_total);
+            system.set_mem_available(msg.mem_available);
+            system.set_disk_total(msg.disk_total);
+            system.set_disk_free(msg.disk_free);
+            system.set_uptime(msg.uptime);
+            system.set_rss(msg.rss);
+            system.set_backlog(msg.backlog as u32);
+            system.set_delivered(msg.delivered);
+            system.set_failed(msg.failed);
+            system.set_capture_dropped(msg.capture_dropped);
+
+            let mut net = system.init_net(msg.net.len() as u32);
+            for (pos, counters) in msg.net.iter().enumerate() {
+                let mut iface = net.reborrow().get(pos as u32);
+                iface.set_name(&counters.name);
+                iface.set_rx_bytes(counters.rx_bytes);
+                iface.set_tx_bytes(counters.tx_bytes);
+                iface.set_rx_packets(counters.rx_packets);
+                iface.set_tx_packets(counters.tx_packets);
+                iface.set_rx_errors(counters.rx_errors);
+                iface.set_tx_errors(counters.tx_errors);
+            }
+        }
+
+        let mut modem_messages = root.reborrow().init_modem(self.modem.len() as u32);
+        for (pos, msg) in self.modem.iter().enumerate() {
+            let mut modem = modem_messages.reborrow().get(pos as u32);
+            let ts = to_ts(&msg.time);
+            modem.set_time(ts);
+            modem.set_rssi(msg.rssi.unwrap_or(f64::NAN));
+            modem.set_rsrp(msg.rsrp.unwrap_or(f64::NAN));
+            modem.set_rsrq(msg.rsrq.unwrap_or(f64::NAN));
+            modem.set_sinr(msg.sinr.unwrap_or(f64::NAN));
+            modem.set_operator(&msg.operator);
+            modem.set_technology(&msg.technology);
+            modem.set_imei(&msg.imei);
+            modem.set_iccid(&msg.iccid);
+            modem.set_rx_bytes(msg.rx_bytes);
+            modem.set_tx_bytes(msg.tx_bytes);
+        }
+
+        let mut record_messages = root.reborrow().init_record(self.record.len() as u32);
+        for (pos, msg) in self.record.iter().enumerate() {
+            let mut record = record_messages.reborrow().get(pos as u32);
+            let ts = to_ts(&msg.time);
+            record.set_time(ts);
+            record.set_source(&msg.source);
+
+            let mut fields = record.init_fields(msg.fields.len() as u32);
+            for (pos, (key, value)) in msg.fields.iter().enumerate() {
+                let mut field = fields.reborrow().get(pos as u32);
+                field.set_key(key);
+                match value {
+                    FieldValue::Number(v) => field.set_number(*v),
+                    FieldValue::Text(v) => field.set_text(v),
+                }
+            }
+        }
+
+        let mut export_messages = root.reborrow().init_export(self.export.len() as u32);
+        for (pos, msg) in self.export.iter().enumerate() {
+            let mut export = export_messages.reborrow().get(pos as u32);
+            let ts = to_ts(&msg.time);
+            export.set_time(ts);
+            export.set_device(&msg.device);
+            export.set_state(&msg.state);
+            export.set_files(msg.files);
+            export.set_total_files(msg.total_files);
+            export.set_bytes(msg.bytes);
+            export.set_total_bytes(msg.total_bytes);
+            export.set_error(&msg.error);
+        }
+
         let mut buf = Vec::new();
         serialize_packed::write_message(&mut buf, &builder).unwrap();
 
         buf
     }
+
+    /// Chunk back from what `to_vec` wrote.
+    pub fn from_slice(mut buf: &[u8]) -> capnp::Result<Chunk> {
+        let message = serialize_packed::read_message(&mut buf, ReaderOptions::new())?;
+        let root = message.get_root::<chunk_capnp::chunk::Reader>()?;
+        if root.get_layout() > SCHEMA_VERSION {
+            return Err(capnp::Error::failed(format!("chunk layout {} is newer than {}", root.get_layout(), SCHEMA_VERSION)));
+        }
+        let mut chunk = Chunk::new(root.get_id()?);
+        chunk.time = from_ts(root.get_time());
+
+        // layout 1
+        for can in root.get_can()?.iter() {
+            let frame = can_frame(can.get_id(), can.get_data()?, can.get_remote(), can.get_error())?;
+            chunk.can.push(CanMessage {
+                time: from_ts(can.get_time()),
+                channel: can.get_channel()?.to_string(),
+                frame,
+                extended: can.get_extended(),
+            });
+        }
+
+        // layout 2, frames of all groups back in the order they came in
+        let base = root.get_base();
+        let channels = root.get_can_channels()?;
+        let mut frames = Vec::new();
+        for group in root.get_can_groups()?.iter() {
+            if group.get_channel() as u32 >= channels.len() {
+                return Err(capnp::Error::failed(format!("no channel {}", group.get_channel())));
+            }
+            let channel = channels.get(group.get_channel() as u32)?;
+            let flags = group.get_flags();
+            let (times, data, repeats) = (group.get_times()?, group.get_data()?, group.get_repeats()?);
+            let count: u64 = repeats.iter().map(u64::from).sum();
+            if (data.len() != repeats.len()) | (times.len() as u64 != count) {
+                return Err(capnp::Error::failed(format!(
+                    "group {:X}: {} payloads, {} repeats, {} times for {} frames",
+                    group.get_id(), data.len(), repeats.len(), times.len(), count)));
+            }
+            let mut times = times.iter();
+            for (payload, repeats) in data.iter().zip(repeats.iter()) {
+                let frame = can_frame(group.get_id(), payload?, flags & REMOTE != 0, flags & ERROR != 0)?;
+                for time in times.by_ref().take(repeats as usize) {
+                    let nanos = base.checked_add(time).and_then(|micros| micros.checked_mul(1000))
+                        .ok_or_else(|| capnp::Error::failed(format!("group {:X}: time {} out of range", group.get_id(), time)))?;
+                    frames.push(CanMessage {
+                        time: Utc.timestamp_nanos(nanos),
+                        channel: channel.to_string(),
+                        frame,
+                        extended: flags & EXTENDED != 0,
+                    });
+                }
+            }
+        }
+        frames.sort_by_key(|msg| msg.time);
+        chunk.can.extend(frames);
+
+        for gps in root.get_gps()?.iter() {
+            chunk.gps.push(GpsMessage {
+                time: from_ts(gps.get_time()),
+                longitude: gps.get_longitude(),
+                latitude: gps.get_latitude(),
+                speed: gps.get_speed(),
+            });
+        }
+
+        for gpio in root.get_gpio()?.iter() {
+            chunk.gpio.push(GpioMessage {
+                time: from_ts(gpio.get_time()),
+                timestamp: gpio.get_timestamp(),
+                name: gpio.get_name()?.to_string(),
+                offset: gpio.get_offset(),
+                value: gpio.get_value(),
+            });
+        }
+
+        for modbus in root.get_modbus()?.iter() {
+            chunk.modbus.push(ModbusMessage {
+                time: from_ts(modbus.get_time()),
+                source: modbus.get_source()?.to_string(),
+                name: modbus.get_name()?.to_string(),
+                value: modbus.get_value(),
+            });
+        }
+
+        for system in root.get_system()?.iter() {
+            let mut net = Vec::new();
+            for iface in system.get_net()?.iter() {
+                net.push(NetCounters {
+                    name: iface.get_name()?.to_string(),
+                    rx_bytes: iface.get_rx_bytes(),
+                    tx_bytes: iface.get_tx_bytes(),
+                    rx_packets: iface.get_rx_packets(),
+                    tx_packets: iface.get_tx_packets(),
+                    rx_errors: iface.get_rx_errors(),
+                    tx_errors: iface.get_tx_errors(),
+                });
+            }
+            chunk.system.push(SystemMessage {
+                time: from_ts(system.get_time()),
+                cpu: system.get_cpu(),
+                load: system.get_load(),
+                mem_total: system.get_mem_total(),
+                mem_available: system.get_mem_available(),
+                disk_total: system.get_disk_total(),
+                disk_free: system.get_disk_free(),
+                uptime: system.get_uptime(),
+                rss: system.get_rss(),
+                backlog: system.get_backlog() as usize,
+                net,
+                delivered: system.get_delivered(),
+                failed: system.get_failed(),
+                capture_dropped: system.get_capture_dropped(),
+            });
+        }
+
+        let known = |v: f64| Some(v).filter(|v| !v.is_nan());
+        for modem in root.get_modem()?.iter() {
+            chunk.modem.push(ModemMessage {
+                time: from_ts(modem.get_time()),
+                rssi: known(modem.get_rssi()),
+                rsrp: known(modem.get_rsrp()),
+                rsrq: known(modem.get_rsrq()),
+                sinr: known(modem.get_sinr()),
+                operator: modem.get_operator()?.to_string(),
+                technology: modem.get_technology()?.to_string(),
+                imei: modem.get_imei()?.to_string(),
+                iccid: modem.get_iccid()?.to_string(),
+                rx_bytes: modem.get_rx_bytes(),
+                tx_bytes: modem.get_tx_bytes(),
+            });
+        }
+
+        for record in root.get_record()?.iter() {
+            let mut fields = BTreeMap::new();
+            for field in record.get_fields()?.iter() {
+                let value = match field.which()? {
+                    chunk_capnp::field::Which::Number(v) => FieldValue::Number(v),
+                    chunk_capnp::field::Which::Text(v) => FieldValue::Text(v?.to_string()),
+                };
+                fields.insert(field.get_key()?.to_string(), value);
+            }
+            chunk.record.push(RecordMessage {
+                time: from_ts(record.get_time()),
+                source: record.get_source()?.to_string(),
+                fields,
+            });
+        }
+
+        for export in root.get_export()?.iter() {
+            chunk.export.push(ExportMessage {
+                time: from_ts(export.get_time()),
+                device: export.get_device()?.to_string(),
+                state: export.get_state()?.to_string(),
+                files: export.get_files(),
+                total_files: export.get_total_files(),
+                bytes: export.get_bytes(),
+                total_bytes: export.get_total_bytes(),
+                error: export.get_error()?.to_string(),
+            });
+        }
+
+        Ok(chunk)
+    }
+}
+
+/// A frame as the chunk has it, whether it was extended is kept next to it.
+fn can_frame(id: u32, data: &[u8], remote: bool, error: bool) -> capnp::Result<CANFrame> {
+    CANFrame::new(id, data, remote, error).map_err(|e| capnp::Error::failed(e.to_string()))
+}
+
+/// Seconds since the epoch as the schema has them, a double only keeps microseconds.
+fn from_ts(ts: f64) -> DateTime<Utc> {
+    Utc.timestamp_nanos((ts * 1_000_000f64).round() as i64 * 1000)
+}
+
+/// Seconds since the epoch as the schema has them.
+fn to_ts(time: &DateTime<Utc>) -> f64 {
+    time.timestamp_micros() as f64 / 1_000_000f64
 }
 
 
@@ -117,6 +527,7 @@ fn test_json() {
             time: ts,
             channel: "can1".to_string(),
             frame: r.1,
+            extended: false,
         };
         can_msgs.push(msg);
     }
@@ -126,6 +537,12 @@ fn test_json() {
         id: "test".to_string(),
         can: can_msgs,
         gps: gps_msgs,
+        gpio: Vec::new(),
+        modbus: Vec::new(),
+        system: Vec::new(),
+        modem: Vec::new(),
+        record: Vec::new(),
+        export: Vec::new(),
     };
     let line_str = serde_json::to_string(&line).unwrap();
     println!("{}", line_str);
@@ -144,7 +561,7 @@ fn test_capnp() {
     use chrono::prelude::*;
 
     let now = Utc::now();
-    let ts = now.timestamp_nanos() as f64 / 1000_000_000f64;
+    let ts = to_ts(&now);
     
     let mut message = ::capnp::message::Builder::new_default();
     let mut root = message.init_root::<chunk_capnp::chunk::Builder<'_>>();
@@ -161,4 +578,120 @@ fn test_capnp() {
     serialize_packed::write_message(&mut buf, &message).unwrap();
 
     println!("{:?}", buf);
-}
\ No newline at end of file
+}
+#[test]
+fn test_from_slice() {
+    let mut chunk = Chunk::new("test");
+    chunk.push(Message::GPS(GpsMessage { time: Utc::now(), latitude: 0.1, longitude: -0.1, speed: 100.0 }));
+    chunk.push(Message::CAN(CanMessage {
+        time: Utc.timestamp_nanos(1_655_098_589_035_226_000),
+        channel: "can1".to_string(),
+        frame: CANFrame::new(0x202, &[0xA1, 0, 0xA1], false, false).unwrap(),
+        extended: false,
+    }));
+    let mut fields = BTreeMap::new();
+    fields.insert("weight".to_string(), FieldValue::from("12.5"));
+    fields.insert("unit".to_string(), FieldValue::from("kg"));
+    chunk.push(Message::RECORD(RecordMessage { time: Utc::now(), source: "scale".to_string(), fields }));
+
+    let decoded = Chunk::from_slice(&chunk.to_vec(SCHEMA_VERSION)).unwrap();
+    assert_eq!(decoded.len(), 3);
+    assert_eq!(decoded.id, "test");
+    assert_eq!(String::from(&decoded.can[0]), String::from(&chunk.can[0]));
+    assert_eq!(decoded.gps[0].speed, 100.0);
+    assert_eq!(decoded.record[0].fields, chunk.record[0].fields);
+    assert!((decoded.time - chunk.time).num_microseconds().unwrap().abs() < 10);
+}
+
+#[test]
+fn test_can_groups() {
+    // 40 IDs every 10 ms to 1 s, a quarter with a rolling counter, a quarter with a slow signal
+    let mut chunk = Chunk::new("truck-7");
+    let base = chunk.time.timestamp_micros();
+    let periods = [10, 20, 50, 100, 200, 1000];
+    let mut payloads: Vec<[u8; 8]> = (0..40u8).map(|n| [n, 0x10, n ^ 0xA5, 0, 0, 0x7D, 0, 0]).collect();
+    let mut ms = 0;
+    while chunk.len() < 2048 {
+        for (n, payload) in payloads.iter_mut().enumerate() {
+            if ms % periods[n % 6] != 0 {
+                continue;
+            }
+            match n % 4 {
+                0 => {
+                    payload[6] = payload[6].wrapping_add(1);
+                    payload[7] = payload[6] ^ 0x5A;
+                },
+                1 if ms % 1000 == 0 => payload[2] = payload[2].wrapping_add(3),
+                _ => {},
+            }
+            chunk.push(Message::CAN(CanMessage {
+                time: Utc.timestamp_nanos((base + ms * 1000 + n as i64 * 3) * 1000),
+                channel: format!("can{}", n % 2),
+                frame: CANFrame::new(0x100 + n as u32 * 0x10, payload, false, false).unwrap(),
+                extended: false,
+            }));
+        }
+        ms += 1;
+    }
+    chunk.push(Message::CAN(CanMessage {
+        time: Utc.timestamp_nanos((base + ms * 1000) * 1000),
+        channel: "can0".to_string(),
+        frame: CANFrame::new(0x18FEF100, &[], true, false).unwrap(),
+        extended: true,
+    }));
+
+    let v1 = chunk.to_vec(1);
+    let v2 = chunk.to_vec(2);
+    assert!(v2.len() * 3 < v1.len());
+
+    let lines = |chunk: &Chunk| chunk.can.iter().map(String::from).collect::<Vec<_>>();
+    for data in [v1, v2] {
+        let decoded = Chunk::from_slice(&data).unwrap();
+        assert_eq!(lines(&decoded), lines(&chunk));
+        assert!(decoded.can.last().unwrap().frame.is_rtr());
+    }
+}
+
+#[test]
+fn test_can_groups_checked() {
+    let mut chunk = Chunk::new("truck-7");
+    chunk.push(Message::CAN(CanMessage {
+        time: chunk.time,
+        channel: "can0".to_string(),
+        frame: can_frame(0x123, &[1, 2], false, false).unwrap(),
+        extended: true,
+    }));
+    for layout in [1, 2] {
+        let decoded = Chunk::from_slice(&chunk.to_vec(layout)).unwrap();
+        assert!(decoded.can[0].extended);
+        assert_eq!(decoded.can[0].frame.id(), 0x123);
+    }
+
+    let group = |times: &[i64], repeats: &[u32], base: i64| {
+        let mut builder = Builder::new_default();
+        let mut root = builder.init_root::<chunk_capnp::chunk::Builder>();
+        root.set_id("truck-7");
+        root.set_layout(2);
+        root.set_base(base);
+        root.reborrow().init_can_channels(1).set(0, "can0");
+        let mut group = root.init_can_groups(1).get(0);
+        let mut list = group.reborrow().init_times(times.len() as u32);
+        for (pos, time) in times.iter().enumerate() {
+            list.set(pos as u32, *time);
+        }
+        group.reborrow().init_data(1).set(0, &[1]);
+        let mut list = group.init_repeats(repeats.len() as u32);
+        for (pos, count) in repeats.iter().enumerate() {
+            list.set(pos as u32, *count);
+        }
+        let mut buf = Vec::new();
+        serialize_packed::write_message(&mut buf, &builder).unwrap();
+        Chunk::from_slice(&buf)
+    };
+    assert_eq!(group(&[0, 10], &[2], 0).unwrap().can.len(), 2);
+    assert!(group(&[0], &[2], 0).is_err());
+    assert!(group(&[0, 10, 20], &[2], 0).is_err());
+    assert!(group(&[0, 10], &[1, 1], 0).is_err());
+    assert!(group(&[1], &[1], i64::MAX).is_err());
+    assert!(group(&[0], &[1], i64::MAX / 10).is_err());
+}
@@ -0,0 +1,37 @@
+use serde::ser::{Serialize, Serializer, SerializeStruct};
+use chrono::prelude::*;
+
+
+#[derive(Debug, Clone)]
+pub struct ModbusMessage {
+    pub time: DateTime<Utc>,
+    pub source: String,
+    pub name: String,
+    pub value: f64,
+}
+
+impl Serialize for ModbusMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ModbusMessage", 4)?;
+        state.serialize_field("ts", &self.time)?;
+        state.serialize_field("source", &self.source)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("value", &self.value)?;
+        state.end()
+    }
+}
+
+#[test]
+fn test_json() {
+    let msg = ModbusMessage {
+        time: Utc::now(),
+        source: "reefer".to_string(),
+        name: "supply_temp".to_string(),
+        value: -18.5,
+    };
+    let s = serde_json::to_string(&msg).unwrap();
+    println!("{}", &s);
+}
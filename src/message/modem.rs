@@ -0,0 +1,53 @@
+use serde::ser::{Serialize, Serializer, SerializeStruct};
+use chrono::prelude::*;
+
+
+#[derive(Debug, Clone, Default)]
+pub struct ModemMessage {
+    pub time: DateTime<Utc>,
+    pub rssi: Option<f64>,      // dBm
+    pub rsrp: Option<f64>,      // dBm
+    pub rsrq: Option<f64>,      // dB
+    pub sinr: Option<f64>,      // dB
+    pub operator: String,
+    pub technology: String,
+    pub imei: String,
+    pub iccid: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+impl Serialize for ModemMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ModemMessage", 11)?;
+        state.serialize_field("ts", &self.time)?;
+        state.serialize_field("rssi", &self.rssi)?;
+        state.serialize_field("rsrp", &self.rsrp)?;
+        state.serialize_field("rsrq", &self.rsrq)?;
+        state.serialize_field("sinr", &self.sinr)?;
+        state.serialize_field("operator", &self.operator)?;
+        state.serialize_field("technology", &self.technology)?;
+        state.serialize_field("imei", &self.imei)?;
+        state.serialize_field("iccid", &self.iccid)?;
+        state.serialize_field("rx_bytes", &self.rx_bytes)?;
+        state.serialize_field("tx_bytes", &self.tx_bytes)?;
+        state.end()
+    }
+}
+
+#[test]
+fn test_json() {
+    let msg = ModemMessage {
+        time: Utc::now(),
+        rssi: Some(-71.0),
+        rsrp: Some(-98.0),
+        operator: "CHINA MOBILE".to_string(),
+        technology: "LTE".to_string(),
+        ..Default::default()
+    };
+    let s = serde_json::to_string(&msg).unwrap();
+    println!("{}", &s);
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+use serde::ser::{Serializer, SerializeStruct};
+use chrono::prelude::*;
+
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum FieldValue {
+    Number(f64),
+    Text(String),
+}
+
+impl From<&str> for FieldValue {
+    fn from(s: &str) -> Self {
+        match s.parse::<f64>() {
+            Ok(v) => FieldValue::Number(v),
+            Err(_) => FieldValue::Text(s.to_string()),
+        }
+    }
+}
+
+/// Generic keyed record, for peripherals described by config alone.
+#[derive(Debug, Clone)]
+pub struct RecordMessage {
+    pub time: DateTime<Utc>,
+    pub source: String,
+    pub fields: BTreeMap<String, FieldValue>,
+}
+
+impl Serialize for RecordMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("RecordMessage", 3)?;
+        state.serialize_field("ts", &self.time)?;
+        state.serialize_field("source", &self.source)?;
+        state.serialize_field("fields", &self.fields)?;
+        state.end()
+    }
+}
+
+#[test]
+fn test_json() {
+    let mut fields = BTreeMap::new();
+    fields.insert("weight".to_string(), FieldValue::from("12.5"));
+    fields.insert("unit".to_string(), FieldValue::from("kg"));
+
+    let msg = RecordMessage {
+        time: Utc::now(),
+        source: "scale".to_string(),
+        fields,
+    };
+    let s = serde_json::to_string(&msg).unwrap();
+    println!("{}", &s);
+}
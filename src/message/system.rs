@@ -0,0 +1,87 @@
+use serde::Serialize;
+use serde::ser::{Serializer, SerializeStruct};
+use chrono::prelude::*;
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetCounters {
+    pub name: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SystemMessage {
+    pub time: DateTime<Utc>,
+    pub cpu: f64,           // percent busy since the previous sample
+    pub load: f64,          // 1 minute load average
+    pub mem_total: u64,
+    pub mem_available: u64,
+    pub disk_total: u64,    // filesystem holding the data files
+    pub disk_free: u64,
+    pub uptime: f64,        // seconds
+    pub rss: u64,
+    pub backlog: usize,     // messages waiting in the source channel
+    pub net: Vec<NetCounters>,
+    pub delivered: u64,     // chunks acknowledged by the broker
+    pub failed: u64,        // chunk deliveries timed out or cut by a disconnect
+    pub capture_dropped: u64,   // CAN frames the capture could not keep up with
+}
+
+impl Serialize for SystemMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("SystemMessage", 14)?;
+        state.serialize_field("ts", &self.time)?;
+        state.serialize_field("cpu", &self.cpu)?;
+        state.serialize_field("load", &self.load)?;
+        state.serialize_field("mem_total", &self.mem_total)?;
+        state.serialize_field("mem_available", &self.mem_available)?;
+        state.serialize_field("disk_total", &self.disk_total)?;
+        state.serialize_field("disk_free", &self.disk_free)?;
+        state.serialize_field("uptime", &self.uptime)?;
+        state.serialize_field("rss", &self.rss)?;
+        state.serialize_field("backlog", &self.backlog)?;
+        state.serialize_field("net", &self.net)?;
+        state.serialize_field("delivered", &self.delivered)?;
+        state.serialize_field("failed", &self.failed)?;
+        state.serialize_field("capture_dropped", &self.capture_dropped)?;
+        state.end()
+    }
+}
+
+#[test]
+fn test_json() {
+    let msg = SystemMessage {
+        time: Utc::now(),
+        cpu: 12.5,
+        load: 0.42,
+        mem_total: 128 * 1024 * 1024,
+        mem_available: 64 * 1024 * 1024,
+        disk_total: 8 * 1024 * 1024 * 1024,
+        disk_free: 6 * 1024 * 1024 * 1024,
+        uptime: 3600.5,
+        rss: 4 * 1024 * 1024,
+        backlog: 12,
+        net: vec![NetCounters {
+            name: "wwan0".to_string(),
+            rx_bytes: 1024,
+            tx_bytes: 4096,
+            rx_packets: 10,
+            tx_packets: 20,
+            rx_errors: 0,
+            tx_errors: 0,
+        }],
+        delivered: 100,
+        failed: 2,
+        capture_dropped: 0,
+    };
+    let s = serde_json::to_string(&msg).unwrap();
+    println!("{}", &s);
+}
@@ -0,0 +1,161 @@
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::message::{Chunk, SCHEMA_VERSION};
+use crate::output::file::open_log;
+
+/// Start of every binary log file, followed by the schema version (u32) and
+/// the device id (u16 length and UTF-8), all little endian.
+pub const MAGIC: &[u8; 8] = b"IECHUNKS";
+
+pub fn header(device_id: &str) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+    header.extend_from_slice(&(device_id.len() as u16).to_le_bytes());
+    header.extend_from_slice(device_id.as_bytes());
+    header
+}
+
+/// Largest frame a reader takes, far above any chunk, a longer length is garbage.
+pub const MAX_FRAME: u32 = 64 << 20;
+
+/// Length (u32) before each packed chunk.
+pub fn frame_len(data: &[u8]) -> [u8; 4] {
+    (data.len() as u32).to_le_bytes()
+}
+
+/// Length of a binary log up to the end of its last whole frame, 0 when
+/// not even the header is whole.
+pub fn whole_len<R: Read + Seek>(mut reader: R) -> io::Result<u64> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut head = [0; 14];
+    if size < head.len() as u64 {
+        return Ok(0);
+    }
+    reader.read_exact(&mut head)?;
+    let mut pos = 14 + u16::from_le_bytes(head[12..14].try_into().unwrap()) as u64;
+    if pos > size {
+        return Ok(0);
+    }
+
+    while pos + 4 <= size {
+        let mut len = [0; 4];
+        reader.seek(SeekFrom::Start(pos))?;
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        let end = pos + 4 + len as u64;
+        if (len > MAX_FRAME) | (end > size) {
+            break;
+        }
+        pos = end;
+    }
+    Ok(pos)
+}
+
+/// Whether `reader` is at the start of a binary log, without consuming anything.
+pub fn is_binary(reader: &mut dyn BufRead) -> io::Result<bool> {
+    Ok(reader.fill_buf()?.starts_with(MAGIC))
+}
+
+/// Reads a binary log file back into chunks.
+#[allow(dead_code)]     // the header is there for callers that want it
+pub struct ChunkReader<R> {
+    reader: R,
+    pub version: u32,
+    pub device_id: String,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+
+        let mut head = [0; 14];
+        reader.read_exact(&mut head)?;
+        if &head[..8] != MAGIC {
+            return Err(invalid("not a binary log"));
+        }
+        let version = u32::from_le_bytes(head[8..12].try_into().unwrap());
+        if version > SCHEMA_VERSION {
+            return Err(invalid(&format!("schema version {} is newer than {}", version, SCHEMA_VERSION)));
+        }
+        let mut id = vec![0; u16::from_le_bytes(head[12..14].try_into().unwrap()) as usize];
+        reader.read_exact(&mut id)?;
+        let device_id = String::from_utf8(id).map_err(|_| invalid("device id is not UTF-8"))?;
+
+        Ok(ChunkReader { reader, version, device_id })
+    }
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // a frame cut short by a power loss ends the file like EOF does
+        let mut len = [0; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        let len = u32::from_le_bytes(len);
+        if len > MAX_FRAME {
+            let e = format!("frame of {} bytes, at most {} expected", len, MAX_FRAME);
+            return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+        let mut data = vec![0; len as usize];
+        match self.reader.read_exact(&mut data) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        Some(Chunk::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())))
+    }
+}
+
+/// Chunks of a log file as JSON lines, whichever encoding it was stored in.
+pub fn log_lines(path: &Path) -> io::Result<Box<dyn Iterator<Item = io::Result<String>> + Send>> {
+    let mut reader = open_log(path)?;
+    match is_binary(&mut reader)? {
+        true => Ok(Box::new(ChunkReader::new(reader)?.map(|chunk| chunk.map(|chunk| chunk.to_json())))),
+        false => Ok(Box::new(reader.lines())),
+    }
+}
+
+#[test]
+fn test_chunk_reader() {
+    use crate::message::{GpsMessage, Message};
+    use chrono::prelude::*;
+
+    let mut file = header("dev-1");
+    for speed in [10.0, 20.0] {
+        let mut chunk = Chunk::new("dev-1");
+        chunk.push(Message::GPS(GpsMessage { time: Utc::now(), latitude: 0.1, longitude: -0.1, speed }));
+        let data = chunk.to_vec(SCHEMA_VERSION);
+        file.extend_from_slice(&frame_len(&data));
+        file.extend_from_slice(&data);
+    }
+    // torn last frame
+    file.extend_from_slice(&[100, 0, 0, 0, 1, 2]);
+
+    let mut reader: &[u8] = &file;
+    assert!(is_binary(&mut reader).unwrap());
+    let reader = ChunkReader::new(reader).unwrap();
+    assert_eq!((reader.version, reader.device_id.as_str()), (SCHEMA_VERSION, "dev-1"));
+    let chunks: Vec<Chunk> = reader.collect::<io::Result<_>>().unwrap();
+    assert_eq!(chunks.len(), 2);
+    assert!(chunks[1].to_json().contains("\"speed\":20.0"));
+
+    assert!(ChunkReader::new(&b"{\"time\":1}\n"[..]).is_err());
+
+    let whole = file.len() as u64 - 6;
+    assert_eq!(whole_len(io::Cursor::new(&file)).unwrap(), whole);
+    assert_eq!(whole_len(io::Cursor::new(&file[..10])).unwrap(), 0);
+
+    // a length no chunk has is not allocated
+    let mut file = header("dev-1");
+    file.extend_from_slice(&u32::MAX.to_le_bytes());
+    let mut reader = ChunkReader::new(&file[..]).unwrap();
+    let e = reader.next().unwrap().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
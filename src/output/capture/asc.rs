@@ -0,0 +1,114 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use chrono::prelude::*;
+
+use crate::output::capture::{channel_number, cut_torn_line, CaptureFormat, CaptureFrame};
+
+const TRAILER: &[u8] = b"End TriggerBlock\n";
+
+/// Vector ASC text, timestamps are seconds since the start of the file.
+#[derive(Default)]
+pub struct Asc {
+    start: Option<DateTime<Utc>>,
+}
+
+/// `Sat Oct 18 10:00:00.000 am 2026`, the way CANalyzer writes dates.
+fn asc_date(time: DateTime<Utc>) -> String {
+    format!("{}.{:03} {}", time.format("%a %b %d %I:%M:%S"), time.timestamp_subsec_millis(), time.format("%P %Y"))
+}
+
+impl CaptureFormat for Asc {
+    fn header(&mut self, start: DateTime<Utc>) -> Vec<u8> {
+        self.start = Some(start);
+        let date = asc_date(start);
+        format!(
+            "date {}\nbase hex  timestamps absolute\ninternal events logged\n// version 9.0.0\n\
+             Begin Triggerblock {}\n{:>11.6} Start of measurement\n",
+            date, date, 0.0
+        ).into_bytes()
+    }
+
+    fn frame(&mut self, frame: &CaptureFrame) -> Vec<u8> {
+        let start = *self.start.get_or_insert(frame.time);
+        let time = (frame.time - start).num_microseconds().unwrap_or(0).max(0) as f64 / 1_000_000f64;
+        let channel = channel_number(&frame.channel);
+        let id = match frame.extended {
+            true => format!("{:X}x", frame.id),
+            false => format!("{:X}", frame.id),
+        };
+        let data = frame.data.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+
+        let line = match (frame.error, frame.fd) {
+            (true, _) => format!("{}  ErrorFrame", channel),
+            (false, true) => {
+                // EDL, BRS and ESI in the message flags, the bus timing fields are unknown
+                let flags = (1 << 12) | ((frame.brs as u32) << 13) | ((frame.esi as u32) << 14);
+                format!(
+                    "CANFD {:>3} {:<4} {:>8} {:>32} {} {} {:x} {:>2} {} {:>8} {:>4} {:>8X} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                    channel, "Rx", id, "", frame.brs as u8, frame.esi as u8, frame.dlc, frame.data.len(),
+                    data, 0, 0, flags, 0, 0, 0, 0, 0
+                )
+            },
+            (false, false) => match frame.remote {
+                true => format!("{}  {:<15} {:<4} r {:x}", channel, id, "Rx", frame.dlc),
+                false => format!("{}  {:<15} {:<4} d {:x} {}", channel, id, "Rx", frame.dlc, data),
+            },
+        };
+        format!("{:>11.6} {}\n", time, line).into_bytes()
+    }
+
+    fn trailer(&mut self) -> Vec<u8> {
+        self.start = None;
+        TRAILER.to_vec()
+    }
+
+    fn recover(&mut self, path: &Path) -> io::Result<()> {
+        cut_torn_line(path)?;
+        if !fs::read(path)?.ends_with(TRAILER) {
+            OpenOptions::new().append(true).open(path)?.write_all(TRAILER)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_asc() {
+    let start = Utc.with_ymd_and_hms(2026, 10, 18, 14, 5, 9).unwrap();
+    let frame = CaptureFrame::sample(start + chrono::Duration::microseconds(1234), "can0", 0x123, &[1, 0xAB, 3]);
+
+    let mut asc = Asc::default();
+    let header = String::from_utf8(asc.header(start)).unwrap();
+    assert!(header.starts_with("date Sun Oct 18 02:05:09.000 pm 2026\nbase hex  timestamps absolute\n"));
+    assert!(header.ends_with("Begin Triggerblock Sun Oct 18 02:05:09.000 pm 2026\n   0.000000 Start of measurement\n"));
+
+    assert_eq!(asc.frame(&frame), b"   0.001234 1  123             Rx   d 3 01 AB 03\n");
+    let extended = CaptureFrame { id: 0x18FEF100, extended: true, ..frame.clone() };
+    assert_eq!(asc.frame(&extended), b"   0.001234 1  18FEF100x       Rx   d 3 01 AB 03\n");
+    let error = CaptureFrame { error: true, channel: "can1".to_string(), ..frame.clone() };
+    assert_eq!(asc.frame(&error), b"   0.001234 2  ErrorFrame\n");
+    let fd = CaptureFrame { fd: true, brs: true, ..frame };
+    let line = String::from_utf8(asc.frame(&fd)).unwrap();
+    assert!(line.starts_with("   0.001234 CANFD   1 Rx        123"));
+    assert!(line.contains(" 1 0 3  3 01 AB 03 "));
+
+    assert_eq!(asc.trailer(), b"End TriggerBlock\n");
+}
+
+#[test]
+fn test_recover() {
+    let path = std::env::temp_dir().join(format!("iot-edge-asc-{}.asc", std::process::id()));
+    let start = Utc.with_ymd_and_hms(2026, 10, 18, 14, 5, 9).unwrap();
+    let mut asc = Asc::default();
+    let mut file = asc.header(start);
+    file.extend_from_slice(b"   0.001234 1  123             Rx   d 3 01 AB 03\n   0.0015");
+    std::fs::write(&path, &file).unwrap();
+
+    Asc::default().recover(&path).unwrap();
+    let text = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+    assert!(text.ends_with(" 01 AB 03\nEnd TriggerBlock\n"));
+    // a second time changes nothing
+    Asc::default().recover(&path).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,312 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use chrono::prelude::*;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::output::capture::{channel_number, CaptureFormat, CaptureFrame};
+
+const FILE_HEADER_SIZE: usize = 144;
+const APPLICATION_ID: u8 = 5;
+const BIN_LOG_VERSION: [u8; 4] = [2, 6, 8, 1];
+
+// object types
+const CAN_MESSAGE: u32 = 1;
+const LOG_CONTAINER: u32 = 10;
+const CAN_ERROR_EXT: u32 = 73;
+const CAN_FD_MESSAGE: u32 = 100;
+
+const TIME_ONE_NANS: u32 = 2;
+const ZLIB_DEFLATE: u16 = 2;
+const CAN_MSG_EXT: u32 = 0x8000_0000;
+const REMOTE_FLAG: u8 = 0x80;
+const FD_EDL: u8 = 0x1;
+const FD_BRS: u8 = 0x2;
+const FD_ESI: u8 = 0x4;
+
+/// Objects are compressed together once this much has been buffered.
+const MAX_CONTAINER: usize = 128 * 1024;
+
+/// SYSTEMTIME as BLF headers have them, in UTC.
+fn system_time(time: DateTime<Utc>) -> [u16; 8] {
+    [
+        time.year() as u16,
+        time.month() as u16,
+        time.weekday().num_days_from_sunday() as u16,
+        time.day() as u16,
+        time.hour() as u16,
+        time.minute() as u16,
+        time.second() as u16,
+        time.timestamp_subsec_millis().min(999) as u16,
+    ]
+}
+
+/// SYSTEMTIME of a BLF header back to a time.
+fn from_system_time(buf: &[u8]) -> Option<DateTime<Utc>> {
+    let field = |idx: usize| u16::from_le_bytes([buf[2 * idx], buf[2 * idx + 1]]) as u32;
+    let time = Utc.with_ymd_and_hms(field(0) as i32, field(1), field(3), field(4), field(5), field(6)).single()?;
+    Some(time + chrono::Duration::milliseconds(field(7) as i64))
+}
+
+/// Length with padding and uncompressed objects of the log container at the
+/// start of `buf`, None unless it is all there.
+fn read_container(buf: &[u8]) -> Option<(usize, Vec<u8>)> {
+    let field = |at: usize| Some(u32::from_le_bytes(buf.get(at..at + 4)?.try_into().ok()?));
+    if (buf.get(..4)? != b"LOBJ") | (field(12)? != LOG_CONTAINER) {
+        return None;
+    }
+    let size = field(8)? as usize;
+    if size + size % 4 > buf.len() {
+        return None;
+    }
+    let mut objects = Vec::new();
+    ZlibDecoder::new(buf.get(32..size)?).read_to_end(&mut objects).ok()?;
+    Some((size + size % 4, objects))
+}
+
+/// `LOBJ` base header, the padding to 4 bytes follows the object and is not
+/// counted in its size.
+fn object_header(header_size: u16, object_size: u32, object_type: u32) -> Vec<u8> {
+    let mut buf = b"LOBJ".to_vec();
+    buf.extend_from_slice(&header_size.to_le_bytes());
+    buf.extend_from_slice(&1u16.to_le_bytes());
+    buf.extend_from_slice(&object_size.to_le_bytes());
+    buf.extend_from_slice(&object_type.to_le_bytes());
+    buf
+}
+
+/// Vector BLF, frames are buffered and written as zlib compressed log
+/// containers. The file header gets its statistics when the file is finished.
+#[derive(Default)]
+pub struct Blf {
+    start: Option<DateTime<Utc>>,
+    stop: Option<DateTime<Utc>>,
+    buffer: Vec<u8>,            // objects waiting for a container
+    objects: u32,
+    uncompressed: u64,          // header and all objects as they were before compression
+}
+
+impl Blf {
+    fn file_header(&self, file_size: u64) -> Vec<u8> {
+        let start = self.start.unwrap_or_else(Utc::now);
+        let mut buf = b"LOGG".to_vec();
+        buf.extend_from_slice(&(FILE_HEADER_SIZE as u32).to_le_bytes());
+        buf.extend_from_slice(&[APPLICATION_ID, 0, 0, 0]);
+        buf.extend_from_slice(&BIN_LOG_VERSION);
+        buf.extend_from_slice(&file_size.to_le_bytes());
+        buf.extend_from_slice(&self.uncompressed.to_le_bytes());
+        buf.extend_from_slice(&self.objects.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());     // objects read
+        for time in [start, self.stop.unwrap_or(start)] {
+            for field in system_time(time) {
+                buf.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        buf.resize(FILE_HEADER_SIZE, 0);
+        buf
+    }
+
+    fn add_object(&mut self, object_type: u32, time: DateTime<Utc>, data: &[u8]) {
+        let start = *self.start.get_or_insert(time);
+        let offset = (time - start).num_nanoseconds().unwrap_or(0).max(0) as u64;
+        let size = 32 + data.len() as u32;
+
+        self.buffer.extend_from_slice(&object_header(32, size, object_type));
+        self.buffer.extend_from_slice(&TIME_ONE_NANS.to_le_bytes());
+        self.buffer.extend_from_slice(&0u16.to_le_bytes());    // client index
+        self.buffer.extend_from_slice(&0u16.to_le_bytes());    // object version
+        self.buffer.extend_from_slice(&offset.to_le_bytes());
+        self.buffer.extend_from_slice(data);
+        self.buffer.resize(self.buffer.len() + (size % 4) as usize, 0);
+
+        self.objects += 1;
+        self.stop = Some(time);
+    }
+
+    fn container(&mut self) -> Vec<u8> {
+        if self.buffer.is_empty() {
+            return Vec::new();
+        }
+        let uncompressed = std::mem::take(&mut self.buffer);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        let compressed = encoder.write_all(&uncompressed).and_then(|_| encoder.finish())
+            .expect("compressing into memory");
+        self.uncompressed += uncompressed.len() as u64;
+
+        let size = 32 + compressed.len() as u32;
+        let mut buf = object_header(16, size, LOG_CONTAINER);
+        buf.extend_from_slice(&ZLIB_DEFLATE.to_le_bytes());
+        buf.extend_from_slice(&[0; 6]);
+        buf.extend_from_slice(&(uncompressed.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&compressed);
+        buf.resize(buf.len() + (size % 4) as usize, 0);
+        buf
+    }
+}
+
+impl CaptureFormat for Blf {
+    fn header(&mut self, start: DateTime<Utc>) -> Vec<u8> {
+        *self = Blf { start: Some(start), uncompressed: FILE_HEADER_SIZE as u64, ..Default::default() };
+        self.file_header(0)
+    }
+
+    fn frame(&mut self, frame: &CaptureFrame) -> Vec<u8> {
+        let channel = channel_number(&frame.channel).to_le_bytes();
+        let id = match frame.extended {
+            true => frame.id | CAN_MSG_EXT,
+            false => frame.id,
+        };
+        let flags = match frame.remote {
+            true => REMOTE_FLAG,
+            false => 0,     // received
+        };
+        let mut data = frame.data.clone();
+
+        let mut object = channel.to_vec();
+        match (frame.error, frame.fd) {
+            (true, _) => {
+                data.resize(8, 0);
+                object.extend_from_slice(&[0; 6]);             // length, flags
+                object.extend_from_slice(&[0, 0, frame.dlc, 0]);   // ecc, position, dlc
+                object.extend_from_slice(&0u32.to_le_bytes());  // frame length
+                object.extend_from_slice(&id.to_le_bytes());
+                object.extend_from_slice(&[0; 4]);             // extended flags
+                object.extend_from_slice(&data);
+                self.add_object(CAN_ERROR_EXT, frame.time, &object);
+            },
+            (false, true) => {
+                data.resize(64, 0);
+                let fd_flags = FD_EDL | (FD_BRS * frame.brs as u8) | (FD_ESI * frame.esi as u8);
+                object.extend_from_slice(&[flags, frame.dlc]);
+                object.extend_from_slice(&id.to_le_bytes());
+                object.extend_from_slice(&0u32.to_le_bytes());  // frame length
+                object.extend_from_slice(&[0, fd_flags, frame.data.len() as u8]);
+                object.extend_from_slice(&[0; 5]);
+                object.extend_from_slice(&data);
+                self.add_object(CAN_FD_MESSAGE, frame.time, &object);
+            },
+            (false, false) => {
+                data.resize(8, 0);
+                object.extend_from_slice(&[flags, frame.dlc]);
+                object.extend_from_slice(&id.to_le_bytes());
+                object.extend_from_slice(&data);
+                self.add_object(CAN_MESSAGE, frame.time, &object);
+            },
+        }
+
+        match self.buffer.len() >= MAX_CONTAINER {
+            true => self.container(),
+            false => Vec::new(),
+        }
+    }
+
+    fn flush(&mut self) -> Vec<u8> {
+        self.container()
+    }
+
+    fn finish(&mut self, path: &Path) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let size = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.file_header(size))?;
+        file.sync_all()
+    }
+
+    /// Keeps the whole containers, the frames still buffered when the previous
+    /// run stopped are gone, and counts their objects for the header.
+    fn recover(&mut self, path: &Path) -> io::Result<()> {
+        let file = fs::read(path)?;
+        let start = match file.get(..FILE_HEADER_SIZE) {
+            Some(header) if &header[..4] == b"LOGG" => from_system_time(&header[40..56]),
+            _ => None,
+        };
+        let start = start.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no BLF file header"))?;
+        *self = Blf { start: Some(start), uncompressed: FILE_HEADER_SIZE as u64, ..Default::default() };
+
+        let mut pos = FILE_HEADER_SIZE;
+        while let Some((len, objects)) = read_container(&file[pos..]) {
+            let mut at = 0;
+            while let Some(header) = objects.get(at..at + 32) {
+                let size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+                let offset = u64::from_le_bytes(header[24..32].try_into().unwrap());
+                self.stop = Some(start + chrono::Duration::nanoseconds(offset as i64));
+                self.objects += 1;
+                at += size.max(32) + size % 4;
+            }
+            self.uncompressed += objects.len() as u64;
+            pos += len;
+        }
+        OpenOptions::new().write(true).open(path)?.set_len(pos as u64)?;
+        self.finish(path)
+    }
+}
+
+#[test]
+fn test_blf() {
+    let start = Utc.with_ymd_and_hms(2026, 10, 18, 10, 0, 0).unwrap();
+    let frame = CaptureFrame::sample(start + chrono::Duration::microseconds(1500), "can1", 0x18FEF100, &[0xAB, 0xCD]);
+
+    let path = std::env::temp_dir().join(format!("iot-edge-blf-{}.blf", std::process::id()));
+    let mut blf = Blf::default();
+    let mut file = blf.header(start);
+    assert!(blf.frame(&frame).is_empty());
+    let fd = CaptureFrame { fd: true, brs: true, dlc: 9, data: vec![1; 12], ..frame.clone() };
+    assert!(blf.frame(&fd).is_empty());
+    file.extend(blf.trailer());
+    std::fs::write(&path, &file).unwrap();
+    blf.finish(&path).unwrap();
+    let file = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(&file[..4], b"LOGG");
+    assert_eq!(u64::from_le_bytes(file[16..24].try_into().unwrap()), file.len() as u64);
+    assert_eq!(u32::from_le_bytes(file[32..36].try_into().unwrap()), 2);
+    assert_eq!(u16::from_le_bytes(file[40..42].try_into().unwrap()), 2026);
+
+    // one container with both frames
+    let container = &file[FILE_HEADER_SIZE..];
+    assert_eq!(&container[..4], b"LOBJ");
+    assert_eq!(u32::from_le_bytes(container[12..16].try_into().unwrap()), LOG_CONTAINER);
+    let size = u32::from_le_bytes(container[8..12].try_into().unwrap()) as usize;
+    assert_eq!(FILE_HEADER_SIZE + size + size % 4, file.len());
+    let mut objects = Vec::new();
+    ZlibDecoder::new(&container[32..size]).read_to_end(&mut objects).unwrap();
+    assert_eq!(u32::from_le_bytes(container[24..28].try_into().unwrap()) as usize, objects.len());
+
+    // CAN_MESSAGE: channel 2, extended id, data padded to 8
+    assert_eq!(u32::from_le_bytes(objects[12..16].try_into().unwrap()), CAN_MESSAGE);
+    assert_eq!(u64::from_le_bytes(objects[24..32].try_into().unwrap()), 1_500_000);
+    assert_eq!(&objects[32..48], &[2, 0, 0, 2, 0x00, 0xF1, 0xFE, 0x98, 0xAB, 0xCD, 0, 0, 0, 0, 0, 0]);
+
+    let fd = &objects[48..];
+    assert_eq!(u32::from_le_bytes(fd[12..16].try_into().unwrap()), CAN_FD_MESSAGE);
+    assert_eq!(&fd[32 + 13..32 + 15], &[FD_EDL | FD_BRS, 12]);
+}
+
+#[test]
+fn test_recover() {
+    let start = Utc.with_ymd_and_hms(2026, 10, 18, 10, 0, 0).unwrap();
+    let frame = CaptureFrame::sample(start + chrono::Duration::milliseconds(20), "can0", 0x123, &[1]);
+
+    // two frames in a container, a torn one after it and no final header
+    let path = std::env::temp_dir().join(format!("iot-edge-blf-recover-{}.blf", std::process::id()));
+    let mut blf = Blf::default();
+    let mut file = blf.header(start);
+    blf.frame(&frame);
+    blf.frame(&CaptureFrame { time: start + chrono::Duration::milliseconds(40), ..frame.clone() });
+    file.extend(blf.flush());
+    let whole = file.len();
+    blf.frame(&frame);
+    file.extend(&blf.flush()[..20]);
+    std::fs::write(&path, &file).unwrap();
+
+    Blf::default().recover(&path).unwrap();
+    let file = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(file.len(), whole);
+    assert_eq!(u64::from_le_bytes(file[16..24].try_into().unwrap()), whole as u64);
+    assert_eq!(u32::from_le_bytes(file[32..36].try_into().unwrap()), 2);
+    assert_eq!(from_system_time(&file[40..56]), Some(start));
+    assert_eq!(from_system_time(&file[56..72]), Some(start + chrono::Duration::milliseconds(40)));
+}
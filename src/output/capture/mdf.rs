@@ -0,0 +1,629 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use chrono::prelude::*;
+
+use crate::dbc::{Dbc, DbcMessage, DbcSignal};
+use crate::message::GpsMessage;
+use crate::output::capture::{channel_number, CaptureFormat, CaptureFrame};
+
+const ID_SIZE: usize = 64;
+const BLOCK_HEADER: u64 = 24;
+const VERSION: u16 = 410;
+
+// unfinalized flags, cleared when the file is finished
+const UPDATE_CG_CYCLE_COUNT: u16 = 0x1;
+const UPDATE_DT_LENGTH: u16 = 0x2;
+
+// cn_type, cn_sync_type
+const FIXED: u8 = 0;
+const MASTER: u8 = 2;
+const TIME: u8 = 1;
+
+// cn_data_type
+const UNSIGNED: u8 = 0;
+const SIGNED: u8 = 2;
+const FLOAT: u8 = 4;
+const BYTE_ARRAY: u8 = 10;
+
+// cn_flags, cg_flags
+const INVAL_BIT_VALID: u32 = 0x2;
+const BUS_EVENT_CHANNEL: u32 = 0x400;
+const BUS_EVENT: u16 = 0x2;
+const PLAIN_BUS_EVENT: u16 = 0x4;
+
+// cc_type
+const LINEAR: u8 = 1;
+const VALUE_TO_TEXT: u8 = 7;
+
+// si_type, si_bus_type
+const SOURCE_BUS: u8 = 2;
+const BUS_CAN: u8 = 2;
+
+// channel groups, the DBC messages come after these
+const DATA_FRAME: usize = 0;
+const FD_FRAME: usize = 1;
+const REMOTE_FRAME: usize = 2;
+const ERROR_FRAME: usize = 3;
+const GPS: usize = 4;
+const MESSAGES: usize = 5;
+
+#[derive(Clone, Debug)]
+enum Conversion {
+    NONE,
+    LINEAR { factor: f64, offset: f64 },
+    VALUES(Vec<(i64, String)>, Box<Conversion>),    // descriptions, and how to scale the rest
+}
+
+#[derive(Clone, Debug)]
+struct Channel {
+    name: String,
+    unit: String,
+    kind: u8,
+    sync: u8,
+    data_type: u8,
+    byte: u32,          // after the record id
+    bit: u8,
+    bits: u32,
+    flags: u32,
+    inval: u32,         // invalidation bit, with INVAL_BIT_VALID
+    conversion: Conversion,
+    components: Vec<Channel>,
+}
+
+impl Channel {
+    fn new(name: &str, data_type: u8, byte: u32, bit: u8, bits: u32) -> Self {
+        Channel {
+            name: name.to_string(),
+            unit: String::new(),
+            kind: FIXED,
+            sync: 0,
+            data_type,
+            byte,
+            bit,
+            bits,
+            flags: 0,
+            inval: 0,
+            conversion: Conversion::NONE,
+            components: Vec::new(),
+        }
+    }
+
+    /// Seconds since the start of the file, first in every record.
+    fn time() -> Self {
+        Channel { kind: MASTER, sync: TIME, ..Channel::new("Timestamp", FLOAT, 0, 0, 64).unit("s") }
+    }
+
+    fn unit(self, unit: &str) -> Self {
+        Channel { unit: unit.to_string(), ..self }
+    }
+}
+
+struct Group {
+    name: String,
+    flags: u16,
+    channels: Vec<Channel>,
+    bytes: u32,
+    inval_bytes: u32,
+}
+
+/// ASAM MDF bus logging layout of a CAN frame, `data` payload bytes or none
+/// for remote frames.
+fn bus_group(name: &str, data: u32) -> Group {
+    let field = |suffix: &str, data_type, byte, bit, bits| {
+        let channel = Channel::new(&format!("{}.{}", name, suffix), data_type, byte, bit, bits);
+        Channel { flags: BUS_EVENT_CHANNEL, ..channel }
+    };
+    let mut components = vec![
+        field("BusChannel", UNSIGNED, 8, 0, 8),
+        field("ID", UNSIGNED, 9, 0, 29),
+        field("IDE", UNSIGNED, 12, 7, 1),
+        field("DLC", UNSIGNED, 13, 0, 4),
+        field("Dir", UNSIGNED, 13, 4, 1),
+        field("EDL", UNSIGNED, 13, 5, 1),
+        field("BRS", UNSIGNED, 13, 6, 1),
+        field("ESI", UNSIGNED, 13, 7, 1),
+        field("DataLength", UNSIGNED, 14, 0, 8),
+    ];
+    if data > 0 {
+        components.push(field("DataBytes", BYTE_ARRAY, 15, 0, data * 8));
+    }
+    let frame = Channel { components, ..field("", BYTE_ARRAY, 8, 0, (7 + data) * 8) };
+    Group {
+        name: name.to_string(),
+        flags: BUS_EVENT | PLAIN_BUS_EVENT,
+        channels: vec![Channel::time(), Channel { name: name.to_string(), ..frame }],
+        bytes: 15 + data,
+        inval_bytes: 0,
+    }
+}
+
+fn bus_record(frame: &CaptureFrame, data: usize) -> Vec<u8> {
+    let mut record = vec![channel_number(&frame.channel).min(255) as u8];
+    record.extend_from_slice(&((frame.id & 0x1FFF_FFFF) | ((frame.extended as u32) << 31)).to_le_bytes());
+    // received frames, Dir stays 0
+    record.push((frame.dlc & 0xF) | ((frame.fd as u8) << 5) | ((frame.brs as u8) << 6) | ((frame.esi as u8) << 7));
+    record.push(frame.data.len().min(data) as u8);
+    let mut payload = frame.data.clone();
+    payload.resize(data, 0);
+    record.extend_from_slice(&payload);
+    record
+}
+
+fn gps_group() -> Group {
+    Group {
+        name: "GPS".to_string(),
+        flags: 0,
+        channels: vec![
+            Channel::time(),
+            Channel::new("Latitude", FLOAT, 8, 0, 64).unit("deg"),
+            Channel::new("Longitude", FLOAT, 16, 0, 64).unit("deg"),
+            Channel::new("Speed", FLOAT, 24, 0, 64).unit("m/s"),
+        ],
+        bytes: 32,
+        inval_bytes: 0,
+    }
+}
+
+/// Bytes a raw signal value is stored in.
+fn storage(signal: &DbcSignal) -> u32 {
+    match signal.length {
+        0..=8 => 1,
+        9..=16 => 2,
+        17..=32 => 4,
+        _ => 8,
+    }
+}
+
+/// Raw values as the DBC has them, scaled by conversion blocks. A signal that
+/// is not in the frame, multiplexed away or past its end, is marked invalid.
+fn message_group(message: &DbcMessage) -> Group {
+    let mut channels = vec![Channel::time()];
+    let mut byte = 8;
+    for (i, signal) in message.signals.iter().enumerate() {
+        let data_type = match signal.signed {
+            true => SIGNED,
+            false => UNSIGNED,
+        };
+        let scale = match (signal.factor, signal.offset) {
+            (factor, offset) if (factor == 1.0) & (offset == 0.0) => Conversion::NONE,
+            (factor, offset) => Conversion::LINEAR { factor, offset },
+        };
+        let conversion = match signal.values.is_empty() {
+            true => scale,
+            false => Conversion::VALUES(signal.values.clone(), Box::new(scale)),
+        };
+        channels.push(Channel {
+            flags: INVAL_BIT_VALID,
+            inval: i as u32,
+            conversion,
+            ..Channel::new(&signal.name, data_type, byte, 0, storage(signal) * 8).unit(&signal.unit)
+        });
+        byte += storage(signal);
+    }
+    Group {
+        name: message.name.clone(),
+        flags: 0,
+        channels,
+        bytes: byte,
+        inval_bytes: (message.signals.len() as u32).div_ceil(8),
+    }
+}
+
+fn message_record(message: &DbcMessage, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::new();
+    let mut invalid = vec![0; message.signals.len().div_ceil(8)];
+    for (i, signal) in message.signals.iter().enumerate() {
+        let raw = match message.present(signal, data) {
+            true => signal.raw(data),
+            false => None,
+        };
+        if raw.is_none() {
+            invalid[i / 8] |= 1 << (i % 8);
+        }
+        record.extend_from_slice(&raw.unwrap_or(0).to_le_bytes()[..storage(signal) as usize]);
+    }
+    record.extend_from_slice(&invalid);
+    record
+}
+
+/// Blocks of the file, all of it is known up front but the data.
+struct Blocks {
+    data: Vec<u8>,
+}
+
+impl Blocks {
+    fn block(&mut self, id: &[u8; 2], links: &[u64], body: &[u8]) -> u64 {
+        let at = self.data.len() as u64;
+        let len = (BLOCK_HEADER as usize + links.len() * 8 + body.len() + 7) & !7;
+        self.data.extend_from_slice(b"##");
+        self.data.extend_from_slice(id);
+        self.data.extend_from_slice(&[0; 4]);
+        self.data.extend_from_slice(&(len as u64).to_le_bytes());
+        self.data.extend_from_slice(&(links.len() as u64).to_le_bytes());
+        for link in links {
+            self.data.extend_from_slice(&link.to_le_bytes());
+        }
+        self.data.extend_from_slice(body);
+        self.data.resize(at as usize + len, 0);
+        at
+    }
+
+    /// Point link `index` of the block at `block` to `target`.
+    fn link(&mut self, block: u64, index: usize, target: u64) {
+        let at = (block + BLOCK_HEADER) as usize + index * 8;
+        self.data[at..at + 8].copy_from_slice(&target.to_le_bytes());
+    }
+
+    fn text(&mut self, id: &[u8; 2], text: &str) -> u64 {
+        if text.is_empty() {
+            return 0;
+        }
+        let mut body = text.as_bytes().to_vec();
+        body.push(0);
+        self.block(id, &[], &body)
+    }
+
+    fn conversion(&mut self, conversion: &Conversion) -> u64 {
+        let (kind, refs, values) = match conversion {
+            Conversion::NONE => return 0,
+            Conversion::LINEAR { factor, offset } => (LINEAR, vec![], vec![*offset, *factor]),
+            Conversion::VALUES(table, scale) => {
+                let mut refs: Vec<u64> = table.iter().map(|(_, text)| self.text(b"TX", text)).collect();
+                refs.push(self.conversion(scale));
+                (VALUE_TO_TEXT, refs, table.iter().map(|(value, _)| *value as f64).collect())
+            },
+        };
+        let mut links = vec![0; 4];     // name, unit, comment, inverse
+        links.extend(refs.iter());
+        let mut body = vec![kind, 0];
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(refs.len() as u16).to_le_bytes());
+        body.extend_from_slice(&(values.len() as u16).to_le_bytes());
+        body.extend_from_slice(&[0; 16]);   // physical range
+        for value in values {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+        self.block(b"CC", &links, &body)
+    }
+
+    /// A chain of channels, returns the first.
+    fn channels(&mut self, channels: &[Channel]) -> u64 {
+        let mut next = 0;
+        for channel in channels.iter().rev() {
+            let components = self.channels(&channel.components);
+            let name = self.text(b"TX", &channel.name);
+            let conversion = self.conversion(&channel.conversion);
+            let unit = self.text(b"TX", &channel.unit);
+            let mut body = vec![channel.kind, channel.sync, channel.data_type, channel.bit];
+            body.extend_from_slice(&channel.byte.to_le_bytes());
+            body.extend_from_slice(&channel.bits.to_le_bytes());
+            body.extend_from_slice(&channel.flags.to_le_bytes());
+            body.extend_from_slice(&channel.inval.to_le_bytes());
+            body.extend_from_slice(&[0; 4]);    // precision, attachments
+            body.extend_from_slice(&[0; 48]);   // value range and limits
+            next = self.block(b"CN", &[next, components, name, 0, conversion, 0, unit, 0], &body);
+        }
+        next
+    }
+}
+
+/// Fill in the data block length and the record count of each group, at
+/// their offsets, and mark the file finalized.
+fn finalize(path: &Path, data_offset: u64, counts: &[(u64, u64)]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(data_offset + 8))?;
+    file.write_all(&(size - data_offset).to_le_bytes())?;
+    for (offset, count) in counts {
+        file.seek(SeekFrom::Start(*offset))?;
+        file.write_all(&count.to_le_bytes())?;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(b"MDF     ")?;
+    file.seek(SeekFrom::Start(ID_SIZE as u64 - 4))?;
+    file.write_all(&0u16.to_le_bytes())?;
+    file.sync_all()
+}
+
+/// Record id, record size and where the count is of a group in a file.
+type GroupLayout = (u64, u64, u64);
+
+/// Where an unfinished file has its records, the bytes of its record ids and
+/// its groups, as its own blocks have them.
+fn layout(file: &[u8]) -> Option<(u64, usize, Vec<GroupLayout>)> {
+    let u64_at = |at: u64| Some(u64::from_le_bytes(file.get(at as usize..at as usize + 8)?.try_into().unwrap()));
+    let u32_at = |at: u64| Some(u32::from_le_bytes(file.get(at as usize..at as usize + 4)?.try_into().unwrap()));
+    let dg = u64_at(ID_SIZE as u64 + BLOCK_HEADER)?;
+    let id_size = *file.get((dg + BLOCK_HEADER + 32) as usize)? as usize;
+    let data_offset = u64_at(dg + BLOCK_HEADER + 16)?;
+    if !matches!(id_size, 1 | 2) | (file.get(data_offset as usize..data_offset as usize + 4)? != b"##DT") {
+        return None;
+    }
+    let mut groups = vec![];
+    let mut cg = u64_at(dg + BLOCK_HEADER + 8)?;
+    while cg != 0 {
+        let body = cg + BLOCK_HEADER + 6 * 8;
+        let size = id_size as u64 + u32_at(body + 24)? as u64 + u32_at(body + 28)? as u64;
+        groups.push((u64_at(body)?, size, body + 8));
+        cg = u64_at(cg + BLOCK_HEADER)?;
+    }
+    Some((data_offset, id_size, groups))
+}
+
+/// ASAM MDF 4.10 for asammdf and CANape. Frames go to channel groups laid out
+/// as the bus logging standard has them, frames of DBC messages to one more
+/// group each with their signals, and GPS fixes to a group of their own. The
+/// file is written unfinalized, the data block length and record counts are
+/// filled in when it is finished, readers can still recover it after a crash.
+pub struct Mdf {
+    dbc: Dbc,
+    messages: HashMap<(u32, bool), usize>,
+    start: DateTime<Utc>,
+    id_size: usize,             // bytes of the record id
+    counts: Vec<u64>,           // records of each group
+    count_offsets: Vec<u64>,    // where the group records their count
+    data_offset: u64,
+}
+
+impl Mdf {
+    pub fn new(dbc: Dbc) -> Self {
+        let messages = dbc.messages.iter().enumerate().map(|(i, m)| ((m.id, m.extended), i)).collect();
+        Mdf {
+            dbc,
+            messages,
+            start: Utc::now(),
+            id_size: 1,
+            counts: Vec::new(),
+            count_offsets: Vec::new(),
+            data_offset: 0,
+        }
+    }
+
+    fn groups(&self) -> Vec<Group> {
+        let mut groups = vec![
+            bus_group("CAN_DataFrame", 8),
+            bus_group("CAN_DataFrame", 64),
+            bus_group("CAN_RemoteFrame", 0),
+            bus_group("CAN_ErrorFrame", 8),
+            gps_group(),
+        ];
+        groups.extend(self.dbc.messages.iter().map(message_group));
+        groups
+    }
+
+    fn record(&mut self, group: usize, time: DateTime<Utc>, data: &[u8]) -> Vec<u8> {
+        self.counts[group] += 1;
+        let mut record = ((group + 1) as u16).to_le_bytes()[..self.id_size].to_vec();
+        let seconds = (time - self.start).num_nanoseconds().unwrap_or(0) as f64 / 1e9;
+        record.extend_from_slice(&seconds.to_le_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+}
+
+impl CaptureFormat for Mdf {
+    fn header(&mut self, start: DateTime<Utc>) -> Vec<u8> {
+        let groups = self.groups();
+        self.start = start;
+        self.id_size = match groups.len() {
+            0..=255 => 1,
+            _ => 2,
+        };
+        self.counts = vec![0; groups.len()];
+        self.count_offsets.clear();
+
+        let mut id = b"UnFinMF 4.10    IotEdge ".to_vec();
+        id.extend_from_slice(&[0; 4]);
+        id.extend_from_slice(&VERSION.to_le_bytes());
+        id.extend_from_slice(&[0; 30]);
+        id.extend_from_slice(&(UPDATE_CG_CYCLE_COUNT | UPDATE_DT_LENGTH).to_le_bytes());
+        id.extend_from_slice(&[0; 2]);
+        let mut blocks = Blocks { data: id };
+
+        // start time in UTC, no angle or distance
+        let mut body = (start.timestamp_nanos_opt().unwrap_or(0) as u64).to_le_bytes().to_vec();
+        body.extend_from_slice(&[0; 24]);
+        let hd = blocks.block(b"HD", &[0; 6], &body);
+
+        let comment = format!(
+            "<FHcomment><TX>created</TX><tool_id>iot-edge</tool_id><tool_vendor>iot-edge</tool_vendor>\
+            <tool_version>{}</tool_version></FHcomment>",
+            env!("CARGO_PKG_VERSION")
+        );
+        let comment = blocks.text(b"MD", &comment);
+        let mut body = (Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64).to_le_bytes().to_vec();
+        body.extend_from_slice(&[0; 8]);
+        let fh = blocks.block(b"FH", &[0, comment], &body);
+        blocks.link(hd, 1, fh);
+
+        let mut body = vec![self.id_size as u8];
+        body.extend_from_slice(&[0; 7]);
+        let dg = blocks.block(b"DG", &[0; 4], &body);
+        blocks.link(hd, 0, dg);
+
+        let source_name = blocks.text(b"TX", "CAN");
+        let source = blocks.block(b"SI", &[source_name, 0, 0], &[SOURCE_BUS, BUS_CAN, 0, 0, 0, 0, 0, 0]);
+
+        let mut previous = None;
+        for (i, group) in groups.iter().enumerate() {
+            let channels = blocks.channels(&group.channels);
+            let name = blocks.text(b"TX", &group.name);
+            let (source, separator) = match group.flags {
+                0 => (0, 0),
+                _ => (source, b'.' as u16),
+            };
+            let mut body = ((i + 1) as u64).to_le_bytes().to_vec();
+            body.extend_from_slice(&0u64.to_le_bytes());
+            body.extend_from_slice(&group.flags.to_le_bytes());
+            body.extend_from_slice(&separator.to_le_bytes());
+            body.extend_from_slice(&[0; 4]);
+            body.extend_from_slice(&group.bytes.to_le_bytes());
+            body.extend_from_slice(&group.inval_bytes.to_le_bytes());
+            let cg = blocks.block(b"CG", &[0, channels, name, source, 0, 0], &body);
+            self.count_offsets.push(cg + BLOCK_HEADER + 6 * 8 + 8);
+            match previous {
+                Some(previous) => blocks.link(previous, 0, cg),
+                None => blocks.link(dg, 1, cg),
+            }
+            previous = Some(cg);
+        }
+
+        // records go on to the end of the file
+        self.data_offset = blocks.data.len() as u64;
+        blocks.link(dg, 2, self.data_offset);
+        let mut data = blocks.data;
+        data.extend_from_slice(b"##DT");
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&BLOCK_HEADER.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data
+    }
+
+    fn frame(&mut self, frame: &CaptureFrame) -> Vec<u8> {
+        let (group, len) = match (frame.error, frame.remote, frame.fd) {
+            (true, _, _) => (ERROR_FRAME, 8),
+            (false, true, _) => (REMOTE_FRAME, 0),
+            (false, false, true) => (FD_FRAME, 64),
+            (false, false, false) => (DATA_FRAME, 8),
+        };
+        let mut data = self.record(group, frame.time, &bus_record(frame, len));
+        if !frame.error & !frame.remote {
+            if let Some(&i) = self.messages.get(&(frame.id, frame.extended)) {
+                let record = message_record(&self.dbc.messages[i], &frame.data);
+                data.extend(self.record(MESSAGES + i, frame.time, &record));
+            }
+        }
+        data
+    }
+
+    fn gps(&mut self, fix: &GpsMessage) -> Vec<u8> {
+        let mut record = fix.latitude.to_le_bytes().to_vec();
+        record.extend_from_slice(&fix.longitude.to_le_bytes());
+        record.extend_from_slice(&fix.speed.to_le_bytes());
+        self.record(GPS, fix.time, &record)
+    }
+
+    fn finish(&mut self, path: &Path) -> io::Result<()> {
+        let counts: Vec<(u64, u64)> = self.count_offsets.iter().copied().zip(self.counts.iter().copied()).collect();
+        finalize(path, self.data_offset, &counts)
+    }
+
+    /// Count the records after the data block up to the last whole one, cut
+    /// the rest and finish the file with those counts.
+    fn recover(&mut self, path: &Path) -> io::Result<()> {
+        let file = fs::read(path)?;
+        if file.starts_with(b"MDF     ") {
+            return Ok(());
+        }
+        let (data_offset, id_size, groups) = match file.starts_with(b"UnFinMF ") {
+            true => layout(&file),
+            false => None,
+        }.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no MDF header"))?;
+
+        let mut counts = vec![0; groups.len()];
+        let mut pos = (data_offset + BLOCK_HEADER) as usize;
+        while let Some(id) = file.get(pos..pos + id_size) {
+            let mut bytes = [0; 8];
+            bytes[..id_size].copy_from_slice(id);
+            let id = u64::from_le_bytes(bytes);
+            // a torn record, or a torn id
+            match groups.iter().position(|(record_id, _, _)| *record_id == id) {
+                Some(group) if pos + groups[group].1 as usize <= file.len() => {
+                    counts[group] += 1;
+                    pos += groups[group].1 as usize;
+                },
+                _ => break,
+            }
+        }
+        OpenOptions::new().write(true).open(path)?.set_len(pos as u64)?;
+        let counts: Vec<(u64, u64)> = groups.iter().map(|(_, _, offset)| *offset).zip(counts).collect();
+        finalize(path, data_offset, &counts)
+    }
+}
+
+#[test]
+fn test_mdf() {
+    use crate::output::file::test_dir;
+
+    let dbc = Dbc::parse(r#"
+BO_ 2364539904 EEC1: 8 Vector__XXX
+ SG_ EngineSpeed : 24|16@1+ (0.125,0) [0|8031.875] "rpm" Vector__XXX
+ SG_ EngineStarterMode : 48|4@1+ (1,0) [0|15] "" Vector__XXX
+VAL_ 2364539904 EngineStarterMode 0 "start not requested" 1 "starter active" ;
+"#);
+    let start = Utc.with_ymd_and_hms(2026, 10, 18, 10, 0, 0).unwrap();
+    let frame = CaptureFrame::sample(start + chrono::Duration::milliseconds(250), "can0", 0x0CF00400, &[0, 0, 0, 0x40, 0x1F, 0, 0x01, 0]);
+
+    let dir = test_dir("mdf");
+    let path = dir.join("capture.mf4");
+    let mut mdf = Mdf::new(dbc);
+    let mut file = mdf.header(start);
+    assert_eq!(&file[..8], b"UnFinMF ");
+    let data_offset = file.len() - BLOCK_HEADER as usize;
+    assert_eq!(&file[data_offset..data_offset + 4], b"##DT");
+
+    let records = mdf.frame(&frame);
+    // the bus logging record and the decoded EEC1 record, one byte record ids
+    assert_eq!(records.len(), (1 + 8 + 15) + (1 + 8 + 2 + 1 + 1));
+    assert_eq!(records[0], DATA_FRAME as u8 + 1);
+    assert_eq!(f64::from_le_bytes(records[1..9].try_into().unwrap()), 0.25);
+    assert_eq!(u32::from_le_bytes(records[10..14].try_into().unwrap()), 0x8CF00400);
+    assert_eq!(&records[16..24], &frame.data[..]);
+    let decoded = &records[24..];
+    assert_eq!(decoded[0], MESSAGES as u8 + 1);
+    assert_eq!(u16::from_le_bytes(decoded[9..11].try_into().unwrap()), 8000);
+    assert_eq!((decoded[11], decoded[12]), (1, 0));
+    file.extend(records);
+
+    let short = CaptureFrame { dlc: 4, data: vec![0, 0, 0, 0x40], ..frame.clone() };
+    let records = mdf.frame(&short);
+    // EngineSpeed is cut short, both signals are invalid
+    assert_eq!(records[records.len() - 1], 0b11);
+    file.extend(records);
+    file.extend(mdf.gps(&GpsMessage { time: start, latitude: 52.5, longitude: 13.4, speed: 3.0 }));
+    file.extend(mdf.trailer());
+
+    let unfinished = file.clone();
+    std::fs::write(&path, &file).unwrap();
+    mdf.finish(&path).unwrap();
+    let file = std::fs::read(&path).unwrap();
+
+    // a crash tore the GPS record, the rest is counted from the blocks of the file
+    std::fs::write(&path, &unfinished[..unfinished.len() - 3]).unwrap();
+    Mdf::new(Dbc::default()).recover(&path).unwrap();
+    let recovered = std::fs::read(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let u64_at = |at: usize| u64::from_le_bytes(file[at..at + 8].try_into().unwrap());
+    assert_eq!(&file[..8], b"MDF     ");
+    assert_eq!(u16::from_le_bytes(file[28..30].try_into().unwrap()), VERSION);
+    assert_eq!(&file[60..62], &[0, 0]);
+    assert_eq!(u64_at(data_offset + 8), (file.len() - data_offset) as u64);
+
+    // HD, DG and the first CG it links to
+    assert_eq!(&file[ID_SIZE..ID_SIZE + 4], b"##HD");
+    let dg = u64_at(ID_SIZE + 24) as usize;
+    assert_eq!(&file[dg..dg + 4], b"##DG");
+    assert_eq!(u64_at(dg + 24 + 16), data_offset as u64);
+    let counts = |file: &[u8]| {
+        let u64_at = |at: usize| u64::from_le_bytes(file[at..at + 8].try_into().unwrap());
+        let mut cg = u64_at(dg + 24 + 8) as usize;
+        let mut counts = vec![];
+        while cg != 0 {
+            assert_eq!(&file[cg..cg + 4], b"##CG");
+            counts.push(u64_at(cg + 24 + 6 * 8 + 8));
+            cg = u64_at(cg + 24) as usize;
+        }
+        counts
+    };
+    assert_eq!(counts(&file), vec![2, 0, 0, 0, 1, 2]);
+
+    let gps_record = 1 + 32;
+    assert_eq!(recovered.len(), file.len() - gps_record);
+    assert_eq!(&recovered[..8], b"MDF     ");
+    assert_eq!(&recovered[60..62], &[0, 0]);
+    assert_eq!(u64::from_le_bytes(recovered[data_offset + 8..data_offset + 16].try_into().unwrap()), (recovered.len() - data_offset) as u64);
+    assert_eq!(counts(&recovered), vec![2, 0, 0, 0, 0, 2]);
+}
@@ -0,0 +1,329 @@
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use chrono::prelude::*;
+use log::*;
+use tokio::select;
+use tokio::sync::{broadcast, mpsc::Receiver, Mutex};
+use tokio::time::{self, Duration};
+
+mod asc;
+mod blf;
+mod mdf;
+mod pcapng;
+mod stream;
+
+pub use asc::Asc;
+pub use blf::Blf;
+pub use mdf::Mdf;
+pub use pcapng::Pcapng;
+pub use stream::CaptureStream;
+
+use crate::config::ConfigCapture;
+use crate::message::{CanMessage, GpsMessage};
+use crate::output::file::FileLogger;
+
+/// Capture counters, shared with the system telemetry.
+#[derive(Debug, Default)]
+pub struct CaptureStats {
+    pub dropped: AtomicU64,     // CAN frames the capture sinks had no room for
+}
+
+/// A CAN or CAN FD frame as the capture formats see it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureFrame {
+    pub time: DateTime<Utc>,
+    pub channel: String,
+    pub id: u32,            // error class for error frames
+    pub extended: bool,
+    pub remote: bool,
+    pub error: bool,
+    pub fd: bool,
+    pub brs: bool,          // FD bit rate switch
+    pub esi: bool,          // FD error state indicator
+    pub dlc: u8,
+    pub data: Vec<u8>,      // up to 64 bytes for FD
+}
+
+#[cfg(test)]
+impl CaptureFrame {
+    /// A classic data frame, extended when the id needs it.
+    pub fn sample(time: DateTime<Utc>, channel: &str, id: u32, data: &[u8]) -> Self {
+        CaptureFrame {
+            time,
+            channel: channel.to_string(),
+            id,
+            extended: id > 0x7FF,
+            remote: false,
+            error: false,
+            fd: false,
+            brs: false,
+            esi: false,
+            dlc: data.len() as u8,
+            data: data.to_vec(),
+        }
+    }
+}
+
+impl From<&CanMessage> for CaptureFrame {
+    fn from(msg: &CanMessage) -> Self {
+        let frame = &msg.frame;
+        CaptureFrame {
+            time: msg.time,
+            channel: msg.channel.clone(),
+            id: match frame.is_error() {
+                true => frame.err(),
+                false => frame.id(),
+            },
+            extended: msg.extended,
+            remote: frame.is_rtr(),
+            error: frame.is_error(),
+            fd: false,      // the CAN socket only reads classic frames, FD ones never get here
+            brs: false,
+            esi: false,
+            dlc: fd_dlc(frame.data().len()),
+            data: frame.data().to_vec(),
+        }
+    }
+}
+
+/// DLC of an FD payload length.
+pub fn fd_dlc(len: usize) -> u8 {
+    match len {
+        0..=8 => len as u8,
+        9..=12 => 9,
+        13..=16 => 10,
+        17..=20 => 11,
+        21..=24 => 12,
+        25..=32 => 13,
+        33..=48 => 14,
+        _ => 15,
+    }
+}
+
+/// Channel number of an interface, 1 based like Vector tools count them: can0 is 1.
+pub fn channel_number(name: &str) -> u16 {
+    let digits = name.trim_start_matches(|c: char| !c.is_ascii_digit());
+    digits.parse::<u16>().map_or(1, |n| n.saturating_add(1))
+}
+
+/// A capture file format, the bytes it makes are appended to the file.
+pub trait CaptureFormat: Send {
+    /// Start of a file whose first frame is at `start`.
+    fn header(&mut self, _start: DateTime<Utc>) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// A frame, formats that buffer may return nothing.
+    fn frame(&mut self, frame: &CaptureFrame) -> Vec<u8>;
+
+    /// A GPS fix, formats without a place for it leave it out.
+    fn gps(&mut self, _fix: &GpsMessage) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Whatever is buffered, asked for now and then so a crash loses little.
+    fn flush(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// End of the file.
+    fn trailer(&mut self) -> Vec<u8> {
+        self.flush()
+    }
+
+    /// Last touches to the written file, like a header with its statistics.
+    fn finish(&mut self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Make a file a previous run left unfinished whole, it lacks the trailer
+    /// and what `finish` does and may end in a torn frame.
+    fn recover(&mut self, path: &Path) -> io::Result<()> {
+        cut_torn_line(path).map(|_| ())
+    }
+}
+
+/// Cut a text capture after its last whole line, returning the new length.
+fn cut_torn_line(path: &Path) -> io::Result<u64> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut buf = vec![0; 64 * 1024];
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let block = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(block)?;
+        if let Some(pos) = block.iter().rposition(|b| *b == b'\n') {
+            end = start + pos as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    file.set_len(end)?;
+    Ok(end)
+}
+
+/// `candump -l`, one `(1469439874.299591) can1 701#7F` line per frame, see Candump::frame.
+pub struct Candump;
+
+impl CaptureFormat for Candump {
+    fn frame(&mut self, frame: &CaptureFrame) -> Vec<u8> {
+        let id = match (frame.error, frame.extended) {
+            (true, _) => format!("{:08X}", frame.id | 0x2000_0000),
+            (false, true) => format!("{:08X}", frame.id),
+            (false, false) => format!("{:03X}", frame.id),
+        };
+        let data: String = frame.data.iter().map(|b| format!("{:02X}", b)).collect();
+        let data = match (frame.remote, frame.fd) {
+            (true, _) if frame.dlc > 0 => format!("R{:X}", frame.dlc),
+            (true, _) => "R".to_string(),
+            // FD frames have a second '#' and their flags
+            (false, true) => format!("#{:X}{}", (frame.brs as u8) | ((frame.esi as u8) << 1), data),
+            (false, false) => data,
+        };
+        format!(
+            "({}.{:06}) {} {}#{}\n",
+            frame.time.timestamp(),
+            frame.time.timestamp_subsec_micros(),
+            frame.channel,
+            id,
+            data
+        ).into_bytes()
+    }
+}
+
+/// Writes the frames of every CAN task to one rotating capture file.
+pub struct CaptureTask {
+    logger: FileLogger,
+    format: Box<dyn CaptureFormat>,
+    rx: Arc<Mutex<Receiver<CaptureFrame>>>,    // outlives the task, the CAN tasks keep sending to it
+    gps: Option<broadcast::Receiver<GpsMessage>>,
+}
+
+impl CaptureTask {
+    pub fn new(
+        config: &ConfigCapture, mut format: Box<dyn CaptureFormat>, rx: Arc<Mutex<Receiver<CaptureFrame>>>
+    ) -> Self {
+        // left by a previous run, its trailer or final header are missing
+        let logger = FileLogger::raw(&config.log_config(), |path| format.recover(path));
+        CaptureTask { logger, format, rx, gps: None }
+    }
+
+    /// Fixes to write along with the frames.
+    pub fn with_gps(mut self, gps: broadcast::Receiver<GpsMessage>) -> Self {
+        self.gps = Some(gps);
+        self
+    }
+
+    fn close(&mut self) {
+        if self.logger.size() == 0 {
+            return;
+        }
+        let trailer = self.format.trailer();
+        self.logger.write_raw(&trailer);
+        let format = &mut self.format;
+        self.logger.close(|path| format.finish(path));
+    }
+
+    fn write(&mut self, time: DateTime<Utc>, record: impl FnOnce(&mut dyn CaptureFormat) -> Vec<u8>) {
+        if self.logger.full(1) {
+            self.close();
+        }
+        if self.logger.size() == 0 {
+            let header = self.format.header(time);
+            self.logger.write_raw(&header);
+        }
+        let data = record(self.format.as_mut());
+        self.logger.write_raw(&data);
+    }
+
+    pub async fn run(mut self) {
+        let rx = self.rx.clone();
+        let mut rx = rx.lock().await;
+        let mut gps = self.gps.take();
+        let mut interval = time::interval(Duration::from_secs(1));
+        loop {
+            select! {
+                frame = rx.recv() => match frame {
+                    Some(frame) => self.write(frame.time, |format| format.frame(&frame)),
+                    None => break,
+                },
+                fix = async { gps.as_mut().unwrap().recv().await }, if gps.is_some() => match fix {
+                    Ok(fix) => self.write(fix.time, |format| format.gps(&fix)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => warn!("capture: {} GPS fixes skipped", skipped),
+                    Err(broadcast::error::RecvError::Closed) => gps = None,
+                },
+                _ = interval.tick() => {
+                    let data = self.format.flush();
+                    self.logger.write_raw(&data);
+                },
+            }
+        }
+    }
+}
+
+/// Restarts and shutdown drop the task, the file is finished all the same.
+impl Drop for CaptureTask {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[test]
+fn test_candump() {
+    let frame = CaptureFrame::sample(Utc.timestamp_nanos(1_469_439_874_299_591_000), "can1", 0x701, &[0x7F]);
+    assert_eq!(Candump.frame(&frame), b"(1469439874.299591) can1 701#7F\n");
+
+    let fd = CaptureFrame { fd: true, brs: true, dlc: fd_dlc(12), data: vec![0x11; 12], ..frame.clone() };
+    assert_eq!(Candump.frame(&fd), b"(1469439874.299591) can1 701##1111111111111111111111111\n");
+
+    let remote = CaptureFrame { remote: true, dlc: 8, data: vec![], ..frame.clone() };
+    assert_eq!(Candump.frame(&remote), b"(1469439874.299591) can1 701#R8\n");
+
+    // error frames keep their class with the error flag
+    let msg = CanMessage {
+        time: frame.time,
+        channel: "can1".to_string(),
+        frame: socketcan::CANFrame::new(0x004, &[0, 0, 0x80], false, true).unwrap(),
+        extended: false,
+    };
+    assert_eq!(Candump.frame(&CaptureFrame::from(&msg)), b"(1469439874.299591) can1 20000004#000080\n");
+
+    assert_eq!((channel_number("can0"), channel_number("vcan2"), channel_number("any")), (1, 3, 1));
+    assert_eq!((fd_dlc(8), fd_dlc(12), fd_dlc(64)), (8, 9, 15));
+}
+
+#[tokio::test]
+async fn test_finish_on_drop() {
+    use tokio::sync::mpsc::channel;
+    use crate::dbc::Dbc;
+    use crate::output::file::{closed_logs, test_dir};
+
+    let dir = test_dir("capture-drop");
+    let path = dir.join("capture.mf4");
+    let config: ConfigCapture = toml::from_str(&format!("path = {:?}", path.to_string_lossy())).unwrap();
+    let (tx, rx) = channel(4);
+    let task = CaptureTask::new(&config, Box::new(Mdf::new(Dbc::default())), Arc::new(Mutex::new(rx)));
+    tx.send(CaptureFrame::sample(Utc::now(), "can0", 0x123, &[1, 2])).await.unwrap();
+
+    // a restart or shutdown aborts the task, which drops it
+    let handle = tokio::spawn(task.run());
+    time::sleep(Duration::from_millis(100)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let mut closed = vec![];
+    for _ in 0..100 {
+        closed = closed_logs(&path).unwrap();
+        if !closed.is_empty() {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    let file = std::fs::read(&closed[0].path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(&file[..8], b"MDF     ");
+}
@@ -0,0 +1,215 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::Path;
+use chrono::prelude::*;
+
+use crate::output::capture::{CaptureFormat, CaptureFrame};
+
+// block types
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+
+// options
+const OPT_ENDOFOPT: u16 = 0;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+
+// can_id flags, the id is big endian in LINKTYPE_CAN_SOCKETCAN
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+const CANFD_BRS: u8 = 0x01;
+const CANFD_ESI: u8 = 0x02;
+const CANFD_FDF: u8 = 0x04;
+
+fn padded(data: &[u8]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    data.resize((data.len() + 3) & !3, 0);
+    data
+}
+
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let body = padded(body);
+    let len = (body.len() as u32 + 12).to_le_bytes();
+    let mut buf = kind.to_le_bytes().to_vec();
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(&body);
+    buf.extend_from_slice(&len);
+    buf
+}
+
+fn option(code: u16, value: &[u8]) -> Vec<u8> {
+    let mut buf = code.to_le_bytes().to_vec();
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&padded(value));
+    buf
+}
+
+/// struct can_frame or struct canfd_frame as SocketCAN has them.
+fn packet(frame: &CaptureFrame) -> Vec<u8> {
+    let mut id = match frame.extended | frame.error {
+        true => frame.id & 0x1FFF_FFFF,
+        false => frame.id & 0x7FF,
+    };
+    if frame.extended & !frame.error {
+        id |= CAN_EFF_FLAG;
+    }
+    if frame.remote {
+        id |= CAN_RTR_FLAG;
+    }
+    if frame.error {
+        id |= CAN_ERR_FLAG;
+    }
+    let (len, flags, size) = match (frame.fd, frame.remote) {
+        (true, _) => (frame.data.len(), CANFD_FDF | (frame.brs as u8 * CANFD_BRS) | (frame.esi as u8 * CANFD_ESI), 64),
+        // remote frames have the requested length and no data
+        (false, true) => (frame.dlc as usize, 0, 8),
+        (false, false) => (frame.data.len(), 0, 8),
+    };
+    let mut buf = id.to_be_bytes().to_vec();
+    buf.extend_from_slice(&[len as u8, flags, 0, 0]);
+    let mut data = match frame.remote {
+        true => vec![],
+        false => frame.data.clone(),
+    };
+    data.resize(size, 0);
+    buf.extend_from_slice(&data);
+    buf
+}
+
+/// pcapng with LINKTYPE_CAN_SOCKETCAN for Wireshark, an interface for every
+/// CAN interface as it shows up and nanosecond timestamps.
+#[derive(Default)]
+pub struct Pcapng {
+    interfaces: Vec<String>,    // described so far in this section
+}
+
+impl Pcapng {
+    fn interface(&mut self, channel: &str, buf: &mut Vec<u8>) -> u32 {
+        if let Some(i) = self.interfaces.iter().position(|name| name == channel) {
+            return i as u32;
+        }
+        let mut body = LINKTYPE_CAN_SOCKETCAN.to_le_bytes().to_vec();
+        body.extend_from_slice(&[0; 2]);
+        body.extend_from_slice(&0u32.to_le_bytes());    // no snap length
+        body.extend(option(IF_NAME, channel.as_bytes()));
+        body.extend(option(IF_TSRESOL, &[9]));
+        body.extend(option(OPT_ENDOFOPT, &[]));
+        buf.extend(block(INTERFACE_DESCRIPTION, &body));
+        self.interfaces.push(channel.to_string());
+        self.interfaces.len() as u32 - 1
+    }
+}
+
+impl CaptureFormat for Pcapng {
+    fn header(&mut self, _start: DateTime<Utc>) -> Vec<u8> {
+        self.interfaces.clear();
+        let mut body = BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());   // section length not known
+        body.extend(option(SHB_USERAPPL, format!("iot-edge {}", env!("CARGO_PKG_VERSION")).as_bytes()));
+        body.extend(option(OPT_ENDOFOPT, &[]));
+        block(SECTION_HEADER, &body)
+    }
+
+    fn frame(&mut self, frame: &CaptureFrame) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let interface = self.interface(&frame.channel, &mut buf);
+        let time = frame.time.timestamp_nanos_opt().unwrap_or(0) as u64;
+        let packet = packet(frame);
+        let mut body = interface.to_le_bytes().to_vec();
+        body.extend_from_slice(&((time >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(time as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend(padded(&packet));
+        buf.extend(block(ENHANCED_PACKET, &body));
+        buf
+    }
+
+    /// Cut the file after its last whole block, there is nothing to finish.
+    fn recover(&mut self, path: &Path) -> io::Result<()> {
+        let file = fs::read(path)?;
+        if file.get(..4) != Some(&SECTION_HEADER.to_le_bytes()[..]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no pcapng section header"));
+        }
+        let u32_at = |at: usize| file.get(at..at + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize);
+        let mut pos = 0;
+        // the length is at both ends of a whole block
+        while let Some(len) = u32_at(pos + 4) {
+            match (len >= 12) & (len % 4 == 0) {
+                true if u32_at(pos + len - 4) == Some(len) => pos += len,
+                _ => break,
+            }
+        }
+        OpenOptions::new().write(true).open(path)?.set_len(pos as u64)
+    }
+}
+
+#[test]
+fn test_pcapng() {
+    let frame = CaptureFrame::sample(Utc.timestamp_nanos(1_469_439_874_299_591_123), "can0", 0x18FEF100, &[0xAB, 0xCD]);
+    let u32_at = |buf: &[u8], at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+
+    let mut pcapng = Pcapng::default();
+    let header = pcapng.header(frame.time);
+    assert_eq!((u32_at(&header, 0), u32_at(&header, 8)), (SECTION_HEADER, BYTE_ORDER_MAGIC));
+    assert_eq!(u32_at(&header, 4) as usize, header.len());
+    assert_eq!(header.len() % 4, 0);
+
+    // an interface description before the first frame of can0
+    let buf = pcapng.frame(&frame);
+    assert_eq!(u32_at(&buf, 0), INTERFACE_DESCRIPTION);
+    let idb = u32_at(&buf, 4) as usize;
+    assert_eq!(u16::from_le_bytes(buf[8..10].try_into().unwrap()), LINKTYPE_CAN_SOCKETCAN);
+    assert_eq!(&buf[16..24], &[2, 0, 4, 0, b'c', b'a', b'n', b'0']);
+    let epb = &buf[idb..];
+    assert_eq!((u32_at(epb, 0), u32_at(epb, 8)), (ENHANCED_PACKET, 0));
+    let time = ((u32_at(epb, 12) as u64) << 32) | u32_at(epb, 16) as u64;
+    assert_eq!(time, 1_469_439_874_299_591_123);
+    assert_eq!(u32_at(epb, 20), 16);
+    assert_eq!(&epb[28..36], &[0x98, 0xFE, 0xF1, 0x00, 2, 0, 0, 0]);
+    assert_eq!(&epb[36..38], &[0xAB, 0xCD]);
+
+    // can0 is known now, can1 is the second interface
+    assert_eq!(u32_at(&pcapng.frame(&frame), 0), ENHANCED_PACKET);
+    let buf = pcapng.frame(&CaptureFrame { channel: "can1".to_string(), ..frame.clone() });
+    let epb = &buf[u32_at(&buf, 4) as usize..];
+    assert_eq!(u32_at(epb, 8), 1);
+
+    let fd = CaptureFrame { fd: true, brs: true, extended: false, id: 0x123, dlc: 9, data: vec![1; 12], ..frame.clone() };
+    let fd = packet(&fd);
+    assert_eq!((fd.len(), &fd[..6]), (72, &[0, 0, 0x01, 0x23, 12, CANFD_FDF | CANFD_BRS][..]));
+    let remote = CaptureFrame { remote: true, extended: false, id: 0x7FF, dlc: 8, data: vec![], ..frame.clone() };
+    assert_eq!(&packet(&remote)[..5], &[0x40, 0, 0x07, 0xFF, 8]);
+    let error = CaptureFrame { error: true, extended: false, id: 0x004, ..frame.clone() };
+    assert_eq!(&packet(&error)[..4], &[0x20, 0, 0, 0x04]);
+}
+
+#[test]
+fn test_pcapng_recover() {
+    use crate::output::file::test_dir;
+
+    let frame = CaptureFrame::sample(Utc.timestamp_nanos(1_469_439_874_299_591_123), "can0", 0x123, &[1, 2]);
+    let mut pcapng = Pcapng::default();
+    let mut whole = pcapng.header(frame.time);
+    whole.extend(pcapng.frame(&frame));
+
+    // a crash tore the last packet
+    let dir = test_dir("pcapng");
+    let path = dir.join("capture.pcapng");
+    let torn = pcapng.frame(&frame);
+    std::fs::write(&path, [&whole[..], &torn[..torn.len() - 2]].concat()).unwrap();
+    pcapng.recover(&path).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), whole);
+
+    std::fs::write(&path, b"junk").unwrap();
+    assert!(pcapng.recover(&path).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
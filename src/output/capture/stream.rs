@@ -0,0 +1,151 @@
+use std::sync::Arc;
+use chrono::prelude::*;
+use log::{info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::{mpsc::{channel, error::TrySendError, Receiver, Sender}, Mutex};
+use tokio::time::{self, Duration};
+
+use crate::output::capture::{CaptureFormat, CaptureFrame};
+
+/// Records a client may fall behind by before it is dropped.
+const BACKLOG: usize = 4096;
+
+/// Longest wait between attempts to bind the port.
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// A client's own format state and the queue to its socket.
+type Client = (Box<dyn CaptureFormat>, Sender<Vec<u8>>);
+
+async fn send(mut stream: TcpStream, mut rx: Receiver<Vec<u8>>) {
+    while let Some(data) = rx.recv().await {
+        if stream.write_all(&data).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// A capture format live over TCP, every client gets its own stream from the
+/// moment it connects. Meant for a local port reached through an SSH tunnel,
+/// `ssh gateway nc 127.0.0.1 5555 | wireshark -k -i -` for pcapng.
+pub struct CaptureStream {
+    listen: String,
+    format: fn() -> Box<dyn CaptureFormat>,
+    rx: Arc<Mutex<Receiver<CaptureFrame>>>,
+}
+
+impl CaptureStream {
+    pub fn new(listen: &str, format: fn() -> Box<dyn CaptureFormat>, rx: Arc<Mutex<Receiver<CaptureFrame>>>) -> Self {
+        CaptureStream { listen: listen.to_string(), format, rx }
+    }
+
+    /// Frames are taken off the channel even while the port can't be bound,
+    /// so the CAN tasks never wait on the stream.
+    pub async fn run(self) {
+        let rx = self.rx.clone();
+        let mut rx = rx.lock().await;
+        let mut clients: Vec<Client> = Vec::new();
+        let mut interval = time::interval(Duration::from_secs(1));
+        let mut listener: Option<TcpListener> = None;
+        let (bind, mut retry) = (time::sleep(Duration::ZERO), Duration::from_secs(1));
+        tokio::pin!(bind);
+        loop {
+            select! {
+                frame = rx.recv() => match frame {
+                    Some(frame) => clients.retain_mut(|(format, tx)| forward(tx, format.frame(&frame))),
+                    None => break,
+                },
+                _ = &mut bind, if listener.is_none() => match TcpListener::bind(&self.listen).await {
+                    Ok(bound) => listener = Some(bound),
+                    Err(e) => {
+                        warn!("{}: {}, retrying in {}s", self.listen, e, retry.as_secs());
+                        bind.as_mut().reset(time::Instant::now() + retry);
+                        retry = (retry * 2).min(MAX_RETRY);
+                    }
+                },
+                client = async { listener.as_ref().unwrap().accept().await }, if listener.is_some() => match client {
+                    Ok((stream, addr)) => {
+                        info!("{}: streaming to {}", self.listen, addr);
+                        let mut format = (self.format)();
+                        let (tx, client_rx) = channel(BACKLOG);
+                        if forward(&tx, format.header(Utc::now())) {
+                            tokio::spawn(send(stream, client_rx));
+                            clients.push((format, tx));
+                        }
+                    },
+                    Err(e) => warn!("{}: {}", self.listen, e),
+                },
+                _ = interval.tick() => {
+                    clients.retain_mut(|(format, tx)| forward(tx, format.flush()));
+                },
+            }
+        }
+    }
+}
+
+/// Queue data for a client, false once it is gone or too slow to keep up.
+fn forward(tx: &Sender<Vec<u8>>, data: Vec<u8>) -> bool {
+    if data.is_empty() {
+        return !tx.is_closed();
+    }
+    match tx.try_send(data) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            warn!("capture stream client fell behind, dropped");
+            false
+        },
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+#[tokio::test]
+async fn test_stream() {
+    use tokio::io::AsyncReadExt;
+    use crate::output::capture::Candump;
+
+    let (tx, rx) = channel(16);
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let listen = format!("127.0.0.1:{}", port);
+    tokio::spawn(CaptureStream::new(&listen, || Box::new(Candump), Arc::new(Mutex::new(rx))).run());
+
+    let mut client = loop {
+        match TcpStream::connect(&listen).await {
+            Ok(client) => break client,
+            Err(_) => time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let frame = CaptureFrame::sample(Utc.timestamp_nanos(1_469_439_874_299_591_000), "can1", 0x701, &[0x7F]);
+    // frames before the client is taken are not sent to it, keep sending
+    tokio::spawn(async move {
+        while tx.send(frame.clone()).await.is_ok() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    });
+    let expected = b"(1469439874.299591) can1 701#7F\n";
+    let mut buf = vec![0; expected.len()];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, expected);
+}
+
+#[tokio::test]
+async fn test_bind_retry() {
+    // the port is taken, frames keep being taken off the channel meanwhile
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let listen = taken.local_addr().unwrap().to_string();
+    let (tx, rx) = channel(1);
+    tokio::spawn(CaptureStream::new(&listen, || Box::new(crate::output::capture::Candump), Arc::new(Mutex::new(rx))).run());
+
+    let frame = CaptureFrame::sample(Utc::now(), "can0", 0x123, &[]);
+    for _ in 0..3 {
+        time::timeout(Duration::from_secs(1), tx.send(frame.clone())).await.unwrap().unwrap();
+    }
+
+    // and the port is bound once it is free
+    drop(taken);
+    time::timeout(Duration::from_secs(5), async {
+        while TcpStream::connect(&listen).await.is_err() {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    }).await.unwrap();
+}
@@ -0,0 +1,190 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use flate2::{read::GzDecoder, write::GzEncoder};
+
+use crate::config::{ConfigMqtt, PayloadCompression};
+
+/// How a compression is named in topics and MQTT 5 user properties.
+pub fn content_encoding(compression: &PayloadCompression) -> Option<&'static str> {
+    match compression {
+        PayloadCompression::NONE => None,
+        PayloadCompression::GZIP => Some("gzip"),
+        PayloadCompression::LZ4 => Some("lz4"),
+        PayloadCompression::ZSTD => Some("zstd"),
+    }
+}
+
+/// Byte in front of a queued chunk naming its compression, a queue outlives
+/// a change of the setting.
+fn codec_id(compression: &PayloadCompression) -> u8 {
+    match compression {
+        PayloadCompression::NONE => 0,
+        PayloadCompression::GZIP => 1,
+        PayloadCompression::LZ4 => 2,
+        PayloadCompression::ZSTD => 3,
+    }
+}
+
+/// The compression of a queued chunk and its payload as the broker gets it.
+pub fn split_entry(entry: &[u8]) -> io::Result<(PayloadCompression, &[u8])> {
+    let compression = match entry.first() {
+        Some(0) => PayloadCompression::NONE,
+        Some(1) => PayloadCompression::GZIP,
+        Some(2) => PayloadCompression::LZ4,
+        Some(3) => PayloadCompression::ZSTD,
+        Some(id) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown codec {}", id))),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "empty queue entry")),
+    };
+    Ok((compression, &entry[1..]))
+}
+
+/// Compresses encoded chunks on their way to the queue and the broker, and
+/// undoes it for chunks that end up in the log after all. Queue entries start
+/// with the codec they were compressed with.
+pub struct PayloadCodec {
+    compression: PayloadCompression,
+    #[cfg_attr(not(feature = "zstd"), allow(dead_code))]
+    dictionary: Vec<u8>,    // zstd dictionary, empty without one
+}
+
+impl PayloadCodec {
+    pub fn new(config: &ConfigMqtt) -> io::Result<Self> {
+        let dictionary = match &config.dictionary {
+            Some(path) => fs::read(path)?,
+            None => Vec::new(),
+        };
+        Ok(PayloadCodec { compression: config.compression.clone(), dictionary })
+    }
+
+    /// The queue entry of an encoded chunk.
+    pub fn compress(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut entry = vec![codec_id(&self.compression)];
+        entry.extend(self.compress_with(data)?);
+        Ok(entry)
+    }
+
+    fn compress_with(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self.compression {
+            PayloadCompression::NONE => Ok(data),
+            PayloadCompression::GZIP => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()
+            },
+            #[cfg(feature = "lz4")]
+            PayloadCompression::LZ4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder.write_all(&data)?;
+                Ok(encoder.finish()?)
+            },
+            #[cfg(not(feature = "lz4"))]
+            PayloadCompression::LZ4 => Err(io::Error::new(io::ErrorKind::Unsupported, "built without lz4")),
+            #[cfg(feature = "zstd")]
+            PayloadCompression::ZSTD => {
+                let mut encoder = zstd::Encoder::with_dictionary(Vec::new(), 0, &self.dictionary)?;
+                encoder.write_all(&data)?;
+                encoder.finish()
+            },
+            #[cfg(not(feature = "zstd"))]
+            PayloadCompression::ZSTD => Err(io::Error::new(io::ErrorKind::Unsupported, "built without zstd")),
+        }
+    }
+
+    /// The encoded chunk of a queue entry, by the codec the entry names.
+    pub fn decompress(&self, entry: &[u8]) -> io::Result<Vec<u8>> {
+        let (compression, data) = split_entry(entry)?;
+        let mut buf = Vec::new();
+        match compression {
+            PayloadCompression::NONE => return Ok(data.to_vec()),
+            PayloadCompression::GZIP => GzDecoder::new(data).read_to_end(&mut buf)?,
+            #[cfg(feature = "lz4")]
+            PayloadCompression::LZ4 => lz4_flex::frame::FrameDecoder::new(data).read_to_end(&mut buf)?,
+            #[cfg(not(feature = "lz4"))]
+            PayloadCompression::LZ4 => return Err(io::Error::new(io::ErrorKind::Unsupported, "built without lz4")),
+            #[cfg(feature = "zstd")]
+            PayloadCompression::ZSTD => zstd::Decoder::with_dictionary(data, &self.dictionary)?.read_to_end(&mut buf)?,
+            #[cfg(not(feature = "zstd"))]
+            PayloadCompression::ZSTD => return Err(io::Error::new(io::ErrorKind::Unsupported, "built without zstd")),
+        };
+        Ok(buf)
+    }
+}
+
+/// A zstd dictionary of at most `size` bytes trained on the chunks of a log,
+/// encoded as the log has them, so a log of the encoder the broker gets.
+/// Binary chunks are written again in the chunk `layout` published.
+#[cfg(feature = "zstd")]
+pub fn train(log: &std::path::Path, layout: u32, size: usize) -> io::Result<Vec<u8>> {
+    use std::io::BufRead;
+    use crate::output::binlog::{is_binary, ChunkReader};
+    use crate::output::file::open_log;
+
+    let mut reader = open_log(log)?;
+    let samples: Vec<Vec<u8>> = match is_binary(&mut reader)? {
+        true => ChunkReader::new(reader)?.map(|chunk| chunk.map(|chunk| chunk.to_vec(layout))).collect::<io::Result<_>>()?,
+        false => reader.split(b'\n').collect::<io::Result<_>>()?,
+    };
+    zstd::dict::from_samples(&samples, size)
+}
+
+#[test]
+fn test_codec() {
+    let chunk = br#"{"time":"2026-10-19T08:00:00Z","id":"truck-7","can":[{"data":"(1655098589.035226) can1 202#A1000000000000A1"}]}"#;
+    let mut config = ConfigMqtt { compression: PayloadCompression::GZIP, ..Default::default() };
+    let codec = PayloadCodec::new(&config).unwrap();
+    let data = codec.compress(chunk.to_vec()).unwrap();
+    assert_eq!(&data[..3], &[1, 0x1F, 0x8B]);
+    assert_eq!(codec.decompress(&data).unwrap(), chunk);
+
+    // entries queued before the setting changed keep their codec
+    config.compression = PayloadCompression::NONE;
+    let plain = PayloadCodec::new(&config).unwrap();
+    assert_eq!(plain.decompress(&data).unwrap(), chunk);
+    let entry = plain.compress(chunk.to_vec()).unwrap();
+    assert_eq!((entry[0], &entry[1..]), (0, &chunk[..]));
+    assert_eq!(codec.decompress(&entry).unwrap(), chunk);
+    assert_eq!(split_entry(&data).unwrap().0, PayloadCompression::GZIP);
+    assert!(split_entry(&[9, 1, 2]).is_err());
+    assert_eq!(content_encoding(&config.compression), None);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_dictionary() {
+    use crate::config::Encoder;
+    use crate::output::file::{test_dir, FileLogger};
+
+    let dir = test_dir("dictionary");
+    let log = crate::config::ConfigLog {
+        path: dir.join("iot-edge.log").to_string_lossy().to_string(),
+        encoder: Encoder::JSON,
+        ..Default::default()
+    };
+    let chunk = |n: u32| format!(
+        r#"{{"time":"2026-10-19T08:00:{:02}Z","id":"truck-7","can":[{{"data":"(16550985{:02}.035226) can1 202#A1{:08X}A1"}}],"gps":[]}}"#,
+        n % 60, n % 100, n * 7919);
+    let mut logger = FileLogger::new(&log, "truck-7");
+    for n in 0..500 {
+        logger.write(&chunk(n));
+    }
+    drop(logger);
+
+    let dictionary = train(&dir.join("iot-edge.log"), 1, 4096).unwrap();
+    fs::write(dir.join("chunks.dict"), &dictionary).unwrap();
+    let config = ConfigMqtt {
+        compression: PayloadCompression::ZSTD,
+        dictionary: Some(dir.join("chunks.dict").to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    let codec = PayloadCodec::new(&config).unwrap();
+    let plain = PayloadCodec::new(&ConfigMqtt { dictionary: None, ..config.clone() }).unwrap();
+
+    let data = chunk(1000).into_bytes();
+    let compressed = codec.compress(data.clone()).unwrap();
+    assert!(compressed.len() < plain.compress(data.clone()).unwrap().len());
+    assert_eq!(codec.decompress(&compressed).unwrap(), data);
+    // the frame names its dictionary, it can't be read without
+    assert!(plain.decompress(&compressed).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,57 +1,795 @@
-use std::io::Write;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str::FromStr;
-use file_rotate::{
-    FileRotate, 
-    ContentLimit, 
-    suffix::AppendCount,
-    compression::Compression,
-};
-use bytesize;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use log::*;
+use chrono::prelude::*;
+use chrono::Duration;
+use flate2::{read::GzDecoder, write::GzEncoder};
 
-use crate::config::ConfigLog;
+use crate::config::{ConfigLog, Encoder, LogCompression, LogRotation};
+use crate::output::binlog::{self, MAGIC};
+use crate::utils::rotated;
 
-pub struct FileLogger(FileRotate<AppendCount>);
+/// Closers of every logger take turns, a reopened logger may still have an old one finishing.
+static CLOSING: Mutex<()> = Mutex::new(());
 
-impl From<&ConfigLog> for FileLogger {
-    fn from(config: &ConfigLog) -> Self {
-        let keep = config.rotate_keep;
-        let size = bytesize::ByteSize::from_str(&config.rotate_size).unwrap().as_u64();
+/// A closed log file, the active one is always at the configured path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClosedLog {
+    pub path: PathBuf,
+    pub compression: String,    // "", ".gz" or ".zst"
+    pub period: Option<(DateTime<Utc>, DateTime<Utc>)>,     // covered time, for time rotation
+}
+
+impl ClosedLog {
+    /// Whether the file can hold anything between `start` and `end`.
+    pub fn overlaps(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        match self.period {
+            Some((from, to)) => (from < end) & (to > start),
+            None => true,
+        }
+    }
+}
+
+fn compression_ext(compression: &LogCompression) -> &'static str {
+    match compression {
+        LogCompression::GZIP => ".gz",
+        LogCompression::ZSTD => ".zst",
+    }
+}
+
+/// Period a time rotated file covers, from the date part of its name.
+fn parse_period(name: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    match name.len() {
+        13 => {
+            let start = NaiveDateTime::parse_from_str(&format!("{}:00", name), "%Y-%m-%dT%H:%M").ok()?;
+            Some((Utc.from_utc_datetime(&start), Utc.from_utc_datetime(&start) + Duration::hours(1)))
+        },
+        10 => {
+            let start = NaiveDate::parse_from_str(name, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0)?;
+            Some((Utc.from_utc_datetime(&start), Utc.from_utc_datetime(&start) + Duration::days(1)))
+        },
+        _ => None,
+    }
+}
+
+/// `<stem>-` and `.<ext>` around the period of time rotated files.
+fn stem_ext(log: &Path) -> (String, String) {
+    let stem = log.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let ext = match log.extension() {
+        Some(ext) => format!(".{}", ext.to_string_lossy()),
+        None => String::new(),
+    };
+    (format!("{}-", stem), ext)
+}
+
+fn log_dir(log: &Path) -> PathBuf {
+    match log.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Time rotated name `name` split into its period, sequence within the
+/// period and compression.
+fn timed<'a>(stem: &str, ext: &str, name: &'a str) -> Option<(&'a str, u32, &'a str)> {
+    let rest = name.strip_prefix(stem)?;
+    let (rest, compression) = match rest.rsplit_once('.') {
+        Some((rest, "gz")) => (rest, ".gz"),
+        Some((rest, "zst")) => (rest, ".zst"),
+        _ => (rest, ""),
+    };
+    let rest = rest.strip_suffix(ext)?;
+    let (period, seq) = match rest.get(..13) {
+        Some(hour) if hour.as_bytes()[10] == b'T' => (hour, &rest[13..]),
+        _ => (rest.get(..10)?, &rest[10..]),
+    };
+    let seq = match seq {
+        "" => 0,
+        seq => seq.strip_prefix('-')?.parse().ok()?,
+    };
+    parse_period(period)?;
+    Some((period, seq, compression))
+}
+
+/// Closed files of the log at `log`, oldest first, whichever rotation wrote them.
+pub fn closed_logs(log: &Path) -> io::Result<Vec<ClosedLog>> {
+    let base = log.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let (stem, ext) = stem_ext(log);
+
+    let mut counted = vec![];
+    let mut timed_logs = vec![];
+    for entry in fs::read_dir(log_dir(log))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some((number, compression)) = rotated(&base, &name) {
+            let closed = ClosedLog { path: entry.path(), compression: compression.to_string(), period: None };
+            counted.push((number, closed));
+        } else if let Some((period, seq, compression)) = timed(&stem, &ext, &name) {
+            let closed = ClosedLog {
+                path: entry.path(),
+                compression: compression.to_string(),
+                period: parse_period(period),
+            };
+            timed_logs.push(((period.to_string(), seq), closed));
+        }
+    }
+    counted.sort_by_key(|(number, _)| std::cmp::Reverse(*number));
+    timed_logs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(counted.into_iter().map(|(_, closed)| closed)
+        .chain(timed_logs.into_iter().map(|(_, closed)| closed))
+        .collect())
+}
+
+/// Delete the closed files of the log at `log` that `pins` hard link to. A
+/// rotation may have renamed them since they were pinned, so they are found by
+/// inode. Returns how many were deleted.
+pub fn remove_pinned(log: &Path, pins: &[PathBuf]) -> io::Result<usize> {
+    let inodes: Vec<u64> = pins.iter().filter_map(|pin| fs::metadata(pin).ok()).map(|meta| meta.ino()).collect();
+    let mut removed = 0;
+    for closed in closed_logs(log)? {
+        if fs::metadata(&closed.path).is_ok_and(|meta| inodes.contains(&meta.ino())) {
+            fs::remove_file(&closed.path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Read a closed log file, compressed or not.
+pub fn open_log(path: &Path) -> io::Result<Box<dyn BufRead + Send>> {
+    let file = File::open(path)?;
+    let name = path.to_string_lossy();
+    if name.ends_with(".gz") {
+        return Ok(Box::new(BufReader::new(GzDecoder::new(file))));
+    }
+    if name.ends_with(".zst") {
+        #[cfg(feature = "zstd")]
+        {
+            return Ok(Box::new(BufReader::new(zstd::Decoder::new(file)?)));
+        }
+        #[cfg(not(feature = "zstd"))]
+        {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "built without zstd"));
+        }
+    }
+    Ok(Box::new(BufReader::new(file)))
+}
+
+fn compress(from: &Path, to: &Path, compression: &LogCompression) -> io::Result<()> {
+    let mut reader = File::open(from)?;
+    let writer = BufWriter::new(File::create(to)?);
+    let file = match compression {
+        LogCompression::GZIP => {
+            let mut encoder = GzEncoder::new(writer, flate2::Compression::default());
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?
+        },
+        #[cfg(feature = "zstd")]
+        LogCompression::ZSTD => {
+            let mut encoder = zstd::Encoder::new(writer, 0)?;
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?
+        },
+        #[cfg(not(feature = "zstd"))]
+        LogCompression::ZSTD => return Err(io::Error::new(io::ErrorKind::Unsupported, "built without zstd")),
+    };
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// Length of a line log up to the end of its last line.
+fn line_end(mut file: File) -> io::Result<u64> {
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut buf = vec![0; 64 * 1024];
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let block = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(block)?;
+        if let Some(pos) = block.iter().rposition(|b| *b == b'\n') {
+            return Ok(start + pos as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
 
-        FileLogger(FileRotate::new(
-            config.path.clone(), 
-            AppendCount::new(keep), 
-            ContentLimit::Bytes(size as usize),
-            Compression::None
-        ))
+/// Start of the period `time` falls in.
+fn period_start(rotation: &LogRotation, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let date = time.date_naive();
+    let start = match rotation {
+        LogRotation::SIZE => return None,
+        LogRotation::HOURLY => date.and_hms_opt(time.hour(), 0, 0)?,
+        LogRotation::DAILY => date.and_hms_opt(0, 0, 0)?,
+    };
+    Some(Utc.from_utc_datetime(&start))
+}
+
+/// Gives closed files their final name, compressed if asked, and drops the
+/// oldest beyond `keep`. Runs on its own thread so writes don't wait for it.
+struct Closer {
+    log: PathBuf,
+    rotation: LogRotation,
+    keep: usize,
+    compression: Option<LogCompression>,
+}
+
+impl Closer {
+    /// Staged files are named `.<base>.<YYYYmmddTHHMMSS>.<nanos>.closing` after
+    /// their period. A closer claims one by renaming it to `.<pid>.claimed`
+    /// and compresses it to `.<pid>.tmp`, so no other closer picks it up.
+    /// Returns what closers of an earlier run left, their stale copies are removed.
+    fn leftover(&self) -> io::Result<Vec<PathBuf>> {
+        let prefix = format!(".{}.", self.log.file_name().unwrap_or_default().to_string_lossy());
+        let pid = process::id().to_string();
+        let mut staged = vec![];
+        for entry in fs::read_dir(log_dir(&self.log))? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if !name.starts_with(&prefix) {
+                continue;
+            }
+            // claims of this process belong to a closer that is still at it
+            let mut parts = name.rsplit('.');
+            match (parts.next(), parts.next()) {
+                (Some("closing"), _) => staged.push(path),
+                (Some("claimed"), Some(owner)) if owner != pid => staged.push(path),
+                (Some("tmp"), Some(owner)) if owner != pid => fs::remove_file(&path)?,
+                _ => {},
+            }
+        }
+        staged.sort();
+        Ok(staged)
     }
+
+    /// Final name of the staged file `.<base>.<period>.<nanos>` in `key`.
+    fn target(&self, key: &str, compression: &str) -> io::Result<PathBuf> {
+        let dir = log_dir(&self.log);
+        let period = key.rsplit('.').nth(1)
+            .and_then(|time| NaiveDateTime::parse_from_str(time, "%Y%m%dT%H%M%S").ok());
+        let format = match self.rotation {
+            LogRotation::HOURLY => "%Y-%m-%dT%H",
+            LogRotation::DAILY => "%Y-%m-%d",
+            LogRotation::SIZE => {
+                // the closed files move up one to make room for .1
+                let base = self.log.file_name().unwrap_or_default().to_string_lossy().to_string();
+                for closed in closed_logs(&self.log)?.iter().filter(|closed| closed.period.is_none()) {
+                    let name = closed.path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                    if let Some((number, ext)) = rotated(&base, &name) {
+                        fs::rename(&closed.path, dir.join(format!("{}.{}{}", base, number + 1, ext)))?;
+                    }
+                }
+                return Ok(dir.join(format!("{}.1{}", base, compression)));
+            },
+        };
+
+        let (stem, ext) = stem_ext(&self.log);
+        let period = period.unwrap_or_else(|| Utc::now().naive_utc()).format(format).to_string();
+        let mut seq = 0;
+        loop {
+            let name = match seq {
+                0 => format!("{}{}{}{}", stem, period, ext, compression),
+                seq => format!("{}{}-{}{}{}", stem, period, seq, ext, compression),
+            };
+            if !dir.join(&name).exists() {
+                return Ok(dir.join(name));
+            }
+            seq += 1;
+        }
+    }
+
+    fn close(&self, staged: &Path) -> io::Result<()> {
+        let _closing = CLOSING.lock().unwrap_or_else(|e| e.into_inner());
+
+        let name = staged.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let key = match name.strip_suffix(".closing") {
+            Some(key) => key,
+            None => match name.strip_suffix(".claimed").and_then(|rest| rest.rsplit_once('.')) {
+                Some((key, _)) => key,
+                None => return Ok(()),
+            },
+        };
+        let dir = log_dir(&self.log);
+        let claimed = dir.join(format!("{}.{}.claimed", key, process::id()));
+        match fs::rename(staged, &claimed) {
+            Ok(()) => {},
+            // another closer got there first
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        // compress under a name no reader picks up, then move it in place
+        let (source, compression) = match &self.compression {
+            Some(compression) => {
+                let tmp = dir.join(format!("{}.{}.tmp", key, process::id()));
+                compress(&claimed, &tmp, compression)?;
+                (tmp, compression_ext(compression))
+            },
+            None => (claimed.clone(), ""),
+        };
+        let target = self.target(key, compression)?;
+        fs::rename(&source, &target)?;
+        if source != claimed {
+            fs::remove_file(&claimed)?;
+        }
+
+        let closed = closed_logs(&self.log)?;
+        for old in closed.iter().take(closed.len().saturating_sub(self.keep)) {
+            fs::remove_file(&old.path)?;
+        }
+        Ok(())
+    }
+
+    fn run(self, rx: mpsc::Receiver<PathBuf>) {
+        // files a crash left half closed come first
+        let leftover = self.leftover().unwrap_or_else(|e| {
+            error!("{}: {}", self.log.display(), e);
+            Vec::new()
+        });
+        for staged in leftover.into_iter().chain(rx.iter()) {
+            if let Err(e) = self.close(&staged) {
+                error!("closing {}: {}", staged.display(), e);
+            }
+        }
+    }
+}
+
+/// Log of the chunks as JSON lines or framed capnp, rotated by size and
+/// optionally by the hour or day.
+pub struct FileLogger {
+    path: PathBuf,
+    limit: u64,
+    rotation: LogRotation,
+    header: Option<Vec<u8>>,            // binary files start with it
+    file: Option<File>,
+    size: u64,
+    period: Option<DateTime<Utc>>,      // of the active file
+    closer: mpsc::Sender<PathBuf>,
+    dropped: u64,                       // writes lost while the file could not be opened
 }
 
 impl FileLogger {
+    pub fn new(config: &ConfigLog, device_id: &str) -> Self {
+        let header = match config.encoder {
+            Encoder::BINARY => Some(binlog::header(device_id)),
+            Encoder::JSON => None,
+        };
+        let mut logger = FileLogger::create(config, header);
+        logger.open();
+        logger
+    }
+
+    /// A logger for formats that look after their own files, one a previous
+    /// run left unfinished goes to `recover` and is closed as it comes out.
+    pub fn raw(config: &ConfigLog, recover: impl FnOnce(&Path) -> io::Result<()>) -> Self {
+        let mut logger = FileLogger::create(config, None);
+        match fs::metadata(&logger.path) {
+            Ok(meta) if meta.len() > 0 => {
+                if let Err(e) = recover(&logger.path) {
+                    error!("{}: {}", logger.path.display(), e);
+                }
+                let written = meta.modified().map_or_else(|_| Utc::now(), DateTime::<Utc>::from);
+                logger.period = period_start(&logger.rotation, written);
+                logger.rotate();
+            },
+            _ => logger.open(),
+        }
+        logger
+    }
+
+    fn create(config: &ConfigLog, header: Option<Vec<u8>>) -> Self {
+        let limit = bytesize::ByteSize::from_str(&config.rotate_size).unwrap().as_u64();
+        let path = PathBuf::from(&config.path);
+        if let Err(e) = fs::create_dir_all(log_dir(&path)) {
+            error!("{}: {}", path.display(), e);
+        }
+
+        let closer = Closer {
+            log: path.clone(),
+            rotation: config.rotate.clone(),
+            keep: config.rotate_keep,
+            compression: match config.rotate_compress {
+                true => Some(config.compression.clone()),
+                false => None,
+            },
+        };
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || closer.run(rx));
+
+        FileLogger {
+            path,
+            limit,
+            rotation: config.rotate.clone(),
+            header,
+            file: None,
+            size: 0,
+            period: None,
+            closer: tx,
+            dropped: 0,
+        }
+    }
+
+    /// Whether the file at the path starts the way this logger writes it,
+    /// a binary one with the whole header, so the same schema version and device.
+    fn same_encoding(&self) -> bool {
+        let mut head = vec![0; self.header.as_ref().map_or(MAGIC.len(), Vec::len)];
+        let read = File::open(&self.path).and_then(|mut file| file.read_exact(&mut head)).is_ok();
+        match &self.header {
+            Some(header) => read & (&head == header),
+            None => !(read & (head == MAGIC)),
+        }
+    }
+
+    fn open(&mut self) {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path);
+        match file {
+            Ok(file) => {
+                let meta = file.metadata().ok();
+                self.size = meta.as_ref().map_or(0, |meta| meta.len());
+                // an existing file belongs to the period it was last written in
+                let written = meta.and_then(|meta| meta.modified().ok())
+                    .filter(|_| self.size > 0)
+                    .map(DateTime::<Utc>::from)
+                    .unwrap_or_else(Utc::now);
+                self.period = period_start(&self.rotation, written);
+                self.file = Some(file);
+                if self.dropped > 0 {
+                    warn!("{}: open again, {} writes dropped", self.path.display(), self.dropped);
+                    self.dropped = 0;
+                }
+                // written before the encoding changed, it is closed as it is
+                if (self.size > 0) & !self.same_encoding() {
+                    self.rotate();
+                } else if let Err(e) = self.drop_torn() {
+                    error!("{}: {}", self.path.display(), e);
+                }
+            },
+            // once, it is tried again on every write
+            Err(e) if self.dropped == 0 => error!("{}: {}", self.path.display(), e),
+            Err(_) => {},
+        }
+    }
+
+    /// Cut the record a power loss left torn at the end of the file,
+    /// appending after it would make the next one unreadable too.
+    fn drop_torn(&mut self) -> io::Result<()> {
+        let whole = match &self.header {
+            Some(_) => binlog::whole_len(File::open(&self.path)?)?,
+            None => line_end(File::open(&self.path)?)?,
+        };
+        if whole < self.size {
+            warn!("{}: dropping a torn record of {} bytes", self.path.display(), self.size - whole);
+            if let Some(file) = &self.file {
+                file.set_len(whole)?;
+            }
+            self.size = whole;
+        }
+        Ok(())
+    }
+
+    /// Hand the active file to the closer and start a new one.
+    fn rotate(&mut self) {
+        self.file = None;
+        self.size = 0;
+        let period = self.period.unwrap_or_else(Utc::now);
+        let staged = log_dir(&self.path).join(format!(
+            ".{}.{}.{:020}.closing",
+            self.path.file_name().unwrap_or_default().to_string_lossy(),
+            period.format("%Y%m%dT%H%M%S"),
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()));
+        match fs::rename(&self.path, &staged) {
+            Ok(()) => {
+                let _ = self.closer.send(staged);
+            },
+            Err(e) => error!("{}: {}", self.path.display(), e),
+        }
+        self.open();
+    }
+
+    /// Append a JSON line.
     pub fn write(&mut self, line: &str) {
-        writeln!(self.0, "{}", line).unwrap();
+        self.append(&[line.as_bytes(), b"\n"]);
+    }
+
+    /// Append a packed capnp chunk, after its length.
+    pub fn write_chunk(&mut self, data: &[u8]) {
+        self.append(&[&binlog::frame_len(data), data]);
     }
+
+    /// Whether `len` more bytes belong in a new file, by size or period.
+    pub fn full(&self, len: u64) -> bool {
+        let period = period_start(&self.rotation, Utc::now());
+        (self.size > 0) & ((self.size + len > self.limit) | (period != self.period))
+    }
+
+    /// Bytes in the active file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Close the active file after `finish` had its way with it, for formats
+    /// with a header that is only known at the end.
+    pub fn close(&mut self, finish: impl FnOnce(&Path) -> io::Result<()>) {
+        if self.size > 0 {
+            if let Err(e) = finish(&self.path) {
+                error!("{}: {}", self.path.display(), e);
+            }
+            self.rotate();
+        }
+    }
+
+    /// Write one record, never split across files.
+    fn append(&mut self, parts: &[&[u8]]) {
+        if self.file.is_none() {
+            self.open();
+        }
+        let len: u64 = parts.iter().map(|part| part.len() as u64).sum();
+        if self.full(len) {
+            self.rotate();
+        }
+        if let (0, Some(header)) = (self.size, self.header.clone()) {
+            self.write_raw(&header);
+        }
+        // one write, a crash leaves whole records or a torn last one
+        self.write_raw(&parts.concat());
+    }
+
+    /// Append as is, rotation is up to the caller.
+    pub fn write_raw(&mut self, data: &[u8]) {
+        // the open failed, the disk may be back by now
+        if self.file.is_none() {
+            self.open();
+        }
+        if self.size == 0 {
+            self.period = period_start(&self.rotation, Utc::now());
+        }
+        match self.file.as_mut() {
+            Some(file) => match file.write_all(data) {
+                Ok(()) => self.size += data.len() as u64,
+                Err(e) => error!("{}: {}", self.path.display(), e),
+            },
+            None => self.dropped += 1,
+        }
+    }
+}
+
+/// An empty directory for a test's files.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("iot-edge-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+fn wait_closed(log: &Path, count: usize) -> Vec<ClosedLog> {
+    for _ in 0..100 {
+        let closed = closed_logs(log).unwrap();
+        if closed.len() == count {
+            return closed;
+        }
+        thread::sleep(std::time::Duration::from_millis(20));
+    }
+    closed_logs(log).unwrap()
 }
 
 #[test]
 fn test() {
-    let mut log = FileRotate::new(
-        "logs/test.log", 
-        AppendCount::new(2), 
-        ContentLimit::Lines(3),
-        Compression::None
-    );
+    let dir = test_dir("log");
+    let path = dir.join("test.log");
+    let config = ConfigLog {
+        path: path.to_string_lossy().to_string(),
+        rotate_size: "30B".to_string(),
+        rotate_keep: 2,
+        ..Default::default()
+    };
+    let mut log = FileLogger::new(&config, "test");
 
     // Write a bunch of lines
-    writeln!(log, "Line 1: Hello World!").unwrap();
+    log.write("Line 1: Hello World!");
     for idx in 2..=10 {
-        writeln!(log, "Line {}", idx).unwrap();
+        log.write(&format!("Line {}", idx));
     }
+
+    let closed = wait_closed(&path, 2);
+    assert_eq!(closed.len(), 2);
+    assert_eq!(closed[0].path, dir.join("test.log.2"));
+    assert_eq!(fs::read_to_string(&closed[0].path).unwrap(), "Line 1: Hello World!\nLine 2\n");
+    assert_eq!(fs::read_to_string(&closed[1].path).unwrap(), "Line 3\nLine 4\nLine 5\nLine 6\n");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "Line 7\nLine 8\nLine 9\nLine 10\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_compress() {
+    let dir = test_dir("log-gz");
+    let path = dir.join("test.log");
+    let config = ConfigLog {
+        path: path.to_string_lossy().to_string(),
+        rotate_size: "10B".to_string(),
+        rotate_compress: true,
+        ..Default::default()
+    };
+    let mut log = FileLogger::new(&config, "test");
+    log.write("first line");
+    log.write("second line");
+
+    let closed = wait_closed(&path, 1);
+    assert_eq!(closed[0].path, dir.join("test.log.1.gz"));
+    let lines: Vec<String> = open_log(&closed[0].path).unwrap().lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec!["first line"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_torn() {
+    use crate::message::{Chunk, SCHEMA_VERSION};
+    use crate::output::binlog::ChunkReader;
+
+    let dir = test_dir("log-torn");
+    let path = dir.join("test.log");
+    let mut config = ConfigLog { path: path.to_string_lossy().to_string(), ..Default::default() };
+    let mut log = FileLogger::new(&config, "dev-1");
+    log.write("{\"n\":1}");
+    drop(log);
+    fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"n\":").unwrap();
+    let mut log = FileLogger::new(&config, "dev-1");
+    log.write("{\"n\":2}");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "{\"n\":1}\n{\"n\":2}\n");
+    drop(log);
+    fs::remove_file(&path).unwrap();
+
+    config.encoder = Encoder::BINARY;
+    let mut log = FileLogger::new(&config, "dev-1");
+    log.write_chunk(&Chunk::new("dev-1").to_vec(SCHEMA_VERSION));
+    drop(log);
+    fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&[100, 0, 0, 0, 1, 2]).unwrap();
+    let mut log = FileLogger::new(&config, "dev-1");
+    log.write_chunk(&Chunk::new("dev-1").to_vec(SCHEMA_VERSION));
+    let reader = ChunkReader::new(File::open(&path).unwrap()).unwrap();
+    assert_eq!(reader.collect::<io::Result<Vec<_>>>().unwrap().len(), 2);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_leftover() {
+    let dir = test_dir("log-leftover");
+    let path = dir.join("test.log");
+    // an earlier run died while compressing, a closer of this one is busy
+    fs::write(dir.join(".test.log.20261018T110000.00000000000000000001.1.claimed"), "old\n").unwrap();
+    fs::write(dir.join(".test.log.20261018T110000.00000000000000000001.1.tmp"), "").unwrap();
+    let busy = dir.join(format!(".test.log.20261018T120000.00000000000000000002.{}.claimed", process::id()));
+    fs::write(&busy, "busy\n").unwrap();
+
+    let config = ConfigLog {
+        path: path.to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let _log = FileLogger::new(&config, "test");
+
+    let closed = wait_closed(&path, 1);
+    assert_eq!(closed.len(), 1);
+    assert_eq!(closed[0].path, dir.join("test.log.1"));
+    assert_eq!(fs::read_to_string(&closed[0].path).unwrap(), "old\n");
+    assert!(!dir.join(".test.log.20261018T110000.00000000000000000001.1.tmp").exists());
+    assert!(busy.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_raw() {
+    let dir = test_dir("log-raw");
+    let path = dir.join("test.blf");
+    // binary and unfinished, no torn line to cut
+    fs::write(&path, b"LOGG\n\x01\x02").unwrap();
+
+    let config = ConfigLog {
+        path: path.to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let log = FileLogger::raw(&config, |path| {
+        OpenOptions::new().append(true).open(path)?.write_all(b"end")
+    });
+    assert_eq!(log.size(), 0);
+
+    let closed = wait_closed(&path, 1);
+    assert_eq!(fs::read(&closed[0].path).unwrap(), b"LOGG\n\x01\x02end");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_binary() {
+    use crate::message::{Chunk, SCHEMA_VERSION};
+    use crate::output::binlog::ChunkReader;
+
+    let dir = test_dir("log-bin");
+    let path = dir.join("test.log");
+    let mut config = ConfigLog { path: path.to_string_lossy().to_string(), ..Default::default() };
+    config.encoder = Encoder::BINARY;
+    let mut log = FileLogger::new(&config, "dev-1");
+    log.write_chunk(&Chunk::new("dev-1").to_vec(SCHEMA_VERSION));
+    log.write_chunk(&Chunk::new("dev-1").to_vec(SCHEMA_VERSION));
+
+    let reader = ChunkReader::new(File::open(&path).unwrap()).unwrap();
+    assert_eq!(reader.device_id, "dev-1");
+    assert_eq!(reader.count(), 2);
+
+    // switching back to JSON closes the binary file first
+    config.encoder = Encoder::JSON;
+    let mut log = FileLogger::new(&config, "dev-1");
+    log.write("{}");
+    let closed = wait_closed(&path, 1);
+    assert!(ChunkReader::new(File::open(&closed[0].path).unwrap()).is_ok());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "{}\n");
+    drop(log);
+
+    // a file of another device or schema version is closed as it is
+    config.encoder = Encoder::BINARY;
+    let mut log = FileLogger::new(&config, "dev-1");
+    log.write_chunk(&Chunk::new("dev-1").to_vec(SCHEMA_VERSION));
+    drop(log);
+    let mut log = FileLogger::new(&config, "dev-2");
+    log.write_chunk(&Chunk::new("dev-2").to_vec(SCHEMA_VERSION));
+    drop(log);
+    assert_eq!(wait_closed(&path, 3).len(), 3);
+    assert_eq!(ChunkReader::new(File::open(&path).unwrap()).unwrap().device_id, "dev-2");
+
+    let mut old = binlog::header("dev-2");
+    old[8..12].copy_from_slice(&1u32.to_le_bytes());
+    fs::write(&path, &old).unwrap();
+    let mut log = FileLogger::new(&config, "dev-2");
+    log.write_chunk(&Chunk::new("dev-2").to_vec(SCHEMA_VERSION));
+    assert_eq!(wait_closed(&path, 4).len(), 4);
+    assert_eq!(ChunkReader::new(File::open(&path).unwrap()).unwrap().version, SCHEMA_VERSION);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_closed_logs() {
+    let dir = test_dir("log-names");
+    for name in [
+        "iot-edge.log", "iot-edge.log.2.gz", "iot-edge.log.1",
+        "iot-edge-2026-10-18T10-1.log", "iot-edge-2026-10-18T10.log.zst", "iot-edge-2026-10-17.log",
+        ".iot-edge.log.20261018T110000.00000000000000000001.closing", "iot-edge-notes.log",
+    ] {
+        fs::write(dir.join(name), "").unwrap();
+    }
+
+    let closed = closed_logs(&dir.join("iot-edge.log")).unwrap();
+    let names: Vec<String> = closed.iter()
+        .map(|closed| closed.path.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(names, vec![
+        "iot-edge.log.2.gz", "iot-edge.log.1",
+        "iot-edge-2026-10-17.log", "iot-edge-2026-10-18T10.log.zst", "iot-edge-2026-10-18T10-1.log",
+    ]);
+    assert_eq!(closed[3].compression, ".zst");
+
+    let start = Utc.with_ymd_and_hms(2026, 10, 18, 10, 0, 0).unwrap();
+    assert_eq!(closed[3].period, Some((start, start + Duration::hours(1))));
+    assert!(closed[3].overlaps(start + Duration::minutes(30), start + Duration::hours(5)));
+    assert!(!closed[2].overlaps(start, start + Duration::hours(1)));
+
+    fs::remove_dir_all(&dir).unwrap();
 }
 
 #[test]
 fn test_size() {
     let expected = bytesize::ByteSize::gb(20);
-    
+
     let size = bytesize::ByteSize::from_str("20 G").unwrap();
     assert_eq!(size, expected);
 
@@ -71,3 +809,43 @@ fn test_size() {
     assert_eq!(size, expected);
 }
 
+#[test]
+fn test_remove_pinned() {
+    let dir = test_dir("pinned");
+    let log = dir.join("iot-edge.log");
+    fs::write(dir.join("iot-edge.log.2"), "older\n").unwrap();
+    fs::write(dir.join("iot-edge.log.1"), "newer\n").unwrap();
+    fs::create_dir(dir.join("pins")).unwrap();
+    fs::hard_link(dir.join("iot-edge.log.1"), dir.join("pins").join("iot-edge.log.1")).unwrap();
+
+    // rotated while pinned, the pin follows the file to its new name
+    fs::rename(dir.join("iot-edge.log.2"), dir.join("iot-edge.log.3")).unwrap();
+    fs::rename(dir.join("iot-edge.log.1"), dir.join("iot-edge.log.2")).unwrap();
+    fs::write(dir.join("iot-edge.log.1"), "newest\n").unwrap();
+
+    assert_eq!(remove_pinned(&log, &[dir.join("pins").join("iot-edge.log.1")]).unwrap(), 1);
+    assert!(!dir.join("iot-edge.log.2").exists());
+    assert_eq!(fs::read_to_string(dir.join("iot-edge.log.1")).unwrap(), "newest\n");
+    assert_eq!(fs::read_to_string(dir.join("iot-edge.log.3")).unwrap(), "older\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_open_again() {
+    let dir = test_dir("open-again");
+    // the log directory is in the way of itself
+    fs::write(dir.join("logs"), "").unwrap();
+    let config = ConfigLog { path: dir.join("logs").join("iot-edge.log").to_string_lossy().to_string(), ..Default::default() };
+    let mut logger = FileLogger::new(&config, "test");
+    logger.write("lost");
+    assert_eq!(logger.dropped, 1);
+
+    fs::remove_file(dir.join("logs")).unwrap();
+    fs::create_dir(dir.join("logs")).unwrap();
+    logger.write("kept");
+    assert_eq!(logger.dropped, 0);
+    assert_eq!(fs::read_to_string(&config.path).unwrap(), "kept\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,99 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::error;
+use serde::Serialize;
+use chrono::prelude::*;
+
+use crate::config::{ConfigLog, Encoder};
+use crate::output::file::FileLogger;
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+pub enum Delivery {
+    QUEUED,     // stored in the disk queue
+    SENT,       // published, waiting for the broker
+    ACKED,      // the broker has it
+    FAILED,     // timed out or cut by a disconnect, it is sent again
+    DROPPED,    // evicted from a full queue before it got through
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    seq: u64,               // disk queue sequence number
+    state: Delivery,
+    time: DateTime<Utc>,
+    pkid: u16,              // MQTT packet id, 0 when there is none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk: Option<DateTime<Utc>>,   // time of the chunk, when queued
+}
+
+/// JSON lines with the delivery state of every chunk, next to the log as
+/// `<stem>.journal` and rotated like it. The last undelivered chunk written to
+/// the log is kept in `<stem>.logged`, a restart replays the queue and would
+/// log it again.
+pub struct Journal {
+    logger: FileLogger,
+    cursor: PathBuf,
+}
+
+impl Journal {
+    pub fn new(log: &ConfigLog, device_id: &str) -> Self {
+        let config = ConfigLog {
+            path: Path::new(&log.path).with_extension("journal").to_string_lossy().to_string(),
+            encoder: Encoder::JSON,
+            ..log.clone()
+        };
+        let cursor = Path::new(&log.path).with_extension("logged");
+        Journal { logger: FileLogger::new(&config, device_id), cursor }
+    }
+
+    /// Last undelivered chunk written to the log, by this run or an earlier one.
+    pub fn logged(&self) -> Option<u64> {
+        fs::read_to_string(&self.cursor).ok().and_then(|text| text.trim().parse().ok())
+    }
+
+    pub fn set_logged(&mut self, seq: u64) {
+        // replaced whole, a crash leaves the old cursor or the new one
+        let tmp = self.cursor.with_extension("logged.tmp");
+        if let Err(e) = fs::write(&tmp, seq.to_string()).and_then(|_| fs::rename(&tmp, &self.cursor)) {
+            error!("{}: {}", self.cursor.display(), e);
+        }
+    }
+
+    pub fn queued(&mut self, seq: u64, chunk: DateTime<Utc>) {
+        self.write(Entry { seq, state: Delivery::QUEUED, time: Utc::now(), pkid: 0, chunk: Some(chunk) });
+    }
+
+    pub fn record(&mut self, seq: u64, state: Delivery, pkid: u16) {
+        self.write(Entry { seq, state, time: Utc::now(), pkid, chunk: None });
+    }
+
+    fn write(&mut self, entry: Entry) {
+        self.logger.write(&serde_json::to_string(&entry).unwrap());
+    }
+}
+
+#[test]
+fn test_journal() {
+    let dir = crate::output::file::test_dir("journal");
+    let config = ConfigLog { path: dir.join("iot-edge.log").to_string_lossy().to_string(), ..Default::default() };
+
+    let mut journal = Journal::new(&config, "test");
+    journal.queued(7, Utc.with_ymd_and_hms(2026, 10, 18, 10, 0, 0).unwrap());
+    journal.record(7, Delivery::SENT, 3);
+    journal.record(7, Delivery::ACKED, 3);
+
+    let text = std::fs::read_to_string(dir.join("iot-edge.journal")).unwrap();
+    let lines: Vec<serde_json::Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["state"], "QUEUED");
+    assert_eq!(lines[0]["chunk"], "2026-10-18T10:00:00Z");
+    assert_eq!((&lines[2]["state"], &lines[2]["pkid"], &lines[2]["seq"]), (&"ACKED".into(), &3.into(), &7.into()));
+    assert!(lines[1].get("chunk").is_none());
+
+    // the cursor outlives the journal
+    assert_eq!(journal.logged(), None);
+    journal.set_logged(7);
+    assert_eq!(Journal::new(&config, "test").logged(), Some(7));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,61 +1,366 @@
-use log::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::collections::BTreeSet;
+use log::{error, warn};
 use tokio::time::{self, Duration};
 use chrono::prelude::*;
 
+mod binlog;
+mod capture;
+mod compression;
 mod file;
+mod journal;
 mod mqtt;
+#[cfg(feature = "parquet")]
+mod parquet;
+mod queue;
+mod session;
+mod tls;
 
-pub use crate::output::file::FileLogger;
-pub use crate::output::mqtt::MqttOutput;
+pub use crate::output::binlog::log_lines;
+pub use crate::output::capture::{
+    Asc, Blf, Candump, CaptureFormat, CaptureFrame, CaptureStats, CaptureStream, CaptureTask, Mdf, Pcapng,
+};
+pub use crate::output::compression::PayloadCodec;
+#[cfg(feature = "zstd")]
+pub use crate::output::compression::train;
+pub use crate::output::file::{closed_logs, FileLogger};
+pub use crate::output::journal::{Delivery, Journal};
+pub use crate::output::mqtt::{MqttOutput, MqttStatus, MqttStats};
+#[cfg(feature = "parquet")]
+pub use crate::output::parquet::{convert, ParquetExport, ParquetTask};
+pub use crate::output::queue::DiskQueue;
 
 use tokio::{
     select,
-    sync::mpsc::Receiver
+    sync::{mpsc::{channel, Receiver, Sender}, oneshot, watch}
 };
-use crate::config::{ConfigMqtt, ConfigLog, Encoder};
+use crate::config::{ConfigMqtt, ConfigLog, ConfigQueue, Encoder};
 use crate::message::{Message, Chunk};
+use crate::errors::IotEdgeError;
+use crate::output::file::remove_pinned;
+
+
+/// How long `publish` waits for room in the backlog.
+const PUBLISH_WAIT: Duration = Duration::from_secs(30);
 
+/// Requests from the command channel.
+#[derive(Debug)]
+pub enum Control {
+    Flush,                      // send the current chunk now
+    Publish(String, Vec<u8>, oneshot::Sender<Result<(), String>>),  // topic and payload, at least once
+    Reconfigure(Box<ConfigMqtt>, ConfigLog, oneshot::Sender<Result<(), String>>),
+    Prune(Vec<PathBuf>),        // hard links to closed log files to delete, the logger has to forget them too
+}
+
+/// Publish on `topic` through the output, waiting up to `PUBLISH_WAIT` while
+/// its backlog is full.
+pub async fn publish(control: &Sender<Control>, topic: &str, data: Vec<u8>) -> Result<(), String> {
+    let deadline = time::Instant::now() + PUBLISH_WAIT;
+    loop {
+        let (reply, rx) = oneshot::channel();
+        control.send(Control::Publish(topic.to_string(), data.clone(), reply)).await
+            .map_err(|_| "output is gone".to_string())?;
+        match rx.await.map_err(|_| "output is gone".to_string())? {
+            Err(_) if time::Instant::now() < deadline => time::sleep(Duration::from_millis(100)).await,
+            result => return result,
+        }
+    }
+}
 
 pub struct Output {
     id: String,
     mqtt_config: ConfigMqtt,
+    log_config: ConfigLog,
+    drain_rate: usize,
 
     rx: Receiver<Message>,
+    ignition: watch::Receiver<bool>,
+    control: Receiver<Control>,
+    control_tx: Sender<Control>,
+    commands: Option<(String, Sender<Vec<u8>>)>,
+    exports: Vec<Sender<Chunk>>,  // get every chunk as it is stored
+    online: watch::Sender<bool>,
 
+    stats: Arc<MqttStats>,
     mqtt: MqttOutput,
+    codec: PayloadCodec,        // compresses after encoding, queue entries name their codec
     logger: FileLogger,
+    journal: Journal,
+    queue: DiskQueue,
+
+    connected: bool,
+    inflight: BTreeSet<u64>,    // queue sequence numbers published but not delivered
+    sent: Option<u64>,          // last sequence number published
+    tokens: usize,              // sends left in the current second
+    logged: Option<u64>,        // last undelivered chunk logged without include_success, kept by the journal
 }
 
 impl Output {
     pub fn new(
-        id: &str, mqtt_config: ConfigMqtt, log_config: ConfigLog, rx: Receiver<Message>
-    ) -> Self {
-        let mqtt = MqttOutput::new(id, &mqtt_config);
-        let logger = FileLogger::from(&log_config);
+        id: &str, mqtt_config: ConfigMqtt, log_config: ConfigLog, queue_config: ConfigQueue,
+        rx: Receiver<Message>, ignition: watch::Receiver<bool>, stats: Arc<MqttStats>
+    ) -> Result<Self, IotEdgeError> {
+        let mqtt = MqttOutput::new(id, &mqtt_config, stats.clone())?;
+        let codec = PayloadCodec::new(&mqtt_config)?;
+        let logger = FileLogger::new(&log_config, id);
+        let journal = Journal::new(&log_config, id);
+        let queue = DiskQueue::open(&queue_config)?;
+        // a cursor past the queue belongs to one that was deleted
+        let logged = journal.logged().filter(|logged| *logged < queue.end());
+        let (control_tx, control) = channel(16);
+        let drain_rate = match queue_config.drain_rate {
+            0 => usize::MAX,
+            rate => rate,
+        };
 
-        Output {
+        Ok(Output {
             id: id.to_string(),
             mqtt_config,
+            log_config,
+            drain_rate,
 
             rx,
+            ignition,
+            control,
+            control_tx,
+            commands: None,
+            exports: Vec::new(),
+            online: watch::channel(false).0,
 
+            stats,
             mqtt,
+            codec,
             logger,
+            journal,
+            queue,
+
+            connected: false,
+            inflight: BTreeSet::new(),
+            sent: None,
+            tokens: drain_rate,
+            logged,
+        })
+    }
+
+    /// Hand publishes on `topic` to the command task.
+    pub fn commands(&mut self, topic: &str, commands: Sender<Vec<u8>>) {
+        self.mqtt.subscribe(topic);
+        self.commands = Some((topic.to_string(), commands));
+    }
+
+    /// Hand every chunk to an export as well.
+    #[cfg(feature = "parquet")]
+    pub fn export(&mut self, export: Sender<Chunk>) {
+        self.exports.push(export);
+    }
+
+    pub fn control(&self) -> Sender<Control> {
+        self.control_tx.clone()
+    }
+
+    /// Follows whether the broker connection is up.
+    pub fn online(&self) -> watch::Receiver<bool> {
+        self.online.subscribe()
+    }
+
+    fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+        self.online.send_replace(connected);
+    }
+
+    /// Switch to a new broker connection and log file, the queue stays and
+    /// whatever the old connection had not delivered is replayed.
+    fn reconfigure(&mut self, mqtt_config: ConfigMqtt, log_config: ConfigLog) -> Result<(), String> {
+        let mut mqtt = MqttOutput::new(&self.id, &mqtt_config, self.stats.clone())
+            .map_err(|e| e.to_string())?;
+        let codec = PayloadCodec::new(&mqtt_config).map_err(|e| e.to_string())?;
+        if let Some((topic, _)) = &self.commands {
+            mqtt.subscribe(topic);
         }
+
+        // the old connection takes what it had not delivered with it
+        for seq in std::mem::take(&mut self.inflight) {
+            self.failed(seq, 0);
+        }
+
+        self.mqtt = mqtt;
+        self.codec = codec;
+        self.mqtt_config = mqtt_config;
+        self.logger = FileLogger::new(&log_config, &self.id);
+        self.journal = Journal::new(&log_config, &self.id);
+        self.log_config = log_config;
+
+        self.set_connected(false);
+        self.reset();
+        Ok(())
     }
 
-    async fn send(&mut self, chunk: Chunk) {
-        let line = chunk.to_json();
-        self.logger.write(&line);
+    /// Delete the closed log files `pins` link to between writes and reopen
+    /// the log, its rotation would trip over the missing files otherwise.
+    fn prune(&mut self, pins: Vec<PathBuf>) {
+        if let Err(e) = remove_pinned(Path::new(&self.log_config.path), &pins) {
+            warn!("{}: {}", self.log_config.path, e);
+        }
+        for pin in pins {
+            let _ = fs::remove_file(pin);
+        }
+        self.logger = FileLogger::new(&self.log_config, &self.id);
+    }
 
+    /// Log a chunk as the queue has it, encoded for the broker.
+    fn log_queued(&mut self, data: &[u8]) {
+        let data = match self.codec.decompress(data) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("decompress queued chunk failed: {}", e);
+                return;
+            }
+        };
+        let data = &data[..];
+        match (&self.log_config.encoder, &self.mqtt_config.encoder) {
+            (Encoder::BINARY, Encoder::BINARY) => self.logger.write_chunk(data),
+            (Encoder::JSON, Encoder::JSON) => self.logger.write(&String::from_utf8_lossy(data)),
+            (Encoder::JSON, Encoder::BINARY) => match Chunk::from_slice(data) {
+                Ok(chunk) => self.logger.write(&chunk.to_json()),
+                Err(e) => error!("decode queued chunk failed: {}", e),
+            },
+            // refused by Config::validate
+            (Encoder::BINARY, Encoder::JSON) => error!("can't log a JSON chunk in a binary log"),
+        }
+    }
+
+    /// A chunk did not get through, without include_success it is logged the first time.
+    fn failed(&mut self, seq: u64, pkid: u16) {
+        self.journal.record(seq, Delivery::FAILED, pkid);
+        if self.log_config.include_success | self.logged.is_some_and(|logged| seq <= logged) {
+            return;
+        }
+        match self.queue.get(seq) {
+            Ok(Some(data)) => self.log_queued(&data),
+            Ok(None) => {},
+            Err(e) => error!("read queue failed: {}", e),
+        }
+        self.logged = Some(seq);
+        self.journal.set_logged(seq);
+    }
+
+    async fn send(&mut self, chunk: Chunk) {
         let data = match self.mqtt_config.encoder {
-            Encoder::BINARY => chunk.to_vec(),
-            Encoder::JSON => line.into_bytes(),
+            Encoder::BINARY => chunk.to_vec(self.mqtt_config.chunk_layout),
+            Encoder::JSON => chunk.to_json().into_bytes(),
         };
 
-        if let Err(e) = self.mqtt.write(data).await {
-            error!("{}", e);
+        // encoded once when the log and the broker take the same
+        if self.log_config.include_success {
+            match (&self.log_config.encoder, &self.mqtt_config.encoder) {
+                (Encoder::BINARY, Encoder::BINARY) => self.logger.write_chunk(&data),
+                (Encoder::BINARY, Encoder::JSON) => self.logger.write_chunk(&chunk.to_vec(self.mqtt_config.chunk_layout)),
+                (Encoder::JSON, Encoder::JSON) => self.logger.write(&String::from_utf8_lossy(&data)),
+                (Encoder::JSON, Encoder::BINARY) => self.logger.write(&chunk.to_json()),
+            }
+        }
+
+        let data = match self.codec.compress(data) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("compress chunk failed: {}", e);
+                return;
+            }
+        };
+        match self.queue.push(&data) {
+            Ok(seq) => self.journal.queued(seq, chunk.time),
+            Err(e) => error!("queue chunk failed: {}", e),
+        }
+        for (seq, data) in self.queue.take_lost() {
+            self.journal.record(seq, Delivery::DROPPED, 0);
+            if !self.log_config.include_success {
+                self.log_queued(&data);
+            }
+        }
+        for export in self.exports.iter() {
+            if export.try_send(chunk.clone()).is_err() {
+                warn!("export fell behind, chunk dropped");
+            }
+        }
+        self.drain().await;
+    }
+
+    /// Publish queued chunks in order, bounded by the inflight window and the drain rate.
+    async fn drain(&mut self) {
+        while self.connected & (self.inflight.len() < self.mqtt.inflight_max()) & (self.tokens > 0) {
+            let (seq, data) = match self.queue.next_unsent() {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("read queue failed: {}", e);
+                    break;
+                }
+            };
+
+            match self.mqtt.write(seq, data) {
+                Ok(()) => { self.inflight.insert(seq); },
+                Err(e) => {
+                    // never publishable, the queue moves past it with the next delivery
+                    error!("queued chunk {}: {}", seq, e);
+                    self.failed(seq, 0);
+                }
+            }
+            self.sent = Some(seq);
+            self.tokens -= 1;
+        }
+    }
+
+    /// A chunk is done with, delivered or refused for good.
+    fn settled(&mut self, seq: u64) {
+        self.inflight.remove(&seq);
+
+        // the queue only moves past chunks with nothing undelivered before them
+        let done = match self.inflight.iter().next() {
+            Some(first) => first.checked_sub(1),
+            None => self.sent,
+        };
+        if let Some(done) = done {
+            if let Err(e) = self.queue.ack(done) {
+                error!("ack queue failed: {}", e);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.inflight.clear();
+        self.sent = None;
+        self.queue.rewind();
+    }
+
+    async fn status(&mut self, status: MqttStatus) {
+        match status {
+            MqttStatus::Connected(resumed) => {
+                // a resumed session resends its own publishes, only a new one replays the queue
+                self.set_connected(true);
+                if !resumed {
+                    self.reset();
+                }
+            },
+            MqttStatus::Message(topic, payload) => {
+                match &self.commands {
+                    Some((_, commands)) if commands.try_send(payload).is_ok() => {},
+                    _ => warn!("dropped message on {}", topic),
+                }
+            },
+            MqttStatus::Sent(seq, pkid) => self.journal.record(seq, Delivery::SENT, pkid),
+            MqttStatus::Delivered(seq, pkid) => {
+                self.journal.record(seq, Delivery::ACKED, pkid);
+                self.settled(seq);
+            },
+            MqttStatus::Rejected(seq, pkid) => {
+                // sent again it would be refused again, with everything behind it waiting
+                self.failed(seq, pkid);
+                self.settled(seq);
+            },
         }
+        self.drain().await;
     }
 
     pub async fn run(&mut self) {
@@ -66,6 +371,14 @@ impl Output {
             select! {
                 msg = self.rx.recv() => {
                     if let Some(msg) = msg {
+                        if let (true, Some(topic)) = (msg.is_status(), &self.mqtt_config.status_topic) {
+                            let data = serde_json::to_vec(&msg).unwrap();
+                            if let Err(e) = self.mqtt.status(topic, data) {
+                                error!("{}", e);
+                            }
+                            continue;
+                        }
+
                         chunk.push(msg);
                         if chunk.len() >= self.mqtt_config.chunk_size {
                             self.send(chunk).await;
@@ -73,12 +386,52 @@ impl Output {
                         } 
                     }
                 }
+                Some(control) = self.control.recv() => {
+                    match control {
+                        Control::Flush => {
+                            if chunk.len() > 0 {
+                                self.send(chunk).await;
+                                chunk = Chunk::new(&self.id);
+                            }
+                        },
+                        Control::Publish(topic, data, reply) => {
+                            let _ = reply.send(self.mqtt.publish(&topic, data).map_err(|e| e.to_string()));
+                        },
+                        Control::Reconfigure(mqtt_config, log_config, reply) => {
+                            let _ = reply.send(self.reconfigure(*mqtt_config, log_config));
+                        },
+                        Control::Prune(paths) => self.prune(paths),
+                    }
+                }
+                Ok(()) = self.ignition.changed() => {
+                    // ignition off, flush what we have before the vehicle powers down
+                    let on = *self.ignition.borrow();
+                    if !on & (chunk.len() > 0) {
+                        self.send(chunk).await;
+                        chunk = Chunk::new(&self.id);
+                    }
+                }
                 ack = self.mqtt.ack() => {
-                    if let Err(e) = ack {
-                        error!("{}", e);
+                    match ack {
+                        Ok(Some(status)) => self.status(status).await,
+                        Ok(None) => {},
+                        Err(e) => {
+                            error!("{}", e);
+                            self.set_connected(false);
+                        }
+                    }
+                    // cut by an error or by a broker that lost the session
+                    for (seq, pkid) in self.mqtt.take_failed() {
+                        self.failed(seq, pkid);
                     }
                 }
                 _ = interval.tick() => { // tick
+                    self.tokens = self.drain_rate;
+                    if let Err(e) = self.queue.sync_ack() {
+                        error!("ack queue failed: {}", e);
+                    }
+                    self.drain().await;
+
                     let now = Utc::now();
                     if (chunk.len() > 0) & (now.timestamp() - chunk.time.timestamp() > self.mqtt_config.chunk_period) {
                         self.send(chunk).await;
@@ -88,4 +441,4 @@ impl Output {
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -1,65 +1,698 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{HashMap, VecDeque};
 use std::time::Duration;
-use log::debug;
+use log::warn;
+use tokio::select;
+use tokio::time::{self, Instant};
+use http::{HeaderName, HeaderValue};
 use rumqttc::{
+    v5,
+    LastWill,
     MqttOptions, 
     QoS, 
-    EventLoop, 
-    Publish, 
-    Request,
-    ConnectionError,
+    Transport,
 };
 
-use crate::config::{ConfigMqtt, Encoder};
+use crate::config::{ConfigMqtt, ConfigMqttTls, ConfigMqttWebsocket, Encoder, MqttProtocol};
 use crate::errors::IotEdgeError;
+use crate::output::compression::{content_encoding, split_entry};
+use crate::output::session::{Options, Properties, Session, SessionEvent};
+use crate::output::tls::{tls_config, TlsTunnel};
+
+/// Publishes that are not chunks waiting for the client at most.
+const PUBLISH_MAX: usize = 64;
+
+/// Delivery counters, shared with the system telemetry.
+#[derive(Debug, Default)]
+pub struct MqttStats {
+    pub delivered: AtomicU64,
+    pub failed: AtomicU64,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]     // the packet id is shown through Debug
+pub enum MqttStatus {
+    Connected(bool),    // true when the previous session was resumed with its publishes
+    Sent(u64, u16),     // chunk and its packet id, waiting for the broker
+    Delivered(u64, u16),    // packet id 0 for QoS 0
+    Rejected(u64, u16),     // refused by the broker for good, not to be sent again
+    Message(String, Vec<u8>),
+}
+
+/// Publishes waiting for room in the client's request channel.
+enum Request {
+    Chunk(u64, Option<&'static str>, Vec<u8>),  // with its compression, None if not compressed
+    Publish(String, Vec<u8>),   // not a chunk, at least once
+}
+
+struct InFlight {
+    seq: Option<u64>,   // None for publishes that are not chunks
+    qos: QoS,
+    sent: Instant,
+    received: bool,     // PUBREC seen, waiting for PUBCOMP
+}
 
 pub struct MqttOutput {
     topic: String,
-    eventloop: EventLoop,
+    compression_suffix: bool,   // 3.1.1 has no user properties, the topic names the compression
+    qos: QoS,
+    retain: bool,
+    ack_timeout: Duration,
+    inflight_max: usize,
+    clean_session: bool,
+    presence_topic: Option<String>,
+    subscriptions: Vec<String>,
+    options: Options,
+    session: Session,
+    resumed: bool,              // the session outlived a connection error
+    retry: Option<Instant>,
+    _tunnel: Option<TlsTunnel>, // held while the options point at its socket
+
+    properties: Properties,     // sent with every publish on MQTT 5
+    topic_alias: bool,
+    alias_max: u16,             // topic aliases the broker accepts, 0 for none
+    alias_set: bool,            // the topic has been sent along with its alias this session
+
+    backlog: VecDeque<Request>,                 // not handed to the client yet
+    unassigned: VecDeque<(Option<u64>, QoS)>,   // requests waiting for a pkid, None for non chunks
+    inflight: HashMap<u16, InFlight>,
+    failed: Vec<(u64, u16)>,    // chunks given up on since the last take_failed, packet id 0 if none yet
+    stats: Arc<MqttStats>,
+}
+
+fn websocket_url(host: &str, port: u16, tls: bool, path: &str) -> String {
+    let scheme = match tls {
+        true => "wss",
+        false => "ws",
+    };
+    // an IPv6 literal is bracketed, its colons would run into the port
+    let host = match host.parse::<std::net::Ipv6Addr>() {
+        Ok(_) => format!("[{}]", host),
+        Err(_) => host.to_string(),
+    };
+    match path.starts_with('/') {
+        true => format!("{}://{}:{}{}", scheme, host, port, path),
+        false => format!("{}://{}:{}/{}", scheme, host, port, path),
+    }
+}
+
+fn qos(qos: u8) -> Result<QoS, IotEdgeError> {
+    match qos {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        _ => Err(IotEdgeError::Generic("mqtt qos must be 0, 1 or 2")),
+    }
+}
+
+fn websocket_headers(ws: &ConfigMqttWebsocket) -> Result<Vec<(HeaderName, HeaderValue)>, IotEdgeError> {
+    let mut headers = Vec::new();
+    for (name, value) in ws.headers.iter() {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| IotEdgeError::Generic("invalid websocket header name"))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| IotEdgeError::Generic("invalid websocket header value"))?;
+        headers.push((name, value));
+    }
+    Ok(headers)
 }
 
 impl MqttOutput {
-    pub fn new(id: &str, mqtt: &ConfigMqtt) -> Self {
-        let mut options = MqttOptions::new(
-            id,
-            &mqtt.host,
-            mqtt.port
-        );
-        options.set_keep_alive(Duration::from_secs(5));
-        let eventloop = EventLoop::new(options, 10);
+    pub fn new(id: &str, mqtt: &ConfigMqtt, stats: Arc<MqttStats>) -> Result<Self, IotEdgeError> {
+        let tunnel = match (&mqtt.websocket, &mqtt.tls) {
+            (None, Some(tls @ ConfigMqttTls { server_name: Some(server_name), .. })) => {
+                Some(TlsTunnel::new(&mqtt.host, mqtt.port, tls, server_name)?)
+            },
+            _ => None,
+        };
+        // websockets take the whole url as the broker address
+        let host = match (&mqtt.websocket, &tunnel) {
+            (Some(ws), _) => websocket_url(&mqtt.host, mqtt.port, mqtt.tls.is_some(), &ws.path),
+            (None, Some(tunnel)) => tunnel.path.to_string_lossy().to_string(),
+            (None, None) => mqtt.host.clone(),
+        };
 
-        let topic = match mqtt.encoder {
-            Encoder::BINARY => format!("{}/capnp", mqtt.topic),
-            Encoder::JSON => format!("{}/json", mqtt.topic)
+        let tls = match &mqtt.tls {
+            Some(tls) => Some(tls_config(tls)?),
+            None => None,
+        };
+        let transport = match (&mqtt.websocket, tls) {
+            (None, _) if tunnel.is_some() => Transport::Unix,
+            (None, None) => Transport::Tcp,
+            (None, Some(tls)) => Transport::Tls(tls),
+            (Some(_), None) => Transport::Ws,
+            (Some(_), Some(tls)) => Transport::Wss(tls),
         };
 
-        MqttOutput {
+        let headers = match &mqtt.websocket {
+            Some(ws) => websocket_headers(ws)?,
+            None => Vec::new(),
+        };
+        let modifier = move |mut request: http::Request<()>| {
+            for (name, value) in headers.iter() {
+                request.headers_mut().insert(name.clone(), value.clone());
+            }
+            async move { request }
+        };
+
+        if mqtt.inflight == 0 {
+            return Err(IotEdgeError::Generic("mqtt inflight must be at least 1"));
+        }
+        let keep_alive = Duration::from_secs(mqtt.keep_alive);
+        let options = match mqtt.protocol {
+            MqttProtocol::V4 => {
+                let mut options = MqttOptions::new(id, host, mqtt.port);
+                options.set_keep_alive(keep_alive);
+                options.set_inflight(mqtt.inflight);
+                options.set_clean_session(mqtt.clean_session);
+                options.set_transport(transport);
+                options.set_request_modifier(modifier);
+                if let Some(username) = &mqtt.username {
+                    options.set_credentials(username, mqtt.password.as_deref().unwrap_or(""));
+                }
+                if let Some(topic) = &mqtt.presence_topic {
+                    options.set_last_will(LastWill::new(topic, "offline", QoS::AtLeastOnce, true));
+                }
+                Options::V4(options)
+            },
+            MqttProtocol::V5 => {
+                let mut options = v5::MqttOptions::new(id, host, mqtt.port);
+                options.set_keep_alive(keep_alive);
+                options.set_outgoing_inflight_upper_limit(mqtt.inflight);
+                options.set_clean_start(mqtt.clean_session);
+                if !mqtt.clean_session {
+                    let mut properties = options.connect_properties().unwrap_or_default();
+                    properties.session_expiry_interval = Some(mqtt.session_expiry);
+                    options.set_connect_properties(properties);
+                }
+                options.set_transport(transport);
+                options.set_request_modifier(modifier);
+                if let Some(username) = &mqtt.username {
+                    options.set_credentials(username, mqtt.password.as_deref().unwrap_or(""));
+                }
+                if let Some(topic) = &mqtt.presence_topic {
+                    let qos = v5::mqttbytes::QoS::AtLeastOnce;
+                    options.set_last_will(v5::mqttbytes::v5::LastWill::new(topic, "offline", qos, true, None));
+                }
+                Options::V5(options)
+            },
+        };
+        let inflight_max = mqtt.inflight as usize;
+        let session = Session::new(&options, inflight_max);
+
+        // MQTT 5 carries the encoding as content type instead of a topic suffix
+        let (topic, content_type) = match (&mqtt.protocol, &mqtt.encoder) {
+            (MqttProtocol::V4, Encoder::BINARY) => (format!("{}/capnp", mqtt.topic), "application/capnp"),
+            (MqttProtocol::V4, Encoder::JSON) => (format!("{}/json", mqtt.topic), "application/json"),
+            (MqttProtocol::V5, Encoder::BINARY) => (mqtt.topic.clone(), "application/capnp"),
+            (MqttProtocol::V5, Encoder::JSON) => (mqtt.topic.clone(), "application/json"),
+        };
+
+        let mut user_properties = vec![
+            ("device_id".to_string(), id.to_string()),
+            ("schema_version".to_string(), mqtt.chunk_layout.to_string()),
+            ("firmware".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ];
+        user_properties.extend(mqtt.user_properties.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let properties = Properties {
+            content_type: Some(content_type.to_string()),
+            message_expiry: match mqtt.message_expiry {
+                0 => None,
+                expiry => Some(expiry),
+            },
+            topic_alias: None,
+            user_properties,
+        };
+
+        Ok(MqttOutput {
             topic,
-            eventloop
+            compression_suffix: mqtt.protocol == MqttProtocol::V4,
+            qos: qos(mqtt.qos)?,
+            retain: mqtt.retain,
+            ack_timeout: Duration::from_secs(mqtt.ack_timeout),
+            inflight_max,
+            clean_session: mqtt.clean_session,
+            presence_topic: mqtt.presence_topic.clone(),
+            subscriptions: Vec::new(),
+            options,
+            session,
+            resumed: false,
+            retry: None,
+            _tunnel: tunnel,
+
+            properties,
+            // aliases die with the connection, resent publishes would refer to unknown ones
+            topic_alias: mqtt.topic_alias & mqtt.clean_session,
+            alias_max: 0,
+            alias_set: false,
+
+            backlog: VecDeque::new(),
+            unassigned: VecDeque::new(),
+            inflight: HashMap::new(),
+            failed: Vec::new(),
+            stats,
+        })
+    }
+
+    /// Publish chunk `seq` as the queue has it, its delivery is reported by
+    /// `ack`. The compression it was queued with goes along as a user
+    /// property, and on 3.1.1 as a second suffix like <topic>/capnp/zstd.
+    pub fn write(&mut self, seq: u64, entry: Vec<u8>) -> io::Result<()> {
+        let (compression, data) = split_entry(&entry)?;
+        self.backlog.push_back(Request::Chunk(seq, content_encoding(&compression), data.to_vec()));
+        self.flush();
+        Ok(())
+    }
+
+    /// Hand waiting publishes to the client while its request channel has
+    /// room. Awaiting a full channel would stall the event loop that empties it.
+    fn flush(&mut self) {
+        while let Some(request) = self.backlog.front() {
+            let sent = match request {
+                Request::Chunk(seq, compression, data) => {
+                    let mut properties = self.properties.clone();
+                    let topic = match (self.compression_suffix, compression) {
+                        (true, Some(compression)) => format!("{}/{}", self.topic, compression),
+                        _ => self.topic.clone(),
+                    };
+                    let mut topic = topic.as_str();
+                    if let Some(compression) = compression {
+                        properties.user_properties.push(("compression".to_string(), compression.to_string()));
+                    }
+
+                    // the first publish binds the alias, later ones leave the topic out
+                    if self.topic_alias & (self.alias_max > 0) {
+                        properties.topic_alias = Some(1);
+                        if self.alias_set {
+                            topic = "";
+                        }
+                    }
+
+                    let alias = properties.topic_alias.is_some();
+                    let sent = self.session.try_publish(topic, self.qos, self.retain, data.clone(), properties);
+                    if sent.is_ok() {
+                        self.alias_set |= alias;
+                        self.unassigned.push_back((Some(*seq), self.qos));
+                    }
+                    sent
+                },
+                Request::Publish(topic, data) => {
+                    let properties = Properties {
+                        content_type: Some("application/json".to_string()),
+                        ..self.properties.clone()
+                    };
+                    let sent = self.session.try_publish(topic, QoS::AtLeastOnce, false, data.clone(), properties);
+                    if sent.is_ok() {
+                        self.unassigned.push_back((None, QoS::AtLeastOnce));
+                    }
+                    sent
+                },
+            };
+            match sent {
+                Ok(()) => { self.backlog.pop_front(); },
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Best effort publish, dropped when the connection is backed up.
+    pub fn status(&mut self, topic: &str, data: Vec<u8>) -> Result<(), IotEdgeError> {
+        let properties = Properties {
+            content_type: Some("application/json".to_string()),
+            ..self.properties.clone()
+        };
+        self.session.try_publish(topic, QoS::AtMostOnce, false, data, properties)?;
+        self.unassigned.push_back((None, QoS::AtMostOnce));
+        Ok(())
+    }
+
+    /// Reliable publish that is not a chunk, like command responses. Refused
+    /// while `PUBLISH_MAX` of them wait for the client, they would pile up
+    /// without bound while the connection is down.
+    pub fn publish(&mut self, topic: &str, data: Vec<u8>) -> Result<(), IotEdgeError> {
+        let waiting = self.backlog.iter().filter(|request| matches!(request, Request::Publish(..))).count();
+        if waiting >= PUBLISH_MAX {
+            return Err(IotEdgeError::MqttBacklogFull);
+        }
+        self.backlog.push_back(Request::Publish(topic.to_string(), data));
+        self.flush();
+        Ok(())
+    }
+
+    /// Subscribe on every connection, the broker may have lost the session.
+    pub fn subscribe(&mut self, topic: &str) {
+        self.subscriptions.push(topic.to_string());
+    }
+
+    /// Retained birth message, the broker publishes the last will "offline" for us.
+    fn online(&mut self) {
+        let topic = match &self.presence_topic {
+            Some(topic) => topic.clone(),
+            None => return,
+        };
+        let properties = Properties {
+            content_type: Some("text/plain".to_string()),
+            ..self.properties.clone()
+        };
+        match self.session.try_publish(&topic, QoS::AtLeastOnce, true, b"online".to_vec(), properties) {
+            Ok(()) => self.unassigned.push_back((None, QoS::AtLeastOnce)),
+            Err(e) => warn!("presence publish failed: {}", e),
         }
     }
 
-    pub async fn write(&mut self, data: Vec<u8>) -> Result<u16, IotEdgeError> {
-        let mut publish = Publish::new(&self.topic, QoS::AtLeastOnce, data);
-        publish.retain = false;
-        let pkid = publish.pkid;
+    /// Most chunks allowed on the wire at once.
+    pub fn inflight_max(&self) -> usize {
+        self.inflight_max
+    }
+
+    /// Chunks published but not delivered yet.
+    pub fn pending(&self) -> usize {
+        let backlog = self.backlog.iter().filter(|request| matches!(request, Request::Chunk(..))).count();
+        let unassigned = self.unassigned.iter().filter(|(seq, _)| seq.is_some()).count();
+        let inflight = self.inflight.values().filter(|inflight| inflight.seq.is_some()).count();
+        backlog + unassigned + inflight
+    }
+
+    /// Chunks given up on since the last call, with their packet ids.
+    pub fn take_failed(&mut self) -> Vec<(u64, u16)> {
+        std::mem::take(&mut self.failed)
+    }
+
+    /// Start over with an empty session, whatever was not delivered counts
+    /// as failed and is replayed by the disk queue after reconnecting.
+    fn reset(&mut self) {
+        self.fail_pending();
+        self.alias_set = false;
 
-        let request = Request::Publish(publish);
-        let tx = self.eventloop.handle();
-        match tx.send_async(request).await {
-            Ok(_) => Ok(pkid),
-            Err(_) => Err(IotEdgeError::MqttPubAckError),
+        self.session = Session::new(&self.options, self.inflight_max);
+        self.resumed = false;
+        self.retry = Some(Instant::now() + Duration::from_secs(1));
+    }
+
+    /// Give up on every chunk not delivered yet, responses are kept.
+    fn fail_pending(&mut self) {
+        self.stats.failed.fetch_add(self.pending() as u64, Ordering::Relaxed);
+        let mut failed: Vec<(u64, u16)> = self.inflight.iter()
+            .filter_map(|(pkid, inflight)| Some((inflight.seq?, *pkid)))
+            .chain(self.unassigned.iter().filter_map(|(seq, _)| Some(((*seq)?, 0))))
+            .chain(self.backlog.iter().filter_map(|request| match request {
+                Request::Chunk(seq, ..) => Some((*seq, 0)),
+                Request::Publish(..) => None,
+            }))
+            .collect();
+        failed.sort_unstable();
+        self.failed.extend(failed);
+        self.backlog.retain(|request| matches!(request, Request::Publish(..)));
+        self.unassigned.clear();
+        self.inflight.clear();
+    }
+
+    /// Connection lost, a persistent session keeps its publishes and the
+    /// event loop sends them again once reconnected.
+    fn disconnected(&mut self) {
+        match self.clean_session {
+            true => self.reset(),
+            false => {
+                self.resumed = true;
+                self.retry = Some(Instant::now() + Duration::from_secs(1));
+            }
         }
     }
 
-    pub async fn ack(&mut self) -> Result<(), ConnectionError> {
-        match self.eventloop.poll().await {
-            Ok(event) => {
-                debug!("Received = {:?}", event);
-                Ok(())
+    fn delivered(&mut self, pkid: u16) -> Option<MqttStatus> {
+        let seq = self.inflight.remove(&pkid)?.seq?;
+        self.stats.delivered.fetch_add(1, Ordering::Relaxed);
+        Some(MqttStatus::Delivered(seq, pkid))
+    }
+
+    fn handle(&mut self, event: SessionEvent) -> Result<Option<MqttStatus>, IotEdgeError> {
+        let status = match event {
+            SessionEvent::Published(pkid) => {
+                // resent from a resumed session, it already has its chunk
+                if let Some(inflight) = self.inflight.get_mut(&pkid) {
+                    inflight.sent = Instant::now();
+                    return Ok(None);
+                }
+                let (seq, qos) = match self.unassigned.pop_front() {
+                    Some(next) => next,
+                    None => return Ok(None),
+                };
+                match (qos, seq) {
+                    (QoS::AtMostOnce, Some(seq)) => {
+                        self.stats.delivered.fetch_add(1, Ordering::Relaxed);
+                        Some(MqttStatus::Delivered(seq, pkid))
+                    },
+                    (QoS::AtMostOnce, None) => None,
+                    (qos, seq) => {
+                        let sent = Instant::now();
+                        self.inflight.insert(pkid, InFlight { seq, qos, sent, received: false });
+                        seq.map(|seq| MqttStatus::Sent(seq, pkid))
+                    }
+                }
+            },
+            SessionEvent::Connected(alias_max, session_present) => {
+                // only the broker knows whether it kept the session, a
+                // lost one never acknowledges what was inflight in it
+                let resumed = std::mem::replace(&mut self.resumed, false) & session_present;
+                if !resumed {
+                    // the disk queue replays its chunks from the start
+                    self.fail_pending();
+                }
+                self.alias_max = alias_max;
+                self.alias_set = false;
+                for topic in self.subscriptions.iter() {
+                    if let Err(e) = self.session.try_subscribe(topic, QoS::AtLeastOnce) {
+                        warn!("subscribe {} failed: {}", topic, e);
+                    }
+                }
+                self.online();
+                Some(MqttStatus::Connected(resumed))
+            },
+            SessionEvent::PubAck(pkid) => {
+                match self.inflight.get(&pkid) {
+                    Some(inflight) if inflight.qos == QoS::AtLeastOnce => self.delivered(pkid),
+                    _ => {
+                        warn!("unexpected PUBACK for packet {}", pkid);
+                        None
+                    }
+                }
+            },
+            SessionEvent::PubRec(pkid) => {
+                if let Some(inflight) = self.inflight.get_mut(&pkid) {
+                    inflight.received = true;
+                }
+                None
+            },
+            SessionEvent::PubComp(pkid) => {
+                match self.inflight.get(&pkid) {
+                    Some(inflight) if inflight.received => self.delivered(pkid),
+                    _ => {
+                        warn!("unexpected PUBCOMP for packet {}", pkid);
+                        None
+                    }
+                }
+            },
+            SessionEvent::Rejected(pkid, reason) => {
+                // the session is fine, only this publish failed
+                warn!("broker rejected packet {}: {}", pkid, reason);
+                match self.inflight.remove(&pkid) {
+                    Some(InFlight { seq: Some(seq), .. }) => {
+                        self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                        Some(MqttStatus::Rejected(seq, pkid))
+                    },
+                    _ => None,
+                }
+            },
+            SessionEvent::Message(topic, payload) => Some(MqttStatus::Message(topic, payload)),
+            SessionEvent::Other => None,
+        };
+        Ok(status)
+    }
+
+    /// The publish waiting longest for its acknowledgement and when it times out.
+    fn deadline(&self) -> Option<(u16, Instant)> {
+        self.inflight.iter()
+            .min_by_key(|(_, inflight)| inflight.sent)
+            .map(|(pkid, inflight)| (*pkid, inflight.sent + self.ack_timeout))
+    }
+
+    /// No acknowledgement in time, the connection is started over.
+    fn timed_out(&mut self, pkid: u16) -> IotEdgeError {
+        self.reset();
+        IotEdgeError::MqttPubAckError(pkid)
+    }
+
+    pub async fn ack(&mut self) -> Result<Option<MqttStatus>, IotEdgeError> {
+        if let Some(retry) = self.retry {
+            time::sleep_until(retry).await;
+            self.retry = None;
+        }
+        self.flush();
+
+        let event = match self.deadline() {
+            Some((pkid, deadline)) => select! {
+                event = self.session.poll() => event,
+                _ = time::sleep_until(deadline) => return Err(self.timed_out(pkid)),
             },
+            None => self.session.poll().await,
+        };
+
+        match event {
+            Ok(event) => self.handle(event),
             Err(e) => {
+                self.disconnected();
                 Err(e)
             }
         }
     }
 }
+
+#[test]
+fn test_websocket_url() {
+    assert_eq!(websocket_url("broker", 443, true, "/mqtt"), "wss://broker:443/mqtt");
+    assert_eq!(websocket_url("10.0.0.1", 8080, false, "mqtt"), "ws://10.0.0.1:8080/mqtt");
+    assert_eq!(websocket_url("::1", 8080, false, "/mqtt"), "ws://[::1]:8080/mqtt");
+    assert_eq!(websocket_url("fd00::7", 443, true, "/mqtt"), "wss://[fd00::7]:443/mqtt");
+}
+
+#[test]
+fn test_delivery() {
+    let stats = Arc::new(MqttStats::default());
+    let mut mqtt = MqttOutput::new("test", &ConfigMqtt::default(), stats.clone()).unwrap();
+    mqtt.write(5, vec![0, b'{']).unwrap();
+    mqtt.write(6, vec![0, b'{']).unwrap();
+    assert_eq!(mqtt.pending(), 2);
+
+    // packet ids come with the outgoing publishes, in order
+    assert!(matches!(mqtt.handle(SessionEvent::Published(1)), Ok(Some(MqttStatus::Sent(5, 1)))));
+    assert!(matches!(mqtt.handle(SessionEvent::Published(2)), Ok(Some(MqttStatus::Sent(6, 2)))));
+    assert!(matches!(mqtt.handle(SessionEvent::PubAck(9)), Ok(None)));
+    assert!(matches!(mqtt.handle(SessionEvent::PubAck(1)), Ok(Some(MqttStatus::Delivered(5, 1)))));
+    assert!(matches!(mqtt.handle(SessionEvent::PubAck(1)), Ok(None)));
+    assert_eq!((mqtt.pending(), stats.delivered.load(Ordering::Relaxed)), (1, 1));
+
+    // chunk 6 is the oldest unacknowledged, it times out and is given up on
+    let (pkid, deadline) = mqtt.deadline().unwrap();
+    assert_eq!(pkid, 2);
+    assert!(deadline > Instant::now() + Duration::from_secs(29));
+    assert!(matches!(mqtt.timed_out(pkid), IotEdgeError::MqttPubAckError(2)));
+    assert_eq!(mqtt.take_failed(), vec![(6, 2)]);
+    assert_eq!((mqtt.pending(), stats.failed.load(Ordering::Relaxed)), (0, 1));
+    assert!(mqtt.deadline().is_none());
+
+    // a rejected chunk is failed on its own, the session goes on
+    mqtt.write(7, vec![0, b'{']).unwrap();
+    mqtt.write(8, vec![0, b'{']).unwrap();
+    mqtt.handle(SessionEvent::Published(3)).unwrap();
+    mqtt.handle(SessionEvent::Published(4)).unwrap();
+    let rejected = mqtt.handle(SessionEvent::Rejected(3, "QuotaExceeded".to_string()));
+    assert!(matches!(rejected, Ok(Some(MqttStatus::Rejected(7, 3)))));
+    assert!(matches!(mqtt.handle(SessionEvent::PubAck(4)), Ok(Some(MqttStatus::Delivered(8, 4)))));
+    assert_eq!((mqtt.pending(), stats.failed.load(Ordering::Relaxed)), (0, 2));
+    assert!(mqtt.take_failed().is_empty());
+}
+
+#[test]
+fn test_publish_backlog() {
+    let mut mqtt = MqttOutput::new("test", &ConfigMqtt::default(), Arc::new(MqttStats::default())).unwrap();
+    // nothing polls the connection, what the request channel can't take waits
+    for part in 0..100u8 {
+        let accepted = mqtt.publish("test/response", vec![part; 64]).is_ok();
+        assert_eq!(accepted, (part as usize) < mqtt.inflight_max() + PUBLISH_MAX);
+    }
+    assert_eq!(mqtt.backlog.len(), PUBLISH_MAX);
+    mqtt.write(7, vec![0; 64]).unwrap();
+    assert_eq!(mqtt.pending(), 1);
+
+    // the chunk is replayed by the disk queue, responses still go out
+    mqtt.reset();
+    assert_eq!(mqtt.backlog.len(), PUBLISH_MAX);
+    assert_eq!(mqtt.pending(), 0);
+    assert_eq!(mqtt.take_failed(), vec![(7, 0)]);
+
+    // a broker that lost the session fails the chunks of the old one
+    mqtt.write(8, vec![0; 64]).unwrap();
+    mqtt.handle(SessionEvent::Connected(0, false)).unwrap();
+    assert_eq!(mqtt.pending(), 0);
+    assert_eq!(mqtt.take_failed(), vec![(8, 0)]);
+}
+
+#[test]
+fn test_chunk_compression() {
+    let mut mqtt = MqttOutput::new("test", &ConfigMqtt::default(), Arc::new(MqttStats::default())).unwrap();
+    for _ in 0..mqtt.inflight_max() {
+        mqtt.publish("test/response", vec![]).unwrap();
+    }
+    // each chunk goes out with the codec it was queued with
+    mqtt.write(1, vec![3, 0x28, 0xB5]).unwrap();
+    mqtt.write(2, vec![0, b'{']).unwrap();
+    assert!(mqtt.write(3, vec![7]).is_err());
+    let chunks: Vec<_> = mqtt.backlog.iter().filter_map(|request| match request {
+        Request::Chunk(seq, compression, data) => Some((*seq, *compression, data.clone())),
+        Request::Publish(..) => None,
+    }).collect();
+    assert_eq!(chunks, vec![(1, Some("zstd"), vec![0x28, 0xB5]), (2, None, b"{".to_vec())]);
+}
+
+#[test]
+fn test_session_present() {
+    let config = ConfigMqtt { clean_session: false, ..Default::default() };
+    let mut mqtt = MqttOutput::new("test", &config, Arc::new(MqttStats::default())).unwrap();
+    mqtt.write(1, vec![0, b'{']).unwrap();
+    mqtt.handle(SessionEvent::Published(1)).unwrap();
+
+    // the broker kept the session, it gets the publish again from the event loop
+    mqtt.disconnected();
+    assert!(matches!(mqtt.handle(SessionEvent::Connected(0, true)), Ok(Some(MqttStatus::Connected(true)))));
+    assert_eq!((mqtt.pending(), mqtt.take_failed()), (1, vec![]));
+
+    // it did not, the disk queue replays the chunk
+    mqtt.disconnected();
+    assert!(matches!(mqtt.handle(SessionEvent::Connected(0, false)), Ok(Some(MqttStatus::Connected(false)))));
+    assert_eq!((mqtt.pending(), mqtt.take_failed()), (0, vec![(1, 1)]));
+}
+
+#[test]
+fn test_exactly_once() {
+    let config = ConfigMqtt { qos: 2, ..Default::default() };
+    let stats = Arc::new(MqttStats::default());
+    let mut mqtt = MqttOutput::new("test", &config, stats.clone()).unwrap();
+    mqtt.write(4, vec![0, b'{']).unwrap();
+    assert!(matches!(mqtt.handle(SessionEvent::Published(1)), Ok(Some(MqttStatus::Sent(4, 1)))));
+
+    // PUBACK is no delivery for QoS 2, PUBCOMP only counts after PUBREC
+    assert!(matches!(mqtt.handle(SessionEvent::PubAck(1)), Ok(None)));
+    assert!(matches!(mqtt.handle(SessionEvent::PubComp(1)), Ok(None)));
+    assert!(matches!(mqtt.handle(SessionEvent::PubRec(1)), Ok(None)));
+    assert_eq!(mqtt.pending(), 1);
+    assert!(matches!(mqtt.handle(SessionEvent::PubComp(1)), Ok(Some(MqttStatus::Delivered(4, 1)))));
+    assert_eq!((mqtt.pending(), stats.delivered.load(Ordering::Relaxed)), (0, 1));
+}
+
+#[test]
+fn test_presence() {
+    for protocol in [MqttProtocol::V4, MqttProtocol::V5] {
+        let config = ConfigMqtt {
+            presence_topic: Some("trucks/7/presence".to_string()),
+            protocol: protocol.clone(),
+            ..Default::default()
+        };
+        let mut mqtt = MqttOutput::new("test", &config, Arc::new(MqttStats::default())).unwrap();
+        let will = match &mqtt.options {
+            Options::V4(options) => options.last_will().map(|will| (will.topic, will.message.to_vec(), will.retain)),
+            Options::V5(options) => options.last_will().map(|will| {
+                (String::from_utf8_lossy(&will.topic).to_string(), will.message.to_vec(), will.retain)
+            }),
+        };
+        assert_eq!(will, Some(("trucks/7/presence".to_string(), b"offline".to_vec(), true)));
+
+        // the retained "online" goes out first on every connection, it is no chunk
+        mqtt.handle(SessionEvent::Connected(0, false)).unwrap();
+        assert!(matches!(mqtt.handle(SessionEvent::Published(1)), Ok(None)));
+        assert_eq!(mqtt.pending(), 0);
+        assert!(matches!(mqtt.handle(SessionEvent::PubAck(1)), Ok(None)));
+        assert!(mqtt.inflight.is_empty());
+    }
+}
@@ -0,0 +1,618 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use chrono::prelude::*;
+use log::{error, warn};
+use parquet::basic::{Compression, GzipLevel, Type as PhysicalType, ZstdLevel};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int32Type, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+use parquet::schema::types::{ColumnPath, SchemaDescriptor, Type};
+use socketcan::dump::Reader;
+use tokio::select;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{self, Duration};
+
+use crate::config::{ConfigParquet, ParquetCompression};
+use crate::dbc::Dbc;
+use crate::message::{CanMessage, Chunk, GpsMessage, Message};
+use crate::output::binlog::{is_binary, ChunkReader};
+use crate::output::capture::CaptureFrame;
+use crate::output::file::{open_log, FileLogger};
+
+const CAN: &str = "message can {
+    REQUIRED INT64 time (TIMESTAMP(MICROS, true));
+    REQUIRED BYTE_ARRAY channel (STRING);
+    REQUIRED INT32 id (INTEGER(32, false));
+    REQUIRED BOOLEAN extended;
+    REQUIRED BOOLEAN remote;
+    REQUIRED BOOLEAN error;
+    REQUIRED BYTE_ARRAY data;
+}";
+
+const GPS: &str = "message gps {
+    REQUIRED INT64 time (TIMESTAMP(MICROS, true));
+    REQUIRED DOUBLE latitude;
+    REQUIRED DOUBLE longitude;
+    REQUIRED DOUBLE speed;
+}";
+
+const SIGNALS: &str = "message signals {
+    REQUIRED INT64 time (TIMESTAMP(MICROS, true));
+    REQUIRED BYTE_ARRAY channel (STRING);
+    REQUIRED INT32 id (INTEGER(32, false));
+    REQUIRED BYTE_ARRAY message (STRING);
+    REQUIRED BYTE_ARRAY signal (STRING);
+    REQUIRED INT64 raw;
+    REQUIRED DOUBLE value;
+    REQUIRED BYTE_ARRAY unit (STRING);
+}";
+
+/// Columns with few distinct values, the rest is plain encoded.
+// unfinished tables kept of each, they are not rotated or uploaded
+const UNFINISHED_KEEP: usize = 4;
+
+const DICTIONARY: [&str; 5] = ["channel", "id", "message", "signal", "unit"];
+
+/// A value of a row, in schema order.
+enum Value {
+    Boolean(bool),
+    Int32(i32),
+    Int64(i64),
+    Double(f64),
+    Bytes(ByteArray),
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Bytes(ByteArray::from(s))
+    }
+}
+
+/// Values of a column waiting for the next row group.
+enum Column {
+    Boolean(Vec<bool>),
+    Int32(Vec<i32>),
+    Int64(Vec<i64>),
+    Double(Vec<f64>),
+    Bytes(Vec<ByteArray>),
+}
+
+impl Column {
+    fn new(kind: PhysicalType) -> Self {
+        match kind {
+            PhysicalType::BOOLEAN => Column::Boolean(Vec::new()),
+            PhysicalType::INT32 => Column::Int32(Vec::new()),
+            PhysicalType::INT64 => Column::Int64(Vec::new()),
+            PhysicalType::DOUBLE => Column::Double(Vec::new()),
+            _ => Column::Bytes(Vec::new()),
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        match (self, value) {
+            (Column::Boolean(column), Value::Boolean(value)) => column.push(value),
+            (Column::Int32(column), Value::Int32(value)) => column.push(value),
+            (Column::Int64(column), Value::Int64(value)) => column.push(value),
+            (Column::Double(column), Value::Double(value)) => column.push(value),
+            (Column::Bytes(column), Value::Bytes(value)) => column.push(value),
+            _ => unreachable!("value does not match the schema"),
+        }
+    }
+
+    fn write(&self, writer: &mut SerializedColumnWriter) -> Result<usize, ParquetError> {
+        match self {
+            Column::Boolean(column) => writer.typed::<BoolType>().write_batch(column, None, None),
+            Column::Int32(column) => writer.typed::<Int32Type>().write_batch(column, None, None),
+            Column::Int64(column) => writer.typed::<Int64Type>().write_batch(column, None, None),
+            Column::Double(column) => writer.typed::<DoubleType>().write_batch(column, None, None),
+            Column::Bytes(column) => writer.typed::<ByteArrayType>().write_batch(column, None, None),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Column::Boolean(column) => column.clear(),
+            Column::Int32(column) => column.clear(),
+            Column::Int64(column) => column.clear(),
+            Column::Double(column) => column.clear(),
+            Column::Bytes(column) => column.clear(),
+        }
+    }
+}
+
+/// What the file writer writes to, taken out after every row group.
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Shared {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Target {
+    Logger(FileLogger),    // rotated, a file is closed once it has its footer
+    File(File),         // one file, for conversions
+}
+
+impl Target {
+    fn write(&mut self, data: &[u8]) {
+        match self {
+            Target::Logger(logger) => logger.write_raw(data),
+            Target::File(file) => {
+                if let Err(e) = file.write_all(data) {
+                    error!("parquet: {}", e);
+                }
+            },
+        }
+    }
+
+    fn full(&self) -> bool {
+        match self {
+            Target::Logger(logger) => logger.full(1),
+            Target::File(_) => false,
+        }
+    }
+
+    fn close(&mut self) {
+        if let Target::Logger(logger) = self {
+            logger.close(|_| Ok(()));
+        }
+    }
+}
+
+/// A table of rows buffered by column, written as a row group when the hour
+/// of the rows changes or there are enough of them.
+struct Table {
+    name: &'static str,
+    schema: Arc<Type>,
+    properties: Arc<WriterProperties>,
+    columns: Vec<Column>,
+    rows: usize,
+    limit: usize,
+    hour: i64,          // of the buffered rows, in hours since the epoch
+    buffer: Shared,
+    writer: Option<SerializedFileWriter<Shared>>,   // of the open file
+    target: Target,
+}
+
+impl Table {
+    fn new(name: &'static str, schema: &str, config: &ConfigParquet, target: Target) -> Self {
+        let schema = Arc::new(parse_message_type(schema).unwrap());
+        let descriptor = SchemaDescriptor::new(schema.clone());
+        let compression = match config.compression {
+            ParquetCompression::NONE => Compression::UNCOMPRESSED,
+            ParquetCompression::SNAPPY => Compression::SNAPPY,
+            ParquetCompression::GZIP => Compression::GZIP(GzipLevel::default()),
+            ParquetCompression::ZSTD => Compression::ZSTD(ZstdLevel::default()),
+        };
+        let mut properties = WriterProperties::builder()
+            .set_created_by(format!("iot-edge {}", env!("CARGO_PKG_VERSION")))
+            .set_compression(compression)
+            .set_dictionary_enabled(false);
+        for column in descriptor.columns().iter().filter(|column| DICTIONARY.contains(&column.name())) {
+            properties = properties.set_column_dictionary_enabled(ColumnPath::from(column.name()), true);
+        }
+
+        Table {
+            name,
+            columns: descriptor.columns().iter().map(|column| Column::new(column.physical_type())).collect(),
+            schema,
+            properties: Arc::new(properties.build()),
+            rows: 0,
+            limit: config.row_group_rows,
+            hour: 0,
+            buffer: Shared::default(),
+            writer: None,
+            target,
+        }
+    }
+
+    fn push(&mut self, time: DateTime<Utc>, row: Vec<Value>) {
+        let hour = time.timestamp().div_euclid(3600);
+        if (self.rows > 0) & ((hour != self.hour) | (self.rows >= self.limit)) {
+            self.flush();
+        }
+        self.hour = hour;
+        let row = std::iter::once(Value::Int64(time.timestamp_micros())).chain(row);
+        for (column, value) in self.columns.iter_mut().zip(row) {
+            column.push(value);
+        }
+        self.rows += 1;
+    }
+
+    fn row_group(&mut self) -> Result<(), ParquetError> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        if self.writer.is_none() {
+            self.writer = Some(SerializedFileWriter::new(self.buffer.clone(), self.schema.clone(), self.properties.clone())?);
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            let mut group = writer.next_row_group()?;
+            for column in self.columns.iter() {
+                let mut writer = group.next_column()?
+                    .ok_or_else(|| ParquetError::General("more columns than the schema".to_string()))?;
+                column.write(&mut writer)?;
+                writer.close()?;
+            }
+            group.close()?;
+        }
+        Ok(())
+    }
+
+    fn write_rows(&mut self) {
+        if let Err(e) = self.row_group() {
+            error!("parquet: {}: {}", self.name, e);
+        }
+        self.columns.iter_mut().for_each(Column::clear);
+        self.rows = 0;
+        let data = self.buffer.take();
+        self.target.write(&data);
+    }
+
+    /// Write the buffered rows, and close the file when it is due.
+    fn flush(&mut self) {
+        self.write_rows();
+        if self.target.full() {
+            self.finish();
+        }
+    }
+
+    /// Write the buffered rows and the footer.
+    fn finish(&mut self) {
+        self.write_rows();
+        if let Some(writer) = self.writer.take() {
+            if let Err(e) = writer.close() {
+                error!("parquet: {}: {}", self.name, e);
+            }
+            let data = self.buffer.take();
+            self.target.write(&data);
+        }
+        self.target.close();
+    }
+}
+
+/// CAN frames, GPS fixes and the signals a DBC decodes from the frames, as
+/// Parquet tables with a row group per hour at most.
+pub struct ParquetExport {
+    can: Table,
+    gps: Table,
+    signals: Option<Table>,     // with a DBC to decode frames with
+    dbc: Dbc,
+}
+
+impl ParquetExport {
+    fn open(config: &ConfigParquet, dbc: Dbc, mut target: impl FnMut(&str) -> io::Result<Target>) -> io::Result<Self> {
+        let signals = match dbc.messages.is_empty() {
+            true => None,
+            false => Some(Table::new("signals", SIGNALS, config, target("signals")?)),
+        };
+        Ok(ParquetExport {
+            can: Table::new("can", CAN, config, target("can")?),
+            gps: Table::new("gps", GPS, config, target("gps")?),
+            signals,
+            dbc,
+        })
+    }
+
+    /// Rotated files as the config has them.
+    pub fn new(config: &ConfigParquet, dbc: Dbc, device_id: &str) -> io::Result<Self> {
+        ParquetExport::open(config, dbc, |name| {
+            // finished files are closed right away, one still here lost its footer in a crash
+            set_aside(&config.table(name))?;
+            Ok(Target::Logger(FileLogger::new(&config.log_config(name), device_id)))
+        })
+    }
+
+    /// A file per table, named after the config path, for conversions.
+    pub fn create(config: &ConfigParquet, dbc: Dbc) -> io::Result<Self> {
+        ParquetExport::open(config, dbc, |name| Ok(Target::File(File::create(config.table(name))?)))
+    }
+
+    pub fn chunk(&mut self, chunk: &Chunk) {
+        for msg in chunk.can() {
+            self.frame(&CaptureFrame::from(msg));
+        }
+        for fix in chunk.gps() {
+            self.fix(fix);
+        }
+    }
+
+    pub fn frame(&mut self, frame: &CaptureFrame) {
+        self.can.push(frame.time, vec![
+            frame.channel.as_str().into(),
+            Value::Int32(frame.id as i32),
+            Value::Boolean(frame.extended),
+            Value::Boolean(frame.remote),
+            Value::Boolean(frame.error),
+            Value::Bytes(ByteArray::from(frame.data.clone())),
+        ]);
+
+        let message = self.dbc.find(frame.id, frame.extended).filter(|_| !frame.remote & !frame.error);
+        if let (Some(signals), Some(message)) = (self.signals.as_mut(), message) {
+            for signal in message.signals.iter().filter(|signal| message.present(signal, &frame.data)) {
+                if let Some(raw) = signal.raw(&frame.data) {
+                    signals.push(frame.time, vec![
+                        frame.channel.as_str().into(),
+                        Value::Int32(frame.id as i32),
+                        message.name.as_str().into(),
+                        signal.name.as_str().into(),
+                        Value::Int64(raw),
+                        Value::Double(signal.value(raw)),
+                        signal.unit.as_str().into(),
+                    ]);
+                }
+            }
+        }
+    }
+
+    pub fn fix(&mut self, fix: &GpsMessage) {
+        self.gps.push(fix.time, vec![
+            Value::Double(fix.latitude),
+            Value::Double(fix.longitude),
+            Value::Double(fix.speed),
+        ]);
+    }
+
+    fn tables(&mut self) -> impl Iterator<Item = &mut Table> {
+        [Some(&mut self.can), Some(&mut self.gps), self.signals.as_mut()].into_iter().flatten()
+    }
+
+    /// Write out an hour that has gone by and close files that are due.
+    pub fn tick(&mut self) {
+        let hour = Utc::now().timestamp().div_euclid(3600);
+        for table in self.tables() {
+            if (table.rows > 0) & (table.hour < hour) {
+                table.flush();
+            } else if table.target.full() {
+                table.finish();
+            }
+        }
+    }
+
+    /// Write what is buffered and the footers.
+    pub fn finish(&mut self) {
+        for table in self.tables() {
+            table.finish();
+        }
+    }
+}
+
+impl Drop for ParquetExport {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Feeds the export with the chunks the output stores.
+pub struct ParquetTask {
+    export: ParquetExport,
+    rx: Receiver<Chunk>,
+}
+
+impl ParquetTask {
+    pub fn new(export: ParquetExport, rx: Receiver<Chunk>) -> Self {
+        ParquetTask { export, rx }
+    }
+
+    pub async fn run(mut self) {
+        let mut interval = time::interval(Duration::from_secs(1));
+        loop {
+            select! {
+                chunk = self.rx.recv() => match chunk {
+                    Some(chunk) => self.export.chunk(&chunk),
+                    None => break,
+                },
+                _ = interval.tick() => self.export.tick(),
+            }
+        }
+    }
+}
+
+/// Move a table without its footer out of the way of the rotation and the
+/// upload, its row groups can still be salvaged by hand. Only the last few
+/// are kept, a crash loop would fill the disk with them.
+fn set_aside(path: &Path) -> io::Result<()> {
+    let written = match fs::metadata(path) {
+        Ok(meta) if meta.len() > 0 => meta.modified().map_or_else(|_| Utc::now(), DateTime::<Utc>::from),
+        _ => return Ok(()),
+    };
+    let aside = path.with_file_name(format!(
+        "{}.{}.unfinished",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        written.format("%Y%m%dT%H%M%S")));
+    warn!("{}: unfinished, kept as {}", path.display(), aside.display());
+    fs::rename(path, aside)?;
+
+    let prefix = format!("{}.", path.file_name().unwrap_or_default().to_string_lossy());
+    let mut unfinished: Vec<_> = fs::read_dir(path.parent().unwrap_or(Path::new(".")))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|aside| {
+            let name = aside.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with(&prefix) & name.ends_with(".unfinished")
+        })
+        .collect();
+    // named after when they were written, oldest first
+    unfinished.sort();
+    for old in unfinished.iter().rev().skip(UNFINISHED_KEEP) {
+        warn!("{}: removed, more than {} unfinished", old.display(), UNFINISHED_KEEP);
+        fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+/// The CAN frames and GPS fixes of a JSON chunk.
+fn json_chunk(line: &str) -> io::Result<Chunk> {
+    let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+
+    let value: serde_json::Value = serde_json::from_str(line)?;
+    let mut chunk = Chunk::new(value["id"].as_str().unwrap_or_default());
+    for can in value["can"].as_array().into_iter().flatten() {
+        let dump = can["data"].as_str().unwrap_or_default();
+        let mut reader = Reader::from_reader(dump.as_bytes());
+        let record = reader.next_record()
+            .map_err(|e| invalid(&format!("{}: {:?}", dump, e)))?
+            .ok_or_else(|| invalid("empty CAN frame"))?;
+        chunk.push(Message::CAN(CanMessage {
+            time: Utc.timestamp_nanos(record.t_us as i64 * 1000),
+            channel: record.device.to_string(),
+            frame: record.frame,
+            // eight hex digits are an extended ID, whatever its value
+            extended: dump.split([' ', '#']).nth(2).is_some_and(|id| id.len() == 8),
+        }));
+    }
+    for gps in value["gps"].as_array().into_iter().flatten() {
+        let time = DateTime::parse_from_rfc3339(gps["ts"].as_str().unwrap_or_default())
+            .map_err(|e| invalid(&e.to_string()))?;
+        chunk.push(Message::GPS(GpsMessage {
+            time: time.with_timezone(&Utc),
+            longitude: gps["lon"].as_f64().unwrap_or_default(),
+            latitude: gps["lat"].as_f64().unwrap_or_default(),
+            speed: gps["speed"].as_f64().unwrap_or_default(),
+        }));
+    }
+    Ok(chunk)
+}
+
+/// Write the chunks of a log file, JSON or binary, as Parquet tables named
+/// after the config path. Returns the number of chunks.
+pub fn convert(log: &Path, config: &ConfigParquet, dbc: Dbc) -> io::Result<usize> {
+    let mut export = ParquetExport::create(config, dbc)?;
+    let mut reader = open_log(log)?;
+    let chunks: Box<dyn Iterator<Item = io::Result<Chunk>>> = match is_binary(&mut reader)? {
+        true => Box::new(ChunkReader::new(reader)?),
+        false => Box::new(reader.lines().map(|line| json_chunk(&line?))),
+    };
+    let mut count = 0;
+    for chunk in chunks {
+        export.chunk(&chunk?);
+        count += 1;
+    }
+    export.finish();
+    Ok(count)
+}
+
+#[test]
+fn test_export() {
+    use parquet::basic::Encoding;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+    use crate::output::file::test_dir;
+
+    let dir = test_dir("parquet");
+    let config = ConfigParquet {
+        path: dir.join("export.parquet").to_string_lossy().to_string(),
+        row_group_rows: 3,
+        ..Default::default()
+    };
+    let dbc = Dbc::parse(r#"
+BO_ 2364539904 EEC1: 8 Vector__XXX
+ SG_ EngineSpeed : 24|16@1+ (0.125,0) [0|8031.875] "rpm" Vector__XXX
+"#);
+    let mut export = ParquetExport::create(&config, dbc).unwrap();
+
+    let start = Utc.with_ymd_and_hms(2026, 10, 18, 10, 59, 58).unwrap();
+    let frame = CaptureFrame::sample(start, "can0", 0x0CF00400, &[0, 0, 0, 0x40, 0x1F, 0, 0, 0]);
+    // two frames before 11:00 and four after, in row groups of three at most
+    for second in 0..6 {
+        export.frame(&CaptureFrame { time: start + chrono::Duration::seconds(second), ..frame.clone() });
+    }
+    export.fix(&GpsMessage { time: start, latitude: 60.17, longitude: 24.94, speed: 12.5 });
+    export.finish();
+
+    let open = |name| SerializedFileReader::new(File::open(config.table(name)).unwrap()).unwrap();
+    let can = open("can");
+    let rows: Vec<i64> = can.metadata().row_groups().iter().map(|group| group.num_rows()).collect();
+    assert_eq!(rows, vec![2, 3, 1]);
+    let dictionary = |column: usize| can.metadata().row_group(0).column(column).encodings().iter()
+        .any(|encoding| matches!(encoding, Encoding::PLAIN_DICTIONARY | Encoding::RLE_DICTIONARY));
+    assert_eq!((dictionary(1), dictionary(2), dictionary(6)), (true, true, false));
+
+    let row = can.get_row_iter(None).unwrap().next().unwrap().unwrap();
+    assert_eq!(row.get_timestamp_micros(0).unwrap(), start.timestamp_micros());
+    assert_eq!((row.get_string(1).unwrap().as_str(), row.get_uint(2).unwrap()), ("can0", 0x0CF00400));
+    assert!(row.get_bool(3).unwrap() & !row.get_bool(4).unwrap());
+    assert_eq!(row.get_bytes(6).unwrap().data(), &frame.data[..]);
+
+    let signals = open("signals");
+    assert_eq!(signals.metadata().file_metadata().num_rows(), 6);
+    let row = signals.get_row_iter(None).unwrap().next().unwrap().unwrap();
+    assert_eq!((row.get_string(3).unwrap().as_str(), row.get_string(4).unwrap().as_str()), ("EEC1", "EngineSpeed"));
+    assert_eq!((row.get_long(5).unwrap(), row.get_double(6).unwrap()), (8000, 1000.0));
+    assert_eq!(row.get_string(7).unwrap(), "rpm");
+
+    let gps = open("gps");
+    let row = gps.get_row_iter(None).unwrap().next().unwrap().unwrap();
+    assert_eq!((row.get_double(1).unwrap(), row.get_double(3).unwrap()), (60.17, 12.5));
+
+    // JSON logs have the frames in candump format
+    let chunk = json_chunk(r#"{"time":"2026-10-18T10:00:00Z","id":"test",
+        "can":[{"data":"(1469439874.299591) can1 701#7F"}],
+        "gps":[{"ts":"2026-10-18T10:00:00Z","lon":24.94,"lat":60.17,"speed":12.5}]}"#).unwrap();
+    let frame = CaptureFrame::from(&chunk.can()[0]);
+    assert_eq!((frame.channel.as_str(), frame.id, frame.data), ("can1", 0x701, vec![0x7F]));
+    assert_eq!(frame.time, Utc.timestamp_nanos(1_469_439_874_299_591_000));
+    assert_eq!(chunk.gps()[0].latitude, 60.17);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_unfinished() {
+    use crate::output::closed_logs;
+    use crate::output::file::test_dir;
+
+    let dir = test_dir("parquet-unfinished");
+    let config = ConfigParquet {
+        path: dir.join("export.parquet").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    // row groups written, the footer never was
+    fs::write(config.table("can"), b"PAR1 row groups").unwrap();
+
+    let export = ParquetExport::new(&config, Dbc::default(), "test").unwrap();
+    drop(export);
+
+    let aside: Vec<_> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().ends_with(".unfinished"))
+        .collect();
+    assert_eq!(aside.len(), 1);
+    assert!(aside[0].file_name().unwrap().to_string_lossy().starts_with("export-can.parquet."));
+    assert_eq!(fs::read(&aside[0]).unwrap(), b"PAR1 row groups");
+    assert!(closed_logs(&config.table("can")).unwrap().is_empty());
+
+    // older ones go first
+    fs::remove_file(&aside[0]).unwrap();
+    for hour in 0..UNFINISHED_KEEP {
+        fs::write(dir.join(format!("export-can.parquet.20261018T{:02}0000.unfinished", hour)), b"").unwrap();
+    }
+    fs::write(config.table("can"), b"PAR1 row groups").unwrap();
+    drop(ParquetExport::new(&config, Dbc::default(), "test").unwrap());
+    let mut aside: Vec<_> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|name| name.ends_with(".unfinished"))
+        .collect();
+    aside.sort();
+    assert_eq!(aside.len(), UNFINISHED_KEEP);
+    assert_eq!(aside[0], "export-can.parquet.20261018T010000.unfinished");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
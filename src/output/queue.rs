@@ -0,0 +1,456 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use log::{info, warn};
+
+use crate::config::ConfigQueue;
+
+const HEADER: u64 = 8;      // payload length + crc32, little endian
+const ACK_FILE: &str = "ack";
+
+struct Segment {
+    base: u64,              // sequence number of the first record
+    path: PathBuf,
+    size: u64,
+    offsets: Vec<u64>,      // start of every record in the file
+}
+
+impl Segment {
+    fn end(&self) -> u64 {
+        self.base + self.offsets.len() as u64
+    }
+
+    /// Scan an existing segment, cutting off a record torn by power loss.
+    fn load(base: u64, path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+
+        let mut offsets = Vec::new();
+        let mut pos = 0;
+        while pos + HEADER <= len {
+            let mut header = [0u8; HEADER as usize];
+            reader.read_exact(&mut header)?;
+            let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+            let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            if pos + HEADER + size > len {
+                break;
+            }
+
+            let mut payload = vec![0u8; size as usize];
+            reader.read_exact(&mut payload)?;
+            if crc32fast::hash(&payload) != crc {
+                break;
+            }
+            offsets.push(pos);
+            pos += HEADER + size;
+        }
+
+        if pos < len {
+            warn!("{}: dropping {} bytes of torn data", path.display(), len - pos);
+            file.set_len(pos)?;
+            file.sync_all()?;
+        }
+
+        Ok(Segment { base, path, size: pos, offsets })
+    }
+
+    fn read(&self, seq: u64) -> io::Result<Vec<u8>> {
+        let offset = self.offsets[(seq - self.base) as usize];
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut header = [0u8; HEADER as usize];
+        file.read_exact(&mut header)?;
+        let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let mut payload = vec![0u8; size as usize];
+        file.read_exact(&mut payload)?;
+
+        Ok(payload)
+    }
+}
+
+/// Append-only store of encoded chunks waiting for the broker.
+///
+/// Chunks live in numbered segment files, a record is `len | crc32 | payload`.
+/// The `ack` file holds the first sequence number not yet acknowledged and is
+/// only ever replaced atomically, so a power cut can at worst replay chunks.
+/// Acknowledgements reach it through `sync_ack`, not one write per PUBACK.
+pub struct DiskQueue {
+    dir: PathBuf,
+    segment_size: u64,
+    max_size: u64,
+
+    segments: VecDeque<Segment>,
+    writer: Option<File>,   // appends to the last segment
+    acked: u64,             // everything below is acknowledged
+    synced: u64,            // acked as the ack file has it
+    next: u64,              // next record to hand out for sending
+    dropped: u64,           // evicted before being acknowledged
+    lost: Vec<(u64, Vec<u8>)>,  // evicted since the last take_lost
+}
+
+impl DiskQueue {
+    pub fn open(config: &ConfigQueue) -> io::Result<Self> {
+        let dir = PathBuf::from(&config.path);
+        fs::create_dir_all(&dir)?;
+
+        let size = |s: &str| bytesize::ByteSize::from_str(s)
+            .map(|size| size.as_u64())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+
+        let mut queue = DiskQueue {
+            segment_size: size(&config.segment_size)?,
+            max_size: size(&config.max_size)?,
+            segments: VecDeque::new(),
+            writer: None,
+            acked: read_ack(&dir),
+            synced: 0,
+            next: 0,
+            dropped: 0,
+            lost: Vec::new(),
+            dir,
+        };
+
+        let mut bases: Vec<u64> = fs::read_dir(&queue.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                name.strip_suffix(".seg")?.parse().ok()
+            })
+            .collect();
+        bases.sort_unstable();
+
+        for base in bases {
+            let segment = Segment::load(base, queue.segment_path(base))?;
+            if segment.end() <= queue.acked {
+                fs::remove_file(&segment.path)?;
+                continue;
+            }
+            queue.segments.push_back(segment);
+        }
+
+        if let Some(first) = queue.segments.front() {
+            queue.acked = queue.acked.max(first.base);
+        }
+        queue.next = queue.acked;
+        queue.synced = queue.acked;
+
+        if queue.pending() > 0 {
+            info!("queue {}: {} chunks waiting for upload", queue.dir.display(), queue.pending());
+        }
+
+        Ok(queue)
+    }
+
+    fn segment_path(&self, base: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.seg", base))
+    }
+
+    /// Sequence number the next chunk gets.
+    pub fn end(&self) -> u64 {
+        match self.segments.back() {
+            Some(segment) => segment.end(),
+            None => self.acked,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    /// Chunks stored but not acknowledged yet.
+    pub fn pending(&self) -> u64 {
+        self.end() - self.acked
+    }
+
+    #[allow(dead_code)]
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        let base = self.end();
+        let path = self.segment_path(base);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        File::open(&self.dir)?.sync_all()?;
+
+        self.segments.push_back(Segment { base, path, size: 0, offsets: Vec::new() });
+        self.writer = Some(file);
+        Ok(())
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> io::Result<u64> {
+        let full = match self.segments.back() {
+            Some(segment) => (segment.size >= self.segment_size) & !segment.offsets.is_empty(),
+            None => true,
+        };
+        if full {
+            self.roll()?;
+        } else if self.writer.is_none() {
+            // after a restart the last segment takes the appends, a new one
+            // would have its base and the same file
+            let path = &self.segments.back().unwrap().path;
+            self.writer = Some(OpenOptions::new().append(true).open(path)?);
+        }
+
+        let mut record = Vec::with_capacity(HEADER as usize + data.len());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        record.extend_from_slice(data);
+
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write_all(&record)?;
+            writer.sync_data()?;
+        }
+
+        let segment = self.segments.back_mut().unwrap();
+        segment.offsets.push(segment.size);
+        segment.size += record.len() as u64;
+        let seq = segment.end() - 1;
+
+        self.evict()?;
+        Ok(seq)
+    }
+
+    /// Drop the oldest segments while the queue is over its size cap.
+    fn evict(&mut self) -> io::Result<()> {
+        while self.size() > self.max_size && self.segments.len() > 1 {
+            let segment = self.segments.pop_front().unwrap();
+            let lost = segment.end().saturating_sub(self.acked.max(segment.base));
+            if lost > 0 {
+                warn!("queue full, dropping {} unsent chunks", lost);
+                self.dropped += lost;
+                for seq in self.acked.max(segment.base)..segment.end() {
+                    self.lost.push((seq, segment.read(seq)?));
+                }
+            }
+            fs::remove_file(&segment.path)?;
+
+            self.acked = self.acked.max(segment.end());
+            self.next = self.next.max(self.acked);
+            self.sync_ack()?;
+        }
+        Ok(())
+    }
+
+    /// Write the acknowledgements since the last call to the ack file.
+    pub fn sync_ack(&mut self) -> io::Result<()> {
+        if self.synced != self.acked {
+            write_ack(&self.dir, self.acked)?;
+            self.synced = self.acked;
+        }
+        Ok(())
+    }
+
+    /// Chunks evicted unacknowledged since the last call.
+    pub fn take_lost(&mut self) -> Vec<(u64, Vec<u8>)> {
+        std::mem::take(&mut self.lost)
+    }
+
+    /// Chunk `seq` if it is still stored and not acknowledged.
+    pub fn get(&self, seq: u64) -> io::Result<Option<Vec<u8>>> {
+        if seq < self.acked {
+            return Ok(None);
+        }
+        match self.segments.iter().find(|s| (s.base <= seq) & (seq < s.end())) {
+            Some(segment) => segment.read(seq).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Next chunk to send, in order.
+    pub fn next_unsent(&mut self) -> io::Result<Option<(u64, Vec<u8>)>> {
+        let seq = self.next;
+        let segment = self.segments.iter().find(|s| (s.base <= seq) & (seq < s.end()));
+        match segment {
+            Some(segment) => {
+                let data = segment.read(seq)?;
+                self.next += 1;
+                Ok(Some((seq, data)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Acknowledge every chunk up to and including `seq`.
+    pub fn ack(&mut self, seq: u64) -> io::Result<()> {
+        if seq < self.acked {
+            return Ok(());
+        }
+        self.acked = seq + 1;
+
+        // keep the last segment, it is still being appended to
+        while self.segments.len() > 1 && self.segments[0].end() <= self.acked {
+            let segment = self.segments.pop_front().unwrap();
+            fs::remove_file(&segment.path)?;
+        }
+        Ok(())
+    }
+
+    /// Start sending again from the oldest unacknowledged chunk.
+    pub fn rewind(&mut self) {
+        self.next = self.acked;
+    }
+}
+
+impl Drop for DiskQueue {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync_ack() {
+            warn!("queue {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+fn read_ack(dir: &Path) -> u64 {
+    let mut buf = [0u8; 12];
+    match File::open(dir.join(ACK_FILE)).and_then(|mut f| f.read_exact(&mut buf)) {
+        Ok(()) => {
+            let seq = u64::from_le_bytes(buf[..8].try_into().unwrap());
+            let crc = u32::from_le_bytes(buf[8..].try_into().unwrap());
+            match crc32fast::hash(&buf[..8]) == crc {
+                true => seq,
+                false => {
+                    warn!("{}: corrupt ack file, replaying the queue", dir.display());
+                    0
+                }
+            }
+        },
+        Err(_) => 0,
+    }
+}
+
+fn write_ack(dir: &Path, seq: u64) -> io::Result<()> {
+    let mut buf = seq.to_le_bytes().to_vec();
+    buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+
+    let tmp = dir.join(format!("{}.tmp", ACK_FILE));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(ACK_FILE))?;
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+fn test_config(name: &str, segment_size: &str, max_size: &str) -> ConfigQueue {
+    let path = std::env::temp_dir().join(format!("iot-edge-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+
+    ConfigQueue {
+        path: path.to_string_lossy().to_string(),
+        segment_size: segment_size.to_string(),
+        max_size: max_size.to_string(),
+        drain_rate: 0,
+    }
+}
+
+#[test]
+fn test_replay() {
+    let config = test_config("replay", "64", "1M");
+
+    let mut queue = DiskQueue::open(&config).unwrap();
+    for idx in 0..10u8 {
+        queue.push(&[idx; 20]).unwrap();
+    }
+    assert_eq!(queue.next_unsent().unwrap(), Some((0, vec![0; 20])));
+    assert_eq!(queue.next_unsent().unwrap(), Some((1, vec![1; 20])));
+    assert_eq!(queue.next_unsent().unwrap(), Some((2, vec![2; 20])));
+    queue.ack(1).unwrap();
+    assert_eq!(read_ack(Path::new(&config.path)), 0);
+    queue.sync_ack().unwrap();
+    assert_eq!(read_ack(Path::new(&config.path)), 2);
+    drop(queue);
+
+    // chunk 2 was sent but never acknowledged
+    let mut queue = DiskQueue::open(&config).unwrap();
+    assert_eq!(queue.pending(), 8);
+    assert_eq!(queue.next_unsent().unwrap(), Some((2, vec![2; 20])));
+    assert_eq!(queue.get(5).unwrap(), Some(vec![5; 20]));
+    assert_eq!(queue.get(1).unwrap(), None);
+    queue.rewind();
+    assert_eq!(queue.next_unsent().unwrap(), Some((2, vec![2; 20])));
+
+    queue.ack(9).unwrap();
+    assert_eq!(queue.pending(), 0);
+    assert_eq!(queue.next_unsent().unwrap(), None);
+    assert_eq!(queue.push(b"after").unwrap(), 10);
+
+    fs::remove_dir_all(&config.path).unwrap();
+}
+
+#[test]
+fn test_torn_write() {
+    let config = test_config("torn", "1M", "1M");
+
+    let mut queue = DiskQueue::open(&config).unwrap();
+    queue.push(b"first").unwrap();
+    queue.push(b"second").unwrap();
+    drop(queue);
+
+    // power cut in the middle of the third record
+    let segment = Path::new(&config.path).join(format!("{:020}.seg", 0));
+    let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+    file.write_all(&[100, 0, 0, 0, 1, 2, 3, 4, b't', b'o']).unwrap();
+    drop(file);
+
+    let mut queue = DiskQueue::open(&config).unwrap();
+    assert_eq!(queue.pending(), 2);
+    assert_eq!(queue.push(b"third").unwrap(), 2);
+    assert_eq!(queue.next_unsent().unwrap(), Some((0, b"first".to_vec())));
+    assert_eq!(queue.next_unsent().unwrap(), Some((1, b"second".to_vec())));
+    assert_eq!(queue.next_unsent().unwrap(), Some((2, b"third".to_vec())));
+
+    fs::remove_dir_all(&config.path).unwrap();
+}
+
+#[test]
+fn test_eviction() {
+    let config = test_config("evict", "100", "300");
+
+    let mut queue = DiskQueue::open(&config).unwrap();
+    for idx in 0..20u8 {
+        queue.push(&[idx; 42]).unwrap();
+    }
+    assert!(queue.dropped() > 0);
+    assert_eq!(queue.pending() + queue.dropped(), 20);
+    let lost = queue.take_lost();
+    assert_eq!(lost.len() as u64, queue.dropped());
+    assert_eq!(lost[0], (0, vec![0; 42]));
+    assert!(queue.take_lost().is_empty());
+
+    // the oldest surviving chunk comes out first
+    let (seq, data) = queue.next_unsent().unwrap().unwrap();
+    assert_eq!(seq, queue.dropped());
+    assert_eq!(data, vec![seq as u8; 42]);
+
+    fs::remove_dir_all(&config.path).unwrap();
+}
+
+#[test]
+fn test_reopen_empty_segment() {
+    let config = test_config("reopen", "64", "1M");
+
+    let mut queue = DiskQueue::open(&config).unwrap();
+    queue.push(&[1; 60]).unwrap();
+    drop(queue);
+    // rolled to the next segment, then cut before anything went in
+    let empty = Path::new(&config.path).join(format!("{:020}.seg", 1));
+    File::create(&empty).unwrap();
+
+    let mut queue = DiskQueue::open(&config).unwrap();
+    assert_eq!(queue.push(b"second").unwrap(), 1);
+    assert_eq!(queue.push(b"third").unwrap(), 2);
+    assert_eq!(queue.segments.len(), 2);
+    queue.ack(0).unwrap();
+    assert!(empty.exists());
+    drop(queue);
+
+    let mut queue = DiskQueue::open(&config).unwrap();
+    assert_eq!(queue.next_unsent().unwrap(), Some((1, b"second".to_vec())));
+    assert_eq!(queue.next_unsent().unwrap(), Some((2, b"third".to_vec())));
+
+    fs::remove_dir_all(&config.path).unwrap();
+}
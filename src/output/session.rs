@@ -0,0 +1,152 @@
+use log::debug;
+use rumqttc::{
+    self,
+    v5,
+    v5::mqttbytes::v5::{Packet as Packet5, PubAckReason, PubRecReason, PubCompReason, PublishProperties},
+    Event,
+    Packet,
+    Outgoing,
+    QoS,
+};
+
+use crate::errors::IotEdgeError;
+
+/// Connection options for either protocol version.
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]    // there is one per output
+pub enum Options {
+    V4(rumqttc::MqttOptions),
+    V5(v5::MqttOptions),
+}
+
+/// Publish properties, only sent with MQTT 5.
+#[derive(Clone, Debug, Default)]
+pub struct Properties {
+    pub content_type: Option<String>,
+    pub message_expiry: Option<u32>,
+    pub topic_alias: Option<u16>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl From<Properties> for PublishProperties {
+    fn from(properties: Properties) -> Self {
+        PublishProperties {
+            content_type: properties.content_type,
+            message_expiry_interval: properties.message_expiry,
+            topic_alias: properties.topic_alias,
+            user_properties: properties.user_properties,
+            ..Default::default()
+        }
+    }
+}
+
+/// What the output needs to know from the event loop, whatever the protocol.
+#[derive(Debug)]
+pub enum SessionEvent {
+    Connected(u16, bool),       // topic aliases the broker accepts, whether it kept our session
+    Published(u16),
+    PubAck(u16),
+    PubRec(u16),
+    PubComp(u16),
+    Rejected(u16, String),      // acknowledgement with a failure reason code
+    Message(String, Vec<u8>),   // publish on one of our subscriptions
+    Other,
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum Session {
+    V4(rumqttc::AsyncClient, rumqttc::EventLoop),
+    V5(v5::AsyncClient, v5::EventLoop),
+}
+
+fn qos5(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+impl Session {
+    pub fn new(options: &Options, cap: usize) -> Self {
+        match options {
+            Options::V4(options) => {
+                let (client, eventloop) = rumqttc::AsyncClient::new(options.clone(), cap);
+                Session::V4(client, eventloop)
+            },
+            Options::V5(options) => {
+                let (client, eventloop) = v5::AsyncClient::new(options.clone(), cap);
+                Session::V5(client, eventloop)
+            },
+        }
+    }
+
+    pub fn try_publish(
+        &self, topic: &str, qos: QoS, retain: bool, data: Vec<u8>, properties: Properties
+    ) -> Result<(), IotEdgeError> {
+        match self {
+            Session::V4(client, _) => client.try_publish(topic, qos, retain, data)?,
+            Session::V5(client, _) => {
+                client.try_publish_with_properties(topic, qos5(qos), retain, data, properties.into())?
+            },
+        }
+        Ok(())
+    }
+
+    pub fn try_subscribe(&self, topic: &str, qos: QoS) -> Result<(), IotEdgeError> {
+        match self {
+            Session::V4(client, _) => client.try_subscribe(topic, qos)?,
+            Session::V5(client, _) => client.try_subscribe(topic, qos5(qos))?,
+        }
+        Ok(())
+    }
+
+    pub async fn poll(&mut self) -> Result<SessionEvent, IotEdgeError> {
+        match self {
+            Session::V4(_, eventloop) => Ok(match eventloop.poll().await? {
+                Event::Outgoing(Outgoing::Publish(pkid)) => SessionEvent::Published(pkid),
+                Event::Incoming(Packet::ConnAck(ack)) => SessionEvent::Connected(0, ack.session_present),
+                Event::Incoming(Packet::PubAck(ack)) => SessionEvent::PubAck(ack.pkid),
+                Event::Incoming(Packet::PubRec(rec)) => SessionEvent::PubRec(rec.pkid),
+                Event::Incoming(Packet::PubComp(comp)) => SessionEvent::PubComp(comp.pkid),
+                Event::Incoming(Packet::Publish(publish)) => {
+                    SessionEvent::Message(publish.topic, publish.payload.to_vec())
+                },
+                event => {
+                    debug!("Received = {:?}", event);
+                    SessionEvent::Other
+                }
+            }),
+            Session::V5(_, eventloop) => Ok(match eventloop.poll().await? {
+                v5::Event::Outgoing(Outgoing::Publish(pkid)) => SessionEvent::Published(pkid),
+                v5::Event::Incoming(Packet5::ConnAck(ack)) => {
+                    let aliases = ack.properties.and_then(|p| p.topic_alias_max).unwrap_or(0);
+                    SessionEvent::Connected(aliases, ack.session_present)
+                },
+                v5::Event::Incoming(Packet5::PubAck(ack)) => match ack.reason {
+                    PubAckReason::Success | PubAckReason::NoMatchingSubscribers => SessionEvent::PubAck(ack.pkid),
+                    reason => SessionEvent::Rejected(ack.pkid, format!("{:?}", reason)),
+                },
+                v5::Event::Incoming(Packet5::PubRec(rec)) => match rec.reason {
+                    PubRecReason::Success | PubRecReason::NoMatchingSubscribers => SessionEvent::PubRec(rec.pkid),
+                    reason => SessionEvent::Rejected(rec.pkid, format!("{:?}", reason)),
+                },
+                v5::Event::Incoming(Packet5::PubComp(comp)) => match comp.reason {
+                    PubCompReason::Success => SessionEvent::PubComp(comp.pkid),
+                    reason => SessionEvent::Rejected(comp.pkid, format!("{:?}", reason)),
+                },
+                v5::Event::Incoming(Packet5::Publish(publish)) => {
+                    let topic = String::from_utf8_lossy(&publish.topic).to_string();
+                    SessionEvent::Message(topic, publish.payload.to_vec())
+                },
+                v5::Event::Incoming(Packet5::Disconnect(disconnect)) => {
+                    return Err(IotEdgeError::MqttDisconnectError(format!("{:?}", disconnect.reason_code)));
+                },
+                event => {
+                    debug!("Received = {:?}", event);
+                    SessionEvent::Other
+                }
+            }),
+        }
+    }
+}
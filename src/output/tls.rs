@@ -0,0 +1,190 @@
+use std::fs;
+use std::io::{self, BufReader};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::debug;
+use pkcs8::{Document, EncryptedPrivateKeyInfo};
+use tokio::net::{TcpStream, UnixListener};
+use tokio::task::{self, JoinHandle};
+use rumqttc::TlsConfiguration;
+use rumqttc::tokio_rustls::TlsConnector;
+use rumqttc::tokio_rustls::rustls::{
+    ClientConfig,
+    RootCertStore,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+};
+
+use crate::config::ConfigMqttTls;
+use crate::errors::IotEdgeError;
+
+fn read(path: &str) -> Result<Vec<u8>, IotEdgeError> {
+    fs::read(path).map_err(|e| IotEdgeError::MqttTlsError(format!("read {}: {}", path, e)))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, IotEdgeError> {
+    let pem = read(path)?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(pem.as_slice()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| IotEdgeError::MqttTlsError(format!("parse {}: {}", path, e)))?;
+    match certs.is_empty() {
+        true => Err(IotEdgeError::MqttTlsError(format!("no certificates in {}", path))),
+        false => Ok(certs),
+    }
+}
+
+fn load_key(path: &str, password: Option<&str>) -> Result<PrivateKeyDer<'static>, IotEdgeError> {
+    let pem = read(path)?;
+    let invalid = |e: &dyn std::fmt::Display| IotEdgeError::MqttTlsError(format!("parse {}: {}", path, e));
+
+    let password = match password {
+        Some(password) => password,
+        None => {
+            return rustls_pemfile::private_key(&mut BufReader::new(pem.as_slice()))
+                .map_err(|e| invalid(&e))?
+                .ok_or_else(|| IotEdgeError::MqttTlsError(format!("no private key in {}", path)));
+        }
+    };
+
+    // rustls only takes plain keys, decrypt PKCS#8 ourselves
+    let pem = String::from_utf8(pem).map_err(|e| invalid(&e))?;
+    let (label, doc) = Document::from_pem(&pem).map_err(|e| invalid(&e))?;
+    if label != "ENCRYPTED PRIVATE KEY" {
+        return Err(IotEdgeError::MqttTlsError(format!("{} is not an encrypted PKCS#8 key", path)));
+    }
+    let encrypted = EncryptedPrivateKeyInfo::try_from(doc.as_bytes()).map_err(|e| invalid(&e))?;
+    let key = encrypted.decrypt(password)
+        .map_err(|e| IotEdgeError::MqttTlsError(format!("decrypt {}: {}", path, e)))?;
+    Ok(PrivatePkcs8KeyDer::from(key.as_bytes().to_vec()).into())
+}
+
+/// Build the rustls client configuration, any unreadable or invalid file is an error.
+fn client_config(tls: &ConfigMqttTls) -> Result<Arc<ClientConfig>, IotEdgeError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&tls.ca)? {
+        roots.add(cert)
+            .map_err(|e| IotEdgeError::MqttTlsError(format!("CA {}: {}", tls.ca, e)))?;
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+
+    let mut config = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            let certs = load_certs(cert)?;
+            let key = load_key(key, tls.key_password.as_deref())?;
+            builder.with_client_auth_cert(certs, key)
+                .map_err(|e| IotEdgeError::MqttTlsError(format!("client certificate {}: {}", cert, e)))?
+        },
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(IotEdgeError::MqttTlsError("client_cert and client_key go together".to_string())),
+    };
+    config.alpn_protocols = tls.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+    Ok(Arc::new(config))
+}
+
+pub fn tls_config(tls: &ConfigMqttTls) -> Result<TlsConfiguration, IotEdgeError> {
+    Ok(TlsConfiguration::Rustls(client_config(tls)?))
+}
+
+/// A new directory in the temp dir that only this user can enter. Creating
+/// fails if the name is taken, so nobody else can have prepared it.
+fn private_dir() -> io::Result<PathBuf> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.subsec_nanos());
+    let dir = std::env::temp_dir().join(format!(
+        "iot-edge-mqtt-{}-{}-{:08x}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed), nanos));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// rumqttc sends the host it connects to as SNI and verifies the broker
+/// against it. A broker known by another name is reached through a unix
+/// socket in front of a TLS session to `host` that is set up for `server_name`.
+pub struct TlsTunnel {
+    pub path: PathBuf,
+    dir: PathBuf,
+    handle: JoinHandle<()>,
+}
+
+impl TlsTunnel {
+    pub fn new(host: &str, port: u16, tls: &ConfigMqttTls, server_name: &str) -> Result<Self, IotEdgeError> {
+        let name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| IotEdgeError::MqttTlsError(format!("server name {}: {}", server_name, e)))?;
+        let connector = TlsConnector::from(client_config(tls)?);
+
+        // the session carries the client certificate, the socket is out of
+        // reach of other users from the moment it exists
+        let dir = private_dir()?;
+        let path = dir.join("mqtt.sock");
+        let listener = UnixListener::bind(&path)?;
+
+        let addr = format!("{}:{}", host, port);
+        let handle = task::spawn(async move {
+            while let Ok((mut local, _)) = listener.accept().await {
+                let (connector, name, addr) = (connector.clone(), name.clone(), addr.clone());
+                task::spawn(async move {
+                    // a failed connect closes the socket and rumqttc retries
+                    let result = async {
+                        let tcp = TcpStream::connect(&addr).await?;
+                        let mut remote = connector.connect(name, tcp).await?;
+                        tokio::io::copy_bidirectional(&mut local, &mut remote).await
+                    }.await;
+                    if let Err(e) = result {
+                        debug!("tls tunnel to {}: {}", addr, e);
+                    }
+                });
+            }
+        });
+
+        Ok(TlsTunnel { path, dir, handle })
+    }
+}
+
+impl Drop for TlsTunnel {
+    fn drop(&mut self) {
+        self.handle.abort();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn test_tls_config_missing() {
+    let tls = ConfigMqttTls {
+        ca: "/nonexistent/ca.pem".to_string(),
+        client_cert: None,
+        client_key: None,
+        key_password: None,
+        alpn: vec![],
+        server_name: None,
+    };
+    assert!(matches!(tls_config(&tls), Err(IotEdgeError::MqttTlsError(_))));
+}
+
+#[test]
+fn test_tunnel_server_name() {
+    let tls = ConfigMqttTls {
+        ca: "/nonexistent/ca.pem".to_string(),
+        client_cert: None,
+        client_key: None,
+        key_password: None,
+        alpn: vec![],
+        server_name: Some("not a host name".to_string()),
+    };
+    let result = TlsTunnel::new("127.0.0.1", 8883, &tls, "not a host name");
+    assert!(matches!(result, Err(IotEdgeError::MqttTlsError(e)) if e.starts_with("server name")));
+}
+
+#[test]
+fn test_private_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = private_dir().unwrap();
+    assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+    assert_eq!(fs::DirBuilder::new().create(&dir).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    let other = private_dir().unwrap();
+    assert_ne!(other, dir);
+    fs::remove_dir(&dir).unwrap();
+    fs::remove_dir(&other).unwrap();
+}
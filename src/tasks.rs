@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::task::{self, JoinHandle};
+use log::error;
+use tokio::sync::{broadcast, mpsc::{channel, Sender}, watch, Mutex};
+
+use crate::config::{Config, ConfigCapture, PARQUET_TABLES};
+use crate::connect::{
+    CanTask, GpsTask, GpioTask, ModbusTask, SystemTask, ModemTask, SerialTask, UsbTask,
+};
+use crate::dbc::Dbc;
+use crate::errors::IotEdgeError;
+use crate::message::{GpsMessage, Message};
+use crate::output::{
+    Asc, Blf, Candump, CaptureFormat, CaptureFrame, CaptureStats, CaptureStream, CaptureTask, Control, Mdf, MqttStats, Pcapng,
+};
+use crate::upload::UploadTask;
+use crate::utils::can_devices;
+
+type Spawner = Box<dyn Fn() -> JoinHandle<()> + Send + Sync>;
+type NewFormat = fn() -> Box<dyn CaptureFormat>;
+
+/// Config sections that drive source tasks, a task is named after its
+/// section, with the instance after a colon when there can be several.
+pub const SECTIONS: [&str; 9] = ["can", "gpio", "modbus", "system", "serial", "modem", "gps", "upload", "usb"];
+
+/// What source tasks are started with besides their own config.
+#[derive(Clone)]
+pub struct Context {
+    pub tx: Sender<Message>,
+    pub ignition: Arc<watch::Sender<bool>>,
+    pub stats: Arc<MqttStats>,
+    pub capture: Arc<CaptureStats>,
+    pub control: Sender<Control>,
+    pub fixes: broadcast::Sender<GpsMessage>,     // every fix of the gps task, for the captures
+}
+
+/// Source tasks by name, kept with what it takes to start them again.
+#[derive(Default)]
+pub struct Tasks {
+    tasks: BTreeMap<String, (Spawner, JoinHandle<()>)>,
+}
+
+impl Tasks {
+    pub fn spawn<F>(&mut self, name: &str, spawner: F)
+    where
+        F: Fn() -> JoinHandle<()> + Send + Sync + 'static
+    {
+        let handle = spawner();
+        self.tasks.insert(name.to_string(), (Box::new(spawner), handle));
+    }
+
+    /// Stop the task and wait for it to let go of its device before starting it again.
+    pub async fn restart(&mut self, name: &str) -> Result<(), IotEdgeError> {
+        let (spawner, handle) = self.tasks.get_mut(name)
+            .ok_or(IotEdgeError::Generic("no such task"))?;
+        handle.abort();
+        let _ = (&mut *handle).await;
+        *handle = spawner();
+        Ok(())
+    }
+
+    /// Task names and whether they are still running.
+    pub fn status(&self) -> BTreeMap<String, bool> {
+        self.tasks.iter()
+            .map(|(name, (_, handle))| (name.clone(), !handle.is_finished()))
+            .collect()
+    }
+
+    /// Stop every task of a config section.
+    pub async fn stop_section(&mut self, section: &str) {
+        let prefix = format!("{}:", section);
+        let names: Vec<String> = self.tasks.keys()
+            .filter(|name| (*name == section) | name.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for name in names {
+            if let Some((_, handle)) = self.tasks.remove(&name) {
+                handle.abort();
+                let _ = handle.await;
+            }
+        }
+    }
+
+    /// A capture file task, returns where the CAN tasks send it frames.
+    fn spawn_capture<F>(
+        &mut self, name: &str, config: &ConfigCapture, format: F, gps: Option<broadcast::Sender<GpsMessage>>
+    ) -> Sender<CaptureFrame>
+    where
+        F: Fn() -> Box<dyn CaptureFormat> + Send + Sync + 'static
+    {
+        let (tx, rx) = channel(4096);
+        let (rx, config) = (Arc::new(Mutex::new(rx)), config.clone());
+        self.spawn(&format!("can:{}", name), move || {
+            let task = CaptureTask::new(&config, format(), rx.clone());
+            task::spawn(match &gps {
+                Some(gps) => task.with_gps(gps.subscribe()),
+                None => task,
+            }.run())
+        });
+        tx
+    }
+
+    /// Start the tasks a config section asks for.
+    pub fn spawn_section(&mut self, section: &str, config: &Config, context: &Context) {
+        match section {
+            "can" => {
+                let can_config = config.can_config();
+                // one capture file of each format for all interfaces, as candump -l any writes it
+                let formats: [(&str, _, NewFormat); 4] = [
+                    ("candump", &can_config.candump, || Box::new(Candump)),
+                    ("asc", &can_config.asc, || Box::new(Asc::default())),
+                    ("blf", &can_config.blf, || Box::new(Blf::default())),
+                    ("pcapng", &can_config.pcapng, || Box::new(Pcapng::default())),
+                ];
+                let mut captures = vec![];
+                for (name, capture_config, format) in formats {
+                    if let Some(capture_config) = capture_config {
+                        captures.push(self.spawn_capture(name, capture_config, format, None));
+                    }
+                }
+                if let Some(mf4_config) = &can_config.mf4 {
+                    let dbc = Dbc::open_all(&can_config.dbc).unwrap_or_else(|e| {
+                        error!("can: dbc: {}", e);
+                        Dbc::default()
+                    });
+                    // fixes from the gps task, they keep coming when it restarts
+                    let gps = config.gps.as_ref().map(|_| context.fixes.clone());
+                    let format = move || Box::new(Mdf::new(dbc.clone())) as Box<dyn CaptureFormat>;
+                    captures.push(self.spawn_capture("mf4", mf4_config, format, gps));
+                }
+                if let Some(listen) = can_config.pcapng_listen.clone() {
+                    let (tx, rx) = channel(4096);
+                    let rx = Arc::new(Mutex::new(rx));
+                    self.spawn("can:pcapng-stream", move || {
+                        task::spawn(CaptureStream::new(&listen, || Box::new(Pcapng::default()), rx.clone()).run())
+                    });
+                    captures.push(tx);
+                }
+                for dev in can_devices() {
+                    let (out, frequency, captures) = (context.tx.clone(), can_config.frequency, captures.clone());
+                    let stats = context.capture.clone();
+                    self.spawn(&format!("can:{}", dev), move || {
+                        let (dev, out, captures, stats) = (dev.clone(), out.clone(), captures.clone(), stats.clone());
+                        task::spawn(async move {
+                            let mut can_task = CanTask::new(&dev, out, frequency, captures, stats);
+                            can_task.run().await;
+                        })
+                    });
+                }
+            },
+            "gpio" => {
+                let gpio_config = config.gpio_config();
+                for line in gpio_config.lines.iter() {
+                    let out = context.tx.clone();
+                    let ignition = match line.ignition {
+                        true => Some(context.ignition.clone()),
+                        false => None,
+                    };
+                    let (chip, line, debounce) = (gpio_config.chip.clone(), line.clone(), gpio_config.debounce);
+                    self.spawn(&format!("gpio:{}", line.name), move || {
+                        let (chip, line, out, ignition) = (chip.clone(), line.clone(), out.clone(), ignition.clone());
+                        task::spawn(async move {
+                            match GpioTask::new(&chip, &line, debounce, out, ignition) {
+                                Ok(mut gpio_task) => gpio_task.run().await,
+                                Err(e) => error!("gpio {}: {}", line.name, e),
+                            }
+                        })
+                    });
+                }
+            },
+            "modbus" => {
+                for modbus_config in config.modbus_config() {
+                    let out = context.tx.clone();
+                    self.spawn(&format!("modbus:{}", modbus_config.name), move || {
+                        let (modbus_config, out) = (modbus_config.clone(), out.clone());
+                        task::spawn(async move {
+                            let task = ModbusTask::new(&modbus_config, out);
+                            task.run().await;
+                        })
+                    });
+                }
+            },
+            "system" => {
+                let out = context.tx.clone();
+                let (system_config, log_config) = (config.system_config(), config.log_config());
+                let (stats, capture) = (context.stats.clone(), context.capture.clone());
+                self.spawn("system", move || {
+                    let (system_config, path) = (system_config.clone(), log_config.path.clone());
+                    let (out, stats, capture) = (out.clone(), stats.clone(), capture.clone());
+                    task::spawn(async move {
+                        let mut task = SystemTask::new(&system_config, &path, out, stats, capture);
+                        task.run().await;
+                    })
+                });
+            },
+            "serial" => {
+                for serial_config in config.serial_config() {
+                    let out = context.tx.clone();
+                    self.spawn(&format!("serial:{}", serial_config.name), move || {
+                        let (serial_config, out) = (serial_config.clone(), out.clone());
+                        task::spawn(async move {
+                            let task = SerialTask::new(&serial_config, out);
+                            task.run().await;
+                        })
+                    });
+                }
+            },
+            "modem" => {
+                if let Some(modem_config) = config.modem_config() {
+                    let out = context.tx.clone();
+                    self.spawn("modem", move || {
+                        let (modem_config, out) = (modem_config.clone(), out.clone());
+                        task::spawn(async move {
+                            let task = ModemTask::new(&modem_config, out);
+                            task.run().await;
+                        })
+                    });
+                }
+            },
+            "gps" => {
+                let (gps_config, out, fixes) = (config.gps_config(), context.tx.clone(), context.fixes.clone());
+                self.spawn("gps", move || {
+                    let (gps_config, out, fixes) = (gps_config.clone(), out.clone(), fixes.clone());
+                    task::spawn(async move {
+                        let task = GpsTask::new(&gps_config, out, fixes);
+                        task.run().await;
+                    })
+                });
+            },
+            "upload" => {
+                if let Some(upload_config) = config.upload_config() {
+                    let (log_config, device) = (config.log_config(), config.id());
+                    let parquet = config.parquet_config().filter(|_| upload_config.parquet);
+                    self.spawn("upload", move || {
+                        let mut task = UploadTask::new(&upload_config, &log_config, &device);
+                        if let Some(parquet) = &parquet {
+                            for table in PARQUET_TABLES {
+                                task = task.with_log(parquet.table(table), &format!(".{}.parquet", table));
+                            }
+                        }
+                        task::spawn(task.run())
+                    });
+                }
+            },
+            "usb" => {
+                if let Some(usb_config) = config.usb_config() {
+                    let (log_config, device) = (config.log_config(), config.id());
+                    let (out, control) = (context.tx.clone(), context.control.clone());
+                    self.spawn("usb", move || {
+                        task::spawn(UsbTask::new(&usb_config, &log_config, &device, out.clone(), control.clone()).run())
+                    });
+                }
+            },
+            _ => {},
+        }
+    }
+}
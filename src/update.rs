@@ -0,0 +1,301 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use log::*;
+use serde_json::{json, Value};
+use tokio::select;
+use tokio::sync::{mpsc::{Receiver, Sender}, oneshot, watch, Mutex};
+use tokio::time::{self, Duration};
+
+use crate::config::Config;
+use crate::output::{publish, Control};
+use crate::tasks::{Context, Tasks, SECTIONS};
+
+/// A new config to run with, whether it is in use or was refused is reported
+/// on `<topic>/<device_id>/config` under `source`.
+#[derive(Debug)]
+pub struct Update {
+    pub config: Config,
+    pub source: String,
+}
+
+/// Top level sections whose value differs between two configs.
+fn changed(old: &Config, new: &Config) -> Vec<String> {
+    let (old, new) = match (serde_json::to_value(old), serde_json::to_value(new)) {
+        (Ok(Value::Object(old)), Ok(Value::Object(new))) => (old, new),
+        _ => return vec![],
+    };
+    new.iter()
+        .filter(|(section, value)| old.get(*section) != Some(*value))
+        .map(|(section, _)| section.clone())
+        .collect()
+}
+
+/// Applies new configs to the running tasks, from commands or files dropped
+/// in `[update] watch_dir`, and goes back to the last good one when the
+/// broker can't be reached with the new one.
+pub struct UpdateTask {
+    config: Config,             // last good config, what is running
+    path: Option<PathBuf>,      // where the good config is saved
+    topic: String,              // results are reported here
+
+    rx: Receiver<Update>,
+    current: watch::Sender<Config>,
+    online: watch::Receiver<bool>,
+    control: Sender<Control>,
+    tasks: Arc<Mutex<Tasks>>,
+    context: Context,
+}
+
+impl UpdateTask {
+    pub fn new(
+        config: &Config, path: Option<PathBuf>, rx: Receiver<Update>, online: watch::Receiver<bool>,
+        control: Sender<Control>, tasks: Arc<Mutex<Tasks>>, context: Context
+    ) -> Self {
+        let topic = format!("{}/{}/config", config.mqtt_config().topic, config.id());
+        let (current, _) = watch::channel(config.clone());
+
+        UpdateTask {
+            config: config.clone(),
+            path,
+            topic,
+
+            rx,
+            current,
+            online,
+            control,
+            tasks,
+            context,
+        }
+    }
+
+    /// Follows the config in use.
+    pub fn current(&self) -> watch::Receiver<Config> {
+        self.current.subscribe()
+    }
+
+    /// Switch the running tasks from `old` to `new`, returns the changed
+    /// sections only a restart picks up.
+    async fn apply(&self, old: &Config, new: &Config) -> Result<Vec<String>, String> {
+        let sections = changed(old, new);
+        let mut restart = vec![];
+
+        let mut tasks = self.tasks.lock().await;
+        for section in SECTIONS.iter().filter(|s| sections.iter().any(|c| c == *s)) {
+            info!("config: restarting {}", section);
+            tasks.stop_section(section).await;
+            tasks.spawn_section(section, new, &self.context);
+        }
+        drop(tasks);
+
+        if sections.iter().any(|s| (s == "mqtt") | (s == "log")) {
+            info!("config: reconnecting output");
+            let (tx, rx) = oneshot::channel();
+            let mqtt = Box::new(new.mqtt_config());
+            self.control.send(Control::Reconfigure(mqtt, new.log_config(), tx)).await
+                .map_err(|_| "output is gone".to_string())?;
+            rx.await.map_err(|_| "output is gone".to_string())??;
+        }
+        // the command topic and queue are set up once
+        if old.mqtt_config().topic != new.mqtt_config().topic {
+            restart.push("mqtt.topic".to_string());
+        }
+        for section in ["device_id", "queue", "command", "update", "parquet"] {
+            if sections.iter().any(|s| s == section) {
+                restart.push(section.to_string());
+            }
+        }
+
+        Ok(restart)
+    }
+
+    /// Wait for the output to get through to the broker.
+    async fn reconnected(&mut self, timeout: u64) -> bool {
+        let online = &mut self.online;
+        time::timeout(Duration::from_secs(timeout), async {
+            while !*online.borrow_and_update() {
+                if online.changed().await.is_err() {
+                    return false;
+                }
+            }
+            true
+        }).await.unwrap_or(false)
+    }
+
+    async fn update(&mut self, config: Config) -> Result<Value, String> {
+        config.validate()?;
+        let sections = changed(&self.config, &config);
+        if sections.is_empty() {
+            return Ok(json!({ "applied": sections }));
+        }
+
+        let old = self.config.clone();
+        let applied = self.apply(&old, &config).await;
+
+        let timeout = config.update_config().rollback_timeout;
+        let failure = match &applied {
+            Err(e) => Some(e.clone()),
+            // only a reconnected output can lose the broker
+            Ok(_) if sections.iter().any(|s| (s == "mqtt") | (s == "log")) => {
+                match self.reconnected(timeout).await {
+                    true => None,
+                    false => Some(format!("broker not reached within {}s", timeout)),
+                }
+            },
+            Ok(_) => None,
+        };
+        if let Some(e) = failure {
+            warn!("config: {}, rolling back", e);
+            if let Err(e) = self.apply(&config, &old).await {
+                error!("config: rollback failed: {}", e);
+            }
+            return Err(format!("{}, rolled back", e));
+        }
+
+        if let Some(path) = &self.path {
+            config.save(path)?;
+        }
+        self.config = config.clone();
+        self.current.send_replace(config);
+        info!("config: applied {}", sections.join(", "));
+
+        Ok(json!({ "applied": sections, "restart": applied? }))
+    }
+
+    async fn report(&self, source: &str, result: &Result<Value, String>) {
+        let report = match result {
+            Ok(result) => json!({ "source": source, "ok": true, "result": result }),
+            Err(e) => json!({ "source": source, "ok": false, "error": e }),
+        };
+        let data = serde_json::to_vec(&report).unwrap();
+        if let Err(e) = publish(&self.control, &self.topic, data).await {
+            error!("dropped config report: {}", e);
+        }
+    }
+
+    /// Apply every *.toml in `dir` in name order, then move it to applied/ or rejected/.
+    async fn scan(&mut self, dir: &Path) {
+        let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.is_file() & path.extension().is_some_and(|e| e == "toml"))
+                .collect(),
+            Err(e) => {
+                warn!("config: {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        files.sort();
+
+        for file in files {
+            let name = file.file_name().unwrap().to_string_lossy().to_string();
+            info!("config: applying {}", name);
+            let result = match Config::load(&file) {
+                Ok(config) => self.update(config).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &result {
+                warn!("config: {} rejected: {}", name, e);
+            }
+
+            let done = dir.join(match result.is_ok() {
+                true => "applied",
+                false => "rejected",
+            });
+            let moved = fs::create_dir_all(&done).and_then(|_| fs::rename(&file, done.join(&name)));
+            if let Err(e) = moved {
+                // left in place it would be applied again on every scan
+                error!("config: moving {}: {}", name, e);
+                let _ = fs::remove_file(&file);
+            }
+            self.report(&name, &result).await;
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut interval = time::interval(Duration::from_secs(self.config.update_config().interval));
+        loop {
+            select! {
+                // without a command task only the watch directory is left
+                Some(Update { config, source }) = self.rx.recv() => {
+                    let result = self.update(config).await;
+                    self.report(&source, &result).await;
+                },
+                _ = interval.tick() => {
+                    if let Some(dir) = self.config.update_config().watch_dir {
+                        self.scan(Path::new(&dir)).await;
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[test]
+fn test_changed() {
+    let old = Config::parse("device_id = \"a\"\n[gps]\nhost = \"127.0.0.1\"\nport = 2947\n").unwrap();
+    let mut new = old.clone();
+    assert!(changed(&old, &new).is_empty());
+
+    new.device_id = "b".to_string();
+    new.gps = None;
+    new.log = Some(Default::default());
+    let mut sections = changed(&old, &new);
+    sections.sort();
+    assert_eq!(sections, vec!["device_id", "gps", "log"]);
+}
+
+#[tokio::test]
+async fn test_rollback() {
+    use tokio::sync::{broadcast, mpsc::channel};
+    use crate::config::{ConfigMqtt, ConfigUpdate};
+    use crate::output::{CaptureStats, MqttStats};
+
+    let old = Config::parse("device_id = \"a\"\n[update]\nrollback_timeout = 1\n").unwrap();
+    let (control, mut output) = channel(16);
+    let (online_tx, online) = watch::channel(false);
+    let context = Context {
+        tx: channel(1).0, ignition: Arc::new(watch::channel(true).0), stats: Arc::new(MqttStats::default()),
+        capture: Arc::new(CaptureStats::default()), control: control.clone(), fixes: broadcast::channel(1).0,
+    };
+    let (_updates, rx) = channel(1);
+    let mut task = UpdateTask::new(&old, None, rx, online, control, Arc::new(Mutex::new(Tasks::default())), context);
+
+    // the output takes every config, the broker is never reached
+    let hosts = tokio::spawn(async move {
+        let mut hosts = vec![];
+        while let Some(control) = output.recv().await {
+            match control {
+                Control::Reconfigure(mqtt, _, reply) => {
+                    hosts.push(mqtt.host);
+                    let _ = reply.send(Ok(()));
+                },
+                Control::Publish(_, _, reply) => { let _ = reply.send(Ok(())); },
+                _ => {},
+            }
+        }
+        hosts
+    });
+
+    let mut new = old.clone();
+    new.mqtt = Some(ConfigMqtt { host: "10.0.0.1".to_string(), ..Default::default() });
+    let result = task.update(new.clone()).await;
+    assert_eq!(result, Err("broker not reached within 1s, rolled back".to_string()));
+    assert!(changed(&task.config, &old).is_empty());
+    assert!(changed(&task.current().borrow(), &old).is_empty());
+
+    // offline, a change the output does not see is applied without waiting
+    let mut other = old.clone();
+    other.update = Some(ConfigUpdate { rollback_timeout: 1, interval: 60, ..Default::default() });
+    assert!(task.update(other.clone()).await.is_ok());
+    assert!(changed(&task.config, &other).is_empty());
+
+    // back online, the broker is reached with the new config
+    online_tx.send_replace(true);
+    new.update = other.update.clone();
+    assert!(task.update(new.clone()).await.is_ok());
+    assert!(changed(&task.config, &new).is_empty());
+
+    drop(task);
+    assert_eq!(hosts.await.unwrap(), vec!["10.0.0.1", "127.0.0.1", "10.0.0.1"]);
+}
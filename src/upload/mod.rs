@@ -0,0 +1,533 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use log::*;
+use bytesize::ByteSize;
+use chrono::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio::time::{self, Duration};
+
+mod s3;
+
+use s3::{Part, S3};
+
+use crate::config::{AfterUpload, ConfigLog, ConfigUpload};
+use crate::errors::IotEdgeError;
+use crate::output::closed_logs;
+
+/// S3 takes at most this many parts per upload.
+const MAX_PARTS: u64 = 10000;
+
+/// A rotated log file already linked into the spool.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Seen {
+    ino: u64,
+    size: u64,
+    mtime: i64,
+}
+
+/// What the spool remembers between runs, in `<spool>/state.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    seq: u64,           // last sequence number handed out
+    seen: Vec<Seen>,
+}
+
+/// A multipart upload in progress, in `<spool>/<name>.json` until it completes.
+#[derive(Debug, Serialize, Deserialize)]
+struct Progress {
+    key: String,
+    upload_id: String,
+    part_size: u64,
+    parts: Vec<Part>,
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let text = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+/// Replace the file at `path`, a crash leaves either the old or the new one.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), IotEdgeError> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(value).unwrap())?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// An upload the store no longer knows is started over next time.
+fn forget(progress: &Path, e: IotEdgeError) -> IotEdgeError {
+    if let IotEdgeError::S3Error(_, code) = &e {
+        if code == "NoSuchUpload" {
+            let _ = fs::remove_file(progress);
+        }
+    }
+    e
+}
+
+/// Object key for a spool file named `<seq>-<YYYYmmddTHHMMSS><ext>`.
+fn object_key(template: &str, device: &str, name: &str) -> Option<String> {
+    let (seq, rest) = name.split_once('-')?;
+    let time = NaiveDateTime::parse_from_str(rest.get(..15)?, "%Y%m%dT%H%M%S").ok()?;
+    let ext = &rest[15..];
+
+    Some(template
+        .replace("{device}", device)
+        .replace("{date}", &time.format("%Y-%m-%d").to_string())
+        .replace("{time}", &time.format("%H%M%S").to_string())
+        .replace("{seq}", seq)
+        .replace("{ext}", ext))
+}
+
+/// Ships closed log files to S3 or MinIO. Rotated files are hard linked into
+/// the spool so the rotation can't take them away before they are uploaded,
+/// and are deleted or archived from there once the store has them.
+pub struct UploadTask {
+    config: ConfigUpload,
+    device: String,
+    logs: Vec<(PathBuf, String)>,   // and the extension their spool files get
+    spool: PathBuf,
+    archive: PathBuf,
+    part_size: u64,
+    max_spool: u64,
+}
+
+impl UploadTask {
+    pub fn new(config: &ConfigUpload, log: &ConfigLog, device: &str) -> Self {
+        // hard links need the log's filesystem
+        let spool = match &config.spool {
+            Some(spool) => PathBuf::from(spool),
+            None => Path::new(&log.path).parent().unwrap_or(Path::new("")).join("upload"),
+        };
+        let archive = match &config.archive {
+            Some(archive) => PathBuf::from(archive),
+            None => spool.join("archive"),
+        };
+
+        UploadTask {
+            config: config.clone(),
+            device: device.to_string(),
+            logs: vec![(PathBuf::from(&log.path), ".log".to_string())],
+            spool,
+            archive,
+            part_size: ByteSize::from_str(&config.part_size).unwrap().as_u64(),
+            max_spool: ByteSize::from_str(&config.max_spool).unwrap().as_u64(),
+        }
+    }
+
+    /// Closed files of another rotated log, spooled with `ext` after their time.
+    pub fn with_log(mut self, path: PathBuf, ext: &str) -> Self {
+        self.logs.push((path, ext.to_string()));
+        self
+    }
+
+    /// Link rotated log files the spool has not seen yet, oldest first.
+    fn collect(&self) -> Result<(), IotEdgeError> {
+        fs::create_dir_all(&self.spool)?;
+        let state_path = self.spool.join("state.json");
+        let mut state: State = read_json(&state_path).unwrap_or_default();
+
+        // a crash between linking and saving the state leaves a link the state
+        // does not know, the shared inode gives it away
+        let mut spooled = BTreeSet::new();
+        for name in self.pending()? {
+            spooled.insert(fs::metadata(self.spool.join(&name))?.ino());
+            if let Some(seq) = name.split_once('-').and_then(|(seq, _)| seq.parse().ok()) {
+                state.seq = state.seq.max(seq);
+            }
+        }
+
+        let mut files = vec![];
+        for (log, ext) in self.logs.iter() {
+            for closed in closed_logs(log)? {
+                let meta = fs::metadata(&closed.path)?;
+                let seen = Seen { ino: meta.ino(), size: meta.len(), mtime: meta.mtime() };
+                files.push((closed, ext, seen));
+            }
+        }
+
+        for (closed, ext, seen) in files.iter() {
+            if state.seen.contains(seen) {
+                continue;
+            }
+            if spooled.contains(&seen.ino) {
+                state.seen.push(seen.clone());
+                continue;
+            }
+            // named after the period the file covers when it has one
+            let time = match closed.period {
+                Some((start, _)) => start,
+                None => Utc.timestamp_opt(seen.mtime, 0).single().unwrap_or_else(Utc::now),
+            };
+            let name = format!("{:08}-{}{}{}", state.seq + 1, time.format("%Y%m%dT%H%M%S"), ext, closed.compression);
+            fs::hard_link(&closed.path, self.spool.join(&name))?;
+            info!("upload: {} spooled as {}", closed.path.display(), name);
+            state.seq += 1;
+            state.seen.push(seen.clone());
+        }
+        // forget files the rotation has deleted, their inodes come back
+        state.seen.retain(|seen| files.iter().any(|(_, _, file)| file == seen));
+
+        write_json(&state_path, &state)?;
+        self.evict()
+    }
+
+    /// Drop the oldest spool files while the spool holds more than `max_spool`.
+    fn evict(&self) -> Result<(), IotEdgeError> {
+        let mut files = vec![];
+        for name in self.pending()? {
+            let size = fs::metadata(self.spool.join(&name))?.len();
+            files.push((name, size));
+        }
+
+        let mut total: u64 = files.iter().map(|(_, size)| size).sum();
+        for (name, size) in files {
+            if total <= self.max_spool {
+                break;
+            }
+            warn!("upload: spool over {}, dropped {}", ByteSize::b(self.max_spool), name);
+            fs::remove_file(self.spool.join(&name))?;
+            let _ = fs::remove_file(self.spool.join(format!("{}.json", name)));
+            total -= size;
+        }
+        Ok(())
+    }
+
+    /// Part size for a file of `size`, larger than configured when the parts would run out.
+    fn part_size(&self, size: u64) -> u64 {
+        self.part_size.max(size.div_ceil(MAX_PARTS))
+    }
+
+    /// Spool files waiting for upload, in sequence.
+    fn pending(&self) -> Result<Vec<String>, IotEdgeError> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.spool)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let ours = !name.ends_with(".json") & !name.ends_with(".tmp");
+            if entry.file_type()?.is_file() & ours & object_key("", "", &name).is_some() {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    async fn multipart(&self, s3: &S3, key: &str, path: &Path, size: u64) -> Result<(), IotEdgeError> {
+        let progress_path = PathBuf::from(format!("{}.json", path.display()));
+        let part_size = self.part_size(size);
+        let mut progress = match read_json::<Progress>(&progress_path) {
+            Some(progress) if (progress.key == key) & (progress.part_size == part_size) => {
+                info!("upload: resuming {} after part {}", key, progress.parts.len());
+                progress
+            },
+            _ => {
+                let upload_id = s3.create_multipart(key).await?;
+                let progress = Progress { key: key.to_string(), upload_id, part_size, parts: vec![] };
+                write_json(&progress_path, &progress)?;
+                progress
+            }
+        };
+
+        let mut file = File::open(path).await?;
+        let parts = size.div_ceil(part_size) as u32;
+        for number in (progress.parts.len() as u32 + 1)..=parts {
+            let offset = (number as u64 - 1) * part_size;
+            let mut body = vec![0; part_size.min(size - offset) as usize];
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.read_exact(&mut body).await?;
+
+            let etag = s3.upload_part(key, &progress.upload_id, number, body).await
+                .map_err(|e| forget(&progress_path, e))?;
+            progress.parts.push(Part { number, etag });
+            write_json(&progress_path, &progress)?;
+        }
+
+        s3.complete_multipart(key, &progress.upload_id, &progress.parts).await
+            .map_err(|e| forget(&progress_path, e))?;
+        let _ = fs::remove_file(&progress_path);
+        Ok(())
+    }
+
+    /// Upload one spool file and move it out of the spool.
+    async fn upload(&self, s3: &S3, name: &str) -> Result<(), IotEdgeError> {
+        let key = object_key(&self.config.key_template, &self.device, name)
+            .ok_or(IotEdgeError::Generic("unexpected file in spool"))?;
+        let path = self.spool.join(name);
+        let size = fs::metadata(&path)?.len();
+
+        match size > self.part_size {
+            true => self.multipart(s3, &key, &path, size).await?,
+            false => s3.put(&key, tokio::fs::read(&path).await?).await?,
+        }
+        info!("upload: {} ({} bytes) stored as {}", name, size, key);
+
+        match self.config.after {
+            AfterUpload::DELETE => fs::remove_file(&path)?,
+            AfterUpload::ARCHIVE => {
+                fs::create_dir_all(&self.archive)?;
+                fs::rename(&path, self.archive.join(name))?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Spool what the log rotated and upload everything waiting, stops at
+    /// the first failure so files go up in order.
+    pub async fn round(&self, s3: &S3) -> Result<usize, IotEdgeError> {
+        self.collect()?;
+        let mut uploaded = 0;
+        for name in self.pending()? {
+            self.upload(s3, &name).await?;
+            uploaded += 1;
+        }
+        Ok(uploaded)
+    }
+
+    pub async fn run(self) {
+        let s3 = match S3::new(&self.config) {
+            Ok(s3) => s3,
+            Err(e) => {
+                error!("upload: {}", e);
+                return;
+            }
+        };
+
+        let mut interval = time::interval(Duration::from_secs(self.config.interval));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.round(&s3).await {
+                warn!("upload: {}, retrying in {}s", e, self.config.interval);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_object_key() {
+    let key = object_key("{device}/{date}/{seq}{ext}", "truck-7", "00000042-20220415T101010.log");
+    assert_eq!(key.as_deref(), Some("truck-7/2022-04-15/00000042.log"));
+
+    let key = object_key("{device}/{date}/{time}-{seq}{ext}", "truck-7", "00000043-20220415T235959.log.gz");
+    assert_eq!(key.as_deref(), Some("truck-7/2022-04-15/235959-00000043.log.gz"));
+
+    assert_eq!(object_key("{seq}", "truck-7", "state.json"), None);
+}
+
+#[test]
+fn test_collect() {
+    let dir = std::env::temp_dir().join(format!("iot-edge-upload-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let log = ConfigLog { path: dir.join("iot-edge.log").to_string_lossy().to_string(), ..Default::default() };
+    let config: ConfigUpload = toml::from_str(&format!(r#"
+        endpoint = "http://127.0.0.1:9000"
+        bucket = "iot-edge"
+        access_key = "minioadmin"
+        secret_key = "minioadmin"
+        spool = "{}"
+    "#, dir.join("spool").display())).unwrap();
+    let task = UploadTask::new(&config, &log, "truck-7");
+
+    fs::write(dir.join("iot-edge.log"), "open\n").unwrap();
+    fs::write(dir.join("iot-edge.log.2"), "older\n").unwrap();
+    fs::write(dir.join("iot-edge.log.1"), "newer\n").unwrap();
+    task.collect().unwrap();
+    task.collect().unwrap();
+
+    let pending = task.pending().unwrap();
+    assert_eq!(pending.len(), 2);
+    assert!(pending[0].starts_with("00000001-"));
+    assert_eq!(fs::read_to_string(dir.join("spool").join(&pending[0])).unwrap(), "older\n");
+
+    // a rotation renames, the spool has those already
+    fs::rename(dir.join("iot-edge.log.2"), dir.join("iot-edge.log.3")).unwrap();
+    fs::rename(dir.join("iot-edge.log.1"), dir.join("iot-edge.log.2")).unwrap();
+    fs::write(dir.join("iot-edge.log.1"), "newest\n").unwrap();
+    task.collect().unwrap();
+    let pending = task.pending().unwrap();
+    assert_eq!(pending.len(), 3);
+    assert_eq!(fs::read_to_string(dir.join("spool").join(&pending[2])).unwrap(), "newest\n");
+
+    // other logs keep their own extension
+    let task = task.with_log(dir.join("export-can.parquet"), ".can.parquet");
+    fs::write(dir.join("export-can.parquet.1"), "PAR1").unwrap();
+    task.collect().unwrap();
+    let pending = task.pending().unwrap();
+    assert!(pending[3].starts_with("00000004-") & pending[3].ends_with(".can.parquet"));
+    assert_eq!(object_key("{seq}{ext}", "truck-7", &pending[3]).as_deref(), Some("00000004.can.parquet"));
+
+    // linked, then a crash before the state was saved
+    fs::remove_file(dir.join("spool").join("state.json")).unwrap();
+    fs::write(dir.join("export-can.parquet.2"), "PAR2").unwrap();
+    task.collect().unwrap();
+    let pending = task.pending().unwrap();
+    assert_eq!(pending.len(), 5);
+    assert!(pending[4].starts_with("00000005-"));
+
+    // the oldest go when the spool is full
+    let task = UploadTask { max_spool: 12, ..task };
+    task.collect().unwrap();
+    let pending = task.pending().unwrap();
+    assert_eq!(pending.len(), 2);
+    assert!(pending[0].starts_with("00000004-"));
+
+    // parts grow rather than run out
+    assert_eq!(task.part_size(1 << 20), 8 * 1000 * 1000);
+    assert!((100u64 << 30).div_ceil(task.part_size(100 << 30)) <= MAX_PARTS);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Just enough of a store for `round`: puts, multipart uploads, and a 500
+/// for the first try of part 2.
+#[cfg(test)]
+async fn fake_store(
+    listener: tokio::net::TcpListener, objects: std::sync::Arc<std::sync::Mutex<std::collections::BTreeMap<String, Vec<u8>>>>
+) {
+    use std::collections::BTreeMap;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let mut parts: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+    let mut failed = false;
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                break;
+            }
+            let mut words = line.split_whitespace();
+            let (method, target) = (words.next().unwrap().to_string(), words.next().unwrap().to_string());
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await.unwrap();
+
+            let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+            let (status, etag, answer) = match (method.as_str(), query) {
+                ("POST", "uploads=") => (200, None, "<UploadId>u1</UploadId>".to_string()),
+                ("PUT", query) if query.starts_with("partNumber=") => {
+                    let number: u32 = query[11..].split('&').next().unwrap().parse().unwrap();
+                    match (number, failed) {
+                        (2, false) => {
+                            failed = true;
+                            (500, None, "<Error><Code>InternalError</Code></Error>".to_string())
+                        },
+                        _ => {
+                            parts.insert(number, body);
+                            (200, Some(format!("\"e{}\"", number)), String::new())
+                        },
+                    }
+                },
+                ("POST", _) => {
+                    let object = std::mem::take(&mut parts).into_values().flatten().collect();
+                    objects.lock().unwrap().insert(path.to_string(), object);
+                    (200, None, "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".to_string())
+                },
+                _ => {
+                    objects.lock().unwrap().insert(path.to_string(), body);
+                    (200, None, String::new())
+                },
+            };
+            let etag = etag.map(|etag| format!("ETag: {}\r\n", etag)).unwrap_or_default();
+            let response = format!("HTTP/1.1 {} X\r\n{}Content-Length: {}\r\n\r\n{}", status, etag, answer.len(), answer);
+            stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_round() {
+    use std::sync::{Arc, Mutex};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let objects = Arc::new(Mutex::new(Default::default()));
+    tokio::spawn(fake_store(listener, objects.clone()));
+
+    let dir = std::env::temp_dir().join(format!("iot-edge-round-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let log = ConfigLog { path: dir.join("iot-edge.log").to_string_lossy().to_string(), ..Default::default() };
+    let config: ConfigUpload = toml::from_str(&format!(r#"
+        endpoint = "{}"
+        bucket = "iot-edge"
+        access_key = "minioadmin"
+        secret_key = "minioadmin"
+        key_template = "{{device}}/{{seq}}{{ext}}"
+        part_size = "1KiB"
+        after = "ARCHIVE"
+    "#, endpoint)).unwrap();
+    let task = UploadTask::new(&config, &log, "test");
+    assert_eq!(task.spool, dir.join("upload"));
+
+    fs::write(dir.join("iot-edge.log.2"), "small\n").unwrap();
+    let large: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    fs::write(dir.join("iot-edge.log.1"), &large).unwrap();
+    let s3 = S3::new(&config).unwrap();
+
+    // the small file goes up, the large one stops at part 2 and resumes there
+    assert!(task.round(&s3).await.is_err());
+    assert_eq!(task.pending().unwrap().len(), 1);
+    assert_eq!(task.round(&s3).await.unwrap(), 1);
+    assert!(task.pending().unwrap().is_empty());
+    assert_eq!(fs::read_dir(dir.join("upload").join("archive")).unwrap().count(), 2);
+
+    let objects = objects.lock().unwrap();
+    assert_eq!(objects["/iot-edge/test/00000001.log"], b"small\n");
+    assert_eq!(objects["/iot-edge/test/00000002.log"], large);
+    drop(objects);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Against a local MinIO, e.g. `docker run -p 9000:9000 minio/minio server /data`
+/// with a bucket made by `mc mb local/iot-edge`.
+#[tokio::test]
+#[ignore]
+async fn test_minio() {
+    let endpoint = std::env::var("S3_ENDPOINT").unwrap_or_else(|_| "http://127.0.0.1:9000".to_string());
+    let dir = std::env::temp_dir().join(format!("iot-edge-minio-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let log = ConfigLog { path: dir.join("iot-edge.log").to_string_lossy().to_string(), ..Default::default() };
+    let config: ConfigUpload = toml::from_str(&format!(r#"
+        endpoint = "{}"
+        bucket = "iot-edge"
+        access_key = "minioadmin"
+        secret_key = "minioadmin"
+        part_size = "5MiB"
+        spool = "{}"
+        after = "ARCHIVE"
+    "#, endpoint, dir.join("spool").display())).unwrap();
+    let task = UploadTask::new(&config, &log, "test");
+
+    fs::write(dir.join("iot-edge.log.2"), "small\n").unwrap();
+    fs::write(dir.join("iot-edge.log.1"), vec![b'x'; 12 * 1024 * 1024]).unwrap();
+    let s3 = S3::new(&config).unwrap();
+    assert_eq!(task.round(&s3).await.unwrap(), 2);
+    assert!(task.pending().unwrap().is_empty());
+    assert_eq!(fs::read_dir(dir.join("spool").join("archive")).unwrap().count(), 2);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
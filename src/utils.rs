@@ -1,5 +1,8 @@
+use std::convert::TryInto;
 use pnet::datalink::{self, NetworkInterface};
 
+use crate::config::DataType;
+
 
 pub fn can_devices() -> Vec<String> {
     let iface_match =
@@ -13,3 +16,56 @@ pub fn can_devices() -> Vec<String> {
         .map(|iface| iface.name)
         .collect()
 }
+
+
+/// Number of a size rotated file `<base>.<n>` of the log `base`, and its
+/// compression extension.
+pub fn rotated<'a>(base: &str, name: &'a str) -> Option<(u64, &'a str)> {
+    let rest = name.strip_prefix(base)?.strip_prefix('.')?;
+    let (number, ext) = match rest.find('.') {
+        Some(dot) => rest.split_at(dot),
+        None => (rest, ""),
+    };
+    match ext {
+        "" | ".gz" | ".zst" => Some((number.parse().ok()?, ext)),
+        _ => None,
+    }
+}
+
+/// Decode one big-endian value of `data_type` from the head of `buf`.
+pub fn decode_value(data_type: &DataType, buf: &[u8]) -> Option<f64> {
+    let buf = buf.get(..data_type.size())?;
+
+    let value = match data_type {
+        DataType::BOOL => (buf[0] != 0) as u8 as f64,
+        DataType::U8 => buf[0] as f64,
+        DataType::I8 => buf[0] as i8 as f64,
+        DataType::U16 => u16::from_be_bytes(buf.try_into().ok()?) as f64,
+        DataType::I16 => i16::from_be_bytes(buf.try_into().ok()?) as f64,
+        DataType::U32 => u32::from_be_bytes(buf.try_into().ok()?) as f64,
+        DataType::I32 => i32::from_be_bytes(buf.try_into().ok()?) as f64,
+        DataType::F32 => f32::from_be_bytes(buf.try_into().ok()?) as f64,
+        DataType::F64 => f64::from_be_bytes(buf.try_into().ok()?),
+    };
+
+    Some(value)
+}
+
+#[test]
+fn test_decode_value() {
+    assert_eq!(decode_value(&DataType::U16, &[0x01, 0x02]), Some(258.0));
+    assert_eq!(decode_value(&DataType::I16, &[0xFF, 0x4C]), Some(-180.0));
+    assert_eq!(decode_value(&DataType::U32, &[0x00, 0x01, 0x00, 0x00]), Some(65536.0));
+    assert_eq!(decode_value(&DataType::F32, &1.5f32.to_be_bytes()), Some(1.5));
+    assert_eq!(decode_value(&DataType::BOOL, &[0x01]), Some(1.0));
+    assert_eq!(decode_value(&DataType::U32, &[0x00, 0x01]), None);
+}
+
+#[test]
+fn test_rotated() {
+    assert_eq!(rotated("iot-edge.log", "iot-edge.log.3"), Some((3, "")));
+    assert_eq!(rotated("iot-edge.log", "iot-edge.log.3.gz"), Some((3, ".gz")));
+    assert_eq!(rotated("iot-edge.log", "iot-edge.log"), None);
+    assert_eq!(rotated("iot-edge.log", "iot-edge.log.tmp"), None);
+    assert_eq!(rotated("iot-edge.log", "iot-edge.log.1.tmp"), None);
+}